@@ -0,0 +1,83 @@
+// Relay buffers from the pool against allocating each one, time and allocations per buffer:
+//   cargo bench --bench buffer_pool
+// Its unit tests come along without a harness to run them
+#[allow(dead_code, unused_imports)]
+#[path = "../src/buffer_pool.rs"]
+mod buffer_pool;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use buffer_pool::Pool;
+
+// Counts the allocations of the whole process
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// The default relay_buffer_size
+const SIZE: usize = 8 * 1024;
+const ROUNDS: u64 = 200_000;
+
+// Two buffers per relay as with its two directions, each written to once like by the first read
+fn relay(pool: Option<&Pool>) {
+    match pool {
+        Some(pool) => {
+            let (mut up, mut down) = (pool.get(SIZE), pool.get(SIZE));
+            up[0] = 1;
+            down[SIZE - 1] = 1;
+            black_box((&up, &down));
+        }
+        None => {
+            let (mut up, mut down) = (vec![0u8; SIZE].into_boxed_slice(), vec![0u8; SIZE].into_boxed_slice());
+            up[0] = 1;
+            down[SIZE - 1] = 1;
+            black_box((&up, &down));
+        }
+    }
+}
+
+fn run(name: &str, threads: u64, pool: Option<&'static Pool>) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let started = Instant::now();
+    let workers: Vec<_> = (0..threads).map(|_| thread::spawn(move || (0..ROUNDS / threads).for_each(|_| relay(pool)))).collect();
+    workers.into_iter().for_each(|worker| worker.join().unwrap());
+    let elapsed = started.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{name:>8}, {threads} threads: {:.0} relays/s, {:.0} ns per relay, {:.3} allocations per relay",
+        ROUNDS as f64 / elapsed.as_secs_f64(), elapsed.as_nanos() as f64 / ROUNDS as f64, allocations as f64 / ROUNDS as f64,
+    );
+}
+
+fn main() {
+    static POOL: Pool = Pool::new();
+    POOL.set_max(256);
+    for threads in [1, 8] {
+        run("unpooled", threads, None);
+        run("pooled", threads, Some(&POOL));
+    }
+    println!("pool: {} hits, {} misses", POOL.hits.load(Ordering::Relaxed), POOL.misses.load(Ordering::Relaxed));
+}
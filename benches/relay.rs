@@ -0,0 +1,76 @@
+// Throughput of a local transfer through the proxy, and the CPU time the proxy spent on it. Run it with and
+// without the splice relay to compare them:
+//   cargo bench --bench relay
+//   cargo bench --bench relay --features splice
+// ROCK5_BENCH_BYTES changes the amount, 10 GB by default
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CHUNK: usize = 1 << 20;
+
+// Counts what each connection sends, with a buffer big enough not to be what the transfer waits for
+fn sink() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            thread::spawn(move || {
+                let mut buf = vec![0u8; CHUNK];
+                let mut total = 0u64;
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    total += n as u64;
+                }
+                let _ = stream.write_all(&total.to_be_bytes());
+            });
+        }
+    });
+    addr
+}
+
+// User and system time of a process so far
+fn cpu_time(pid: u32) -> Duration {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).unwrap();
+    // The fields after the command, which may contain spaces itself
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+    let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+    let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    Duration::from_millis(ticks * 1000 / per_second)
+}
+
+fn main() {
+    let bytes: u64 = std::env::var("ROCK5_BENCH_BYTES").ok().and_then(|bytes| bytes.parse().ok()).unwrap_or(10_000_000_000);
+    let relay = if cfg!(feature = "splice") { "splice" } else { "copy" };
+    let sink = sink();
+    let proxy = Proxy::start("relay_buffer_size = 256KiB\n");
+    let mut stream = common::connect_through(proxy.addr, Dest::Addr(sink));
+    let chunk = vec![0x5au8; CHUNK];
+
+    let cpu_before = cpu_time(proxy.pid());
+    let started = Instant::now();
+    let mut left = bytes;
+    while left > 0 {
+        let n = left.min(CHUNK as u64) as usize;
+        stream.write_all(&chunk[..n]).unwrap();
+        left -= n as u64;
+    }
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut count = [0u8; 8];
+    stream.set_read_timeout(None).unwrap();
+    stream.read_exact(&mut count).unwrap();
+    let elapsed = started.elapsed();
+    let cpu = cpu_time(proxy.pid()) - cpu_before;
+    assert_eq!(u64::from_be_bytes(count), bytes);
+
+    let gb = bytes as f64 / 1e9;
+    println!(
+        "relay ({relay}): {gb:.1} GB in {:.2}s, {:.2} GB/s, proxy CPU {:.2}s ({:.3}s per GB)",
+        elapsed.as_secs_f64(), gb / elapsed.as_secs_f64(), cpu.as_secs_f64(), cpu.as_secs_f64() / gb,
+    );
+}
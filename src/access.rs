@@ -0,0 +1,67 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+use crate::config::{self, Config};
+use crate::privacy;
+use crate::rotate;
+use crate::session::Session;
+use crate::syslog;
+
+enum Output {
+    File(rotate::Writer),
+    // Records are sent with the tag <syslog_tag>-access
+    Syslog,
+}
+
+// Where the access_log goes, None when it is not configured
+static ACCESS_LOG: Mutex<Option<Output>> = Mutex::new(None);
+
+// (Re)open the access log, on start and on every reload so logrotate can move the file away
+pub fn open(cfg: &Config) {
+    let output = cfg.access_log().and_then(|path| {
+        if path == Path::new(config::ACCESS_LOG_SYSLOG) {
+            info!(" -> Sending access log to syslog");
+            return Some(Output::Syslog);
+        }
+        match rotate::Writer::open(path, cfg.log_rotate(), cfg.log_rotate_keep()) {
+            Ok(writer) => {
+                info!(" -> Writing access log to {:?}", path);
+                Some(Output::File(writer))
+            }
+            Err(e) => {
+                error!("Could not open access log {:?}: {}", path, e);
+                None
+            }
+        }
+    });
+    let old = std::mem::replace(&mut *ACCESS_LOG.lock().unwrap_or_else(|e| e.into_inner()), output);
+    // Flushes what it still has queued, outside the lock
+    drop(old);
+}
+
+// One line per connection, "-" for what is not known:
+// time id client user destination resolved reply sent received duration
+pub fn record(session: &Session, reply: Option<u8>, duration: Duration) {
+    let access_log = ACCESS_LOG.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(output) = access_log.as_ref() else { return };
+    let line = format!(
+        "{} {} {} {} {} {} {} {} {} {:.3}\n",
+        humantime::format_rfc3339_seconds(SystemTime::now()),
+        session.id,
+        privacy::client(session.peer, session.id),
+        session.user.as_deref().unwrap_or("-"),
+        session.target.as_deref().map_or("-".to_string(), |target| privacy::host_port(target, session.id)),
+        session.resolved.map(|addr| privacy::addr(addr, session.id).to_string()).unwrap_or_else(|| "-".to_string()),
+        reply.map(|code| code.to_string()).unwrap_or_else(|| "-".to_string()),
+        session.sent,
+        session.received,
+        duration.as_secs_f64(),
+    );
+    match output {
+        Output::File(writer) => writer.write(line),
+        // Syslog adds its own time
+        Output::Syslog => syslog::send(6, "-access", line.split_once(' ').map_or(line.as_str(), |(_, rest)| rest)),
+    }
+}
@@ -0,0 +1,95 @@
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, warn};
+
+use crate::privacy;
+use crate::stats::Stats;
+use crate::unix_socket;
+
+// Few clients at a time, an operator or a script
+const BACKLOG: i32 = 16;
+
+// Bind the admin socket, replacing a stale one left by a previous run.
+// It lists users and destinations, only for whoever runs rock5
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    unix_socket::bind(path, 0o600, (None, None), BACKLOG)
+}
+
+pub async fn serve(listener: UnixListener, stats: Arc<Stats>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Admin socket failed to accept: {}", e);
+                continue;
+            }
+        };
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = session(stream, &stats).await {
+                debug!("Admin connection failed: {}", e);
+            }
+        });
+    }
+}
+
+// One command per line, every reply ends with an empty line
+async fn session(stream: UnixStream, stats: &Stats) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => continue,
+            ["list"] => list(stats, false),
+            ["list", "json"] => list(stats, true),
+            _ => format!("error: unknown command {:?}, expected list [json]\n", line.trim()),
+        };
+        write.write_all(reply.as_bytes()).await?;
+        write.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    id: u64,
+    client: String,
+    user: Option<String>,
+    destination: Option<String>,
+    state: &'a str,
+    sent: u64,
+    received: u64,
+    age: f64,
+}
+
+// Tab separated: id client user destination state sent received age, "-" where unknown
+// or a JSON object per line
+fn list(stats: &Stats, json: bool) -> String {
+    let mut out = String::new();
+    for connection in stats.connections.list() {
+        let row = Row {
+            id: connection.id,
+            client: privacy::client(connection.peer, connection.id).to_string(),
+            // The username is whatever the client sent
+            user: connection.user().map(|user| user.replace(['\t', '\n'], " ")),
+            destination: connection.dest().map(|dest| privacy::host_port(&dest, connection.id)),
+            state: connection.state().name(),
+            sent: connection.sent.load(Ordering::Relaxed),
+            received: connection.received.load(Ordering::Relaxed),
+            age: (connection.started.elapsed().as_secs_f64() * 1000.0).round() / 1000.0,
+        };
+        if json {
+            out.push_str(&serde_json::to_string(&row).unwrap_or_default());
+        } else {
+            out.push_str(&format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.3}",
+                row.id, row.client, row.user.as_deref().unwrap_or("-"), row.destination.as_deref().unwrap_or("-"),
+                row.state, row.sent, row.received, row.age));
+        }
+        out.push('\n');
+    }
+    out
+}
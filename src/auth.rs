@@ -0,0 +1,181 @@
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use subtle::ConstantTimeEq;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::client_stream::ClientStream;
+use crate::config::AuthMode;
+use crate::privacy::Private;
+
+// Authentication methods (RFC 1928, section 3)
+pub const NO_AUTHENTICATION_REQUIRED: u8 = 0x00;
+pub const GSSAPI: u8 = 0x01;
+pub const USERNAME_PASSWORD: u8 = 0x02;
+pub const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+
+// RFC 1929 constants
+const USERPASS_VERSION: u8 = 0x01;
+const AUTH_SUCCESS: u8 = 0x00;
+const AUTH_FAILURE: u8 = 0x01;
+
+// [users] passwords starting with these are hashes (PHC string, modular crypt format)
+const ARGON2_PREFIX: &str = "$argon2";
+const BCRYPT_PREFIXES: [&str; 3] = ["$2a$", "$2b$", "$2y$"];
+
+// Pick the method to use from the ones offered by the client, None if none is acceptable
+pub fn select_method(mode: AuthMode, gssapi: bool, offered: &[u8]) -> Option<u8> {
+    // GSSAPI, when enabled, is preferred over everything else
+    if gssapi && offered.contains(&GSSAPI) {
+        return Some(GSSAPI);
+    }
+    let preference: &[u8] = match mode {
+        AuthMode::None => &[NO_AUTHENTICATION_REQUIRED],
+        AuthMode::Optional => &[USERNAME_PASSWORD, NO_AUTHENTICATION_REQUIRED],
+        AuthMode::Required => &[USERNAME_PASSWORD],
+    };
+    preference.iter().copied().find(|method| offered.contains(method))
+}
+
+// Run the username/password sub-negotiation, returns the authenticated user
+pub async fn userpass_auth<S: ClientStream>(stream: &mut S, client: Private<'static>, users: &HashMap<String, String>, strict: bool) -> io::Result<String> {
+    // +----+------+----------+------+----------+
+    // |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+    // +----+------+----------+------+----------+
+    // | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+    // +----+------+----------+------+----------+
+    let mut header = [0u8; 2]; // VER, ULEN
+    stream.read_exact(&mut header).await?;
+    if header[0] != USERPASS_VERSION {
+        warn!("Client {} sent unsupported auth version: {}", client, header[0]);
+        stream.write_all(&[USERPASS_VERSION, AUTH_FAILURE]).await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported auth version"));
+    }
+
+    let mut uname = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut uname).await?;
+
+    let mut plen = [0u8; 1];
+    stream.read_exact(&mut plen).await?;
+    let mut passwd = vec![0u8; plen[0] as usize];
+    stream.read_exact(&mut passwd).await?;
+
+    let uname = String::from_utf8_lossy(&uname).to_string();
+    let valid = match users.get(&uname) {
+        // Hashing takes long enough to hold up the other connections of the thread
+        Some(stored) if is_hash(stored) => {
+            let stored = stored.clone();
+            tokio::task::spawn_blocking(move || password_matches(&stored, &passwd)).await.unwrap_or(false)
+        }
+        Some(stored) => password_matches(stored, &passwd),
+        None => false,
+    };
+
+    // +----+--------+
+    // |VER | STATUS |
+    // +----+--------+
+    // | 1  |   1    |
+    // +----+--------+
+    if !valid {
+        warn!("Client {} failed authentication as '{}'", client, uname);
+        stream.write_all(&[USERPASS_VERSION, AUTH_FAILURE]).await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Authentication failed"));
+    }
+
+    // Strict: the request must only be sent once the status has been read
+    if strict && stream.has_pending_data() {
+        warn!("Strict: client {} sent data before the authentication reply (after PASSWD)", client);
+        stream.write_all(&[USERPASS_VERSION, AUTH_FAILURE]).await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Data sent before authentication reply"));
+    }
+
+    stream.write_all(&[USERPASS_VERSION, AUTH_SUCCESS]).await?;
+    info!("Client {} authenticated as '{}'", client, uname);
+    Ok(uname)
+}
+
+fn is_hash(stored: &str) -> bool {
+    stored.starts_with(ARGON2_PREFIX) || BCRYPT_PREFIXES.iter().any(|prefix| stored.starts_with(prefix))
+}
+
+// Whether a [users] password, plain or hashed, is what the client sent
+fn password_matches(stored: &str, passwd: &[u8]) -> bool {
+    if stored.starts_with(ARGON2_PREFIX) {
+        PasswordHash::new(stored).is_ok_and(|hash| Argon2::default().verify_password(passwd, &hash).is_ok())
+    } else if is_hash(stored) {
+        bcrypt::verify(passwd, stored).unwrap_or(false)
+    } else {
+        // Not telling by how long the comparison takes how much of it was right
+        stored.as_bytes().ct_eq(passwd).into()
+    }
+}
+
+// Why a [users] password that looks like a hash isn't one that can be checked
+pub fn check_password(stored: &str) -> Result<(), String> {
+    if stored.starts_with(ARGON2_PREFIX) {
+        match PasswordHash::new(stored) {
+            Ok(hash) if hash.hash.is_some() => Ok(()),
+            Ok(_) => Err("argon2 parameters without a hash".to_string()),
+            Err(e) => Err(format!("not an argon2 hash: {e}")),
+        }
+    } else if is_hash(stored) {
+        stored.parse::<bcrypt::HashParts>().map(|_| ()).map_err(|e| format!("not a bcrypt hash: {e}"))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFERS: [&[u8]; 4] = [&[0x00], &[0x02], &[0x00, 0x02], &[0x03, 0x80]];
+
+    fn selected(mode: AuthMode, gssapi: bool) -> Vec<Option<u8>> {
+        OFFERS.iter().map(|offered| select_method(mode, gssapi, offered)).collect()
+    }
+
+    #[test]
+    fn by_mode() {
+        assert_eq!(selected(AuthMode::None, false), [Some(0x00), None, Some(0x00), None]);
+        assert_eq!(selected(AuthMode::Optional, false), [Some(0x00), Some(0x02), Some(0x02), None]);
+        assert_eq!(selected(AuthMode::Required, false), [None, Some(0x02), Some(0x02), None]);
+        // The order of the offer doesn't matter
+        assert_eq!(select_method(AuthMode::Optional, false, &[0x02, 0x00]), Some(0x02));
+    }
+
+    #[test]
+    fn gssapi_first() {
+        for mode in [AuthMode::None, AuthMode::Optional, AuthMode::Required] {
+            assert_eq!(select_method(mode, true, &[0x00, 0x02, 0x01]), Some(GSSAPI), "{mode:?}");
+            assert_eq!(select_method(mode, false, &[0x01]), None, "{mode:?}");
+        }
+        assert_eq!(selected(AuthMode::Required, true), [None, Some(0x02), Some(0x02), None]);
+    }
+
+    // "secret", hashed with low costs
+    const ARGON2: &str = "$argon2id$v=19$m=256,t=1,p=1$cm9jazUtdGVzdC1zYWx0$7lFBVoiG9dR12ESjks6c2YD+iwuU4Q60+JFF7oOOcjg";
+    const BCRYPT: &str = "$2b$04$8r71KPpoeSePJN8dhQuGb.iyONnaDkECCUkgk.282Run3D.Odp4sK";
+
+    #[test]
+    fn passwords() {
+        for stored in ["secret", ARGON2, BCRYPT] {
+            assert!(password_matches(stored, b"secret"), "{stored}");
+            for wrong in [&b"Secret"[..], b"secre", b"secret2", b""] {
+                assert!(!password_matches(stored, wrong), "{stored} {wrong:?}");
+            }
+            assert_eq!(check_password(stored), Ok(()));
+        }
+        // Only what looks like a hash is taken as one
+        assert!(password_matches("$1$abc", b"$1$abc"));
+        assert!(!password_matches(ARGON2, ARGON2.as_bytes()));
+    }
+
+    #[test]
+    fn broken_hashes() {
+        for stored in ["$argon2id$", "$argon2id$v=19$m=19456", "$argon2id$v=19$m=x$c2FsdHNhbHQ$aGFzaA", "$2b$04$short", "$2b$"] {
+            assert!(check_password(stored).is_err(), "{stored}");
+            assert!(!password_matches(stored, stored.as_bytes()), "{stored}");
+        }
+    }
+}
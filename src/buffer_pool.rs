@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// Relay buffers, one per direction, taken when a relay starts and given back when it ends
+pub static RELAY: Pool = Pool::new();
+
+// Buffers of closed relays for the next ones, so short connections don't each allocate theirs.
+// Keeps at most max of them, by size as listeners may have their own relay_buffer_size.
+#[derive(Debug)]
+pub struct Pool {
+    free: Mutex<BTreeMap<usize, Vec<Box<[u8]>>>>,
+    max: AtomicUsize,
+    kept: AtomicUsize,
+    // Buffers taken from the pool and those that had to be allocated
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+// Goes back to its pool when dropped, if there is room
+pub struct Buffer<'a> {
+    pool: &'a Pool,
+    buf: Box<[u8]>,
+}
+
+impl Pool {
+    pub const fn new() -> Pool {
+        Pool { free: Mutex::new(BTreeMap::new()), max: AtomicUsize::new(0), kept: AtomicUsize::new(0), hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    // On start and reload, which starts over with an empty pool in case relay_buffer_size changed
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::Relaxed);
+        let mut free = self.free.lock().unwrap_or_else(|e| e.into_inner());
+        free.clear();
+        self.kept.store(0, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, size: usize) -> Buffer<'_> {
+        let pooled = {
+            let mut free = self.free.lock().unwrap_or_else(|e| e.into_inner());
+            let buf = free.get_mut(&size).and_then(|bufs| bufs.pop());
+            if buf.is_some() {
+                self.kept.fetch_sub(1, Ordering::Relaxed);
+            }
+            buf
+        };
+        let buf = match pooled {
+            Some(buf) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                vec![0u8; size].into_boxed_slice()
+            }
+        };
+        Buffer { pool: self, buf }
+    }
+
+    // Buffers waiting to be used again
+    pub fn kept(&self) -> usize {
+        self.kept.load(Ordering::Relaxed)
+    }
+
+    fn put(&self, buf: Box<[u8]>) {
+        let mut free = self.free.lock().unwrap_or_else(|e| e.into_inner());
+        if self.kept.load(Ordering::Relaxed) >= self.max.load(Ordering::Relaxed) {
+            return;
+        }
+        free.entry(buf.len()).or_default().push(buf);
+        self.kept.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Deref for Buffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for Buffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for Buffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misses_then_hits() {
+        let pool = Pool::new();
+        pool.set_max(4);
+        drop(pool.get(1024));
+        assert_eq!((pool.hits.load(Ordering::Relaxed), pool.misses.load(Ordering::Relaxed), pool.kept()), (0, 1, 1));
+        let buf = pool.get(1024);
+        assert_eq!(buf.len(), 1024);
+        assert_eq!((pool.hits.load(Ordering::Relaxed), pool.misses.load(Ordering::Relaxed), pool.kept()), (1, 1, 0));
+    }
+
+    #[test]
+    fn buffers_are_kept_by_size() {
+        let pool = Pool::new();
+        pool.set_max(4);
+        drop(pool.get(1024));
+        let buf = pool.get(2048);
+        assert_eq!(buf.len(), 2048);
+        assert_eq!((pool.hits.load(Ordering::Relaxed), pool.misses.load(Ordering::Relaxed), pool.kept()), (0, 2, 1));
+    }
+
+    #[test]
+    fn keeps_at_most_max() {
+        let pool = Pool::new();
+        // Nothing before the config says how many
+        drop(pool.get(16));
+        assert_eq!(pool.kept(), 0);
+        pool.set_max(2);
+        let bufs: Vec<_> = (0..3).map(|_| pool.get(16)).collect();
+        drop(bufs);
+        assert_eq!(pool.kept(), 2);
+    }
+
+    #[test]
+    fn set_max_starts_over() {
+        let pool = Pool::new();
+        pool.set_max(2);
+        drop(pool.get(16));
+        pool.set_max(2);
+        assert_eq!(pool.kept(), 0);
+        drop(pool.get(16));
+        assert_eq!(pool.hits.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,91 @@
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::config;
+
+// Command line options, they take precedence over the config file
+#[derive(Parser, Debug, Clone)]
+#[command(version, about = "A minimal SOCKS5 proxy")]
+pub struct Cli {
+    /// Address to listen on
+    #[arg(long, value_name = "ADDR")]
+    pub host: Option<String>,
+
+    /// Port to listen on
+    #[arg(long)]
+    pub port: Option<String>,
+
+    /// Config file to read instead of the default location, it must exist, - reads stdin
+    #[arg(long, value_name = "PATH", env = "ROCK5_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Apply the [profile.<NAME>] section of the config file on top of [config]
+    #[arg(long, value_name = "NAME", env = "ROCK5_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Config file format, by default toml for .toml files and ini otherwise
+    #[arg(long, value_name = "FORMAT")]
+    pub config_format: Option<config::ConfigFormat>,
+
+    /// Print the effective configuration and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Refuse to start when the config file has unknown sections or keys
+    #[arg(long)]
+    pub strict_config: bool,
+
+    /// Log level: error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+
+    /// More output, repeat for even more (-vv)
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Less output, repeat for even less (-qq)
+    #[arg(short, long, action = ArgAction::Count)]
+    pub quiet: u8,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Write a commented config with every key set to its default
+    GenConfig {
+        /// Print it instead of writing the default config file
+        #[arg(long)]
+        stdout: bool,
+
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+impl Cli {
+    pub fn parse_args() -> Cli {
+        let command = Cli::command().after_help(config::keys_help());
+        match Cli::from_arg_matches(&command.get_matches()) {
+            Ok(cli) => cli,
+            Err(e) => e.exit(),
+        }
+    }
+
+    // Config keys set on the command line
+    pub fn overrides(&self) -> Vec<(&'static str, String)> {
+        let mut overrides = Vec::new();
+        if let Some(host) = &self.host {
+            overrides.push(("host", host.clone()));
+        }
+        if let Some(port) = &self.port {
+            overrides.push(("port", port.clone()));
+        }
+        if let Some(log_level) = &self.log_level {
+            overrides.push(("log_level", log_level.clone()));
+        }
+        overrides
+    }
+}
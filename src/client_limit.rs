@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+
+// Open connections per client for max_connections_per_client, shared by all listeners.
+// An entry goes with the last connection of its client, so there are never more than connections open.
+#[derive(Debug, Default)]
+pub struct ClientLimits {
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+// One of the client's connections until dropped
+pub struct ClientSlot {
+    limits: Arc<ClientLimits>,
+    key: IpAddr,
+}
+
+impl ClientLimits {
+    // None when the client has max connections open already
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr, max: usize, ipv6_prefix: u8) -> Option<ClientSlot> {
+        let key = key(ip, ipv6_prefix);
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        let count = open.entry(key).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ClientSlot { limits: self.clone(), key })
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = open.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.key);
+            }
+        }
+    }
+}
+
+// IPv4 clients count by address, IPv6 ones by their network, privacy addresses change within it
+fn key(ip: IpAddr, ipv6_prefix: u8) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (u128::MAX.checked_shl(128 - u32::from(ipv6_prefix)).unwrap_or(0)))),
+        ip => ip,
+    }
+}
@@ -0,0 +1,75 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::AsFd;
+use tokio::io::{self, AsyncRead, AsyncWrite, Interest, Ready};
+use tokio::net::{TcpStream, UnixStream};
+
+use crate::config::Config;
+use crate::sockopt;
+use crate::strict;
+
+// A connection accepted by a TCP or a unix socket listener, the SOCKS negotiation runs over either
+pub trait ClientStream: AsyncRead + AsyncWrite + AsFd + Unpin + Send + Sync + 'static {
+    // TCP_NODELAY and keepalive, where there is TCP
+    fn tune(&self, cfg: &Config);
+    // The address the client reached us on, BIND and UDP ASSOCIATE announce it
+    fn local_ip(&self) -> io::Result<IpAddr>;
+    // For strict mode
+    fn has_pending_data(&self) -> bool;
+    // Both directions at once for the relay, without a lock between them
+    fn split(&mut self) -> (impl AsyncRead + Unpin + Send + '_, impl AsyncWrite + Unpin + Send + '_);
+    // For the splice relay, which does its own syscalls once the socket is ready, and tfo_connect's peek
+    fn ready(&self, interest: Interest) -> impl Future<Output = io::Result<Ready>> + Send;
+    fn try_io<R>(&self, interest: Interest, f: impl FnOnce() -> io::Result<R>) -> io::Result<R>;
+}
+
+impl ClientStream for TcpStream {
+    fn tune(&self, cfg: &Config) {
+        sockopt::tune(self, cfg, "client");
+    }
+
+    fn local_ip(&self) -> io::Result<IpAddr> {
+        // A dual-stack listener has IPv4 clients on ::ffff:a.b.c.d
+        Ok(self.local_addr()?.ip().to_canonical())
+    }
+
+    fn has_pending_data(&self) -> bool {
+        strict::has_pending_data(self)
+    }
+
+    fn split(&mut self) -> (impl AsyncRead + Unpin + Send + '_, impl AsyncWrite + Unpin + Send + '_) {
+        TcpStream::split(self)
+    }
+
+    fn ready(&self, interest: Interest) -> impl Future<Output = io::Result<Ready>> + Send {
+        TcpStream::ready(self, interest)
+    }
+
+    fn try_io<R>(&self, interest: Interest, f: impl FnOnce() -> io::Result<R>) -> io::Result<R> {
+        TcpStream::try_io(self, interest, f)
+    }
+}
+
+// Its clients are on this host, they reach the BIND and UDP relays over loopback
+impl ClientStream for UnixStream {
+    fn tune(&self, _cfg: &Config) {}
+
+    fn local_ip(&self) -> io::Result<IpAddr> {
+        Ok(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+
+    fn has_pending_data(&self) -> bool {
+        strict::has_pending_data(self)
+    }
+
+    fn split(&mut self) -> (impl AsyncRead + Unpin + Send + '_, impl AsyncWrite + Unpin + Send + '_) {
+        UnixStream::split(self)
+    }
+
+    fn ready(&self, interest: Interest) -> impl Future<Output = io::Result<Ready>> + Send {
+        UnixStream::ready(self, interest)
+    }
+
+    fn try_io<R>(&self, interest: Interest, f: impl FnOnce() -> io::Result<R>) -> io::Result<R> {
+        UnixStream::try_io(self, interest, f)
+    }
+}
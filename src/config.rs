@@ -1,70 +1,1984 @@
 use configparser::ini::Ini;
 use dirs::config_dir;
-use std::path::PathBuf;
+use hickory_resolver::Name;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Debug};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::auth;
+use crate::cli::Cli;
+use crate::policy::Cidr;
+use crate::resolver::{Hosts, Resolver};
+use crate::sockopt;
+use crate::unix_socket;
+use crate::units::{parse_duration, parse_rate, parse_size};
 
 const CFG_PATH: &str = "rock5/config.ini";
+const SYSTEM_CFG_PATH: &str = "/etc/rock5/config.ini";
+const LOCAL_CFG_PATH: &str = "rock5.ini";
+// --config - reads the config from stdin
+pub const STDIN_PATH: &str = "-";
+// [config] key merging further files
+const INCLUDE_KEY: &str = "include";
+// Keys naming a file, found relative to the config file that sets them
+const FILE_PATH_KEYS: &[&str] = &["blocked_domains_file"];
+// Value read from a file, e.g. file:/run/secrets/password
+const FILE_PREFIX: &str = "file:";
+// Shown instead of passwords and interpolated values
+const REDACTED: &str = "<redacted>";
 const MAIN_CFG: &str = "config";
+const USERS_CFG: &str = "users";
+// user = rate, per_connection_limit for that user's connections
+const USER_LIMITS_CFG: &str = "user_limits";
+const HOSTS_CFG: &str = "hosts";
+// [listener.<name>] sections
+const LISTENER_PREFIX: &str = "listener.";
+// [profile.<name>] sections, selected with --profile
+const PROFILE_PREFIX: &str = "profile.";
+// Keys that only make sense for the whole process
+const GLOBAL_ONLY: &[&str] = &["host", "port", "log_level", "log_format", "log_color", "log_timestamps", "log_privacy", "log_target", "syslog_address", "syslog_facility", "syslog_tag", "log_file", "log_stdout", "access_log", "log_rotate", "log_rotate_keep", "metrics_listen", "statsd_addr", "statsd_prefix", "statsd_tags", "health_listen", "health_mode", "admin_socket", "trace_sample_ratio", "runtime_metrics_interval", "max_connections", "bandwidth_limit", "watch_config", "drain_delay", "shutdown_timeout", "listen_backlog", "tfo_listen", "acceptors", "listen_family", "unix_socket_mode", "unix_socket_owner", "relay_buffer_pool"];
+// Ends the help of keys a reload doesn't apply, and what diff says of changes to them
+const RESTART_NOTE: &str = " (needs a restart)";
+// What the resolver is built from, listeners that keep all of them share the one of [config] and its dns_cache
+const RESOLVER_KEYS: &[&str] = &["dns", "doh_url", "doh_bootstrap", "doh_strict", "dot_server", "dot_hostname", "dot_strict", "dns_servers", "dns_search", "dns_fallback", "dns_cache", "dns_cache_ttl", "dns_cache_negative_ttl", "dns_cache_error_ttl", "dns_cache_size", "outbound_ipv4", "outbound_ipv6", "address_family"];
+const ENV_PREFIX: &str = "ROCK5_";
+// Longest dns_cache_negative_ttl and dns_cache_error_ttl
+const MAX_FAILURE_TTL: Duration = Duration::from_secs(60);
+// Name of the listener built from [config] host, port and listen
+pub const DEFAULT_LISTENER: &str = "default";
+
+// A key of the [config] section
+pub struct Key {
+    pub name: &'static str,
+    pub default: &'static str,
+    pub help: &'static str,
+}
+
+// Every supported key, the loader starts from these defaults
+pub const KEYS: &[Key] = &[
+    Key { name: "host", default: "0.0.0.0", help: "Address to listen on (needs a restart)" },
+    Key { name: "port", default: "1080", help: "Port to listen on (needs a restart)" },
+    Key { name: "listen", default: "", help: "Comma separated addr:port list to listen on instead of host and port, unix:<path> for a unix socket (needs a restart)" },
+    Key { name: "unix_socket_mode", default: "660", help: "Permissions of unix:<path> listen sockets, octal (needs a restart)" },
+    Key { name: "unix_socket_owner", default: "", help: "user, user:group or :group to hand unix:<path> listen sockets to, empty keeps the own (needs a restart)" },
+    Key { name: "listen_family", default: "dual", help: "What the wildcard hosts 0.0.0.0 and :: listen on: v4, v6 or dual (both) (needs a restart)" },
+    Key { name: "listen_backlog", default: "1024", help: "Connections the kernel queues for each listen socket until they are accepted, capped by net.core.somaxconn on Linux (needs a restart)" },
+    Key { name: "tfo_listen", default: "false", help: "TCP Fast Open on listen sockets, clients may send their greeting with the SYN (needs a restart)" },
+    Key { name: "acceptors", default: "0", help: "Sockets per listen address sharing it with SO_REUSEPORT, each with its own accept loop, 0 is one per worker thread (needs a restart)" },
+    Key { name: "max_connections", default: "0", help: "Connections handled at once, 0 is unlimited (needs a restart)" },
+    Key { name: "max_connections_action", default: "wait", help: "At max_connections: wait (stop accepting) or reject (refuse the request)" },
+    Key { name: "bandwidth_limit", default: "0", help: "Throughput of all relays together, both directions, e.g. 50Mbps or 5MB/s, 0 is unlimited" },
+    Key { name: "per_connection_limit", default: "0", help: "Throughput of each connection, per direction, e.g. 5Mbps, 0 is unlimited ([user_limits] overrides it per user)" },
+    Key { name: "max_connections_per_client", default: "0", help: "Connections one client may have open at once, 0 is unlimited" },
+    Key { name: "max_connections_per_client_action", default: "drop", help: "Beyond max_connections_per_client: drop (close right away) or reject (refuse the request)" },
+    Key { name: "client_ipv6_prefix", default: "64", help: "IPv6 clients count towards max_connections_per_client by network of this length" },
+    Key { name: "log_level", default: "info", help: "Log level: error, warn, info, debug or trace" },
+    Key { name: "log_format", default: "plain", help: "Log output: plain text or json, one object per line" },
+    Key { name: "log_color", default: "auto", help: "Colored levels on the console: auto (when it is a terminal), always or never" },
+    Key { name: "log_timestamps", default: "auto", help: "Console timestamps: auto (local on a terminal, none into the journal, rfc3339 otherwise), rfc3339 (UTC), local (short local time) or none" },
+    Key { name: "log_privacy", default: "none", help: "Client addresses and destinations in logs: none, partial (/24 or /48 networks, hashed names) or full (per connection tokens)" },
+    Key { name: "log_target", default: "console", help: "Where logs go: console (stdout and stderr), syslog or journald" },
+    Key { name: "syslog_address", default: "", help: "Syslog to send to: empty for /dev/log, a socket path, udp://host:port or tcp://host:port" },
+    Key { name: "syslog_facility", default: "daemon", help: "Syslog facility, e.g. daemon, user or local0 to local7" },
+    Key { name: "syslog_tag", default: "rock5", help: "Syslog tag, access log records get <tag>-access" },
+    Key { name: "log_file", default: "", help: "File to write the log to instead of the console, reopened on reload" },
+    Key { name: "log_stdout", default: "false", help: "Also log to the console when log_file is set" },
+    Key { name: "access_log", default: "", help: "File to append one line per connection to, reopened on reload, or syslog" },
+    Key { name: "log_rotate", default: "", help: "Rotate log files: daily, hourly (UTC) or size:<size>, empty never" },
+    Key { name: "log_rotate_keep", default: "7", help: "Rotated log files to keep, <file>.1 is the newest" },
+    Key { name: "metrics_listen", default: "", help: "addr:port to serve Prometheus metrics on (/metrics), empty disables (needs a restart)" },
+    Key { name: "statsd_addr", default: "", help: "host:port to send StatsD (DogStatsD) metrics to over UDP, empty disables (needs a restart)" },
+    Key { name: "statsd_prefix", default: "rock5", help: "Prefix of the StatsD metric names" },
+    Key { name: "statsd_tags", default: "", help: "DogStatsD tags added to every metric, comma separated, e.g. env:prod,dc:ams" },
+    Key { name: "health_listen", default: "", help: "addr:port for health checks (also served on metrics_listen), empty disables (needs a restart)" },
+    Key { name: "health_mode", default: "http", help: "health_listen serves: http (/healthz, /readyz) or tcp (accept and close while ready) (needs a restart)" },
+    Key { name: "admin_socket", default: "", help: "Unix socket for admin commands (list), empty disables (needs a restart)" },
+    Key { name: "trace_sample_ratio", default: "1", help: "Share of connections exported as traces, 0 to 1 (needs the otel feature)" },
+    Key { name: "runtime_metrics_interval", default: "10s", help: "How often the tokio_ metrics are sampled (needs the runtime-metrics feature)" },
+    Key { name: "watch_config", default: "false", help: "Reload automatically when the config file changes (needs a restart)" },
+    Key { name: "drain_delay", default: "0", help: "How long to keep accepting on SIGINT or SIGTERM once /readyz says not ready, so load balancers can move traffic away first" },
+    Key { name: "shutdown_timeout", default: "30s", help: "How long open connections get to finish on SIGINT or SIGTERM, 0 waits for all of them" },
+    Key { name: "auth", default: "", help: "Authentication: none, optional or required (required when [users] is not empty)" },
+    Key { name: "gssapi", default: "false", help: "Offer GSSAPI authentication (needs the gssapi feature)" },
+    Key { name: "socks4", default: "true", help: "Accept SOCKS4 and SOCKS4a clients" },
+    Key { name: "tor_resolve", default: "false", help: "Support the Tor RESOLVE and RESOLVE_PTR commands" },
+    Key { name: "bind_host", default: "0.0.0.0", help: "Address BIND listeners are bound to" },
+    Key { name: "bind_timeout", default: "60s", help: "How long to wait for the peer of a BIND request" },
+    Key { name: "handshake_timeout", default: "10s", help: "Time a client has to complete the handshake up to its request, 0 disables" },
+    Key { name: "happy_eyeballs_delay", default: "250ms", help: "Head start of each target address before the next one is tried alongside it, 0 tries them one after another" },
+    Key { name: "connect_timeout", default: "30s", help: "Time to wait for the target to accept the connection, 0 disables" },
+    Key { name: "tfo_connect", default: "false", help: "TCP Fast Open to targets: once the kernel has a cookie of the target, the reply goes out at once and the SYN carries the client's first bytes" },
+    Key { name: "connect_retries", default: "0", help: "Tries again after a refused or timed out connect before moving on to the next address of the target" },
+    Key { name: "connect_retry_delay", default: "100ms", help: "Wait before each of the connect_retries" },
+    Key { name: "idle_timeout", default: "0", help: "Close relays without traffic in either direction for this long, 0 disables" },
+    Key { name: "tcp_keepalive", default: "0", help: "Idle time before TCP keepalive probes on client and target connections, 0 disables" },
+    Key { name: "tcp_keepalive_interval", default: "0", help: "Time between keepalive probes, 0 keeps the system default" },
+    Key { name: "tcp_keepalive_retries", default: "0", help: "Unanswered keepalive probes before the connection is dropped, 0 keeps the system default" },
+    Key { name: "peer_probe_interval", default: "0", help: "Probe client and target once they sent nothing for this long and close the relay as peer_lost when one is gone for as long again, replaces tcp_keepalive, 0 disables" },
+    Key { name: "tcp_nodelay", default: "true", help: "Send small writes on client and target connections right away instead of batching them (Nagle)" },
+    Key { name: "loop_protection", default: "true", help: "Refuse CONNECT to the proxy's own listener" },
+    Key { name: "maintenance", default: "false", help: "Refuse every request with 'connection not allowed'" },
+    Key { name: "relay_buffer_size", default: "8KiB", help: "Relay buffer per direction, 4KiB to 4MiB (KB is 1000 bytes, KiB 1024)" },
+    Key { name: "relay_buffer_pool", default: "256", help: "Relay buffers of closed connections kept for new ones, 0 allocates every one" },
+    Key { name: "dns", default: "plain", help: "How target names are resolved: plain (the system resolver or dns_servers), doh (DNS-over-HTTPS to doh_url, needs the doh feature) or dot (DNS-over-TLS to dot_server, needs the dot feature)" },
+    Key { name: "doh_url", default: "", help: "DNS-over-HTTPS endpoint for dns = doh, like https://1.1.1.1/dns-query" },
+    Key { name: "doh_bootstrap", default: "", help: "Comma separated addresses of the doh_url server, needed when the URL names it by host name" },
+    Key { name: "doh_strict", default: "true", help: "Fail lookups while doh_url can't be reached instead of asking the plain resolver, which sends names in cleartext" },
+    Key { name: "dot_server", default: "", help: "Comma separated DNS-over-TLS servers (addr or addr:port, port 853 by default) for dns = dot" },
+    Key { name: "dot_hostname", default: "", help: "Name the dot_server certificates are checked for, empty checks for their address" },
+    Key { name: "dot_strict", default: "true", help: "Fail lookups while dot_server can't be reached instead of asking the plain resolver, which sends names in cleartext" },
+    Key { name: "dns_servers", default: "", help: "Comma separated nameservers (addr or addr:port) to use instead of the system resolver" },
+    Key { name: "dns_search", default: "", help: "Comma separated search domains for dns_servers" },
+    Key { name: "dns_fallback", default: "false", help: "Use the system resolver when a dns_servers lookup fails" },
+    Key { name: "dns_cache", default: "true", help: "Keep resolved target names for their TTL instead of asking again, off asks every time" },
+    Key { name: "dns_cache_ttl", default: "60s", help: "How long names from the system resolver are kept, it has no TTL to go by" },
+    Key { name: "dns_cache_negative_ttl", default: "10s", help: "How long names without addresses are kept, at most 60s, 0 asks again every time" },
+    Key { name: "dns_cache_error_ttl", default: "5s", help: "How long failed lookups, e.g. timeouts, are kept, at most 60s, 0 asks again every time" },
+    Key { name: "dns_cache_size", default: "1024", help: "Names kept at most, the least recently used are dropped first" },
+    Key { name: "outbound_ipv4", default: "true", help: "Connect to IPv4 targets" },
+    Key { name: "outbound_ipv6", default: "true", help: "Connect to IPv6 targets" },
+    Key { name: "address_family", default: "any", help: "any, prefer-ipv4 or prefer-ipv6 tries that family's addresses of a name first, only-ipv4 or only-ipv6 connects to no other" },
+    Key { name: "outbound_bind", default: "", help: "Source address for connections to targets, at most one IPv4 and one IPv6" },
+    Key { name: "outbound_dscp", default: "", help: "DSCP of the traffic to targets, a name like EF, AF41 or CS0 or 0 to 63, empty leaves it" },
+    Key { name: "client_dscp", default: "", help: "DSCP of the traffic back to clients, like outbound_dscp" },
+    Key { name: "outbound_fwmark", default: "0", help: "Firewall mark (SO_MARK) of connections to targets for policy routing, like 0x10, 0 sets none (Linux only)" },
+    Key { name: "outbound_interface", default: "", help: "Network interface connections to targets must leave through, like wg0 (Linux only)" },
+    Key { name: "allowed_clients", default: "", help: "Comma separated CIDR ranges clients may connect from, empty allows all" },
+    Key { name: "blocked_domains", default: "", help: "Comma separated domains to refuse, *.example.com blocks all subdomains" },
+    Key { name: "blocked_domains_file", default: "", help: "File with one blocked_domains rule per line, re-read on SIGHUP, relative to the config file setting it" },
+    Key { name: "strict", default: "false", help: "Enforce every MUST of RFC 1928" },
+    Key { name: "lenient_request_version", default: "false", help: "Accept any VER byte in the request header" },
+];
+
+// A config value and where it came from, for error messages
+#[derive(Debug, Clone)]
+struct Value {
+    value: String,
+    origin: String,
+    // Came from ${ENV} or file:, never shown
+    secret: bool,
+}
+
+type Values = HashMap<String, Value>;
+
+// Config file syntax, picked from the file extension unless given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Ini,
+    Toml,
+}
+
+// Where an entry was read, the line is unknown for entries that aren't on a line of their own
+#[derive(Debug, Clone, Default)]
+struct Source {
+    path: PathBuf,
+    line: Option<usize>,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{:?} line {}", self.path, line),
+            None => write!(f, "{:?}", self.path),
+        }
+    }
+}
+
+// Key, value and where it was read
+type Entries = Vec<(String, String, Source)>;
+// Name, value and where it was read, for [users], [user_limits] and [hosts]
+type Pairs = HashMap<String, (String, Source)>;
+
+// The [config], [users], [user_limits], [hosts], [listener.<name>] and [profile.<name>] sections of a file, in either format
+#[derive(Default)]
+struct FileConfig {
+    config: Entries,
+    users: Pairs,
+    user_limits: Pairs,
+    hosts: Pairs,
+    listeners: Vec<(String, Entries)>,
+    profiles: Vec<(String, Entries)>,
+    // Any other sections
+    sections: Vec<String>,
+    // The file, everything it includes and the include globs
+    files: Vec<PathBuf>,
+}
+
+impl FileConfig {
+    // Entries of other come after ours, so they win
+    fn merge(&mut self, other: FileConfig) {
+        self.config.extend(other.config);
+        self.users.extend(other.users);
+        self.user_limits.extend(other.user_limits);
+        self.hosts.extend(other.hosts);
+        for (sections, other_sections) in [(&mut self.listeners, other.listeners), (&mut self.profiles, other.profiles)] {
+            for (name, keys) in other_sections {
+                match sections.iter_mut().find(|(existing, _)| *existing == name) {
+                    Some((_, existing)) => existing.extend(keys),
+                    None => sections.push((name, keys)),
+                }
+            }
+        }
+        self.sections.extend(other.sections);
+        self.files.extend(other.files);
+    }
+}
+
+#[derive(Deserialize)]
+struct TomlFile {
+    #[serde(default)]
+    config: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    users: HashMap<String, String>,
+    #[serde(default)]
+    user_limits: HashMap<String, String>,
+    #[serde(default)]
+    hosts: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    listener: BTreeMap<String, BTreeMap<String, toml::Value>>,
+    #[serde(default)]
+    profile: BTreeMap<String, BTreeMap<String, toml::Value>>,
+    #[serde(flatten)]
+    other: BTreeMap<String, toml::Value>,
+}
+
+// Why the config could not be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    // The file is missing, unreadable or malformed
+    File(PathBuf, String),
+    // A value does not parse or is out of range
+    Invalid { value: String, origin: String, reason: String },
+    // Sections or keys the loader does not know, with --strict-config
+    Unknown(PathBuf, Vec<String>),
+    // --profile names no [profile.<name>] section, with the ones there are
+    Profile(String, Vec<String>),
+    // Settings that do not work together
+    Conflict(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::File(path, e) => write!(f, "cannot read config {path:?}: {e}"),
+            ConfigError::Invalid { value, origin, reason } => write!(f, "invalid value '{value}' for {origin} ({reason})"),
+            ConfigError::Unknown(path, entries) => write!(f, "unknown entries in config {path:?}: {}", entries.join(", ")),
+            ConfigError::Profile(name, available) if available.is_empty() => write!(f, "unknown profile '{name}', the config has no profiles"),
+            ConfigError::Profile(name, available) => write!(f, "unknown profile '{name}', available: {}", available.join(", ")),
+            ConfigError::Conflict(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// A [listener.<name>] section: its addresses and the settings its connections use
+#[derive(Debug)]
+pub struct Listener {
+    pub name: String,
+    pub addrs: Vec<String>,
+    pub cfg: Arc<Config>,
+}
+
+// Which authentication methods are acceptable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    // Only "No Authentication Required"
+    None,
+    // Username/password when the client offers it, anonymous otherwise
+    Optional,
+    // Username/password only
+    Required,
+}
+
+impl FromStr for AuthMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AuthMode::None),
+            "optional" => Ok(AuthMode::Optional),
+            "required" => Ok(AuthMode::Required),
+            _ => Err("expected none, optional or required"),
+        }
+    }
+}
+
+// Address families a wildcard listen address is bound for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenFamily {
+    V4,
+    V6,
+    Dual,
+}
+
+impl FromStr for ListenFamily {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v4" => Ok(ListenFamily::V4),
+            "v6" => Ok(ListenFamily::V6),
+            "dual" => Ok(ListenFamily::Dual),
+            _ => Err("expected v4, v6 or dual"),
+        }
+    }
+}
+
+// Which addresses of a target are tried first, or at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+    OnlyIpv4,
+    OnlyIpv6,
+}
+
+impl FromStr for AddressFamily {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(AddressFamily::Any),
+            "prefer-ipv4" => Ok(AddressFamily::PreferIpv4),
+            "prefer-ipv6" => Ok(AddressFamily::PreferIpv6),
+            "only-ipv4" => Ok(AddressFamily::OnlyIpv4),
+            "only-ipv6" => Ok(AddressFamily::OnlyIpv6),
+            _ => Err("expected any, prefer-ipv4, prefer-ipv6, only-ipv4 or only-ipv6"),
+        }
+    }
+}
+
+// How target names are resolved, plain is the system resolver or dns_servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DnsBackend {
+    Plain,
+    Doh,
+    Dot,
+}
+
+impl FromStr for DnsBackend {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(DnsBackend::Plain),
+            "doh" => Ok(DnsBackend::Doh),
+            "dot" => Ok(DnsBackend::Dot),
+            _ => Err("expected plain, doh or dot"),
+        }
+    }
+}
+
+// What happens to connections beyond max_connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitAction {
+    // Leave them in the listen backlog until a slot frees up
+    Wait,
+    // Complete the handshake and refuse the request with a general failure
+    Reject,
+}
+
+impl FromStr for LimitAction {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wait" => Ok(LimitAction::Wait),
+            "reject" => Ok(LimitAction::Reject),
+            _ => Err("expected wait or reject"),
+        }
+    }
+}
+
+// What happens to connections beyond max_connections_per_client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientLimitAction {
+    // Close them without a word
+    Drop,
+    // Complete the handshake and refuse the request with connection not allowed
+    Reject,
+}
+
+impl FromStr for ClientLimitAction {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(ClientLimitAction::Drop),
+            "reject" => Ok(ClientLimitAction::Reject),
+            _ => Err("expected drop or reject"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for LogLevel {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err("expected error, warn, info, debug or trace"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Plain,
+    // One JSON object per event, with the fields of its connection span
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected plain or json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogColor {
+    // When both stdout and stderr are terminals
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for LogColor {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(LogColor::Auto),
+            "always" => Ok(LogColor::Always),
+            "never" => Ok(LogColor::Never),
+            _ => Err("expected auto, always or never"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTimestamps {
+    // Local on a terminal, None when stderr is the journal, else Rfc3339
+    Auto,
+    Rfc3339,
+    // HH:MM:SS.mmm in the local timezone
+    Local,
+    // E.g. under systemd, the journal has its own
+    None,
+}
+
+impl FromStr for LogTimestamps {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(LogTimestamps::Auto),
+            "rfc3339" => Ok(LogTimestamps::Rfc3339),
+            "local" => Ok(LogTimestamps::Local),
+            "none" => Ok(LogTimestamps::None),
+            _ => Err("expected auto, rfc3339, local or none"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogPrivacy {
+    None,
+    // IPv4 addresses cut to their /24, IPv6 to their /48, host names hashed
+    Partial,
+    // client-<id> and dest-<id> instead
+    Full,
+}
+
+impl FromStr for LogPrivacy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(LogPrivacy::None),
+            "partial" => Ok(LogPrivacy::Partial),
+            "full" => Ok(LogPrivacy::Full),
+            _ => Err("expected none, partial or full"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTarget {
+    // Errors and warnings on stderr, the rest on stdout
+    Console,
+    Syslog,
+    // Needs the journald feature
+    Journald,
+}
+
+impl FromStr for LogTarget {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "console" => Ok(LogTarget::Console),
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" => Ok(LogTarget::Journald),
+            _ => Err("expected console, syslog or journald"),
+        }
+    }
+}
+
+// access_log value sending the records to syslog instead of a file
+pub const ACCESS_LOG_SYSLOG: &str = "syslog";
+const SYSLOG_SOCKET: &str = "/dev/log";
+// In code order, local0 to local7 are 16 to 23
+const SYSLOG_FACILITIES: &[&str] = &["kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogAddress {
+    Local(PathBuf),
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for SyslogAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let resolve = |addr: &str| addr.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("cannot resolve {addr}, expected host:port"));
+        if s.is_empty() {
+            Ok(SyslogAddress::Local(PathBuf::from(SYSLOG_SOCKET)))
+        } else if let Some(addr) = s.strip_prefix("udp://") {
+            Ok(SyslogAddress::Udp(resolve(addr)?))
+        } else if let Some(addr) = s.strip_prefix("tcp://") {
+            Ok(SyslogAddress::Tcp(resolve(addr)?))
+        } else if s.starts_with('/') {
+            Ok(SyslogAddress::Local(PathBuf::from(s)))
+        } else {
+            Err("expected a socket path, udp://host:port or tcp://host:port".to_string())
+        }
+    }
+}
+
+fn syslog_facility(value: &Value) -> Result<u8, ConfigError> {
+    let name = value.value.as_str();
+    if let Some(code) = SYSLOG_FACILITIES.iter().position(|facility| *facility == name) {
+        return Ok(code as u8);
+    }
+    match name.strip_prefix("local").and_then(|n| n.parse::<u8>().ok()) {
+        Some(n @ 0..=7) => Ok(16 + n),
+        _ => Err(invalid(value, "expected a facility like daemon, user or local0 to local7")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthMode {
+    // GET /healthz and /readyz
+    Http,
+    // Listening only while ready
+    Tcp,
+}
+
+impl FromStr for HealthMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(HealthMode::Http),
+            "tcp" => Ok(HealthMode::Tcp),
+            _ => Err("expected http or tcp"),
+        }
+    }
+}
+
+// When rock5 rotates the files it logs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+    // Before a write would grow the file beyond this many bytes
+    Size(u64),
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            _ => match s.strip_prefix("size:") {
+                Some(size) => match parse_size(size)? {
+                    0 => Err("size must be above 0".to_string()),
+                    size => Ok(Rotation::Size(size as u64)),
+                },
+                None => Err("expected daily, hourly or size:<size>".to_string()),
+            },
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Config {
     host: String,
-    port: i32,
+    port: u16,
+    listen: Vec<String>,
+    listen_family: ListenFamily,
+    unix_socket_mode: u32,
+    unix_socket_owner: (Option<u32>, Option<u32>),
+    listen_backlog: i32,
+    tfo_listen: bool,
+    acceptors: usize,
+    max_connections: usize,
+    max_connections_action: LimitAction,
+    bandwidth_limit: u64,
+    per_connection_limit: u64,
+    max_connections_per_client: usize,
+    max_connections_per_client_action: ClientLimitAction,
+    client_ipv6_prefix: u8,
+    log_level: LogLevel,
+    log_format: LogFormat,
+    log_color: LogColor,
+    log_timestamps: LogTimestamps,
+    log_privacy: LogPrivacy,
+    log_target: LogTarget,
+    syslog_address: SyslogAddress,
+    syslog_facility: u8,
+    syslog_tag: String,
+    log_file: Option<PathBuf>,
+    log_stdout: bool,
+    access_log: Option<PathBuf>,
+    log_rotate: Rotation,
+    log_rotate_keep: usize,
+    metrics_listen: Option<String>,
+    statsd_addr: Option<String>,
+    statsd_prefix: String,
+    statsd_tags: String,
+    health_listen: Option<String>,
+    health_mode: HealthMode,
+    admin_socket: Option<PathBuf>,
+    trace_sample_ratio: f64,
+    runtime_metrics_interval: Duration,
+    watch_config: bool,
+    drain_delay: Duration,
+    shutdown_timeout: Duration,
+    users: HashMap<String, String>,
+    user_limits: HashMap<String, u64>,
+    bind_host: String,
+    bind_timeout: Duration,
+    socks4: bool,
+    handshake_timeout: Duration,
+    happy_eyeballs_delay: Duration,
+    connect_timeout: Duration,
+    tfo_connect: bool,
+    connect_retries: u32,
+    connect_retry_delay: Duration,
+    idle_timeout: Duration,
+    tcp_keepalive: Duration,
+    tcp_keepalive_interval: Duration,
+    tcp_keepalive_retries: u32,
+    peer_probe_interval: Duration,
+    tor_resolve: bool,
+    auth: AuthMode,
+    tcp_nodelay: bool,
+    loop_protection: bool,
+    maintenance: bool,
+    relay_buffer_size: usize,
+    relay_buffer_pool: usize,
+    resolver: Resolver,
+    outbound_ipv4: bool,
+    outbound_ipv6: bool,
+    outbound_bind: Vec<IpAddr>,
+    outbound_interface: String,
+    outbound_fwmark: u32,
+    outbound_dscp: Option<u8>,
+    client_dscp: Option<u8>,
+    allowed_clients: Vec<Cidr>,
+    blocked_domains: Vec<String>,
+    strict: bool,
+    lenient_request_version: bool,
+    #[cfg(feature = "gssapi")]
+    gssapi: bool,
+    // Merged raw values, to report what a reload changed
+    raw: BTreeMap<String, Value>,
+    // The config file actually read, None when running on defaults
+    file: Option<PathBuf>,
+    // It, every included file and the include globs
+    files: Vec<PathBuf>,
+    // Selected [profile.<name>] section
+    profile: Option<String>,
+    listeners: Vec<Listener>,
+    // [users], [user_limits] and [hosts] entries, by section and name, whose name or value came from ${ENV} or file:
+    secret_names: HashSet<(&'static str, String)>,
+    secret_values: HashSet<(&'static str, String)>,
 }
 
 impl Config{
-    pub fn get_host_str (&mut self)-> String {format!("{}:{}", self.host, self.port)}    
-}
-
-pub fn get_config() -> Config {
-    let mut port: i32 = 1080;
-    let mut host: String = "0.0.0.0".to_string();
-    let cfg_opt = config_dir();
-    let mut cfg_path: PathBuf;
-    match cfg_opt {
-        None => {
-            cfg_path = PathBuf::new();
-        }
-        Some(path) => {
-            cfg_path = path;
-        }
-    }
-    cfg_path = cfg_path.join(CFG_PATH);
-    println!(" -> Trying to read config form {cfg_path:?}");
-
-    let mut config = Ini::new();
-    let map_res = config.load(cfg_path);
-
-    match map_res {
-        Ok(res) => {
-            let gco = res.get(MAIN_CFG);
-            if let Some(gc) = gco {
-                // Port
-                let kpo = gc.get("port");
-                if let Some(ppo) = kpo {
-                    if let Some(pstr) = ppo {
-                        let pparse = pstr.parse::<i32>();
-                        match pparse {
-                            Ok(pval) => {
-                                port = pval;
+    // IPv6 hosts in brackets, "::1" and 1080 make "[::1]:1080"
+    pub fn get_host_str (&mut self)-> String {
+        if self.host.contains(':') && !self.host.starts_with('[') { format!("[{}]:{}", self.host, self.port) } else { format!("{}:{}", self.host, self.port) }
+    }
+    // Every (listener name, address) to listen on; host and port unless listen or listener sections are set
+    pub fn listen_addrs(&mut self) -> Vec<(String, String)> {
+        let mut addrs: Vec<(String, String)> = self.listen.iter().map(|addr| (DEFAULT_LISTENER.to_string(), addr.clone())).collect();
+        if addrs.is_empty() && self.listeners.is_empty() {
+            addrs.push((DEFAULT_LISTENER.to_string(), self.get_host_str()));
+        }
+        for listener in &self.listeners {
+            addrs.extend(listener.addrs.iter().map(|addr| (listener.name.clone(), addr.clone())));
+        }
+        addrs
+    }
+    // Settings for connections accepted by the named listener
+    pub fn for_listener(self: &Arc<Self>, name: &str) -> Arc<Config> {
+        match self.listeners.iter().find(|listener| listener.name == name) {
+            Some(listener) => listener.cfg.clone(),
+            None => self.clone(),
+        }
+    }
+    pub fn listen_family(&self) -> ListenFamily {self.listen_family}
+    pub fn unix_socket_mode(&self) -> u32 {self.unix_socket_mode}
+    pub fn unix_socket_owner(&self) -> (Option<u32>, Option<u32>) {self.unix_socket_owner}
+    pub fn listen_backlog(&self) -> i32 {self.listen_backlog}
+    pub fn tfo_listen(&self) -> bool {self.tfo_listen}
+    pub fn acceptors(&self) -> usize {self.acceptors}
+    pub fn max_connections(&self) -> usize {self.max_connections}
+    pub fn max_connections_action(&self) -> LimitAction {self.max_connections_action}
+    pub fn bandwidth_limit(&self) -> u64 {self.bandwidth_limit}
+    // Of the user in [user_limits], else per_connection_limit
+    pub fn per_connection_limit(&self, user: Option<&str>) -> u64 {
+        user.and_then(|user| self.user_limits.get(user)).copied().unwrap_or(self.per_connection_limit)
+    }
+    pub fn max_connections_per_client(&self) -> usize {self.max_connections_per_client}
+    pub fn max_connections_per_client_action(&self) -> ClientLimitAction {self.max_connections_per_client_action}
+    pub fn client_ipv6_prefix(&self) -> u8 {self.client_ipv6_prefix}
+    pub fn log_level(&self) -> LogLevel {self.log_level}
+    pub fn log_format(&self) -> LogFormat {self.log_format}
+    pub fn log_color(&self) -> LogColor {self.log_color}
+    pub fn log_timestamps(&self) -> LogTimestamps {self.log_timestamps}
+    pub fn log_privacy(&self) -> LogPrivacy {self.log_privacy}
+    pub fn log_target(&self) -> LogTarget {self.log_target}
+    pub fn syslog_address(&self) -> &SyslogAddress {&self.syslog_address}
+    pub fn syslog_facility(&self) -> u8 {self.syslog_facility}
+    pub fn syslog_tag(&self) -> &str {&self.syslog_tag}
+    pub fn uses_syslog(&self) -> bool {
+        self.log_target == LogTarget::Syslog || self.access_log.as_deref() == Some(Path::new(ACCESS_LOG_SYSLOG))
+    }
+    pub fn log_file(&self) -> Option<&Path> {self.log_file.as_deref()}
+    pub fn log_stdout(&self) -> bool {self.log_stdout}
+    pub fn access_log(&self) -> Option<&Path> {self.access_log.as_deref()}
+    pub fn log_rotate(&self) -> Rotation {self.log_rotate}
+    pub fn log_rotate_keep(&self) -> usize {self.log_rotate_keep}
+    pub fn metrics_listen(&self) -> Option<&str> {self.metrics_listen.as_deref()}
+    pub fn statsd_addr(&self) -> Option<&str> {self.statsd_addr.as_deref()}
+    pub fn statsd_prefix(&self) -> &str {&self.statsd_prefix}
+    pub fn statsd_tags(&self) -> &str {&self.statsd_tags}
+    pub fn health_listen(&self) -> Option<&str> {self.health_listen.as_deref()}
+    pub fn health_mode(&self) -> HealthMode {self.health_mode}
+    pub fn admin_socket(&self) -> Option<&Path> {self.admin_socket.as_deref()}
+    #[cfg_attr(not(feature = "otel"), allow(dead_code))]
+    pub fn trace_sample_ratio(&self) -> f64 {self.trace_sample_ratio}
+    #[cfg_attr(not(feature = "runtime-metrics"), allow(dead_code))]
+    pub fn runtime_metrics_interval(&self) -> Duration {self.runtime_metrics_interval}
+    pub fn watch_config(&self) -> bool {self.watch_config}
+    pub fn drain_delay(&self) -> Duration {self.drain_delay}
+    pub fn shutdown_timeout(&self) -> Duration {self.shutdown_timeout}
+    pub fn files(&self) -> &[PathBuf] {&self.files}
+    pub fn users(&self) -> &HashMap<String, String> {&self.users}
+    pub fn bind_host(&self) -> &str {&self.bind_host}
+    pub fn bind_timeout(&self) -> Duration {self.bind_timeout}
+    pub fn socks4(&self) -> bool {self.socks4}
+    pub fn handshake_timeout(&self) -> Duration {self.handshake_timeout}
+    pub fn happy_eyeballs_delay(&self) -> Duration {self.happy_eyeballs_delay}
+    pub fn connect_timeout(&self) -> Duration {self.connect_timeout}
+    pub fn tfo_connect(&self) -> bool {self.tfo_connect}
+    pub fn connect_retries(&self) -> u32 {self.connect_retries}
+    pub fn connect_retry_delay(&self) -> Duration {self.connect_retry_delay}
+    pub fn idle_timeout(&self) -> Duration {self.idle_timeout}
+    pub fn tcp_keepalive(&self) -> Duration {self.tcp_keepalive}
+    pub fn tcp_keepalive_interval(&self) -> Duration {self.tcp_keepalive_interval}
+    pub fn tcp_keepalive_retries(&self) -> u32 {self.tcp_keepalive_retries}
+    pub fn peer_probe_interval(&self) -> Duration {self.peer_probe_interval}
+    pub fn tor_resolve(&self) -> bool {self.tor_resolve}
+    pub fn auth(&self) -> AuthMode {self.auth}
+    pub fn tcp_nodelay(&self) -> bool {self.tcp_nodelay}
+    pub fn loop_protection(&self) -> bool {self.loop_protection}
+    pub fn maintenance(&self) -> bool {self.maintenance}
+    pub fn relay_buffer_size(&self) -> usize {self.relay_buffer_size}
+    pub fn relay_buffer_pool(&self) -> usize {self.relay_buffer_pool}
+    pub fn resolver(&self) -> &Resolver {&self.resolver}
+    // Whether targets of this family may be connected to
+    pub fn outbound_allowed(&self, target: IpAddr) -> bool {
+        if target.is_ipv4() { self.outbound_ipv4 } else { self.outbound_ipv6 }
+    }
+    // The outbound_bind address of the target's family
+    pub fn outbound_bind_for(&self, target: IpAddr) -> Option<IpAddr> {
+        self.outbound_bind.iter().copied().find(|source| source.is_ipv4() == target.is_ipv4())
+    }
+    pub fn outbound_interface(&self) -> Option<&str> {
+        (!self.outbound_interface.is_empty()).then_some(self.outbound_interface.as_str())
+    }
+    pub fn outbound_fwmark(&self) -> Option<u32> {
+        (self.outbound_fwmark != 0).then_some(self.outbound_fwmark)
+    }
+    pub fn outbound_dscp(&self) -> Option<u8> {self.outbound_dscp}
+    pub fn client_dscp(&self) -> Option<u8> {self.client_dscp}
+    pub fn allowed_clients(&self) -> &[Cidr] {&self.allowed_clients}
+    pub fn blocked_domains(&self) -> &[String] {&self.blocked_domains}
+    pub fn strict(&self) -> bool {self.strict}
+    pub fn lenient_request_version(&self) -> bool {self.lenient_request_version}
+    #[cfg(feature = "gssapi")]
+    pub fn gssapi(&self) -> bool {self.gssapi}
+    #[cfg(not(feature = "gssapi"))]
+    pub fn gssapi(&self) -> bool {false}
+
+    // Build the config from the merged key/value layers
+    fn from_values(values: &Values, users: HashMap<String, String>, hosts: Hosts) -> Result<Config, ConfigError> {
+        // Without an explicit mode, configured users make authentication required
+        let auth = match values["auth"].value.as_str() {
+            "" => if users.is_empty() { AuthMode::None } else { AuthMode::Required },
+            _ => parse(values, "auth")?,
+        };
+        let gssapi: bool = parse(values, "gssapi")?;
+        #[cfg(not(feature = "gssapi"))]
+        if gssapi {
+            return Err(ConfigError::Conflict(format!("{} enables gssapi but rock5 was built without the gssapi feature", values["gssapi"].origin)));
+        }
+        let log_target: LogTarget = parse(values, "log_target")?;
+        #[cfg(not(feature = "journald"))]
+        if log_target == LogTarget::Journald {
+            return Err(ConfigError::Conflict(format!("{} logs to journald but rock5 was built without the journald feature", values["log_target"].origin)));
+        }
+        let trace_sample_ratio: f64 = parse(values, "trace_sample_ratio")?;
+        if !(0.0..=1.0).contains(&trace_sample_ratio) {
+            return Err(invalid(&values["trace_sample_ratio"], "expected a ratio from 0 to 1"));
+        }
+        let client_ipv6_prefix: u8 = parse(values, "client_ipv6_prefix")?;
+        if !(1..=128).contains(&client_ipv6_prefix) {
+            return Err(invalid(&values["client_ipv6_prefix"], "expected a prefix length from 1 to 128"));
+        }
+        let runtime_metrics_interval = duration(values, "runtime_metrics_interval")?;
+        if runtime_metrics_interval.is_zero() {
+            return Err(invalid(&values["runtime_metrics_interval"], "must be above 0"));
+        }
+        if auth == AuthMode::Required && users.is_empty() && !gssapi {
+            return Err(ConfigError::Conflict(format!("auth is required ({}) but there are no [users]", values["auth"].origin)));
+        }
+
+        let port: u16 = parse(values, "port")?;
+        if port == 0 {
+            return Err(invalid(&values["port"], "port 0 is not allowed"));
+        }
+        let host = &values["host"];
+        if host.value.is_empty() {
+            return Err(invalid(host, "empty host"));
+        }
+        // A bracketed IPv6 address is fine too, get_host_str keeps the brackets
+        let literal = host.value.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(&host.value);
+        match (literal, port).to_socket_addrs() {
+            Ok(mut addrs) => if addrs.next().is_none() { return Err(invalid(host, "no address found")) },
+            Err(e) => return Err(invalid(host, &e.to_string())),
+        }
+
+        let mode = &values["unix_socket_mode"];
+        let unix_socket_mode = u32::from_str_radix(&mode.value, 8).ok().filter(|mode| *mode <= 0o777)
+            .ok_or_else(|| invalid(mode, "expected octal permissions like 660"))?;
+
+        let listen = values["listen"].value.split(',')
+            .map(|addr| addr.trim().to_string())
+            .filter(|addr| !addr.is_empty())
+            .collect();
+
+        // Keepalive counts in seconds
+        let peer_probe_interval = duration(values, "peer_probe_interval")?;
+        if !peer_probe_interval.is_zero() && peer_probe_interval < Duration::from_secs(1) {
+            return Err(invalid(&values["peer_probe_interval"], "must be 0 or at least 1s"));
+        }
+
+        let listen_backlog: i32 = parse(values, "listen_backlog")?;
+        if listen_backlog < 1 {
+            return Err(invalid(&values["listen_backlog"], "must be at least 1"));
+        }
+
+        let buffer = &values["relay_buffer_size"];
+        let relay_buffer_size = parse_size(&buffer.value).map_err(|e| invalid(buffer, e))?;
+        if !(4 * 1024..=4 * 1024 * 1024).contains(&relay_buffer_size) {
+            return Err(invalid(buffer, "must be between 4KiB and 4MiB"));
+        }
+
+        let servers = &values["dns_servers"];
+        let dns_servers = list(servers)
+            .map(|server| server.parse::<SocketAddr>()
+                .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                .map_err(|_| ConfigError::Invalid { value: server.to_string(), origin: servers.origin.clone(), reason: "not an address".to_string() }))
+            .collect::<Result<Vec<_>, _>>()?;
+        let search = &values["dns_search"];
+        let dns_search = list(search)
+            .map(|domain| Name::from_str(domain)
+                .map_err(|e| ConfigError::Invalid { value: domain.to_string(), origin: search.origin.clone(), reason: e.to_string() }))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let sources = &values["outbound_bind"];
+        let mut outbound_bind: Vec<IpAddr> = Vec::new();
+        for source in list(sources) {
+            let ip: IpAddr = source.parse().map_err(|_| ConfigError::Invalid { value: source.to_string(), origin: sources.origin.clone(), reason: "not an IP address".to_string() })?;
+            if outbound_bind.iter().any(|other| other.is_ipv4() == ip.is_ipv4()) {
+                return Err(invalid(sources, "at most one IPv4 and one IPv6 address"));
+            }
+            outbound_bind.push(ip);
+        }
+
+        let outbound_interface = values["outbound_interface"].value.clone();
+        if !outbound_interface.is_empty() {
+            sockopt::check_interface(&outbound_interface).map_err(|e| invalid(&values["outbound_interface"], &e))?;
+        }
+        let mark = &values["outbound_fwmark"];
+        let outbound_fwmark = match mark.value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => mark.value.parse(),
+        }.map_err(|_| invalid(mark, "expected a number, like 16 or 0x10"))?;
+        if outbound_fwmark != 0 {
+            sockopt::check_mark(outbound_fwmark).map_err(|e| invalid(mark, &e))?;
+        }
+
+        // only-ipv4 and only-ipv6 disable the other family like outbound_ipv4 and outbound_ipv6 do
+        let address_family: AddressFamily = parse(values, "address_family")?;
+        let outbound_ipv4 = parse::<bool>(values, "outbound_ipv4")? && address_family != AddressFamily::OnlyIpv6;
+        let outbound_ipv6 = parse::<bool>(values, "outbound_ipv6")? && address_family != AddressFamily::OnlyIpv4;
+        if !outbound_ipv4 && !outbound_ipv6 {
+            let keys = if matches!(address_family, AddressFamily::OnlyIpv4 | AddressFamily::OnlyIpv6) { "address_family and outbound_ipv4 / outbound_ipv6" } else { "outbound_ipv4 and outbound_ipv6" };
+            return Err(ConfigError::Conflict(format!("{} disable both families, no target could be reached", keys)));
+        }
+        let prefer_ipv6 = match address_family {
+            AddressFamily::PreferIpv4 => Some(false),
+            AddressFamily::PreferIpv6 => Some(true),
+            _ => None,
+        };
+        let dns: DnsBackend = parse(values, "dns")?;
+        #[cfg(not(feature = "doh"))]
+        if dns == DnsBackend::Doh {
+            return Err(ConfigError::Conflict(format!("{} selects DNS-over-HTTPS but rock5 was built without the doh feature", values["dns"].origin)));
+        }
+        #[cfg(not(feature = "dot"))]
+        if dns == DnsBackend::Dot {
+            return Err(ConfigError::Conflict(format!("{} selects DNS-over-TLS but rock5 was built without the dot feature", values["dns"].origin)));
+        }
+        let mut resolver = Resolver::new(&dns_servers, &dns_search, parse(values, "dns_fallback")?, outbound_ipv4, outbound_ipv6).preferring(prefer_ipv6).with_hosts(hosts);
+        #[cfg(feature = "doh")]
+        if dns == DnsBackend::Doh {
+            let (host, port, path) = parse_doh_url(&values["doh_url"])?;
+            let bootstrap = &values["doh_bootstrap"];
+            let mut servers = list(bootstrap)
+                .map(|ip| ip.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, port))
+                    .map_err(|_| ConfigError::Invalid { value: ip.to_string(), origin: bootstrap.origin.clone(), reason: "not an IP address".to_string() }))
+                .collect::<Result<Vec<_>, _>>()?;
+            // Looking the server up would need a resolver already, and send its name in cleartext
+            if servers.is_empty() {
+                let ip = host.parse::<IpAddr>().map_err(|_| ConfigError::Conflict(format!("{} names the DoH server {}, doh_bootstrap needs its address", values["doh_url"].origin, host)))?;
+                servers.push(SocketAddr::new(ip, port));
+            }
+            resolver = resolver.over_https(&servers, &host, &path, parse(values, "doh_strict")?);
+        }
+        #[cfg(feature = "dot")]
+        if dns == DnsBackend::Dot {
+            let servers = &values["dot_server"];
+            let dot_servers = list(servers)
+                .map(|server| server.parse::<SocketAddr>()
+                    .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 853)))
+                    .map_err(|_| ConfigError::Invalid { value: server.to_string(), origin: servers.origin.clone(), reason: "not an address".to_string() }))
+                .collect::<Result<Vec<_>, _>>()?;
+            let Some(first) = dot_servers.first() else {
+                return Err(ConfigError::Conflict(format!("{} selects DNS-over-TLS but dot_server is empty", values["dns"].origin)));
+            };
+            let hostname = match values["dot_hostname"].value.as_str() {
+                "" if dot_servers.iter().any(|server| server.ip() != first.ip()) => return Err(ConfigError::Conflict("dot_server has several addresses, dot_hostname needs the name their certificates are for".to_string())),
+                "" => first.ip().to_string(),
+                hostname => hostname.to_string(),
+            };
+            resolver = resolver.over_tls(&dot_servers, &hostname, parse(values, "dot_strict")?);
+        }
+        if switch(values, "dns_cache")? {
+            let dns_cache_size: usize = parse(values, "dns_cache_size")?;
+            if dns_cache_size == 0 {
+                return Err(invalid(&values["dns_cache_size"], "must be above 0, use dns_cache = false to disable"));
+            }
+            // Failures are only kept for long enough to spare the resolver a client that keeps asking
+            let failure_ttl = |key: &str| match duration(values, key)? {
+                ttl if ttl > MAX_FAILURE_TTL => Err(invalid(&values[key], "must be at most 60s")),
+                ttl => Ok(ttl),
+            };
+            resolver = resolver.with_cache(dns_cache_size, duration(values, "dns_cache_ttl")?, failure_ttl("dns_cache_negative_ttl")?, failure_ttl("dns_cache_error_ttl")?);
+        }
+
+        let clients = &values["allowed_clients"];
+        let allowed_clients = list(clients)
+            .map(|range| range.parse::<Cidr>().map_err(|e| ConfigError::Invalid { value: range.to_string(), origin: clients.origin.clone(), reason: e.to_string() }))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut blocked_domains = Vec::new();
+        let rules = &values["blocked_domains"];
+        for rule in rules.value.split(',') {
+            blocked_domains.push(domain_rule(rule, rules)?);
+        }
+        let file = &values["blocked_domains_file"];
+        if !file.value.is_empty() {
+            let path = PathBuf::from(&file.value);
+            let text = std::fs::read_to_string(&path).map_err(|e| ConfigError::File(path, e.to_string()))?;
+            for line in text.lines().filter(|line| !line.trim_start().starts_with('#')) {
+                blocked_domains.push(domain_rule(line, file)?);
+            }
+        }
+        blocked_domains.retain(|rule| !rule.is_empty());
+
+        Ok(Config {
+            host: host.value.clone(),
+            port,
+            listen,
+            listen_family: parse(values, "listen_family")?,
+            unix_socket_mode,
+            unix_socket_owner: unix_socket::parse_owner(&values["unix_socket_owner"].value).map_err(|e| invalid(&values["unix_socket_owner"], &e))?,
+            listen_backlog,
+            tfo_listen: parse(values, "tfo_listen")?,
+            acceptors: parse(values, "acceptors")?,
+            max_connections: parse(values, "max_connections")?,
+            max_connections_action: parse(values, "max_connections_action")?,
+            bandwidth_limit: parse_rate(&values["bandwidth_limit"].value).map_err(|e| invalid(&values["bandwidth_limit"], e))?,
+            per_connection_limit: parse_rate(&values["per_connection_limit"].value).map_err(|e| invalid(&values["per_connection_limit"], e))?,
+            max_connections_per_client: parse(values, "max_connections_per_client")?,
+            max_connections_per_client_action: parse(values, "max_connections_per_client_action")?,
+            client_ipv6_prefix,
+            log_level: parse(values, "log_level")?,
+            log_format: parse(values, "log_format")?,
+            log_color: parse(values, "log_color")?,
+            log_timestamps: parse(values, "log_timestamps")?,
+            log_privacy: parse(values, "log_privacy")?,
+            log_target,
+            syslog_address: parse(values, "syslog_address")?,
+            syslog_facility: syslog_facility(&values["syslog_facility"])?,
+            syslog_tag: values["syslog_tag"].value.clone(),
+            log_file: Some(&values["log_file"].value).filter(|path| !path.is_empty()).map(PathBuf::from),
+            log_stdout: parse(values, "log_stdout")?,
+            access_log: Some(&values["access_log"].value).filter(|path| !path.is_empty()).map(PathBuf::from),
+            log_rotate: parse(values, "log_rotate")?,
+            log_rotate_keep: parse(values, "log_rotate_keep")?,
+            metrics_listen: Some(values["metrics_listen"].value.trim()).filter(|addr| !addr.is_empty()).map(str::to_string),
+            statsd_addr: Some(values["statsd_addr"].value.trim()).filter(|addr| !addr.is_empty()).map(str::to_string),
+            statsd_prefix: values["statsd_prefix"].value.trim().trim_end_matches('.').to_string(),
+            statsd_tags: list(&values["statsd_tags"]).collect::<Vec<_>>().join(","),
+            health_listen: Some(values["health_listen"].value.trim()).filter(|addr| !addr.is_empty()).map(str::to_string),
+            health_mode: parse(values, "health_mode")?,
+            admin_socket: Some(&values["admin_socket"].value).filter(|path| !path.is_empty()).map(PathBuf::from),
+            trace_sample_ratio,
+            runtime_metrics_interval,
+            watch_config: parse(values, "watch_config")?,
+            drain_delay: duration(values, "drain_delay")?,
+            shutdown_timeout: duration(values, "shutdown_timeout")?,
+            users,
+            // Set by load_from
+            user_limits: HashMap::new(),
+            bind_host: values["bind_host"].value.clone(),
+            bind_timeout: duration(values, "bind_timeout")?,
+            socks4: parse(values, "socks4")?,
+            handshake_timeout: duration(values, "handshake_timeout")?,
+            happy_eyeballs_delay: duration(values, "happy_eyeballs_delay")?,
+            connect_timeout: duration(values, "connect_timeout")?,
+            tfo_connect: parse(values, "tfo_connect")?,
+            connect_retries: parse(values, "connect_retries")?,
+            connect_retry_delay: duration(values, "connect_retry_delay")?,
+            idle_timeout: duration(values, "idle_timeout")?,
+            tcp_keepalive: duration(values, "tcp_keepalive")?,
+            tcp_keepalive_interval: duration(values, "tcp_keepalive_interval")?,
+            tcp_keepalive_retries: parse(values, "tcp_keepalive_retries")?,
+            peer_probe_interval,
+            tor_resolve: parse(values, "tor_resolve")?,
+            auth,
+            tcp_nodelay: parse(values, "tcp_nodelay")?,
+            loop_protection: parse(values, "loop_protection")?,
+            maintenance: parse(values, "maintenance")?,
+            relay_buffer_size,
+            relay_buffer_pool: parse(values, "relay_buffer_pool")?,
+            resolver,
+            outbound_ipv4,
+            outbound_ipv6,
+            outbound_bind,
+            outbound_interface,
+            outbound_fwmark,
+            outbound_dscp: parse_dscp(&values["outbound_dscp"])?,
+            client_dscp: parse_dscp(&values["client_dscp"])?,
+            allowed_clients,
+            blocked_domains,
+            strict: parse(values, "strict")?,
+            lenient_request_version: parse(values, "lenient_request_version")?,
+            #[cfg(feature = "gssapi")]
+            gssapi,
+            raw: values.iter().map(|(key, value)| (key.clone(), value.clone())).collect(),
+            file: None,
+            files: Vec::new(),
+            profile: None,
+            listeners: Vec::new(),
+            secret_names: HashSet::new(),
+            secret_values: HashSet::new(),
+        })
+    }
+
+    // Human readable list of what changed between two configs
+    pub fn diff(&self, new: &Config) -> Vec<String> {
+        let mut changes = Vec::new();
+        for (key, old) in &self.raw {
+            let new_entry = new.raw.get(key);
+            let new_value = new_entry.map(|value| value.value.as_str()).unwrap_or_default();
+            if old.value != new_value {
+                let secret = old.secret || new_entry.is_some_and(|value| value.secret);
+                let (old_value, new_value) = if secret { (REDACTED, REDACTED) } else { (old.value.as_str(), new_value) };
+                let note = if needs_restart(key) { RESTART_NOTE } else { "" };
+                changes.push(format!("{key}: '{old_value}' -> '{new_value}'{note}"));
+            }
+        }
+        if self.users != new.users {
+            changes.push(format!("[users]: {} -> {} users", self.users.len(), new.users.len()));
+        }
+        if self.user_limits != new.user_limits {
+            changes.push(format!("[{USER_LIMITS_CFG}]: {} -> {} users", self.user_limits.len(), new.user_limits.len()));
+        }
+        if self.resolver.hosts() != new.resolver.hosts() {
+            changes.push(format!("[{HOSTS_CFG}]: {} -> {} names", self.resolver.hosts().len(), new.resolver.hosts().len()));
+        }
+        let global = changes.clone();
+        for listener in &self.listeners {
+            match new.listeners.iter().find(|other| other.name == listener.name) {
+                Some(other) => {
+                    let section = format!("[{LISTENER_PREFIX}{}]", listener.name);
+                    if listener.addrs != other.addrs {
+                        changes.push(format!("{section} listen: '{}' -> '{}'{RESTART_NOTE}", listener.addrs.join(", "), other.addrs.join(", ")));
+                    }
+                    // Global changes were reported above already
+                    changes.extend(listener.cfg.diff(&other.cfg).into_iter()
+                        .filter(|change| !global.contains(change))
+                        .map(|change| format!("{section} {change}")));
+                }
+                None => changes.push(format!("[{LISTENER_PREFIX}{}] removed{RESTART_NOTE}", listener.name)),
+            }
+        }
+        for listener in &new.listeners {
+            if !self.listeners.iter().any(|other| other.name == listener.name) {
+                changes.push(format!("[{LISTENER_PREFIX}{}] added{RESTART_NOTE}", listener.name));
+            }
+        }
+        changes
+    }
+
+    // The merged config in ini syntax, for --print-config
+    pub fn effective(&self) -> String {
+        let mut text = match &self.file {
+            Some(path) => match &self.profile {
+                Some(profile) => format!("# Effective rock5 config, read from {path:?} with profile {profile}\n\n"),
+                None => format!("# Effective rock5 config, read from {path:?}\n\n"),
+            },
+            None => String::from("# Effective rock5 config, no config file read\n\n"),
+        };
+        text.push_str(&format!("[{MAIN_CFG}]\n"));
+        for key in KEYS {
+            let value = &self.raw[key.name];
+            let mut shown = if value.secret { REDACTED.to_string() } else { value.value.clone() };
+            if !value.origin.starts_with("default ") {
+                text.push_str(&format!("# from {}\n", value.origin));
+            } else if key.name == "auth" {
+                text.push_str("# not set, follows [users]\n");
+                shown = format!("{:?}", self.auth).to_lowercase();
+            }
+            text.push_str(format!("{} = {}", key.name, shown).trim_end());
+            text.push('\n');
+        }
+        for listener in &self.listeners {
+            text.push_str(&format!("\n[{LISTENER_PREFIX}{}]\n", listener.name));
+            text.push_str(&format!("listen = {}\n", listener.addrs.join(", ")));
+            for key in KEYS.iter().filter(|key| key.name != "listen") {
+                let value = &listener.cfg.raw[key.name];
+                if value.origin.contains(LISTENER_PREFIX) {
+                    let shown = if value.secret { REDACTED } else { &value.value };
+                    text.push_str(format!("{} = {}", key.name, shown).trim_end());
+                    text.push('\n');
+                }
+            }
+        }
+        // Passwords are never shown
+        let entry = |section: &'static str, name: &str, value: String| {
+            let key = (section, name.to_string());
+            let name = if self.secret_names.contains(&key) { REDACTED } else { name };
+            let value = if section == USERS_CFG || self.secret_values.contains(&key) { REDACTED.to_string() } else { value };
+            format!("{name} = {value}\n")
+        };
+        text.push_str(&format!("\n[{USERS_CFG}]\n"));
+        let mut users: Vec<&String> = self.users.keys().collect();
+        users.sort();
+        for user in users {
+            text.push_str(&entry(USERS_CFG, user, String::new()));
+        }
+        if !self.user_limits.is_empty() {
+            text.push_str(&format!("\n[{USER_LIMITS_CFG}]\n"));
+            let mut limits: Vec<(&String, &u64)> = self.user_limits.iter().collect();
+            limits.sort();
+            for (user, rate) in limits {
+                text.push_str(&entry(USER_LIMITS_CFG, user, rate.to_string()));
+            }
+        }
+        if !self.resolver.hosts().is_empty() {
+            text.push_str(&format!("\n[{HOSTS_CFG}]\n"));
+            for (name, ips) in self.resolver.hosts().iter() {
+                let ips: Vec<String> = ips.iter().map(IpAddr::to_string).collect();
+                text.push_str(&entry(HOSTS_CFG, name, ips.join(", ")));
+            }
+        }
+        text
+    }
+}
+
+// Keys the running listeners and endpoints were set up with, their help ends with RESTART_NOTE
+fn needs_restart(key: &str) -> bool {
+    KEYS.iter().any(|known| known.name == key && known.help.ends_with(RESTART_NOTE))
+}
+
+fn parse<T: FromStr>(values: &Values, key: &str) -> Result<T, ConfigError> where T::Err: Debug {
+    let value = &values[key];
+    value.value.parse::<T>().map_err(|e| invalid(value, &format!("{e:?}")))
+}
+
+// Entries of a comma separated value
+fn list(value: &Value) -> impl Iterator<Item = &str> {
+    value.value.split(',').map(str::trim).filter(|entry| !entry.is_empty())
+}
+
+// Lowercased like parsed domains, a wildcard is only allowed as the first label
+fn domain_rule(rule: &str, origin: &Value) -> Result<String, ConfigError> {
+    let rule = rule.trim().trim_end_matches('.').to_lowercase();
+    if rule.trim_start_matches("*.").contains('*') {
+        return Err(ConfigError::Invalid { value: rule, origin: origin.origin.clone(), reason: "only a leading *. wildcard is supported".to_string() });
+    }
+    Ok(rule)
+}
+
+// true or false, on and off also read well for a switch like dns_cache
+fn switch(values: &Values, key: &str) -> Result<bool, ConfigError> {
+    let value = &values[key];
+    match value.value.as_str() {
+        "true" | "on" => Ok(true),
+        "false" | "off" => Ok(false),
+        _ => Err(invalid(value, "not true, false, on or off")),
+    }
+}
+
+fn duration(values: &Values, key: &str) -> Result<Duration, ConfigError> {
+    let value = &values[key];
+    parse_duration(&value.value).map_err(|e| invalid(value, e))
+}
+
+// A DSCP name (RFC 4594: EF, AFxy, CSx, LE, VA) or number, None when empty
+fn parse_dscp(value: &Value) -> Result<Option<u8>, ConfigError> {
+    let name = value.value.to_ascii_uppercase();
+    let class = |digit: Option<char>, max: u32| digit.and_then(|digit| digit.to_digit(10)).filter(|n| (1..=max).contains(n));
+    let dscp = match name.as_str() {
+        "" => return Ok(None),
+        "EF" => Some(46),
+        "VA" => Some(44),
+        "LE" => Some(1),
+        "CS0" | "DF" => Some(0),
+        _ if name.len() == 3 && name.starts_with("CS") => class(name.chars().nth(2), 7).map(|x| x * 8),
+        _ if name.len() == 4 && name.starts_with("AF") => class(name.chars().nth(2), 4).zip(class(name.chars().nth(3), 3)).map(|(x, y)| x * 8 + y * 2),
+        _ => name.parse().ok().filter(|dscp| *dscp < 64),
+    };
+    dscp.map(|dscp| Some(dscp as u8)).ok_or_else(|| invalid(value, "expected a DSCP name like EF, AF41 or CS0, or 0 to 63"))
+}
+
+// doh_url as host, without the brackets of an IPv6 address, port and path
+#[cfg(feature = "doh")]
+fn parse_doh_url(value: &Value) -> Result<(String, u16, String), ConfigError> {
+    let bad = || invalid(value, "expected an https:// URL like https://1.1.1.1/dns-query");
+    let rest = value.value.strip_prefix("https://").ok_or_else(bad)?;
+    let (authority, path) = rest.find('/').map_or((rest, "/dns-query"), |i| rest.split_at(i));
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']').ok_or_else(bad)? {
+            (host, "") => (host, None),
+            (host, port) => (host, Some(port.strip_prefix(':').ok_or_else(bad)?)),
+        },
+        None => authority.rsplit_once(':').map_or((authority, None), |(host, port)| (host, Some(port))),
+    };
+    let port = match port {
+        None => 443,
+        Some(port) => port.parse().map_err(|_| bad())?,
+    };
+    if host.is_empty() {
+        return Err(bad());
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+fn invalid(value: &Value, reason: &str) -> ConfigError {
+    let shown = if value.secret { REDACTED.to_string() } else { value.value.clone() };
+    ConfigError::Invalid { value: shown, origin: value.origin.clone(), reason: reason.to_string() }
+}
+
+// A value read from a config file, with ${ENV} and file: resolved
+fn file_value(key: &str, value: String, origin: String, source: &Source) -> Result<Value, ConfigError> {
+    match interpolate(&value) {
+        // Like include, a relative path is next to the file that names it
+        Ok((resolved, secret)) if FILE_PATH_KEYS.contains(&key) && !resolved.is_empty() => {
+            let path = source.path.parent().unwrap_or(Path::new("")).join(resolved);
+            Ok(Value { value: path.to_string_lossy().into_owned(), origin, secret })
+        }
+        Ok((resolved, secret)) => Ok(Value { value: resolved, origin, secret }),
+        Err(reason) => Err(ConfigError::Invalid { value, origin, reason }),
+    }
+}
+
+// A name or value in [users], [user_limits] or [hosts], resolved like the keys of [config]
+fn entry_value(value: String, origin: &str) -> Result<Value, ConfigError> {
+    match interpolate(&value) {
+        Ok((resolved, secret)) => Ok(Value { value: resolved, origin: origin.to_string(), secret }),
+        Err(reason) => Err(ConfigError::Invalid { value, origin: origin.to_string(), reason }),
+    }
+}
+
+// "file:/run/secrets/x" is the file's content, "${NAME}" anywhere in a value an environment variable
+// Returns whether anything was substituted
+fn interpolate(value: &str) -> Result<(String, bool), String> {
+    if let Some(path) = value.strip_prefix(FILE_PREFIX) {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("cannot read {path:?}: {e}"))?;
+        return Ok((content.trim_end_matches(['\r', '\n']).to_string(), true));
+    }
+    let mut resolved = String::new();
+    let mut rest = value;
+    let mut secret = false;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..].find('}').ok_or("unterminated ${")? + start;
+        let name = &rest[start + 2..end];
+        let var = std::env::var(name).map_err(|_| format!("environment variable {name} is not set"))?;
+        resolved.push_str(&rest[..start]);
+        resolved.push_str(&var);
+        rest = &rest[end + 1..];
+        secret = true;
+    }
+    resolved.push_str(rest);
+    Ok((resolved, secret))
+}
+
+// Environment variable overriding a config key, e.g. ROCK5_PORT
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+}
+
+// Description of every config key, for --help
+pub fn keys_help() -> String {
+    let width = KEYS.iter().map(|key| key.name.len()).max().unwrap_or(0);
+    let mut help = String::from("Config file keys ([config] section), each can also be set with ROCK5_<KEY>:\n");
+    for key in KEYS {
+        let default = if key.default.is_empty() { String::new() } else { format!(" [default: {}]", key.default) };
+        help.push_str(&format!("  {:width$}  {}{}\n", key.name, key.help, default));
+    }
+    help.push_str("\nUsers for username/password authentication go in the [users] section as `name = password`, the password may be an argon2 or bcrypt hash.");
+    help.push_str("\n[user_limits] takes `name = rate` to give a user another per_connection_limit.");
+    help.push_str("\n[hosts] takes `name = address, ...` to answer a name, or with *.name the names below it, without DNS.");
+    help.push_str("\n`include = path, ...` in [config] merges further files (globs allowed, relative to the including file).");
+    help.push_str("\n\nPrecedence: command line options > ROCK5_* environment variables > config file > defaults.");
+    help
+}
+
+fn read_ini(text: String, path: &Path) -> Result<FileConfig, String> {
+    // Case sensitive, usernames must not be lowercased
+    let mut config = Ini::new_cs();
+    let res = config.read(text.clone())?;
+    let source = |section: &str, key: &str| source(&text, path, section, key);
+
+    let mut file = FileConfig::default();
+    if let Some(gc) = res.get(MAIN_CFG) {
+        for (key, value) in gc {
+            if let Some(value) = value {
+                file.config.push((key.to_string(), value.to_string(), source(MAIN_CFG, key)));
+            }
+        }
+    }
+    // Users (RFC 1929 username/password)
+    for (section, pairs) in [(USERS_CFG, &mut file.users), (USER_LIMITS_CFG, &mut file.user_limits), (HOSTS_CFG, &mut file.hosts)] {
+        if let Some(keys) = res.get(section) {
+            for (name, value) in keys {
+                pairs.insert(name.to_string(), (value.clone().unwrap_or_default(), source(section, name)));
+            }
+        }
+    }
+    for (section, keys) in &res {
+        let entries = || keys.iter().filter_map(|(key, value)| Some((key.to_string(), value.clone()?, source(section, key)))).collect();
+        if let Some(name) = section.strip_prefix(LISTENER_PREFIX) {
+            file.listeners.push((name.to_string(), entries()));
+        } else if let Some(name) = section.strip_prefix(PROFILE_PREFIX) {
+            file.profiles.push((name.to_string(), entries()));
+        // Keys before the first section end up in "default"
+        } else if section != MAIN_CFG && section != USERS_CFG && section != USER_LIMITS_CFG && section != HOSTS_CFG && !keys.is_empty() {
+            file.sections.push(section.to_string());
+        }
+    }
+    Ok(file)
+}
+
+fn read_toml(text: String, path: &Path) -> Result<FileConfig, String> {
+    let parsed: TomlFile = toml::from_str(&text).map_err(|e| e.to_string())?;
+    let pairs = |section: &str, keys: HashMap<String, String>| -> Pairs {
+        keys.into_iter().map(|(name, value)| {
+            let source = source(&text, path, section, &name);
+            (name, (value, source))
+        }).collect()
+    };
+
+    let mut file = FileConfig {
+        users: pairs(USERS_CFG, parsed.users),
+        user_limits: pairs(USER_LIMITS_CFG, parsed.user_limits),
+        sections: parsed.other.into_keys().collect(),
+        ..Default::default()
+    };
+    file.config = toml_section(MAIN_CFG, parsed.config, &text, path)?;
+    file.hosts = toml_section(HOSTS_CFG, parsed.hosts, &text, path)?.into_iter().map(|(name, ips, source)| (name, (ips, source))).collect();
+    for (name, keys) in parsed.listener {
+        let keys = toml_section(&format!("{LISTENER_PREFIX}{name}"), keys, &text, path)?;
+        file.listeners.push((name, keys));
+    }
+    for (name, keys) in parsed.profile {
+        let keys = toml_section(&format!("{PROFILE_PREFIX}{name}"), keys, &text, path)?;
+        file.profiles.push((name, keys));
+    }
+    Ok(file)
+}
+
+// Same string form as the ini values, arrays become comma separated lists
+fn toml_section(section: &str, keys: BTreeMap<String, toml::Value>, text: &str, path: &Path) -> Result<Entries, String> {
+    keys.into_iter().map(|(key, value)| {
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Array(items) => items.iter()
+                .map(|item| item.as_str().map(str::to_string).unwrap_or_else(|| item.to_string()))
+                .collect::<Vec<_>>()
+                .join(", "),
+            toml::Value::Table(_) => return Err(format!("{key} in [{section}] must not be a table")),
+            value => value.to_string(),
+        };
+        let source = source(text, path, section, &key);
+        Ok((key, value, source))
+    }).collect()
+}
+
+// The line of key in section, the last one as later ini entries win. Neither parser keeps lines, so this
+// looks for `key =` (or `key:`) after a `[section]` header, quotes aside
+fn source(text: &str, path: &Path, section: &str, key: &str) -> Source {
+    let mut current = String::new();
+    let mut line = None;
+    for (number, text) in text.lines().enumerate() {
+        let text = text.trim();
+        if let Some(header) = text.strip_prefix('[') {
+            current = header.split(']').next().unwrap_or_default().replace(['"', '\'', ' '], "");
+        } else if current == section && text.split(['=', ':']).next().is_some_and(|name| name.trim().trim_matches(['"', '\'']) == key) {
+            line = Some(number + 1);
+        }
+    }
+    Source { path: path.to_path_buf(), line }
+}
+
+// Read a file and the files its include key names, `chain` holds the files including it
+fn read_tree(path: &Path, format: Option<ConfigFormat>, chain: &mut Vec<PathBuf>) -> Result<FileConfig, ConfigError> {
+    let (text, canonical) = if path == Path::new(STDIN_PATH) {
+        (std::io::read_to_string(std::io::stdin()), Ok(path.to_path_buf()))
+    } else {
+        (std::fs::read_to_string(path), path.canonicalize())
+    };
+    let error = |e: std::io::Error| ConfigError::File(path.to_path_buf(), e.to_string());
+    let (text, canonical) = (text.map_err(error)?, canonical.map_err(error)?);
+    if chain.contains(&canonical) {
+        let cycle: Vec<String> = chain.iter().chain([&canonical]).map(|file| format!("{file:?}")).collect();
+        return Err(ConfigError::Conflict(format!("include cycle: {}", cycle.join(" -> "))));
+    }
+    let format = format.unwrap_or(
+        if path.extension().is_some_and(|ext| ext == "toml") { ConfigFormat::Toml } else { ConfigFormat::Ini }
+    );
+    let mut file = match format {
+        ConfigFormat::Ini => read_ini(text, path),
+        ConfigFormat::Toml => read_toml(text, path),
+    }.map_err(|e| ConfigError::File(path.to_path_buf(), e))?;
+    // Nothing to watch for a piped config
+    if path != Path::new(STDIN_PATH) {
+        file.files.push(path.to_path_buf());
+    }
+
+    let (includes, config) = file.config.into_iter().partition(|(key, _, _)| key == INCLUDE_KEY);
+    file.config = config;
+    chain.push(canonical);
+    // Relative to the including file, matches of a glob in name order
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let includes: Entries = includes;
+    for (_, value, source) in includes {
+        for pattern in value.split(',').map(str::trim).filter(|pattern| !pattern.is_empty()) {
+            let pattern = dir.join(pattern);
+            let pattern_str = pattern.to_string_lossy();
+            let is_glob = pattern_str.contains(['*', '?', '[']);
+            let mut matches: Vec<PathBuf> = glob::glob(&pattern_str)
+                .map_err(|e| ConfigError::File(path.to_path_buf(), format!("include {pattern:?} at {source}: {e}")))?
+                .filter_map(Result::ok)
+                .collect();
+            matches.sort();
+            if matches.is_empty() && !is_glob {
+                return Err(ConfigError::File(path.to_path_buf(), format!("included file {pattern:?} does not exist (include at {source})")));
+            }
+            // New matches count as changes too
+            if is_glob {
+                file.files.push(pattern.clone());
+            }
+            for included in matches {
+                let included = read_tree(&included, None, chain)?;
+                file.merge(included);
+            }
+        }
+    }
+    chain.pop();
+    Ok(file)
+}
+
+// Commented ini config with every key at its default
+pub fn default_config() -> String {
+    let mut text = String::from("# rock5 config, generated by `rock5 gen-config`\n\n");
+    text.push_str(&format!("[{MAIN_CFG}]\n"));
+    for key in KEYS {
+        text.push_str(&format!("# {}\n", key.help));
+        // No default, so leave it unset
+        let prefix = if key.default.is_empty() { "# " } else { "" };
+        text.push_str(format!("{}{} = {}", prefix, key.name, key.default).trim_end());
+        text.push_str("\n\n");
+    }
+    text.push_str(&format!("# Users for username/password authentication\n[{USERS_CFG}]\n# name = password\n"));
+    text
+}
+
+// gen-config subcommand
+pub fn write_default_config(stdout: bool, force: bool) -> std::io::Result<()> {
+    if stdout {
+        print!("{}", default_config());
+        return Ok(());
+    }
+    let path = default_path();
+    if path.exists() && !force {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{path:?} already exists, use --force to overwrite it")));
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, default_config())?;
+    info!(" -> Wrote default config to {path:?}");
+    Ok(())
+}
+
+// Per user config file, ./rock5.ini when there is no config directory
+fn default_path() -> PathBuf {
+    config_dir().map(|dir| dir.join(CFG_PATH)).unwrap_or_else(|| PathBuf::from(LOCAL_CFG_PATH))
+}
+
+// Where to look for a config without --config, the first existing file is used
+pub fn search_path() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(LOCAL_CFG_PATH)];
+    paths.extend(config_dir().map(|dir| dir.join(CFG_PATH)));
+    paths.push(PathBuf::from(SYSTEM_CFG_PATH));
+    paths
+}
+
+impl Config {
+    pub fn load(cli: &Cli) -> Result<Config, ConfigError> {
+        Config::load_from(cli, &search_path())
+    }
+
+    // Layers, later ones win: defaults, config file, environment, command line
+    pub fn load_from(cli: &Cli, search: &[PathBuf]) -> Result<Config, ConfigError> {
+        let mut values: Values = KEYS.iter()
+            .map(|key| (key.name.to_string(), Value { value: key.default.to_string(), origin: format!("default {}", key.name), secret: false }))
+            .collect();
+        let mut users: HashMap<String, String> = HashMap::new();
+        let mut user_limits: HashMap<String, u64> = HashMap::new();
+        let mut hosts = Hosts::default();
+        let (mut secret_names, mut secret_values) = (HashSet::new(), HashSet::new());
+        let mut file_used = None;
+        let mut listener_sections = Vec::new();
+        let mut files = Vec::new();
+
+        // A file asked for explicitly (--config or ROCK5_CONFIG) must be there, the search path is optional
+        let cfg_path = match &cli.config {
+            Some(path) => Some(path.clone()),
+            None => search.iter().find(|path| path.is_file()).cloned(),
+        };
+        match &cfg_path {
+            Some(path) => info!(" -> Reading config from {path:?}"),
+            None => {
+                let searched: Vec<String> = search.iter().map(|path| format!("{path:?}")).collect();
+                info!(" -> No config file found ({}), using defaults", searched.join(", "));
+            }
+        }
+
+        let file = cfg_path.as_ref().map(|path| read_tree(path, cli.config_format, &mut Vec::new()));
+
+        match (file, cfg_path) {
+            (Some(Ok(mut file)), Some(cfg_path)) => {
+                let mut unknown: Vec<String> = file.sections.iter().map(|section| format!("[{section}]")).collect();
+                let sections = file.listeners.iter().map(|(name, keys)| (LISTENER_PREFIX, name, keys))
+                    .chain(file.profiles.iter().map(|(name, keys)| (PROFILE_PREFIX, name, keys)));
+                for (prefix, name, keys) in sections {
+                    unknown.extend(keys.iter()
+                        .filter(|(key, _, _)| !KEYS.iter().any(|known| known.name == key))
+                        .map(|(key, _, _)| format!("{key} in [{prefix}{name}]")));
+                }
+                // The selected profile overrides [config]
+                let mut sections = vec![(MAIN_CFG.to_string(), file.config)];
+                if let Some(profile) = &cli.profile {
+                    let Some(index) = file.profiles.iter().position(|(name, _)| name == profile) else {
+                        let mut available: Vec<String> = file.profiles.into_iter().map(|(name, _)| name).collect();
+                        available.sort();
+                        return Err(ConfigError::Profile(profile.clone(), available));
+                    };
+                    let (_, keys) = file.profiles.swap_remove(index);
+                    sections.push((format!("{PROFILE_PREFIX}{profile}"), keys));
+                }
+                for (section, keys) in sections {
+                    for (key, value, source) in keys {
+                        if !KEYS.iter().any(|known| known.name == key) {
+                            if section == MAIN_CFG {
+                                unknown.push(format!("{key} in [{MAIN_CFG}]"));
                             }
-                            Err(e) => panic!("invalid port in config: '{port:?}' ({e:?})"),
+                            continue;
                         }
+                        let origin = format!("{key} in [{section}] of {source}");
+                        let value = file_value(&key, value, origin, &source)?;
+                        values.insert(key, value);
+                    }
+                }
+                if !unknown.is_empty() {
+                    if cli.strict_config {
+                        return Err(ConfigError::Unknown(cfg_path, unknown));
                     }
+                    warn!("Ignoring unknown entries in config {:?}: {}", cfg_path, unknown.join(", "));
+                }
+                // RFC 1929 sends both with a one byte length
+                // Their names and values may be ${ENV} or file: too
+                let mut mark = |section, name: &Value, value: &Value| {
+                    if name.secret {
+                        secret_names.insert((section, name.value.clone()));
+                    }
+                    if value.secret {
+                        secret_values.insert((section, name.value.clone()));
+                    }
+                };
+                for (user, (pass, source)) in file.users {
+                    let origin = format!("{user} in [{USERS_CFG}] of {source}");
+                    let (user, pass) = (entry_value(user, &origin)?, entry_value(pass, &origin)?);
+                    if user.value.is_empty() || user.value.len() > 255 {
+                        return Err(invalid(&user, "username must be 1 to 255 bytes"));
+                    }
+                    if pass.value.len() > 255 {
+                        return Err(invalid(&user, "password longer than 255 bytes"));
+                    }
+                    auth::check_password(&pass.value).map_err(|reason| invalid(&user, &reason))?;
+                    mark(USERS_CFG, &user, &pass);
+                    users.insert(user.value, pass.value);
+                }
+                for (user, (rate, source)) in file.user_limits {
+                    let origin = format!("{user} in [{USER_LIMITS_CFG}] of {source}");
+                    let (user, rate) = (entry_value(user, &origin)?, entry_value(rate, &origin)?);
+                    let limit = parse_rate(&rate.value).map_err(|reason| invalid(&rate, reason))?;
+                    mark(USER_LIMITS_CFG, &user, &rate);
+                    user_limits.insert(user.value, limit);
                 }
-                // Host
-                let kho = gc.get("host");
-                if let Some(hco) = kho {
-                    if let Some(h) = hco {
-                        host = h.to_string();
+                for (name, (ips, source)) in file.hosts {
+                    let origin = format!("{name} in [{HOSTS_CFG}] of {source}");
+                    let (name, pinned) = (entry_value(name, &origin)?, entry_value(ips, &origin)?);
+                    let ips = list(&pinned)
+                        .map(|ip| ip.parse::<IpAddr>().map_err(|_| invalid(&Value { value: ip.to_string(), ..pinned.clone() }, "not an IP address")))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if ips.is_empty() {
+                        return Err(invalid(&name, "no addresses"));
                     }
+                    let rule = domain_rule(&name.value, &name)?;
+                    mark(HOSTS_CFG, &Value { value: rule.clone(), ..name }, &pinned);
+                    hosts.insert(rule, ips);
                 }
+                listener_sections = file.listeners;
+                files = file.files;
+                file_used = Some(cfg_path);
+            }
+            (Some(Err(e)), _) => return Err(e),
+            _ if cli.profile.is_some() => return Err(ConfigError::Profile(cli.profile.clone().unwrap_or_default(), Vec::new())),
+            _ => {}
+        }
+
+        for key in KEYS {
+            let name = env_name(key.name);
+            if let Ok(value) = std::env::var(&name) {
+                values.insert(key.name.to_string(), Value { value, origin: name, secret: false });
+            }
+        }
+
+        for (key, value) in cli.overrides() {
+            values.insert(key.to_string(), Value { value, origin: format!("--{}", key.replace('_', "-")), secret: false });
+        }
+
+        let mut cfg = Config::from_values(&values, users.clone(), hosts.clone())?;
+        // Listener sections override the merged global values
+        let mut listeners = Vec::new();
+        for (name, keys) in listener_sections {
+            let section = format!("{LISTENER_PREFIX}{name}");
+            let mut listener_values = values.clone();
+            for (key, value, source) in keys {
+                if !KEYS.iter().any(|known| known.name == key) {
+                    continue;
+                }
+                let origin = format!("{key} in [{section}] of {source}");
+                if GLOBAL_ONLY.contains(&key.as_str()) {
+                    return Err(ConfigError::Invalid { value, origin, reason: "only allowed in [config]".to_string() });
+                }
+                let value = file_value(&key, value, origin, &source)?;
+                listener_values.insert(key, value);
+            }
+            let listen = &listener_values["listen"];
+            if !listen.origin.contains(&section) {
+                return Err(ConfigError::Conflict(format!("[{section}] has no listen address")));
+            }
+            let addrs = list(listen).map(str::to_string).collect();
+            let mut listener_cfg = Config::from_values(&listener_values, users.clone(), hosts.clone())?;
+            listener_cfg.user_limits = user_limits.clone();
+            if RESOLVER_KEYS.iter().all(|key| listener_values[*key].value == values[*key].value) {
+                listener_cfg.resolver.share(&cfg.resolver);
             }
+            listeners.push(Listener { name, addrs, cfg: Arc::new(listener_cfg) });
         }
-        Err(e) => println!("invalid config: {e:?}"),
+
+        cfg.user_limits = user_limits;
+        cfg.secret_names = secret_names;
+        cfg.secret_values = secret_values;
+        cfg.file = file_used;
+        cfg.files = files;
+        cfg.profile = cli.profile.clone();
+        cfg.listeners = listeners;
+        Ok(cfg)
     }
+}
 
-    return Config {
-        port: port,
-        host: host,
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A file of its own for text, removed again once loaded
+    fn with_file<T>(text: &str, f: impl FnOnce(&Path) -> T) -> T {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("rock5-unit-{}-{}.ini", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        std::fs::write(&path, text).unwrap();
+        let result = f(&path);
+        let _ = std::fs::remove_file(&path);
+        result
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from([&["rock5"], args].concat()).unwrap()
+    }
+
+    fn load(text: &str) -> Result<Config, ConfigError> {
+        with_file(text, |path| Config::load_from(&cli(&["--config", path.to_str().unwrap()]), &[]))
+    }
+
+    // The offending value and the reason of an Invalid error
+    fn rejected(text: &str) -> (String, String, String) {
+        match load(text) {
+            Err(ConfigError::Invalid { value, origin, reason }) => (value, origin, reason),
+            other => panic!("{text:?} loaded as {other:?}"),
+        }
+    }
+
+    #[test]
+    fn bad_ports() {
+        for port in ["abc", "-1", "1080x", "10 80", "0x438", ""] {
+            let (value, origin, _) = rejected(&format!("[config]\nport = {port}\n"));
+            assert_eq!(value, port);
+            assert!(origin.starts_with("port in [config] of ") && origin.ends_with(" line 2"), "{origin}");
+        }
+    }
+
+    #[test]
+    fn out_of_range_ports() {
+        for port in ["65536", "70000", "99999999999"] {
+            assert_eq!(rejected(&format!("[config]\nport = {port}\n")).0, port);
+        }
+        let (value, _, reason) = rejected("[config]\nport = 0\n");
+        assert_eq!((value.as_str(), reason.as_str()), ("0", "port 0 is not allowed"));
+        for port in ["1", "1080", "65535"] {
+            assert_eq!(load(&format!("[config]\nport = {port}\n")).unwrap().port.to_string(), port);
+        }
+    }
+
+    #[test]
+    fn empty_host() {
+        let (value, origin, reason) = rejected("[config]\nport = 1080\nhost =\n");
+        assert_eq!((value.as_str(), reason.as_str()), ("", "empty host"));
+        assert!(origin.starts_with("host in [config] of ") && origin.ends_with(" line 3"), "{origin}");
+        for host in ["127.0.0.1", "::1", "0.0.0.0", "localhost"] {
+            assert_eq!(load(&format!("[config]\nhost = {host}\n")).unwrap().host, host);
+        }
+    }
+
+    #[test]
+    fn missing_file() {
+        let path = std::env::temp_dir().join(format!("rock5-unit-{}-missing.ini", std::process::id()));
+        match Config::load_from(&cli(&["--config", path.to_str().unwrap()]), &[]) {
+            Err(ConfigError::File(missing, _)) => assert_eq!(missing, path),
+            other => panic!("loaded {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_keys_in_strict_mode() {
+        let text = "[config]\nprot = 1080\n[users]\nalice = secret\n[listener.a]\nlisten = 127.0.0.1:1081\nlisten_bakclog = 8\n[extra]\nkey = 1\n";
+        let loaded = with_file(text, |path| Config::load_from(&cli(&["--config", path.to_str().unwrap(), "--strict-config"]), &[]));
+        match loaded {
+            Err(ConfigError::Unknown(_, mut entries)) => {
+                entries.sort();
+                assert_eq!(entries, ["[extra]", "listen_bakclog in [listener.a]", "prot in [config]"]);
+            }
+            other => panic!("loaded {other:?}"),
+        }
+        // Without --strict-config they are only warned about
+        assert_eq!(load(text).unwrap().port, 1080);
+    }
+
+    #[test]
+    fn valid_file() {
+        let cfg = load("[config]\nhost = 127.0.0.1\nport = 1081\nconnect_timeout = 5s\nbandwidth_limit = 1MB/s\nsocks4 = true\n[users]\nalice = secret\n[user_limits]\nalice = 100KB/s\n[hosts]\na.test = 192.0.2.1\n").unwrap();
+        assert_eq!((cfg.host.as_str(), cfg.port), ("127.0.0.1", 1081));
+        assert_eq!(cfg.connect_timeout, Duration::from_secs(5));
+        assert_eq!(cfg.bandwidth_limit, 1_000_000);
+        assert!(cfg.socks4);
+        assert_eq!(cfg.users["alice"], "secret");
+        assert_eq!(cfg.user_limits["alice"], 100_000);
+        assert_eq!(cfg.auth, AuthMode::Required);
+        assert!(cfg.file.is_some());
+        // Everything else is the default
+        assert_eq!(cfg.idle_timeout, load("").unwrap().idle_timeout);
+    }
+
+    #[test]
+    fn failures_are_cached_briefly() {
+        for key in ["dns_cache_negative_ttl", "dns_cache_error_ttl"] {
+            for ttl in ["0", "5s", "60s"] {
+                load(&format!("[config]\n{key} = {ttl}\n")).unwrap();
+            }
+            let (value, _, reason) = rejected(&format!("[config]\n{key} = 61s\n"));
+            assert_eq!((value.as_str(), reason.as_str()), ("61s", "must be at most 60s"));
+        }
+        // Not with dns_cache off, there is nothing they are kept in
+        load("[config]\ndns_cache = off\ndns_cache_negative_ttl = 1h\n").unwrap();
+        assert_eq!(rejected("[config]\ndns_cache = maybe\n").2, "not true, false, on or off");
+    }
+
+    #[test]
+    fn broken_password_hashes() {
+        let (value, origin, reason) = rejected("[users]\nalice = secret\nbob = $2b$04$short\n");
+        assert_eq!(value, "bob");
+        assert!(origin.starts_with("bob in [users] of ") && origin.ends_with(" line 3"), "{origin}");
+        assert!(reason.starts_with("not a bcrypt hash"), "{reason}");
+        assert_eq!(rejected("[users]\nalice = $argon2id$v=19$m=256,t=1,p=1\n").2, "argon2 parameters without a hash");
+    }
+
+    #[test]
+    fn interpolation() {
+        assert_eq!(interpolate("plain $2b$ value"), Ok(("plain $2b$ value".to_string(), false)));
+        assert_eq!(interpolate("${ROCK5_UNIT_UNSET}"), Err("environment variable ROCK5_UNIT_UNSET is not set".to_string()));
+        assert_eq!(interpolate("a${PATH"), Err("unterminated ${".to_string()));
+        with_file("secret\n", |path| assert_eq!(interpolate(&format!("file:{}", path.display())), Ok(("secret".to_string(), true))));
+    }
+
+    #[test]
+    fn restart_keys() {
+        // Listeners are set up again with a restart too, everything else needing one is process wide
+        for key in KEYS.iter().filter(|key| needs_restart(key.name) && key.name != "listen") {
+            assert!(GLOBAL_ONLY.contains(&key.name), "{}", key.name);
+        }
+        let changes = load("").unwrap().diff(&load("[config]\nlisten_backlog = 8\nidle_timeout = 5s\nadmin_socket = /tmp/admin.sock\n").unwrap());
+        assert_eq!(changes, [
+            "admin_socket: '' -> '/tmp/admin.sock' (needs a restart)",
+            "idle_timeout: '0' -> '5s'",
+            "listen_backlog: '1024' -> '8' (needs a restart)",
+        ]);
+    }
+
+    #[test]
+    fn first_existing_file_of_the_search_path() {
+        let dir = std::env::temp_dir().join(format!("rock5-unit-{}-search", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let [missing, second, third] = ["missing.ini", "second.ini", "third.ini"].map(|name| dir.join(name));
+        std::fs::write(&second, "[config]\nport = 1082\n").unwrap();
+        std::fs::write(&third, "[config]\nport = 1083\n").unwrap();
+        let search = [missing.clone(), second.clone(), third.clone()];
+        let cfg = Config::load_from(&cli(&[]), &search).unwrap();
+        assert_eq!((cfg.file.as_deref(), cfg.port), (Some(second.as_path()), 1082));
+        // --config wins over the search path
+        let cfg = Config::load_from(&cli(&["--config", third.to_str().unwrap()]), &search).unwrap();
+        assert_eq!((cfg.file.as_deref(), cfg.port), (Some(third.as_path()), 1083));
+        // Nothing found is the defaults, not an error
+        let cfg = Config::load_from(&cli(&[]), std::slice::from_ref(&missing)).unwrap();
+        assert_eq!((cfg.file, cfg.port), (None, load("").unwrap().port));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn search_path_order() {
+        let search = search_path();
+        assert_eq!(search.first().map(PathBuf::as_path), Some(Path::new(LOCAL_CFG_PATH)));
+        assert_eq!(search.last().map(PathBuf::as_path), Some(Path::new(SYSTEM_CFG_PATH)));
+        // Never relative to the working directory but for ./rock5.ini
+        assert!(search[1..].iter().all(|path| path.is_absolute()), "{search:?}");
+    }
+
+    #[test]
+    fn host_str_brackets_ipv6() {
+        for (host, expected) in [
+            ("::1", "[::1]:1080"),
+            ("::", "[::]:1080"),
+            ("[::1]", "[::1]:1080"),
+            ("2001:db8::1", "[2001:db8::1]:1080"),
+            ("::ffff:127.0.0.1", "[::ffff:127.0.0.1]:1080"),
+            ("127.0.0.1", "127.0.0.1:1080"),
+            ("localhost", "localhost:1080"),
+        ] {
+            let mut cfg = load(&format!("[config]\nhost = {host}\nport = 1080\n")).unwrap();
+            assert_eq!(cfg.get_host_str(), expected);
+            // Which is what is listened on without listen
+            assert_eq!(cfg.listen_addrs(), [(DEFAULT_LISTENER.to_string(), expected.to_string())]);
+            assert!(expected.to_socket_addrs().is_ok(), "{expected}");
+        }
+    }
 }
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::session::Peer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    // Method selection, authentication and the request
+    Handshaking,
+    // Resolving and connecting to the target, or waiting for the BIND peer
+    Connecting,
+    Relaying,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Handshaking => "handshaking",
+            State::Connecting => "connecting",
+            State::Relaying => "relaying",
+        }
+    }
+}
+
+// What the admin socket shows of a live connection, shared with its Session
+#[derive(Debug)]
+pub struct Connection {
+    pub id: u64,
+    pub peer: Peer,
+    pub started: Instant,
+    state: AtomicU8,
+    user: Mutex<Option<String>>,
+    dest: Mutex<Option<String>>,
+    // Relayed so far, client to target and back
+    pub sent: AtomicU64,
+    pub received: AtomicU64,
+    // 0 while both sides are open, else CLIENT_EOF or TARGET_EOF, whichever closed first
+    first_eof: AtomicU8,
+}
+
+const CLIENT_EOF: u8 = 1;
+const TARGET_EOF: u8 = 2;
+
+impl Connection {
+    pub fn new(id: u64, peer: Peer) -> Connection {
+        Connection {
+            id,
+            peer,
+            started: Instant::now(),
+            state: AtomicU8::new(State::Handshaking as u8),
+            user: Mutex::new(None),
+            dest: Mutex::new(None),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            first_eof: AtomicU8::new(0),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        match self.state.load(Ordering::Relaxed) {
+            0 => State::Handshaking,
+            1 => State::Connecting,
+            _ => State::Relaying,
+        }
+    }
+
+    pub fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    // Only the first call counts
+    pub fn saw_eof(&self, client: bool) {
+        let side = if client { CLIENT_EOF } else { TARGET_EOF };
+        let _ = self.first_eof.compare_exchange(0, side, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    pub fn target_closed_first(&self) -> bool {
+        self.first_eof.load(Ordering::Relaxed) == TARGET_EOF
+    }
+
+    pub fn user(&self) -> Option<String> {
+        self.user.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_user(&self, user: &str) {
+        *self.user.lock().unwrap_or_else(|e| e.into_inner()) = Some(user.to_string());
+    }
+
+    pub fn dest(&self) -> Option<String> {
+        self.dest.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_dest(&self, dest: &str) {
+        *self.dest.lock().unwrap_or_else(|e| e.into_inner()) = Some(dest.to_string());
+    }
+}
+
+// Every connection from accept until it is done, by id
+#[derive(Debug, Default)]
+pub struct Registry {
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+}
+
+impl Registry {
+    pub fn insert(&self, connection: Arc<Connection>) {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner()).insert(connection.id, connection);
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+    }
+
+    // Oldest first
+    pub fn list(&self) -> Vec<Arc<Connection>> {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner()).values().cloned().collect()
+    }
+}
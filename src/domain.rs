@@ -0,0 +1,126 @@
+use crate::privacy;
+use crate::target::TargetAddr;
+use tracing::debug;
+
+// Longest name DNS can represent in text form (RFC 1035)
+const MAX_DOMAIN_LEN: usize = 253;
+
+// Check a DST.ADDR domain name and normalize it for resolution, logging and policy matching
+fn validate_domain(raw: &[u8]) -> Result<String, &'static str> {
+    if raw.is_empty() {
+        return Err("empty domain name");
+    }
+    let name = std::str::from_utf8(raw).map_err(|_| "domain name is not valid UTF-8")?;
+    // A single trailing dot only marks the name as fully qualified
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+        return Err("empty domain name");
+    }
+    if name.len() > MAX_DOMAIN_LEN {
+        return Err("domain name too long");
+    }
+    if name.chars().any(|c| c == '\0' || c.is_whitespace() || c.is_control()) {
+        return Err("domain name contains invalid characters");
+    }
+    if name.split('.').any(|label| label.is_empty()) {
+        return Err("domain name contains an empty label");
+    }
+    Ok(name.to_lowercase())
+}
+
+// Parse a DST.ADDR domain name into a target, internationalized names are converted to A-labels.
+// id is the connection's for log_privacy
+pub fn parse_domain(raw: &[u8], id: u64) -> Result<TargetAddr, &'static str> {
+    let name = validate_domain(raw)?;
+    match TargetAddr::from_domain(name) {
+        TargetAddr::Domain(name) => {
+            let ascii = idna::domain_to_ascii(&name).map_err(|_| "domain name fails IDNA mapping")?;
+            if ascii.is_empty() || ascii.len() > MAX_DOMAIN_LEN {
+                return Err("domain name fails IDNA mapping");
+            }
+            if ascii != name {
+                debug!("Mapped internationalized domain name {} to {}", privacy::host(&name, id), privacy::host(&ascii, id));
+            }
+            Ok(TargetAddr::Domain(ascii))
+        }
+        ip => Ok(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_names() {
+        let long = format!("{}a", "a.".repeat(127));
+        for (raw, reason) in [
+            (&b""[..], "empty domain name"),
+            (b".", "empty domain name"),
+            (b"\xff\xfe.test", "domain name is not valid UTF-8"),
+            (b"caf\xc3.test", "domain name is not valid UTF-8"),
+            (b"a\0b.test", "domain name contains invalid characters"),
+            (b"a b.test", "domain name contains invalid characters"),
+            (b"a.test\n", "domain name contains invalid characters"),
+            (b"\ta.test", "domain name contains invalid characters"),
+            (b"a\x7f.test", "domain name contains invalid characters"),
+            (b"a..test", "domain name contains an empty label"),
+            (b".a.test", "domain name contains an empty label"),
+            (b"a.test..", "domain name contains an empty label"),
+            (long.as_bytes(), "domain name too long"),
+        ] {
+            assert_eq!(validate_domain(raw), Err(reason), "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn normalized_names() {
+        assert_eq!(validate_domain(b"Example.TEST").as_deref(), Ok("example.test"));
+        assert_eq!(validate_domain(b"example.test.").as_deref(), Ok("example.test"));
+        assert_eq!(validate_domain(b"localhost").as_deref(), Ok("localhost"));
+        // Exactly the longest name, with or without the trailing dot
+        let longest = format!("{}a", "a.".repeat(126));
+        assert_eq!(validate_domain(longest.as_bytes()).as_deref(), Ok(longest.as_str()));
+        assert_eq!(validate_domain(format!("{longest}.").as_bytes()).as_deref(), Ok(longest.as_str()));
+    }
+
+    #[test]
+    fn invalid_names_are_not_targets() {
+        assert_eq!(parse_domain(b"", 0), Err("empty domain name"));
+        assert_eq!(parse_domain(b"a\0b", 0), Err("domain name contains invalid characters"));
+    }
+
+    fn ascii(name: &str) -> Result<TargetAddr, &'static str> {
+        parse_domain(name.as_bytes(), 0)
+    }
+
+    #[test]
+    fn internationalized_names() {
+        let domain = |name: &str| Ok(TargetAddr::Domain(name.to_string()));
+        assert_eq!(ascii("bücher.de"), domain("xn--bcher-kva.de"));
+        assert_eq!(ascii("BÜCHER.de"), domain("xn--bcher-kva.de"));
+        assert_eq!(ascii("日本.jp"), domain("xn--wgv71a.jp"));
+        // Mixed scripts, a Cyrillic "ра" in front of Latin letters, and a U-label among ASCII ones
+        assert_eq!(ascii("раypal.com"), domain("xn--ypal-43d9g.com"));
+        assert_eq!(ascii("www.bücher.example.com"), domain("www.xn--bcher-kva.example.com"));
+    }
+
+    #[test]
+    fn ascii_names_are_kept() {
+        let domain = |name: &str| Ok(TargetAddr::Domain(name.to_string()));
+        for name in ["example.com", "xn--bcher-kva.de", "a_b.test", "ab--c.test", "localhost"] {
+            assert_eq!(ascii(name), domain(name));
+        }
+        assert_eq!(ascii("Example.COM."), domain("example.com"));
+        assert_eq!(ascii("127.0.0.1"), Ok(TargetAddr::Ip([127, 0, 0, 1].into())));
+        assert_eq!(ascii("[::1]"), Ok(TargetAddr::Ip(std::net::Ipv6Addr::LOCALHOST.into())));
+    }
+
+    #[test]
+    fn names_failing_idna() {
+        // Broken punycode, a joiner outside of its context, a leading combining mark
+        for name in ["xn--a.test", "xn--zz-.test", "a\u{200d}b.test", "\u{301}a.test"] {
+            assert_eq!(ascii(name), Err("domain name fails IDNA mapping"), "{name:?}");
+        }
+    }
+}
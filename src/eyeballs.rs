@@ -0,0 +1,147 @@
+use std::future::{poll_fn, Future};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io;
+use tokio::net::TcpStream;
+use tracing::{debug, Instrument};
+
+use crate::config::Config;
+use crate::privacy;
+use crate::reply::Reply;
+
+// connect_retries made, for rock5_connect_retries_total
+pub static RETRIES: AtomicU64 = AtomicU64::new(0);
+
+type Attempt<'a> = Pin<Box<dyn Future<Output = (io::Result<TcpStream>, SocketAddr)> + Send + 'a>>;
+
+// Happy Eyeballs (RFC 8305): each address gets happy_eyeballs_delay before the next one is tried
+// alongside it, or less when it fails sooner. The first to connect wins, the others are dropped.
+// On failure the most telling error is returned along with the address it came from.
+pub async fn connect(addrs: &[SocketAddr], cfg: &Config, id: u64) -> Result<(TcpStream, SocketAddr), (io::Error, SocketAddr)> {
+    let delay = cfg.happy_eyeballs_delay();
+    if delay.is_zero() {
+        return one_by_one(addrs, cfg, id).await;
+    }
+    let mut pending = interleave(addrs).into_iter().peekable();
+    let mut attempts: Vec<Attempt> = Vec::new();
+    let mut failed: Option<(io::Error, SocketAddr)> = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(attempt(addr, cfg, id)),
+                None => return Err(failed.unwrap_or_else(no_address)),
+            }
+        }
+        let more = pending.peek().is_some();
+        tokio::select! {
+            (res, addr) = poll_fn(|cx| first_done(&mut attempts, cx)) => match res {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(e) => {
+                    debug!("Connect attempt to {} failed: {}", privacy::addr(addr, id), e);
+                    if failed.as_ref().is_none_or(|(kept, _)| telling(&e) > telling(kept)) {
+                        failed = Some((e, addr));
+                    }
+                    // No need to wait out the delay for the next one
+                    if let Some(next) = pending.next() {
+                        attempts.push(attempt(next, cfg, id));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(delay), if more => {
+                if let Some(next) = pending.next() {
+                    attempts.push(attempt(next, cfg, id));
+                }
+            }
+        }
+    }
+}
+
+// Without racing: each address in the resolver's order, the last error is the one returned
+async fn one_by_one(addrs: &[SocketAddr], cfg: &Config, id: u64) -> Result<(TcpStream, SocketAddr), (io::Error, SocketAddr)> {
+    let mut failed = None;
+    for addr in addrs {
+        match attempt(*addr, cfg, id).await {
+            (Ok(stream), addr) => return Ok((stream, addr)),
+            (Err(e), addr) => {
+                debug!("Connect attempt to {} failed: {}", privacy::addr(addr, id), e);
+                failed = Some((e, addr));
+            }
+        }
+    }
+    Err(failed.unwrap_or_else(no_address))
+}
+
+fn no_address() -> (io::Error, SocketAddr) {
+    (io::Error::new(io::ErrorKind::AddrNotAvailable, "No address to connect to"), SocketAddr::from(([0, 0, 0, 0], 0)))
+}
+
+fn attempt(addr: SocketAddr, cfg: &Config, id: u64) -> Attempt<'_> {
+    debug!("Connecting to target: {}", privacy::addr(addr, id));
+    Box::pin(async move { (retrying(addr, cfg, id).instrument(crate::connect_span(addr, id)).await, addr) })
+}
+
+// One address, with connect_retries more tries after a refusal or a SYN timeout, all of them within connect_timeout unless it is 0.
+// A retry that could not finish in time is not made, so the error is the target's rather than the timeout.
+async fn retrying(addr: SocketAddr, cfg: &Config, id: u64) -> io::Result<TcpStream> {
+    let connect_timeout = cfg.connect_timeout();
+    let deadline = (!connect_timeout.is_zero()).then(|| Instant::now() + connect_timeout);
+    let tries = async {
+        let (mut retries, delay) = (cfg.connect_retries(), cfg.connect_retry_delay());
+        loop {
+            match crate::connect(addr, cfg, id).await {
+                Err(e) if retries > 0 && retryable(&e) && deadline.is_none_or(|deadline| Instant::now() + delay < deadline) => {
+                    retries -= 1;
+                    RETRIES.fetch_add(1, Ordering::Relaxed);
+                    debug!("Connect attempt to {} failed: {}, retrying in {:?}", privacy::addr(addr, id), e, delay);
+                    tokio::time::sleep(delay).await;
+                }
+                res => return res,
+            }
+        }
+    };
+    let Some(deadline) = deadline else { return tries.await };
+    match tokio::time::timeout_at(deadline.into(), tries).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("Connect timed out after {:?}", connect_timeout))),
+    }
+}
+
+fn retryable(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ECONNREFUSED | libc::ETIMEDOUT))
+}
+
+fn first_done(attempts: &mut Vec<Attempt<'_>>, cx: &mut Context<'_>) -> Poll<(io::Result<TcpStream>, SocketAddr)> {
+    for i in 0..attempts.len() {
+        if let Poll::Ready(out) = attempts[i].as_mut().poll(cx) {
+            drop(attempts.swap_remove(i));
+            return Poll::Ready(out);
+        }
+    }
+    Poll::Pending
+}
+
+// A refusal means the host was reached, a timeout says least
+fn telling(e: &io::Error) -> u8 {
+    match Reply::from(e) {
+        Reply::ConnectionRefused => 4,
+        Reply::HostUnreachable => 3,
+        Reply::NetworkUnreachable => 2,
+        Reply::TtlExpired => 1,
+        _ => 0,
+    }
+}
+
+// Alternate address families, starting with that of the resolver's first answer
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else { return Vec::new() };
+    let (same, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.iter().partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    for i in 0..same.len().max(other.len()) {
+        interleaved.extend(same.get(i).copied());
+        interleaved.extend(other.get(i).copied());
+    }
+    interleaved
+}
@@ -0,0 +1,95 @@
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libgssapi::context::{SecurityContext, ServerCtx};
+use libgssapi::credential::{Cred, CredUsage};
+use tracing::{debug, info, warn};
+
+use crate::privacy::Private;
+
+// RFC 1961 constants
+const GSSAPI_VERSION: u8 = 0x01;
+const MTYP_AUTH: u8 = 0x01;
+const MTYP_PROTECTION: u8 = 0x02;
+const MTYP_ABORT: u8 = 0xFF;
+// Per-message protection is declined, data is relayed as is
+const NO_PROTECTION: u8 = 0x00;
+
+// Run the GSSAPI sub-negotiation, returns the authenticated principal
+pub async fn gssapi_auth<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, client: Private<'static>) -> io::Result<String> {
+    let cred = Cred::acquire(None, None, CredUsage::Accept, None)
+        .map_err(|e| io::Error::other(format!("Could not acquire GSSAPI credentials: {}", e)))?;
+    let mut ctx = ServerCtx::new(Some(cred));
+
+    // Context establishment, tokens are exchanged until the context is complete
+    while !ctx.is_complete() {
+        let token = read_message(stream, MTYP_AUTH).await?;
+        match ctx.step(&token) {
+            Ok(Some(out)) => write_message(stream, MTYP_AUTH, &out).await?,
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Client {} failed GSSAPI authentication: {}", client, e);
+                stream.write_all(&[GSSAPI_VERSION, MTYP_ABORT]).await?;
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "GSSAPI authentication failed"));
+            }
+        }
+    }
+    let principal = ctx.source_name()
+        .map_err(|e| io::Error::other(format!("Could not get GSSAPI source name: {}", e)))?
+        .to_string();
+
+    // Protection level negotiation: a single byte, gss_wrap'ed unless the client uses the NEC variant
+    let token = read_message(stream, MTYP_PROTECTION).await?;
+    let encapsulated = token.len() != 1;
+    let requested = if encapsulated {
+        let level = ctx.unwrap(&token)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Could not unwrap protection level: {}", e)))?;
+        level.first().copied().unwrap_or(NO_PROTECTION)
+    } else {
+        token[0]
+    };
+    if requested != NO_PROTECTION {
+        debug!("Client {} requested GSSAPI protection level {}, declining", client, requested);
+    }
+    if encapsulated {
+        let level = ctx.wrap(false, &[NO_PROTECTION])
+            .map_err(|e| io::Error::other(format!("Could not wrap protection level: {}", e)))?;
+        write_message(stream, MTYP_PROTECTION, &level).await?;
+    } else {
+        write_message(stream, MTYP_PROTECTION, &[NO_PROTECTION]).await?;
+    }
+
+    info!("Client {} authenticated as GSSAPI principal '{}'", client, principal);
+    Ok(principal)
+}
+
+// +------+------+------+.......................+
+// + ver  | mtyp | len  |       token           |
+// +------+------+------+.......................+
+// + 0x01 | 0x01 | 0x02 | up to 2^16 - 1 octets |
+// +------+------+------+.......................+
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S, expected_mtyp: u8) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; 2]; // VER, MTYP
+    stream.read_exact(&mut header).await?;
+    if header[0] != GSSAPI_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported GSSAPI message version"));
+    }
+    if header[1] == MTYP_ABORT {
+        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Client aborted GSSAPI negotiation"));
+    }
+    if header[1] != expected_mtyp {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected GSSAPI message type"));
+    }
+    let len = stream.read_u16().await? as usize;
+    let mut token = vec![0u8; len];
+    stream.read_exact(&mut token).await?;
+    Ok(token)
+}
+
+async fn write_message<S: AsyncWrite + Unpin>(stream: &mut S, mtyp: u8, token: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(token.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "GSSAPI token too long"))?;
+    let mut message = Vec::with_capacity(4 + token.len());
+    message.extend_from_slice(&[GSSAPI_VERSION, mtyp]);
+    message.extend_from_slice(&len.to_be_bytes());
+    message.extend_from_slice(token);
+    stream.write_all(&message).await
+}
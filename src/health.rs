@@ -0,0 +1,59 @@
+use arc_swap::ArcSwap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use crate::config::Config;
+
+// How often health_mode = tcp checks whether to listen
+const TCP_POLL: Duration = Duration::from_secs(1);
+
+// Set once every listener is bound and accepting
+static LIVE: AtomicBool = AtomicBool::new(false);
+// Set on shutdown, before the listeners stop accepting
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_live() {
+    LIVE.store(true, Ordering::Relaxed);
+}
+
+pub fn set_draining() {
+    DRAINING.store(true, Ordering::Relaxed);
+}
+
+pub fn live() -> bool {
+    LIVE.load(Ordering::Relaxed)
+}
+
+// Whether new connections are welcome, not in maintenance mode or while draining
+pub fn ready(cfg: &Config) -> bool {
+    live() && !DRAINING.load(Ordering::Relaxed) && !cfg.maintenance()
+}
+
+// health_mode = tcp: listen only while ready and close every accepted connection
+// Not ready means connection refused, which is all a plain TCP check can tell
+pub async fn serve_tcp(addr: String, cfg: Arc<ArcSwap<Config>>) {
+    loop {
+        while !ready(&cfg.load()) {
+            tokio::time::sleep(TCP_POLL).await;
+        }
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Cannot listen for health checks on {}: {}", addr, e);
+                tokio::time::sleep(TCP_POLL).await;
+                continue;
+            }
+        };
+        info!(" -> Accepting TCP health checks on {}", addr);
+        while ready(&cfg.load()) {
+            tokio::select! {
+                accepted = listener.accept() => drop(accepted),
+                _ = tokio::time::sleep(TCP_POLL) => {}
+            }
+        }
+        info!(" -> Not ready, closed the health check listener on {}", addr);
+    }
+}
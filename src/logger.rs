@@ -0,0 +1,271 @@
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::{OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::SystemTime;
+use tracing::{Event, Metadata, Subscriber};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::format::{Format, Full, Writer};
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+use crate::config::{Config, LogColor, LogFormat, LogLevel, LogTarget, LogTimestamps};
+use crate::rotate;
+use crate::syslog::MakeSyslogWriter;
+
+// Swapped by set_level, the config may change the level on reload
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+// Set by set_format and set_target, pick which of the output layers sees events
+static JSON: AtomicBool = AtomicBool::new(false);
+static TARGET: AtomicU8 = AtomicU8::new(LogTarget::Console as u8);
+// Whether init could open the journald socket
+static JOURNALD_AVAILABLE: AtomicBool = AtomicBool::new(false);
+// Set by set_file, the console only gets the log with log_stdout then
+static LOG_FILE: RwLock<Option<rotate::Writer>> = RwLock::new(None);
+static TO_FILE: AtomicBool = AtomicBool::new(false);
+static LOG_STDOUT: AtomicBool = AtomicBool::new(false);
+// Set by set_console, how plain console lines look, TIMESTAMPS is never Auto
+static COLOR: AtomicBool = AtomicBool::new(false);
+static TIMESTAMPS: AtomicU8 = AtomicU8::new(LogTimestamps::None as u8);
+
+// Sub spans of a connection (negotiate, dns, connect, relay), only for traces
+pub const TRACE_TARGET: &str = "rock5::trace";
+
+// The log outputs leave those out, lines only carry the connection span
+fn logged_to(target: LogTarget, meta: &Metadata<'_>) -> bool {
+    TARGET.load(Ordering::Relaxed) == target as u8 && meta.target() != TRACE_TARGET
+}
+
+fn to_console(meta: &Metadata<'_>) -> bool {
+    logged_to(LogTarget::Console, meta) && (!TO_FILE.load(Ordering::Relaxed) || LOG_STDOUT.load(Ordering::Relaxed))
+}
+
+fn to_file(meta: &Metadata<'_>) -> bool {
+    TO_FILE.load(Ordering::Relaxed) && meta.target() != TRACE_TARGET
+}
+
+// One line per event, connection spans as a prefix (or fields of the JSON object)
+// Errors and warnings go to stderr, everything else to stdout
+pub fn init() {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+    let writer = || std::io::stderr.with_max_level(tracing::Level::WARN).or_else(std::io::stdout);
+    let plain = tracing_subscriber::fmt::layer()
+        .event_format(ConsoleFormat::new())
+        .with_ansi(false)
+        .with_writer(writer())
+        .with_filter(filter_fn(|meta| to_console(meta) && !JSON.load(Ordering::Relaxed)));
+    let json = tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_target(false)
+        .with_writer(writer())
+        .with_filter(filter_fn(|meta| to_console(meta) && JSON.load(Ordering::Relaxed)));
+    // Nobody else adds time and level to the lines in log_file
+    let plain_file = tracing_subscriber::fmt::layer()
+        .with_target(false)
+        .with_ansi(false)
+        .with_writer(MakeFileWriter)
+        .with_filter(filter_fn(|meta| to_file(meta) && !JSON.load(Ordering::Relaxed)));
+    let json_file = tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_target(false)
+        .with_writer(MakeFileWriter)
+        .with_filter(filter_fn(|meta| to_file(meta) && JSON.load(Ordering::Relaxed)));
+    // Syslog has its own time and severity
+    let syslog = tracing_subscriber::fmt::layer()
+        .without_time()
+        .with_target(false)
+        .with_level(false)
+        .with_ansi(false)
+        .with_writer(MakeSyslogWriter)
+        .with_filter(filter_fn(|meta| logged_to(LogTarget::Syslog, meta)));
+    // Span and event fields become journal fields as they are, e.g. CLIENT_ADDR and BYTES_SENT
+    #[cfg(feature = "journald")]
+    let journald = tracing_journald::layer().ok().map(|layer| {
+        JOURNALD_AVAILABLE.store(true, Ordering::Relaxed);
+        layer.with_field_prefix(None).with_filter(filter_fn(|meta| logged_to(LogTarget::Journald, meta)))
+    });
+    let registry = tracing_subscriber::registry().with(filter).with(plain).with(json).with(plain_file).with(json_file).with(syslog);
+    #[cfg(feature = "journald")]
+    let registry = registry.with(journald);
+    #[cfg(feature = "otel")]
+    let registry = registry.with(crate::otel::layer());
+    let _ = registry.try_init();
+    let _ = FILTER.set(handle);
+    // Until the config is loaded
+    set_console(LogColor::Never, LogTimestamps::Auto);
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+// Colors (and with them levels) and timestamps of the plain console lines
+pub fn set_console(color: LogColor, timestamps: LogTimestamps) {
+    let color = match color {
+        LogColor::Auto => io::stdout().is_terminal() && io::stderr().is_terminal(),
+        LogColor::Always => true,
+        LogColor::Never => false,
+    };
+    let timestamps = match timestamps {
+        LogTimestamps::Auto if to_journal() => LogTimestamps::None,
+        LogTimestamps::Auto if io::stderr().is_terminal() => LogTimestamps::Local,
+        LogTimestamps::Auto => LogTimestamps::Rfc3339,
+        timestamps => timestamps,
+    };
+    COLOR.store(color, Ordering::Relaxed);
+    TIMESTAMPS.store(timestamps as u8, Ordering::Relaxed);
+}
+
+// systemd sets JOURNAL_STREAM to the device and inode of stderr when it connected that to the journal
+fn to_journal() -> bool {
+    let Some((dev, ino)) = std::env::var("JOURNAL_STREAM").ok().and_then(|stream| {
+        let (dev, ino) = stream.split_once(':')?;
+        Some((dev.parse::<u64>().ok()?, ino.parse::<u64>().ok()?))
+    }) else {
+        return false;
+    };
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    unsafe { libc::fstat(libc::STDERR_FILENO, &mut stat) == 0 && stat.st_dev as u64 == dev && stat.st_ino as u64 == ino }
+}
+
+// The plain console format as set_console last left it
+struct ConsoleFormat {
+    untimed: [Format<Full, ()>; 2],
+    timed: [Format<Full, Timestamps>; 2],
+}
+
+impl ConsoleFormat {
+    // Without and with colors, the level only shows with them
+    fn new() -> ConsoleFormat {
+        let untimed = |color| tracing_subscriber::fmt::format().without_time().with_target(false).with_level(color).with_ansi(color);
+        let timed = |color| tracing_subscriber::fmt::format().with_timer(Timestamps).with_target(false).with_level(color).with_ansi(color);
+        ConsoleFormat { untimed: [untimed(false), untimed(true)], timed: [timed(false), timed(true)] }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for ConsoleFormat where S: Subscriber + for<'a> LookupSpan<'a>, N: for<'a> FormatFields<'a> + 'static {
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let color = COLOR.load(Ordering::Relaxed) as usize;
+        if TIMESTAMPS.load(Ordering::Relaxed) == LogTimestamps::None as u8 {
+            self.untimed[color].format_event(ctx, writer, event)
+        } else {
+            self.timed[color].format_event(ctx, writer, event)
+        }
+    }
+}
+
+// rfc3339 or local, none never gets here
+struct Timestamps;
+
+impl FormatTime for Timestamps {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        let now = SystemTime::now();
+        if TIMESTAMPS.load(Ordering::Relaxed) != LogTimestamps::Local as u8 {
+            return write!(w, "{}", humantime::format_rfc3339_micros(now));
+        }
+        let since_epoch = now.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs() as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe { libc::localtime_r(&secs, &mut tm) };
+        write!(w, "{:02}:{:02}:{:02}.{:03}", tm.tm_hour, tm.tm_min, tm.tm_sec, since_epoch.subsec_millis())
+    }
+}
+
+// syslog::open has to come first, without journald the console is kept
+pub fn set_target(target: LogTarget) -> io::Result<()> {
+    if target == LogTarget::Journald && !JOURNALD_AVAILABLE.load(Ordering::Relaxed) {
+        TARGET.store(LogTarget::Console as u8, Ordering::Relaxed);
+        return Err(io::Error::new(io::ErrorKind::NotFound, "cannot reach journald"));
+    }
+    TARGET.store(target as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+// (Re)open log_file on start and on every reload, so logrotate can move the file away
+// The file logged to so far is kept when the new one cannot be opened
+pub fn set_file(cfg: &Config) -> io::Result<()> {
+    let writer = cfg.log_file().map(|path| {
+        rotate::Writer::open(path, cfg.log_rotate(), cfg.log_rotate_keep())
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot open log file {}: {e}", path.display())))
+    }).transpose()?;
+    TO_FILE.store(writer.is_some(), Ordering::Relaxed);
+    LOG_STDOUT.store(cfg.log_stdout(), Ordering::Relaxed);
+    let old = std::mem::replace(&mut *LOG_FILE.write().unwrap_or_else(|e| e.into_inner()), writer);
+    // Flushes what it still has queued, outside the lock
+    drop(old);
+    Ok(())
+}
+
+// Lets the fmt layers write to log_file, one queued line per event
+struct MakeFileWriter;
+
+struct FileWriter {
+    buf: Vec<u8>,
+}
+
+impl<'a> MakeWriter<'a> for MakeFileWriter {
+    type Writer = FileWriter;
+
+    fn make_writer(&'a self) -> FileWriter {
+        FileWriter { buf: Vec::new() }
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        if let Some(writer) = LOG_FILE.read().unwrap_or_else(|e| e.into_inner()).as_ref() && !self.buf.is_empty() {
+            writer.write(String::from_utf8_lossy(&self.buf).into_owned());
+        }
+    }
+}
+
+// The configured level, one step more verbose per -v and one step quieter per -q
+// RUST_LOG replaces all of it when set
+pub fn set_level(level: LogLevel, verbose: u8, quiet: u8) {
+    const LEVELS: [LevelFilter; 6] = [
+        LevelFilter::OFF,
+        LevelFilter::ERROR,
+        LevelFilter::WARN,
+        LevelFilter::INFO,
+        LevelFilter::DEBUG,
+        LevelFilter::TRACE,
+    ];
+    let index = level as i32 + 1 + verbose as i32 - quiet as i32;
+    let level = LEVELS[index.clamp(0, 5) as usize];
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        // Libraries only get to warn
+        Err(_) => EnvFilter::new(format!("{},rock5={}", level.min(LevelFilter::WARN), level)),
+    };
+    if let Some(handle) = FILTER.get() {
+        let _ = handle.reload(filter);
+    }
+}
+
+// Most verbose level currently logged
+pub fn level() -> LevelFilter {
+    LevelFilter::current()
+}
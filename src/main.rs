@@ -1,62 +1,930 @@
+mod access;
+mod admin;
+mod auth;
+mod buffer_pool;
+mod client_limit;
+mod client_stream;
+mod cli;
 mod config;
+mod connections;
+mod domain;
+mod eyeballs;
+#[cfg(feature = "gssapi")]
+mod gssapi;
+mod health;
+mod logger;
+mod metrics;
+#[cfg(feature = "otel")]
+mod otel;
+mod relay;
+mod reply;
+mod policy;
+mod privacy;
+mod resolver;
+mod rotate;
+#[cfg(feature = "runtime-metrics")]
+mod runtime_metrics;
+mod session;
+mod shaper;
+mod socks4;
+mod sockopt;
+#[cfg(all(feature = "splice", target_os = "linux"))]
+mod splice;
+mod stats;
+mod statsd;
+mod syslog;
+mod strict;
+mod systemd;
+mod target;
+mod udp;
+mod unix_socket;
+mod units;
 
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
-use bytes::{BytesMut, BufMut}; // Add bytes crate for easier buffer handling
-use ctrlc;
+use std::path::{Path, PathBuf};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use arc_swap::ArcSwap;
+use tokio::sync::Semaphore;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, error_span, info, warn, Instrument};
 
-const SOCKS_VERSION: u8 = 0x05;
-const NO_AUTHENTICATION_REQUIRED: u8 = 0x00;
+use reply::{send_failure, send_reply, send_reply_to, Reply};
+use client_limit::ClientLimits;
+use client_stream::ClientStream;
+use config::{ClientLimitAction, LimitAction, ListenFamily};
+use connections::State;
+use session::{CloseReason, Peer, Session, Stage};
+use stats::Stats;
+use target::TargetAddr;
+use unix_socket::UnixPeer;
+
+pub(crate) const SOCKS_VERSION: u8 = 0x05;
 const CONNECT_COMMAND: u8 = 0x01;
-const RSV: u8 = 0x00; // Reserved byte
+const BIND_COMMAND: u8 = 0x02;
+const UDP_ASSOCIATE_COMMAND: u8 = 0x03;
+// Tor extension, not part of RFC 1928
+const RESOLVE_COMMAND: u8 = 0xF0;
+const RESOLVE_PTR_COMMAND: u8 = 0xF1;
+pub(crate) const RSV: u8 = 0x00; // Reserved byte
 
 // Address Type constants
-const ATYP_IPV4: u8 = 0x01;
-const ATYP_DOMAIN_NAME: u8 = 0x03;
-const ATYP_IPV6: u8 = 0x04;
+pub(crate) const ATYP_IPV4: u8 = 0x01;
+pub(crate) const ATYP_DOMAIN_NAME: u8 = 0x03;
+pub(crate) const ATYP_IPV6: u8 = 0x04;
+
+// Editors write in several steps, wait for them to settle before reloading
+const CONFIG_DEBOUNCE: Duration = Duration::from_millis(500);
+// How long a tfo_connect target waits for the client's first bytes to put into its SYN
+const FAST_OPEN_WAIT: Duration = Duration::from_millis(200);
 
-// Reply Field constants
-const REP_SUCCEEDED: u8 = 0x00;
-const REP_GENERAL_FAILURE: u8 = 0x01;
-// Add other reply codes as needed (e.g., connection refused, network unreachable)
 
+// SIGINT (Ctrl-C) and SIGTERM, the first drains the connections, a second one exits right away
+struct Termination {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Termination {
+    fn new() -> io::Result<Termination> {
+        use tokio::signal::unix::{signal, SignalKind};
+        let map_err = |e: io::Error| io::Error::new(e.kind(), format!("cannot install SIGINT/SIGTERM handler: {e}"));
+        Ok(Termination { interrupt: signal(SignalKind::interrupt()).map_err(map_err)?, terminate: signal(SignalKind::terminate()).map_err(map_err)? })
+    }
+
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.interrupt.recv() => {}
+            _ = self.terminate.recv() => {}
+        }
+    }
+}
+
+// Print the counters on SIGUSR1, reset the destination counters on SIGUSR2
+fn setup_stats_dump(stats: Arc<Stats>) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let (mut usr1, mut usr2) = match (signal(SignalKind::user_defined1()), signal(SignalKind::user_defined2())) {
+            (Ok(usr1), Ok(usr2)) => (usr1, usr2),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Could not install SIGUSR1/SIGUSR2 handler: {}", e);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                Some(()) = usr1.recv() => stats.dump(),
+                Some(()) = usr2.recv() => {
+                    stats.reset_destinations();
+                    info!(" -> Reset the destination counters");
+                }
+                else => break,
+            }
+        }
+    });
+    #[cfg(not(unix))]
+    let _ = stats;
+}
+
+// Re-read the config on SIGHUP, listen addresses need a restart
+fn setup_reload(cli: cli::Cli, cfg: Arc<ArcSwap<config::Config>>) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hup = match signal(SignalKind::hangup()) {
+            Ok(hup) => hup,
+            Err(e) => {
+                error!("Could not install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        while hup.recv().await.is_some() {
+            reload(&cli, &cfg);
+        }
+    });
+    #[cfg(not(unix))]
+    let _ = (cli, cfg);
+}
 
-fn setup_signals(){
-    let res = ctrlc::set_handler(move || {
-        println!("Terminating.");
-        std::process::exit(1) 
+// With watch_config, reload once the config files have been quiet for CONFIG_DEBOUNCE
+fn setup_watch(cli: cli::Cli, cfg: Arc<ArcSwap<config::Config>>) {
+    if cfg.load().files().is_empty() {
+        warn!("watch_config is set but no config file was read, nothing to watch");
+        return;
+    }
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res && !event.kind.is_access() {
+            let _ = tx.send(event.paths);
+        }
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("Could not watch the config: {}", e);
+            return;
+        }
+    };
+    let mut dirs = Vec::new();
+    let mut targets = watch_targets(&mut watcher, &mut dirs, cfg.load().files());
+    tokio::spawn(async move {
+        while let Some(paths) = rx.recv().await {
+            let watched = |changed: &PathBuf| targets.iter().any(|(dir, name)| changed.parent() == Some(dir) && changed.file_name().is_some_and(|file| name.matches(&file.to_string_lossy())));
+            if !paths.iter().any(watched) {
+                continue;
+            }
+            while let Ok(Some(_)) = tokio::time::timeout(CONFIG_DEBOUNCE, rx.recv()).await {}
+            reload(&cli, &cfg);
+            // Includes may have changed
+            targets = watch_targets(&mut watcher, &mut dirs, cfg.load().files());
+        }
     });
+}
+
+// Editors often write a new file and rename it over the old one, so watch the directories
+// Returns each file's directory with the name, which may be a glob
+fn watch_targets(watcher: &mut notify::RecommendedWatcher, dirs: &mut Vec<PathBuf>, files: &[PathBuf]) -> Vec<(PathBuf, glob::Pattern)> {
+    let mut targets = Vec::new();
+    for file in files {
+        let parent = file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let (Ok(dir), Some(name)) = (parent.canonicalize(), file.file_name()) else { continue };
+        let Ok(name) = glob::Pattern::new(&name.to_string_lossy()) else { continue };
+        if !dirs.contains(&dir) {
+            if let Err(e) = notify::Watcher::watch(watcher, &dir, notify::RecursiveMode::NonRecursive) {
+                error!("Could not watch config {:?}: {}", file, e);
+                continue;
+            }
+            info!(" -> Watching {:?} for changes", dir);
+            dirs.push(dir.clone());
+        }
+        targets.push((dir, name));
+    }
+    targets
+}
+
+// Only a config that loads completely replaces the running one, new connections pick it up
+fn reload(cli: &cli::Cli, cfg: &ArcSwap<config::Config>) {
+    if cli.config.as_deref() == Some(Path::new(config::STDIN_PATH)) {
+        warn!("The config was read from stdin and cannot be reloaded, keeping it");
+        access::open(&cfg.load());
+        return;
+    }
+    let new_cfg = match config::Config::load(cli) {
+        Ok(new_cfg) => new_cfg,
+        Err(e) => {
+            error!("Reload failed, keeping the old config: {}", e);
+            return;
+        }
+    };
+    logger::set_level(new_cfg.log_level(), cli.verbose, cli.quiet);
+    logger::set_format(new_cfg.log_format());
+    logger::set_console(new_cfg.log_color(), new_cfg.log_timestamps());
+    privacy::set(new_cfg.log_privacy());
+    shaper::GLOBAL.set_rate(new_cfg.bandwidth_limit());
+    buffer_pool::RELAY.set_max(new_cfg.relay_buffer_pool());
+    syslog::open(&new_cfg);
+    if let Err(e) = logger::set_target(new_cfg.log_target()) {
+        error!("Logging to the console instead: {}", e);
+    }
+    if let Err(e) = logger::set_file(&new_cfg) {
+        error!("Keeping the old log file: {}", e);
+    }
+    access::open(&new_cfg);
+    #[cfg(feature = "otel")]
+    otel::set_sample_ratio(new_cfg.trace_sample_ratio());
+    let changes = cfg.load().diff(&new_cfg);
+    cfg.store(Arc::new(new_cfg));
+    info!(" -> Reloaded config, {} change(s)", changes.len());
+    for change in changes {
+        info!("    {}", change);
+    }
+}
+
+// The runtime is built here instead of by #[tokio::main], runtime_metrics keeps its handle
+fn main() {
+    let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Could not start the tokio runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+    #[cfg(feature = "runtime-metrics")]
+    runtime_metrics::set_handle(runtime.handle().clone());
+    runtime.block_on(start());
+    // Without waiting for blocking tasks such as a hung lookup
+    runtime.shutdown_background();
+}
+
+async fn start() {
+    let cli = cli::Cli::parse_args();
+    logger::init();
+    logger::set_level(config::LogLevel::Info, cli.verbose, cli.quiet);
+    // The config is not loaded yet, its errors should already come out as JSON
+    if let Ok(format) = std::env::var("ROCK5_LOG_FORMAT") && let Ok(format) = format.parse() {
+        logger::set_format(format);
+    }
+    // Keep the printed config clean of progress messages
+    if cli.print_config {
+        logger::set_level(config::LogLevel::Warn, 0, 0);
+    }
+    if let Some(cli::Command::GenConfig { stdout, force }) = cli.command {
+        if let Err(e) = config::write_default_config(stdout, force) {
+            error!("Could not write config: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let cfg = match config::Config::load(&cli) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if cli.print_config {
+        print!("{}", cfg.effective());
+        return;
+    }
+    logger::set_level(cfg.log_level(), cli.verbose, cli.quiet);
+    logger::set_format(cfg.log_format());
+    logger::set_console(cfg.log_color(), cfg.log_timestamps());
+    privacy::set(cfg.log_privacy());
+    shaper::GLOBAL.set_rate(cfg.bandwidth_limit());
+    buffer_pool::RELAY.set_max(cfg.relay_buffer_pool());
+    syslog::open(&cfg);
+    if let Err(e) = logger::set_target(cfg.log_target()) {
+        error!("Logging to the console instead: {}", e);
+    }
+    if let Some(path) = cfg.log_file() {
+        info!(" -> Logging to {:?}", path);
+    }
+    if let Err(e) = logger::set_file(&cfg) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    access::open(&cfg);
+    #[cfg(feature = "otel")]
+    {
+        otel::set_sample_ratio(cfg.trace_sample_ratio());
+        otel::log_status();
+    }
 
-    if res.is_err(){
-        panic!("{res:?}")
+    // Not returned from main, that would print it past the logger
+    let res = run(cli, cfg).await;
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+    if let Err(e) = res {
+        error!("{}", e);
+        std::process::exit(1);
     }
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    let mut cfg = config::get_config();
+async fn run(cli: cli::Cli, mut cfg: config::Config) -> io::Result<()> {
+    let mut termination = Termination::new()?;
+    // Removed again on shutdown, unlike those systemd passed
+    let mut unix_paths = Vec::new();
+    let passed = systemd::listen_fds();
+    let listeners = if passed.is_empty() {
+        bind_listeners(&mut cfg, &mut unix_paths).await?
+    } else {
+        adopt(passed, &cfg.listen_addrs())?
+    };
 
-    setup_signals();
-    let list_addr: String = cfg.get_host_str();
-    println!(" -> Listening on {list_addr:?}");
+    // Swapped on reload, each connection keeps the config it started with
+    let cfg = Arc::new(ArcSwap::from_pointee(cfg));
+    let stats = Arc::new(Stats::default());
+    setup_stats_dump(stats.clone());
+    if let Some(addr) = cfg.load().metrics_listen() {
+        let listener = TcpListener::bind(addr).await
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot serve metrics on {addr}: {e}")))?;
+        info!(" -> Serving metrics on http://{}/metrics", addr);
+        tokio::spawn(metrics::serve(listener, stats.clone(), cfg.clone(), true));
+        #[cfg(feature = "runtime-metrics")]
+        tokio::spawn(runtime_metrics::sample(cfg.clone()));
+    }
+    if let Some(addr) = cfg.load().statsd_addr() {
+        let socket = statsd::connect(addr).await
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot send metrics to StatsD at {addr}: {e}")))?;
+        info!(" -> Sending metrics to StatsD at {}", addr);
+        tokio::spawn(statsd::serve(socket, stats.clone(), cfg.clone()));
+    }
+    if let Some(addr) = cfg.load().health_listen() {
+        if cfg.load().health_mode() == config::HealthMode::Tcp {
+            tokio::spawn(health::serve_tcp(addr.to_string(), cfg.clone()));
+        } else {
+            let listener = TcpListener::bind(addr).await
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot serve health checks on {addr}: {e}")))?;
+            info!(" -> Serving health checks on http://{}/healthz and /readyz", addr);
+            tokio::spawn(metrics::serve(listener, stats.clone(), cfg.clone(), false));
+        }
+    }
+    if let Some(path) = cfg.load().admin_socket() {
+        let listener = admin::bind(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot create admin socket {}: {e}", path.display())))?;
+        info!(" -> Admin socket at {:?}", path);
+        tokio::spawn(admin::serve(listener, stats.clone()));
+    }
+    if cfg.load().watch_config() {
+        setup_watch(cli.clone(), cfg.clone());
+    }
+    setup_reload(cli, cfg.clone());
 
-    let listener = TcpListener::bind(list_addr).await?;
-    
+    // One accept loop per listener, the first failing one stops the proxy
+    // Shared by all listeners, resizing it needs a restart
+    let limit = {
+        let max_connections = cfg.load().max_connections();
+        (max_connections > 0).then(|| Arc::new(Semaphore::new(max_connections)))
+    };
+    let clients = Arc::new(ClientLimits::default());
+    let (stop, stopping) = tokio::sync::watch::channel(false);
+    let mut accept_loops = tokio::task::JoinSet::new();
+    for (name, listener) in listeners {
+        accept_loops.spawn(serve(name, listener, cfg.clone(), stats.clone(), limit.clone(), clients.clone(), stopping.clone()));
+    }
+    health::set_live();
+    tokio::select! {
+        res = async {
+            while let Some(res) = accept_loops.join_next().await {
+                res??;
+            }
+            io::Result::Ok(())
+        } => return res,
+        _ = termination.recv() => {}
+    }
+
+    // Load balancers see /readyz fail first, new connections still get served meanwhile
+    health::set_draining();
+    let drain_delay = cfg.load().drain_delay();
+    if !drain_delay.is_zero() {
+        info!("Not ready, accepting for {:?} before draining", drain_delay);
+        tokio::select! {
+            _ = tokio::time::sleep(drain_delay) => {}
+            _ = termination.recv() => terminate_now(),
+        }
+    }
+    // Each accept loop returns once its connections are done
+    let _ = stop.send(true);
+    let shutdown_timeout = cfg.load().shutdown_timeout();
+    info!("Terminating, waiting for {} open connections", stats.active.load(Ordering::Relaxed));
+    let drained = async { while accept_loops.join_next().await.is_some() {} };
+    let deadline = async {
+        if shutdown_timeout.is_zero() {
+            return std::future::pending().await;
+        }
+        tokio::time::sleep(shutdown_timeout).await
+    };
+    let res = tokio::select! {
+        _ = drained => {
+            info!("All connections closed");
+            Ok(())
+        }
+        // Dropping the accept loops aborts their connections, which is not a clean exit
+        _ = deadline => Err(io::Error::new(io::ErrorKind::TimedOut, format!("Closed {} connections still open after {:?}", stats.active.load(Ordering::Relaxed), shutdown_timeout))),
+        _ = termination.recv() => terminate_now(),
+    };
+    for path in unix_paths {
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Could not remove {:?}: {}", path, e);
+        }
+    }
+    res
+}
+
+// On the second SIGINT or SIGTERM, without waiting for the connections
+fn terminate_now() -> ! {
+    info!("Terminating.");
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+    std::process::exit(1)
+}
+
+// Every configured listen address, unix sockets are added to unix_paths
+async fn bind_listeners(cfg: &mut config::Config, unix_paths: &mut Vec<PathBuf>) -> io::Result<Vec<(String, Listener)>> {
+    let mut acceptors = match cfg.acceptors() {
+        0 => tokio::runtime::Handle::current().metrics().num_workers(),
+        acceptors => acceptors,
+    };
+    if acceptors > 1 && !sockopt::REUSE_PORT {
+        warn!("SO_REUSEPORT does not spread connections on this system, using one acceptor per listen address");
+        acceptors = 1;
+    }
+    let backlog = cfg.listen_backlog();
+    match sockopt::effective_backlog(backlog) {
+        effective if effective < backlog => warn!("listen_backlog {} is capped at {} by net.core.somaxconn", backlog, effective),
+        effective => info!(" -> Listen backlog {}", effective),
+    }
+    if cfg.tfo_listen() && !sockopt::fast_open_server_enabled() {
+        warn!("tfo_listen is on but net.ipv4.tcp_fastopen does not include 2, the kernel ignores Fast Open SYNs");
+    }
+    let mut listeners = Vec::new();
+    for (name, list_addr) in cfg.listen_addrs() {
+        if let Some(path) = list_addr.strip_prefix("unix:") {
+            let listener = unix_socket::bind(Path::new(path), cfg.unix_socket_mode(), cfg.unix_socket_owner(), backlog)
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot listen on {list_addr}: {e}")))?;
+            info!(" -> Listening on {list_addr:?} as {name} (log level {})", logger::level());
+            unix_paths.push(PathBuf::from(path));
+            listeners.push((name, Listener::Unix(listener, Box::leak(path.into()))));
+            continue;
+        }
+        let bound = bind(&list_addr, acceptors, cfg.listen_family(), backlog).await
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot listen on {list_addr}: {e}")))?;
+        if cfg.tfo_listen() {
+            for listener in &bound {
+                sockopt::fast_open(listener, backlog);
+            }
+        }
+        let mut sockets: Vec<String> = bound.iter().map(sockopt::describe).collect();
+        sockets.dedup();
+        let shared = if acceptors > 1 { format!(", {acceptors} acceptors each") } else { String::new() };
+        info!(" -> Listening on {list_addr:?} as {name}: {} (log level {}{shared})", sockets.join(", "), logger::level());
+        listeners.extend(bound.into_iter().map(|listener| (name.clone(), Listener::Tcp(listener))));
+    }
+
+    Ok(listeners)
+}
+
+// Socket activation: serve the sockets systemd passed instead of the configured addresses.
+// Each goes to the listener it is named after (FileDescriptorName=) or whose address it has
+fn adopt(passed: Vec<(Option<String>, socket2::Socket)>, configured: &[(String, String)]) -> io::Result<Vec<(String, Listener)>> {
+    info!(" -> Socket activated, using the {} sockets passed by systemd", passed.len());
+    let mut listeners = Vec::new();
+    let mut covered = vec![false; configured.len()];
+    for (fd_name, socket) in passed {
+        let local = socket.local_addr()?;
+        let shown = match (local.as_socket(), local.as_pathname()) {
+            (Some(addr), _) => addr.to_string(),
+            (None, Some(path)) => format!("unix:{}", path.display()),
+            _ => "an unnamed socket".to_string(),
+        };
+        if socket.r#type()? != socket2::Type::STREAM {
+            warn!("Ignoring {} passed by systemd, not a stream socket", shown);
+            continue;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        if !socket.is_listener()? {
+            warn!("Ignoring {} passed by systemd, not listening (Accept=yes is not supported)", shown);
+            continue;
+        }
+        socket.set_nonblocking(true)?;
+        let by_addr: Vec<usize> = (0..configured.len()).filter(|&i| same_listen_addr(&configured[i].1, &local)).collect();
+        let name = match fd_name {
+            Some(fd_name) if configured.iter().any(|(name, _)| *name == fd_name) => {
+                for (i, (name, _)) in configured.iter().enumerate() {
+                    covered[i] |= *name == fd_name;
+                }
+                fd_name
+            }
+            _ if !by_addr.is_empty() => configured[by_addr[0]].0.clone(),
+            _ => {
+                warn!("{} passed by systemd is not among the listen addresses, serving it as {}", shown, config::DEFAULT_LISTENER);
+                config::DEFAULT_LISTENER.to_string()
+            }
+        };
+        for i in by_addr {
+            covered[i] = true;
+        }
+        let listener = match local.as_pathname() {
+            Some(path) => Listener::Unix(UnixListener::from_std(socket.into())?, Box::leak(path.to_string_lossy().into())),
+            None => Listener::Tcp(TcpListener::from_std(socket.into())?),
+        };
+        info!(" -> Listening on {} as {} (from systemd, log level {})", shown, name, logger::level());
+        listeners.push((name, listener));
+    }
+    for ((name, addr), covered) in configured.iter().zip(covered) {
+        if !covered {
+            warn!("Not listening on {:?} ({}), systemd passed no socket for it", addr, name);
+        }
+    }
+    if listeners.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "none of the sockets passed by systemd can be used"));
+    }
+    Ok(listeners)
+}
+
+// Whether a configured listen address is that of a socket, wildcards of either family count as the same
+fn same_listen_addr(configured: &str, local: &socket2::SockAddr) -> bool {
+    if let Some(path) = configured.strip_prefix("unix:") {
+        return local.as_pathname() == Some(Path::new(path));
+    }
+    let Some(local) = local.as_socket() else { return false };
+    use std::net::ToSocketAddrs;
+    configured.to_socket_addrs().is_ok_and(|mut addrs| addrs.any(|addr| {
+        addr.port() == local.port() && (addr.ip() == local.ip() || (addr.ip().is_unspecified() && local.ip().is_unspecified()))
+    }))
+}
+
+// Sockets for the first address list_addr resolves to that can be bound, several share it with SO_REUSEPORT.
+// Wildcard addresses are bound for the families of listen_family
+async fn bind(list_addr: &str, acceptors: usize, family: ListenFamily, backlog: i32) -> io::Result<Vec<TcpListener>> {
+    let mut failed = None;
+    for addr in tokio::net::lookup_host(list_addr).await? {
+        if !addr.ip().is_unspecified() {
+            match bind_all(&[(addr, None)], acceptors, backlog) {
+                Ok(listeners) => return Ok(listeners),
+                Err(e) => failed = Some(e),
+            }
+            continue;
+        }
+        let v4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, addr.port()));
+        let v6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, addr.port()));
+        let sockets = match family {
+            ListenFamily::V4 => vec![(v4, None)],
+            ListenFamily::V6 => vec![(v6, Some(true))],
+            ListenFamily::Dual if sockopt::DUAL_STACK => vec![(v6, Some(false))],
+            ListenFamily::Dual => vec![(v4, None), (v6, Some(true))],
+        };
+        match bind_all(&sockets, acceptors, backlog) {
+            // IPv6 turned off in the kernel
+            Err(e) if family == ListenFamily::Dual && (e.raw_os_error() == Some(libc::EAFNOSUPPORT) || e.kind() == io::ErrorKind::AddrNotAvailable) => {
+                warn!("Could not listen on {} ({}), IPv4 only", v6, e);
+                return bind_all(&[(v4, None)], acceptors, backlog);
+            }
+            res => return res,
+        }
+    }
+    Err(failed.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on")))
+}
+
+// acceptors sockets for each address, all on the port the first one got
+fn bind_all(sockets: &[(SocketAddr, Option<bool>)], acceptors: usize, backlog: i32) -> io::Result<Vec<TcpListener>> {
+    let mut listeners: Vec<TcpListener> = Vec::new();
+    for &(mut addr, only_v6) in sockets {
+        // Port 0 picks one
+        if let Some(first) = listeners.first() {
+            addr.set_port(first.local_addr()?.port());
+        }
+        let first = sockopt::listener(addr, acceptors > 1, only_v6, backlog)?;
+        addr.set_port(first.local_addr()?.port());
+        listeners.push(first);
+        for _ in 1..acceptors {
+            listeners.push(sockopt::listener(addr, true, only_v6, backlog)?);
+        }
+    }
+    Ok(listeners)
+}
+
+// What an accept loop takes connections from
+enum Listener {
+    Tcp(TcpListener),
+    // With the path its clients are shown with
+    Unix(UnixListener, &'static str),
+}
+
+enum Accepted {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<(Accepted, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                // IPv4 clients of a dual-stack socket come as ::ffff:a.b.c.d
+                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                Ok((Accepted::Tcp(stream), Peer::Tcp(addr)))
+            }
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                let peer = UnixPeer::of(&stream, path);
+                Ok((Accepted::Unix(stream), Peer::Unix(peer)))
+            }
+        }
+    }
+}
+
+// Accepts until stopping turns true, then waits for the connections it accepted
+async fn serve(name: String, listener: Listener, cfg: Arc<ArcSwap<config::Config>>, stats: Arc<Stats>, limit: Option<Arc<Semaphore>>, clients: Arc<ClientLimits>, mut stopping: tokio::sync::watch::Receiver<bool>) -> io::Result<()> {
+    let (listen_addr, local) = match &listener {
+        Listener::Tcp(tcp) => (tcp.local_addr()?, tcp.local_addr()?.to_string()),
+        Listener::Unix(_, path) => (SocketAddr::from(([0, 0, 0, 0], 0)), format!("unix:{path}")),
+    };
+    // A scan or a runaway client could flood the log otherwise
+    let (mut refused_log, mut dropped_log, mut accept_log) = (Throttled::default(), Throttled::default(), Throttled::default());
+    // Grows while accept() runs out of resources
+    let mut backoff = None;
+    let mut tasks = tokio::task::JoinSet::new();
     loop {
-        let (client_stream, client_addr) = listener.accept().await?;
-        println!(" -> Accepted connection from: {}", client_addr);
+        let accepted = async {
+            // Backpressure: without a free slot, leave new connections in the backlog
+            let permit = match &limit {
+                Some(limit) if cfg.load_full().for_listener(&name).max_connections_action() == LimitAction::Wait => Some(limit.clone().acquire_owned().await.map_err(io::Error::other)?),
+                _ => None,
+            };
+            let (client_stream, peer) = listener.accept().await?;
+            io::Result::Ok((permit, client_stream, peer))
+        };
+        let res = tokio::select! {
+            res = accepted => res,
+            Some(_) = tasks.join_next() => continue,
+            _ = stopping.wait_for(|&stop| stop) => break,
+        };
+        let (mut permit, client_stream, peer) = match res {
+            Ok(accepted) => {
+                backoff = None;
+                accepted
+            }
+            Err(e) => {
+                Stats::inc(&stats.accept_errors);
+                match accept_error(&e) {
+                    AcceptError::Fatal => {
+                        error!("Could not accept on {} ({}): {}", local, name, e);
+                        return Err(e);
+                    }
+                    // The client gave up already, the next one may be fine
+                    AcceptError::Dropped => debug!("Accept on {} ({}) failed: {}", local, name, e),
+                    // The connection stays in the backlog, trying again at once would spin until a descriptor frees up
+                    AcceptError::Exhausted => {
+                        let delay = backoff.map_or(ACCEPT_BACKOFF_MIN, |delay: Duration| (delay * 2).min(ACCEPT_BACKOFF_MAX));
+                        backoff = Some(delay);
+                        if let Some(unlogged) = accept_log.ready() {
+                            warn!("Could not accept on {} ({}): {}, trying again in {:?}{}", local, name, e, delay, unlogged);
+                        }
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => {}
+                            _ = stopping.wait_for(|&stop| stop) => break,
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+        // Sections fall back to the [config] values
+        let cfg = cfg.load_full().for_listener(&name);
+
+        let mut client_slot = None;
+        let mut over_client_limit = false;
+        // The permissions of a unix socket decide who may use it
+        if let Peer::Tcp(client_addr) = peer {
+            let allowed = cfg.allowed_clients();
+            if !allowed.is_empty() && !allowed.iter().any(|range| range.contains(client_addr.ip())) {
+                drop(client_stream);
+                if let Some(unlogged) = refused_log.ready() {
+                    warn!("Refused connection from {} on {} ({}), not in allowed_clients{}", privacy::client(client_addr, 0), local, name, unlogged);
+                }
+                continue;
+            }
+
+            let max_per_client = cfg.max_connections_per_client();
+            if max_per_client > 0 {
+                client_slot = clients.acquire(client_addr.ip(), max_per_client, cfg.client_ipv6_prefix());
+                over_client_limit = client_slot.is_none();
+                if over_client_limit && cfg.max_connections_per_client_action() == ClientLimitAction::Drop {
+                    drop(client_stream);
+                    Stats::inc(&stats.client_limit_rejected);
+                    statsd::count(&name, "client_limit_rejected", "", 1);
+                    if let Some(unlogged) = dropped_log.ready() {
+                        warn!("Dropped connection from {} on {} ({}), max_connections_per_client reached{}", privacy::client(client_addr, 0), local, name, unlogged);
+                    }
+                    continue;
+                }
+            }
+        }
+        let mut session = Session::new(peer, listen_addr, name.clone());
+        let span = session.span.clone();
+        span.in_scope(|| info!(" -> Accepted connection from: {} on {} ({})", privacy::client(peer, session.id), local, name));
+        Stats::inc(&stats.accepted);
+        statsd::count(&name, "connections.accepted", "", 1);
+
+        let mut over_limit = false;
+        if let Some(limit) = &limit && permit.is_none() {
+            permit = limit.clone().try_acquire_owned().ok();
+            over_limit = permit.is_none();
+        }
 
         // Spawn a new asynchronous task to handle each client connection
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(client_stream, client_addr).await {
-                eprintln!("Error handling client {}: {}", client_addr, e);
+        let stats = stats.clone();
+        session.over_limit = over_limit;
+        session.over_client_limit = over_client_limit;
+        stats.connections.insert(session.live.clone());
+        tasks.spawn(async move {
+            // Held until the connection is done
+            let (_permit, _client_slot) = (permit, client_slot);
+            let (id, listener) = (session.id, session.listener.clone());
+            Stats::inc(&stats.active);
+            let res = match client_stream {
+                Accepted::Tcp(stream) => handle_client(stream, session, cfg, &stats).await,
+                Accepted::Unix(stream) => handle_client(stream, session, cfg, &stats).await,
+            };
+            if let Err(e) = res {
+                warn!("Error handling client {}: {}", privacy::client(peer, id), e);
+                Stats::inc(&stats.failed);
+                statsd::count(&listener, "connections.failed", "", 1);
             }
-        });
+            stats.connections.remove(id);
+            Stats::dec(&stats.active);
+        }.instrument(span));
+    }
+    // New connections are refused from here on
+    drop(listener);
+    while tasks.join_next().await.is_some() {}
+    Ok(())
+}
+
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq, Eq)]
+enum AcceptError {
+    // Out of file descriptors or memory, until connections close
+    Exhausted,
+    // Only this connection is lost
+    Dropped,
+    // The listening socket itself is broken
+    Fatal,
+}
+
+fn accept_error(e: &io::Error) -> AcceptError {
+    match e.raw_os_error() {
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) => AcceptError::Exhausted,
+        Some(libc::EBADF | libc::ENOTSOCK | libc::EINVAL | libc::EFAULT | libc::EOPNOTSUPP) => AcceptError::Fatal,
+        // ECONNABORTED, EPROTO, EPERM from a firewall and the errors of the new connection Linux passes on
+        _ => AcceptError::Dropped,
+    }
+}
+
+// Lets a warning through at most once a second
+#[derive(Default)]
+struct Throttled {
+    last: Option<Instant>,
+    unlogged: u64,
+}
+
+impl Throttled {
+    // Some with a note of those left out since the last one, None to leave this one out
+    fn ready(&mut self) -> Option<String> {
+        if self.last.is_some_and(|last| last.elapsed() < Duration::from_secs(1)) {
+            self.unlogged += 1;
+            return None;
+        }
+        let note = if self.unlogged > 0 { format!(" ({} more not logged)", self.unlogged) } else { String::new() };
+        self.last = Some(Instant::now());
+        self.unlogged = 0;
+        Some(note)
     }
 }
 
-async fn handle_client(mut client_stream: TcpStream, client_addr: SocketAddr) -> io::Result<()> {
+// What is left to do for a connection once the handshake completed
+enum Negotiated {
+    // Connected to the target, relay data
+    Relay(TcpStream, SocketAddr),
+    // BIND request for the given peer
+    Bind(TargetAddr, u16),
+    // UDP ASSOCIATE request from the given client address
+    Associate(TargetAddr, u16),
+    // Request already fully answered
+    Done,
+}
+
+// Serve the connection, then write its access log line
+async fn handle_client<S: ClientStream>(client_stream: S, mut session: Session, cfg: Arc<config::Config>, stats: &Stats) -> io::Result<()> {
+    let started = Instant::now();
+    client_stream.tune(&cfg);
+    let span = session.span.clone();
+    let serving = reply::LAST_REPLY.scope(Cell::new(None), async {
+        let res = serve_client(client_stream, &mut session, &cfg, stats).await;
+        (res, reply::LAST_REPLY.with(Cell::get))
+    });
+    let (res, reply) = reply::CONN_SPAN.scope(span, serving).await;
+    if let Some(code) = reply {
+        Stats::inc(&stats.replies[code as usize]);
+        statsd::count(&session.listener, "replies", format!("code:{code}"), 1);
+    }
+    if let Some((host, _)) = session.target.as_deref().and_then(|target| target.rsplit_once(':')) {
+        stats.record_destination(host, session.sent, session.received);
+    }
+    stats.record_timings(&session.timings, reply);
+    statsd::record_timings(&session.timings, &session.listener, reply);
+    // The error says more than SOCKS4's single rejection code, the reply more than an "other" error
+    let failed_reply = reply.filter(|&code| code != Reply::Succeeded as u8 && code != socks4::SOCKS4_GRANTED);
+    let reason = match (&res, failed_reply) {
+        (Err(e), _) if stats::error_reason(e) != "other" => Some(stats::error_reason(e)),
+        (_, Some(code)) => Some(stats::reply_reason(code)),
+        (Err(_), None) => Some("other"),
+        (Ok(()), None) => None,
+    };
+    if let Some(reason) = reason {
+        stats.failed_at(session.stage, reason, &session.listener);
+    }
+    let close_reason = match &res {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => CloseReason::ClientEof,
+        Err(_) => CloseReason::Error,
+        Ok(()) => session.close_reason,
+    };
+    Stats::inc(&stats.closed[close_reason as usize]);
+    statsd::count(&session.listener, "connections.closed", format!("reason:{}", close_reason.name()), 1);
+    // Exactly one per connection, whatever happened to it
+    info!(
+        id = session.id,
+        client = %privacy::client(session.peer, session.id),
+        user = session.user.as_deref().unwrap_or("-"),
+        dest = session.target.as_deref().map_or("-".to_string(), |target| privacy::host_port(target, session.id)),
+        resolved = %session.resolved.map_or("-".to_string(), |addr| privacy::addr(addr, session.id).to_string()),
+        reply = %reply.map_or("-".to_string(), |code| code.to_string()),
+        bytes_sent = session.sent,
+        bytes_received = session.received,
+        duration_ms = started.elapsed().as_millis() as u64,
+        close_reason = close_reason.name(),
+        "Connection closed for {}", session
+    );
+    access::record(&session, reply, started.elapsed());
+    res
+}
+
+async fn serve_client<S: ClientStream>(mut client_stream: S, session: &mut Session, cfg: &config::Config, stats: &Stats) -> io::Result<()> {
+    // The handshake deadline ends where resolving and connecting start, connect_timeout covers those
+    let live = session.live.clone();
+    let negotiation = negotiate(&mut client_stream, session, cfg, stats).instrument(error_span!(target: logger::TRACE_TARGET, "negotiate"));
+    let handshake_timeout = cfg.handshake_timeout();
+    let negotiated = if handshake_timeout.is_zero() {
+        Some(negotiation.await)
+    } else {
+        let mut negotiation = std::pin::pin!(negotiation);
+        match tokio::time::timeout(handshake_timeout, &mut negotiation).await {
+            Ok(res) => Some(res),
+            Err(_) if live.state() == State::Connecting => Some(negotiation.await),
+            Err(_) => None,
+        }
+    };
+    let negotiated = negotiated.unwrap_or_else(|| {
+        warn!("Client {} did not complete the handshake within {:?}", session, handshake_timeout);
+        Err(io::Error::new(io::ErrorKind::TimedOut, "Handshake timed out"))
+    });
+    // Errors after a reply (e.g. connect failures) are counted by reply code instead
+    if let Err(e) = &negotiated && reply::LAST_REPLY.with(Cell::get).is_none() {
+        stats.handshake_failed(e, &session.listener);
+    }
+    let negotiated = negotiated?;
+
+    match negotiated {
+        Negotiated::Relay(mut target_stream, target_socket_addr) => {
+            // --- Stage 5: Relay Data ---
+            session.resolved = Some(target_socket_addr);
+            (session.sent, session.received, session.close_reason) = relay::relay(&mut client_stream, &mut target_stream, session, target_socket_addr, cfg, stats).await;
+            Ok(())
+        }
+        Negotiated::Bind(target_addr, target_port) => {
+            handle_bind(client_stream, session, cfg, stats, &target_addr, target_port).await
+        }
+        Negotiated::Associate(target_addr, target_port) => {
+            session.live.set_state(State::Relaying);
+            session.stage = Stage::Relay;
+            // Ends with the TCP connection from the client
+            session.close_reason = CloseReason::ClientEof;
+            udp::handle_associate(client_stream, session.peer, session.id, &target_addr, target_port, cfg).await
+        }
+        Negotiated::Done => Ok(()),
+    }
+}
+
+// Stages 1 to 4: everything up to the point where the request is served
+async fn negotiate<S: ClientStream>(client_stream: &mut S, session: &mut Session, cfg: &config::Config, stats: &Stats) -> io::Result<Negotiated> {
+    let client = privacy::client(session.peer, session.id);
+    let started = Instant::now();
     // --- Stage 1: Method Selection ---
     // Read the client's method selection message
     // +----+----------+----------+
@@ -67,35 +935,71 @@ async fn handle_client(mut client_stream: TcpStream, client_addr: SocketAddr) ->
     let mut handshake_buf = [0u8; 2]; // Buffer for VER and NMETHODS
     client_stream.read_exact(&mut handshake_buf).await?;
 
+    // SOCKS4 requests start with the same VER byte, CMD takes the place of NMETHODS
+    if handshake_buf[0] == socks4::SOCKS4_VERSION {
+        if !cfg.socks4() {
+            warn!("Client {} sent SOCKS4 request but SOCKS4 is disabled", client);
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "SOCKS4 disabled"));
+        }
+        // SOCKS4 has no authentication, don't let it bypass the configured users
+        if cfg.auth() == config::AuthMode::Required {
+            warn!("Client {} sent SOCKS4 request but authentication is required", client);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS4 not allowed with authentication"));
+        }
+        return match socks4::negotiate(client_stream, session, handshake_buf[1], cfg, stats).await? {
+            Some((target_stream, target_socket_addr)) => Ok(Negotiated::Relay(target_stream, target_socket_addr)),
+            None => Ok(Negotiated::Done),
+        };
+    }
+
     // Check SOCKS version
     if handshake_buf[0] != SOCKS_VERSION {
-        eprintln!("Client {} sent unsupported SOCKS version: {}", client_addr, handshake_buf[0]);
+        warn!("Client {} sent unsupported SOCKS version: {}", client, handshake_buf[0]);
         return Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported SOCKS version"));
     }
 
     let nmethods = handshake_buf[1] as usize;
     if nmethods == 0 {
-         eprintln!("Client {} sent zero methods", client_addr);
+         warn!("Client {} sent zero methods", client);
         return Err(io::Error::new(io::ErrorKind::InvalidData, "No methods offered"));
     }
     let mut methods_buf = vec![0u8; nmethods];
     client_stream.read_exact(&mut methods_buf).await?;
+    debug!("Client {} offered methods {:?}", client, methods_buf);
 
-    // Check if "No Authentication Required" (0x00) is supported by the client
-    if !methods_buf.contains(&NO_AUTHENTICATION_REQUIRED) {
-        eprintln!("Client {} does not support 'No Authentication Required'", client_addr);
+    // Select the method according to the configured authentication mode
+    let Some(method) = auth::select_method(cfg.auth(), cfg.gssapi(), &methods_buf) else {
+        warn!("Client {} offered no acceptable method ({:?}, auth {:?})", client, methods_buf, cfg.auth());
         // Send response: Version 5, Method 0xFF (No acceptable methods)
-        client_stream.write_all(&[SOCKS_VERSION, 0xFF]).await?;
+        client_stream.write_all(&[SOCKS_VERSION, auth::NO_ACCEPTABLE_METHODS]).await?;
         return Err(io::Error::new(io::ErrorKind::Unsupported, "No supported authentication method"));
+    };
+    session.method = Some(method);
+
+    // Strict: the request must only be sent once the method reply has been read
+    if cfg.strict() && client_stream.has_pending_data() {
+        warn!("Strict: client {} sent data before the method selection reply (after METHODS)", client);
+        client_stream.write_all(&[SOCKS_VERSION, auth::NO_ACCEPTABLE_METHODS]).await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Data sent before method selection reply"));
     }
 
-    // Send server method selection response: Version 5, Method 0x00
+    // Send server method selection response: Version 5, selected method
     // +----+--------+
     // |VER | METHOD |
     // +----+--------+
     // | 1  |   1    |
     // +----+--------+
-    client_stream.write_all(&[SOCKS_VERSION, NO_AUTHENTICATION_REQUIRED]).await?;
+    client_stream.write_all(&[SOCKS_VERSION, method]).await?;
+
+    if method == auth::USERNAME_PASSWORD {
+        session.set_user(auth::userpass_auth(client_stream, client, cfg.users(), cfg.strict()).await?);
+    }
+    #[cfg(feature = "gssapi")]
+    if method == auth::GSSAPI {
+        session.set_user(gssapi::gssapi_auth(client_stream, client).await?);
+    }
+    session.timings.handshake = Some(started.elapsed());
+    session.stage = Stage::Request;
 
     // --- Stage 2: Connection Request ---
     // Read the client's connection request message
@@ -106,30 +1010,40 @@ async fn handle_client(mut client_stream: TcpStream, client_addr: SocketAddr) ->
     // +----+-----+-------+------+----------+----------+
     let mut request_header = [0u8; 4]; // VER, CMD, RSV, ATYP
     client_stream.read_exact(&mut request_header).await?;
+    debug!("Client {} sent request header {:?}", client, request_header);
 
     // Check SOCKS version again (though unlikely to change)
     if request_header[0] != SOCKS_VERSION {
-         eprintln!("Client {} sent invalid SOCKS version in request: {}", client_addr, request_header[0]);
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SOCKS version in request"));
+        // Some broken firmware sends a wrong VER here after a valid SOCKS5 negotiation
+        if cfg.lenient_request_version() {
+            warn!("Client {} sent invalid SOCKS version in request: {}, accepting", client, request_header[0]);
+        } else {
+            warn!("Client {} sent invalid SOCKS version in request: {}", client, request_header[0]);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SOCKS version in request"));
+        }
     }
 
     // Check reserved byte
     if request_header[2] != RSV {
-         eprintln!("Client {} sent non-zero RSV byte: {}", client_addr, request_header[2]);
+         warn!("Client {} sent non-zero RSV byte: {}", client, request_header[2]);
         return Err(io::Error::new(io::ErrorKind::InvalidData, "Non-zero RSV byte"));
     }
 
-    // Only support CONNECT command for now
-    if request_header[1] != CONNECT_COMMAND {
-         eprintln!("Client {} requested unsupported command: {}", client_addr, request_header[1]);
-         // Send failure reply
-         send_reply(&mut client_stream, REP_GENERAL_FAILURE, SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    let cmd = request_header[1];
+    let supported = match cmd {
+        CONNECT_COMMAND | BIND_COMMAND | UDP_ASSOCIATE_COMMAND => true,
+        RESOLVE_COMMAND | RESOLVE_PTR_COMMAND => cfg.tor_resolve(),
+        _ => false,
+    };
+    if !supported {
+         warn!("Client {} requested unsupported command: {}", client, request_header[1]);
+         // Send "Command not supported" reply
+         send_failure(client_stream, Reply::CommandNotSupported, request_header[3] == ATYP_IPV6).await?;
         return Err(io::Error::new(io::ErrorKind::Unsupported, "Unsupported command"));
     }
 
     let atyp = request_header[3];
-    let target_addr: String;
-    let target_port: u16;
+    let target_addr: TargetAddr;
 
     // Parse DST.ADDR based on ATYP
     match atyp {
@@ -137,8 +1051,7 @@ async fn handle_client(mut client_stream: TcpStream, client_addr: SocketAddr) ->
             // Read 4 bytes for IPv4 address
             let mut addr_buf = [0u8; 4];
             client_stream.read_exact(&mut addr_buf).await?;
-            let ip = IpAddr::V4(Ipv4Addr::from(addr_buf));
-            target_addr = ip.to_string();
+            target_addr = TargetAddr::Ip(IpAddr::V4(Ipv4Addr::from(addr_buf)));
         }
         ATYP_DOMAIN_NAME => {
             // Read 1 byte for domain name length
@@ -148,108 +1061,353 @@ async fn handle_client(mut client_stream: TcpStream, client_addr: SocketAddr) ->
             // Read `len` bytes for domain name
             let mut domain_buf = vec![0u8; len];
             client_stream.read_exact(&mut domain_buf).await?;
-            target_addr = String::from_utf8_lossy(&domain_buf).to_string();
+            target_addr = match domain::parse_domain(&domain_buf, session.id) {
+                Ok(target) => target,
+                Err(reason) => {
+                    warn!("Client {} sent invalid domain name {:?}: {}", client, privacy::host(&String::from_utf8_lossy(&domain_buf), session.id), reason);
+                    send_failure(client_stream, Reply::GeneralFailure, false).await?;
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+                }
+            };
         }
          ATYP_IPV6 => {
             // Read 16 bytes for IPv6 address
             let mut addr_buf = [0u8; 16];
             client_stream.read_exact(&mut addr_buf).await?;
-            let ip = IpAddr::V6(Ipv6Addr::from(addr_buf));
-            target_addr = format!("[{}]", ip); // Format IPv6 correctly
+            target_addr = TargetAddr::Ip(IpAddr::V6(Ipv6Addr::from(addr_buf)));
         }
         _ => {
-            eprintln!("Client {} sent unsupported address type: {}", client_addr, atyp);
-            send_reply(&mut client_stream, REP_GENERAL_FAILURE, SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+            warn!("Client {} sent unsupported address type: {}", client, atyp);
+            send_failure(client_stream, Reply::AddressTypeNotSupported, false).await?;
             return Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported address type"));
         }
     }
     // Read 2 bytes for port
     let mut port_buf = [0u8; 2];
     client_stream.read_exact(&mut port_buf).await?;
-    target_port = u16::from_be_bytes(port_buf);
-    println!("Client {} requested connection to Domain: {}:{}", client_addr, target_addr, target_port);
+    let target_port = u16::from_be_bytes(port_buf);
+    session.set_target(format!("{}:{}", target_addr, target_port));
+    let dest = privacy::target(&target_addr, session.id);
+
+    // Strict: only BIND and UDP ASSOCIATE may leave DST.PORT unspecified
+    if cfg.strict() && cmd == CONNECT_COMMAND && target_port == 0 {
+        warn!("Strict: client {} sent DST.PORT 0 for CONNECT to {}", client, dest);
+        send_failure(client_stream, Reply::GeneralFailure, target_addr.is_ipv6()).await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "DST.PORT is zero"));
+    }
+
+    // Strict: no data may follow the request before the reply, checked again once connected
+    if cfg.strict() && client_stream.has_pending_data() {
+        warn!("Strict: client {} sent data before the request reply (after DST.PORT)", client);
+        send_failure(client_stream, Reply::GeneralFailure, target_addr.is_ipv6()).await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Data sent before request reply"));
+    }
+
+    // Maintenance mode: keep speaking the protocol but refuse every request
+    if cfg.maintenance() {
+        info!("Maintenance mode: denied request from client {} for {}:{}", client, dest, target_port);
+        Stats::inc(&stats.maintenance_denied);
+        statsd::count(&session.listener, "maintenance_denied", "", 1);
+        send_failure(client_stream, Reply::NotAllowed, target_addr.is_ipv6()).await?;
+        return Ok(Negotiated::Done);
+    }
+
+    if session.over_limit {
+        warn!("Refused request from client {} for {}:{}, max_connections reached", client, dest, target_port);
+        Stats::inc(&stats.limit_rejected);
+        statsd::count(&session.listener, "limit_rejected", "", 1);
+        send_failure(client_stream, Reply::GeneralFailure, target_addr.is_ipv6()).await?;
+        return Ok(Negotiated::Done);
+    }
+
+    if session.over_client_limit {
+        warn!("Refused request from client {} for {}:{}, max_connections_per_client reached", client, dest, target_port);
+        Stats::inc(&stats.client_limit_rejected);
+        statsd::count(&session.listener, "client_limit_rejected", "", 1);
+        send_failure(client_stream, Reply::NotAllowed, target_addr.is_ipv6()).await?;
+        return Ok(Negotiated::Done);
+    }
+
+    if let Some(rule) = policy::blocked_domain_rule(&target_addr, cfg.blocked_domains()) {
+        warn!("Client {} requested blocked domain {} (rule {})", client, dest, rule);
+        send_failure(client_stream, Reply::NotAllowed, target_addr.is_ipv6()).await?;
+        return Ok(Negotiated::Done);
+    }
+
+    if cmd == BIND_COMMAND {
+        info!("Client {} requested bind for peer: {}:{}", client, dest, target_port);
+        return Ok(Negotiated::Bind(target_addr, target_port));
+    }
+    if cmd == UDP_ASSOCIATE_COMMAND {
+        info!("Client {} requested UDP association from: {}:{}", client, dest, target_port);
+        return Ok(Negotiated::Associate(target_addr, target_port));
+    }
+    if cmd == RESOLVE_COMMAND {
+        info!("Client {} requested resolution of: {}", client, dest);
+        session.stage = Stage::Dns;
+        session.live.set_state(State::Connecting);
+        return resolve(client_stream, session, &target_addr, cfg).await;
+    }
+    if cmd == RESOLVE_PTR_COMMAND {
+        info!("Client {} requested reverse resolution of: {}", client, dest);
+        session.stage = Stage::Dns;
+        session.live.set_state(State::Connecting);
+        return resolve_ptr(client_stream, session, &target_addr, cfg).await;
+    }
+    info!("Client {} requested connection to Domain: {}:{}", client, dest, target_port);
+    if let TargetAddr::Ip(ip) = target_addr && !cfg.outbound_allowed(ip) {
+        warn!("Client {} requested connection to {}, its address family is disabled", client, dest);
+        send_failure(client_stream, Reply::AddressTypeNotSupported, target_addr.is_ipv6()).await?;
+        return Ok(Negotiated::Done);
+    }
 
     // --- Stage 3: Establish Connection to Target ---
-    let target_socket_addr = match tokio::net::lookup_host(format!("{}:{}", target_addr, target_port)).await?.next() {
-         Some(addr) => addr,
-         None => {
-             eprintln!("Could not resolve target address: {}:{}", target_addr, target_port);
-             send_reply(&mut client_stream, REP_GENERAL_FAILURE, SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    session.live.set_state(State::Connecting);
+    session.stage = Stage::Dns;
+    let resolve_started = Instant::now();
+    let resolved = target_addr.resolve_all(target_port, cfg.resolver(), |ip| cfg.outbound_allowed(ip), session.id)
+        .instrument(dns_span(&target_addr)).await;
+    if let TargetAddr::Domain(_) = target_addr {
+        session.timings.dns = Some(resolve_started.elapsed());
+    }
+    let target_socket_addrs = match resolved {
+         Ok(addrs) if !addrs.is_empty() => addrs,
+         Ok(_) => {
+             warn!("Could not resolve target address to an allowed family: {}:{}", dest, target_port);
+             send_failure(client_stream, Reply::HostUnreachable, target_addr.is_ipv6()).await?;
              return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "Could not resolve target address"));
          }
+         Err(e) => {
+             warn!("Could not resolve target address: {}:{} ({})", dest, target_port, e);
+             send_failure(client_stream, Reply::HostUnreachable, target_addr.is_ipv6()).await?;
+             return Err(e);
+         }
      };
+    // Any of the addresses may be tried
+    if cfg.loop_protection() && let Some(own) = target_socket_addrs.iter().find(|addr| policy::is_self_connect(**addr, session.listen_addr)) {
+        warn!("Client {} requested connection to the proxy itself: {}", client, privacy::addr(*own, session.id));
+        send_failure(client_stream, Reply::NotAllowed, target_addr.is_ipv6()).await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Connection to the proxy itself"));
+    }
 
-
-    println!("Connecting to target: {}", target_socket_addr);
-    let mut target_stream = match TcpStream::connect(target_socket_addr).await {
-        Ok(stream) => stream,
-        Err(e) => {
-            eprintln!("Failed to connect to target {}: {}", target_socket_addr, e);
-            // Determine appropriate reply code based on the error kind
-            let rep_code = match e.kind() {
-                io::ErrorKind::ConnectionRefused => 0x05, // Connection refused
-                io::ErrorKind::AddrNotAvailable => 0x04, // Host unreachable (approximated)
-                io::ErrorKind::TimedOut => 0x06, // TTL expired (approximated)
-                _ => REP_GENERAL_FAILURE, // General SOCKS server failure
-            };
-            send_reply(&mut client_stream, rep_code, target_socket_addr).await?;
+    session.stage = Stage::Connect;
+    let connect_started = Instant::now();
+    let connected = eyeballs::connect(&target_socket_addrs, cfg, session.id).await;
+    session.timings.connect = Some(connect_started.elapsed());
+    let (target_stream, target_socket_addr) = match connected {
+        Ok(connected) => connected,
+        Err((e, target_socket_addr)) => {
+            if e.kind() == io::ErrorKind::TimedOut {
+                warn!("Timed out connecting to target {} after {:?}", privacy::addr(target_socket_addr, session.id), connect_started.elapsed());
+                session.timings.connect_timed_out = true;
+            } else {
+                warn!("Failed to connect to target {}: {}", privacy::addr(target_socket_addr, session.id), e);
+            }
+            // Determine appropriate reply code based on the error
+            send_failure(client_stream, Reply::from(&e), target_addr.is_ipv6()).await?;
             return Err(e);
         }
     };
-    println!("Successfully connected to target: {}", target_socket_addr);
+    info!("Successfully connected to target: {}", privacy::addr(target_socket_addr, session.id));
+    session.timings.fast_open = cfg.tfo_connect() && sockopt::syn_deferred(&target_stream);
+
+    // Strict: nor while the target was connecting
+    if cfg.strict() && client_stream.has_pending_data() {
+        warn!("Strict: client {} sent data before the request reply (while connecting)", client);
+        send_failure(client_stream, Reply::GeneralFailure, target_addr.is_ipv6()).await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Data sent before request reply"));
+    }
 
     // --- Stage 4: Send Success Reply to Client ---
     // Get the local address the proxy used to connect to the target
     let bind_addr = target_stream.local_addr()?;
-    send_reply(&mut client_stream, REP_SUCCEEDED, bind_addr).await?;
-    println!("Sent success reply to client {}", client_addr);
+    send_reply(client_stream, Reply::Succeeded, bind_addr).await?;
+    debug!("Sent success reply to client {}", client);
+    if session.timings.fast_open {
+        start_fast_open(client_stream, &target_stream).await;
+    }
 
-    // --- Stage 5: Relay Data ---
-    println!("Relaying data between {} and {}", client_addr, target_socket_addr);
+    Ok(Negotiated::Relay(target_stream, target_socket_addr))
+}
 
-    // Use copy_bidirectional for efficient data transfer
-    match io::copy_bidirectional(&mut client_stream, &mut target_stream).await {
-        Ok((sent, received)) => {
-            println!(
-                "Connection closed for {}. Sent {} bytes, received {} bytes.",
-                client_addr, sent, received
-            );
+// RESOLVE: answer with the resolved address in BND.ADDR, then close
+async fn resolve<S: ClientStream>(client_stream: &mut S, session: &Session, target_addr: &TargetAddr, cfg: &config::Config) -> io::Result<Negotiated> {
+    let (client, dest) = (privacy::client(session.peer, session.id), privacy::target(target_addr, session.id));
+    match target_addr.resolve(0, cfg.resolver(), session.id).await {
+        Ok(Some(addr)) => {
+            info!("Resolved {} to {} for client {}", dest, privacy::ip_addr(addr.ip(), session.id), client);
+            send_reply(client_stream, Reply::Succeeded, addr).await?;
         }
-        Err(e) => {
-            eprintln!(
-                "Error during data relay for client {}: {}",
-                client_addr, e
-            );
+        Ok(None) | Err(_) => {
+            warn!("Could not resolve {} for client {}", dest, client);
+            send_failure(client_stream, Reply::HostUnreachable, target_addr.is_ipv6()).await?;
         }
     }
+    Ok(Negotiated::Done)
+}
 
-    Ok(())
+// RESOLVE_PTR: answer with the PTR name of the requested address, then close
+async fn resolve_ptr<S: ClientStream>(client_stream: &mut S, session: &Session, target_addr: &TargetAddr, cfg: &config::Config) -> io::Result<Negotiated> {
+    let client = privacy::client(session.peer, session.id);
+    let TargetAddr::Ip(ip) = target_addr else {
+        warn!("Client {} requested reverse resolution of a name: {}", client, privacy::target(target_addr, session.id));
+        send_failure(client_stream, Reply::AddressTypeNotSupported, false).await?;
+        return Ok(Negotiated::Done);
+    };
+    match cfg.resolver().reverse(*ip, session.id).await {
+        Ok(Some(name)) => {
+            info!("Resolved {} to {} for client {}", privacy::ip_addr(*ip, session.id), privacy::host(&name, session.id), client);
+            send_reply_to(client_stream, Reply::Succeeded, &TargetAddr::Domain(name), 0).await?;
+        }
+        Ok(None) => {
+            warn!("No PTR record for {} requested by client {}", privacy::ip_addr(*ip, session.id), client);
+            send_failure(client_stream, Reply::HostUnreachable, ip.is_ipv6()).await?;
+        }
+        Err(e) => {
+            warn!("Could not reverse resolve {} for client {}: {}", privacy::ip_addr(*ip, session.id), client, e);
+            send_failure(client_stream, Reply::HostUnreachable, ip.is_ipv6()).await?;
+        }
+    }
+    Ok(Negotiated::Done)
 }
 
-// Helper function to send a SOCKS5 reply
-async fn send_reply(stream: &mut TcpStream, rep_code: u8, bind_addr: SocketAddr) -> io::Result<()> {
-    // +----+-----+-------+------+----------+----------+
-    // |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
-    // +----+-----+-------+------+----------+----------+
-    // | 1  |  1  | X'00' |  1   | Variable |    2     |
-    // +----+-----+-------+------+----------+----------+
-    let mut reply = BytesMut::new();
-    reply.put_u8(SOCKS_VERSION);
-    reply.put_u8(rep_code);
-    reply.put_u8(RSV);
+// BIND: wait for the peer to connect to us, then relay as for CONNECT
+async fn handle_bind<S: ClientStream>(mut client_stream: S, session: &mut Session, cfg: &config::Config, stats: &Stats, target_addr: &TargetAddr, target_port: u16) -> io::Result<()> {
+    session.live.set_state(State::Connecting);
+    session.stage = Stage::Connect;
+    let listener = match TcpListener::bind((cfg.bind_host(), 0)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind listener for client {}: {}", session, e);
+            send_failure(&mut client_stream, Reply::GeneralFailure, target_addr.is_ipv6()).await?;
+            return Err(e);
+        }
+    };
 
-    match bind_addr.ip() {
-        IpAddr::V4(ipv4) => {
-            reply.put_u8(ATYP_IPV4);
-            reply.put(&ipv4.octets()[..]);
+    // Announce the address the client reached us on when bound to the wildcard address
+    let mut listen_addr = listener.local_addr()?;
+    if listen_addr.ip().is_unspecified() {
+        listen_addr.set_ip(client_stream.local_ip()?);
+    }
+    // First reply: where the peer should connect to
+    send_reply(&mut client_stream, Reply::Succeeded, listen_addr).await?;
+    debug!("Waiting on {} for peer of client {}", listen_addr, session);
+
+    let accept_timeout = cfg.bind_timeout();
+    let (mut peer_stream, peer_addr) = match tokio::time::timeout(accept_timeout, listener.accept()).await {
+        Ok(Ok(accepted)) => accepted,
+        Ok(Err(e)) => {
+            warn!("Failed to accept peer for client {}: {}", session, e);
+            send_failure(&mut client_stream, Reply::GeneralFailure, target_addr.is_ipv6()).await?;
+            return Err(e);
         }
-        IpAddr::V6(ipv6) => {
-            reply.put_u8(ATYP_IPV6);
-             reply.put(&ipv6.octets()[..]);
+        Err(_) => {
+            warn!("Timed out waiting for peer of client {}", session);
+            send_failure(&mut client_stream, Reply::TtlExpired, target_addr.is_ipv6()).await?;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for peer"));
         }
+    };
+
+    // Only the host named in the request may connect, unless it was left unspecified
+    let allowed = match target_addr {
+        TargetAddr::Ip(ip) => ip.is_unspecified() || *ip == peer_addr.ip(),
+        TargetAddr::Domain(name) => match cfg.resolver().lookup_all(name, target_port, session.id).await {
+            Ok(addrs) => addrs.iter().any(|addr| addr.ip() == peer_addr.ip()),
+            Err(_) => false,
+        },
+    };
+    if !allowed {
+        warn!("Unexpected peer {} for client {} (expected {})", privacy::addr(peer_addr, session.id), session, privacy::target(target_addr, session.id));
+        send_failure(&mut client_stream, Reply::NotAllowed, target_addr.is_ipv6()).await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Unexpected peer"));
     }
-    reply.put_u16(bind_addr.port());
 
-    stream.write_all(&reply).await?;
+    sockopt::tune(&peer_stream, cfg, "peer");
+
+    // Second reply: who connected
+    send_reply(&mut client_stream, Reply::Succeeded, peer_addr).await?;
+    info!("Peer {} connected for client {}", privacy::addr(peer_addr, session.id), session);
+
+    session.resolved = Some(peer_addr);
+    (session.sent, session.received, session.close_reason) = relay::relay(&mut client_stream, &mut peer_stream, session, peer_addr, cfg, stats).await;
+
     Ok(())
 }
+
+// Sub spans of the connection for its trace, see logger::TRACE_TARGET
+pub(crate) fn dns_span(target_addr: &TargetAddr) -> tracing::Span {
+    match target_addr {
+        TargetAddr::Domain(name) => error_span!(target: logger::TRACE_TARGET, "dns", name = %name),
+        TargetAddr::Ip(_) => tracing::Span::none(),
+    }
+}
+
+pub(crate) fn connect_span(target_socket_addr: SocketAddr, id: u64) -> tracing::Span {
+    error_span!(target: logger::TRACE_TARGET, "connect", addr = %privacy::addr(target_socket_addr, id))
+}
+
+// Connect to the target through outbound_interface with outbound_fwmark from the outbound_bind source, eyeballs::connect keeps time
+pub(crate) async fn connect(target_socket_addr: SocketAddr, cfg: &config::Config, id: u64) -> io::Result<TcpStream> {
+    let socket = if target_socket_addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    if cfg.tfo_connect() {
+        sockopt::fast_open_connect(&socket);
+    }
+    if let Some(interface) = cfg.outbound_interface()
+        && let Err(e) = sockopt::bind_device(&socket, interface) {
+        error!("Could not bind to outbound interface {} for {}: {}", interface, privacy::addr(target_socket_addr, id), e);
+        return Err(io::Error::other(format!("Could not bind to outbound interface {}: {}", interface, e)));
+    }
+    if let Some(mark) = cfg.outbound_fwmark()
+        && let Err(e) = sockopt::set_mark(&socket, mark) {
+        error!("Could not set fwmark {:#x} for {}: {}", mark, privacy::addr(target_socket_addr, id), e);
+        return Err(io::Error::other(format!("Could not set fwmark {:#x}: {}", mark, e)));
+    }
+    if let Some(source) = cfg.outbound_bind_for(target_socket_addr.ip())
+        && let Err(e) = socket.bind(SocketAddr::new(source, 0)) {
+        error!("Could not bind outbound source {} for {}: {}", source, privacy::addr(target_socket_addr, id), e);
+        // Not the target's fault, so a general failure rather than what the error kind would map to
+        return Err(io::Error::other(format!("Could not bind outbound source {}: {}", source, e)));
+    }
+
+    let stream = socket.connect(target_socket_addr).await?;
+    sockopt::tune(&stream, cfg, "target");
+    Ok(stream)
+}
+
+// tfo_connect: a target connected without a handshake gets its SYN with what the client sends after the
+// reply. Targets that speak first would wait for that forever, so the SYN goes out empty after FAST_OPEN_WAIT
+pub(crate) async fn start_fast_open<S: ClientStream>(client_stream: &S, target_stream: &TcpStream) {
+    let first_bytes = async {
+        // Readable may still be left over from the handshake, a peek clears it when there is nothing
+        while client_stream.ready(io::Interest::READABLE).await.is_ok() {
+            match client_stream.try_io(io::Interest::READABLE, || strict::peek(&client_stream.as_fd())) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                _ => return,
+            }
+        }
+    };
+    if tokio::time::timeout(FAST_OPEN_WAIT, first_bytes).await.is_err() {
+        sockopt::send_deferred_syn(target_stream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_errors() {
+        let class = |errno| accept_error(&io::Error::from_raw_os_error(errno));
+        for errno in [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM] {
+            assert_eq!(class(errno), AcceptError::Exhausted, "{errno}");
+        }
+        for errno in [libc::EBADF, libc::ENOTSOCK, libc::EINVAL, libc::EFAULT, libc::EOPNOTSUPP] {
+            assert_eq!(class(errno), AcceptError::Fatal, "{errno}");
+        }
+        for errno in [libc::ECONNABORTED, libc::EPROTO, libc::EPERM, libc::ENETDOWN, libc::EHOSTUNREACH, libc::EAGAIN, libc::EINTR] {
+            assert_eq!(class(errno), AcceptError::Dropped, "{errno}");
+        }
+        // Without an errno nothing says the listener is broken
+        assert_eq!(accept_error(&io::Error::other("tls handshake")), AcceptError::Dropped);
+    }
+}
@@ -0,0 +1,67 @@
+use arc_swap::ArcSwap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::health;
+use crate::privacy;
+use crate::stats::Stats;
+
+// Larger requests are not what a Prometheus scraper sends
+const MAX_REQUEST: usize = 8 * 1024;
+
+// Answer GET /metrics (unless only serving health checks), /healthz and /readyz, anything else is a 404
+pub async fn serve(listener: TcpListener, stats: Arc<Stats>, cfg: Arc<ArcSwap<Config>>, metrics: bool) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Metrics listener failed to accept: {}", e);
+                continue;
+            }
+        };
+        let stats = stats.clone();
+        let cfg = cfg.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, addr, &stats, &cfg.load(), metrics).await {
+                debug!("HTTP request from {} failed: {}", privacy::client(addr, 0), e);
+            }
+        });
+    }
+}
+
+// Minimal HTTP/1.1: read the request head, answer and close
+async fn respond(mut stream: TcpStream, addr: SocketAddr, stats: &Stats, cfg: &Config, metrics: bool) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    debug!("HTTP request from {}: {} {}", privacy::client(addr, 0), method, path);
+
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") if metrics => ("200 OK", stats.prometheus()),
+        ("GET", "/healthz") if health::live() => ("200 OK", "ok\n".to_string()),
+        ("GET", "/healthz") => ("503 Service Unavailable", "starting\n".to_string()),
+        ("GET", "/readyz") if health::ready(cfg) => ("200 OK", "ready\n".to_string()),
+        ("GET", "/readyz") => ("503 Service Unavailable", "not ready\n".to_string()),
+        ("GET", _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,71 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use opentelemetry::trace::{Link, SpanKind, TraceId, TracerProvider};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::{Sampler, SamplingResult, SdkTracerProvider, ShouldSample};
+use tracing::{error, info, Subscriber};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+// trace_sample_ratio as f64 bits, a reload applies to the connections accepted after it
+static SAMPLE_RATIO: AtomicU64 = AtomicU64::new(1.0f64.to_bits());
+// Set by layer when the exporter could be built, flushed on exit
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+// Or why it could not, logging is not up yet at that point
+static FAILED: OnceLock<String> = OnceLock::new();
+
+pub fn set_sample_ratio(ratio: f64) {
+    SAMPLE_RATIO.store(ratio.to_bits(), Ordering::Relaxed);
+}
+
+// Connections are sampled at the current ratio, their sub spans follow them
+#[derive(Debug, Clone)]
+struct Ratio;
+
+impl ShouldSample for Ratio {
+    fn should_sample(&self, parent: Option<&Context>, trace_id: TraceId, name: &str, kind: &SpanKind, attributes: &[KeyValue], links: &[Link]) -> SamplingResult {
+        let ratio = f64::from_bits(SAMPLE_RATIO.load(Ordering::Relaxed));
+        Sampler::TraceIdRatioBased(ratio).should_sample(parent, trace_id, name, kind, attributes, links)
+    }
+}
+
+// Exports rock5's spans over OTLP/HTTP, None unless the standard endpoint variables are set
+pub fn layer<S>() -> Option<impl Layer<S>> where S: Subscriber + for<'a> LookupSpan<'a> {
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() && std::env::var_os("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_none() {
+        return None;
+    }
+    let exporter = match opentelemetry_otlp::SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            let _ = FAILED.set(e.to_string());
+            return None;
+        }
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Ratio)))
+        .with_resource(Resource::builder().with_service_name("rock5").build())
+        .build();
+    let tracer = provider.tracer("rock5");
+    let _ = PROVIDER.set(provider);
+    // Library spans and events stay out of the traces
+    Some(tracing_opentelemetry::layer().with_tracer(tracer).with_filter(filter_fn(|meta| meta.target().starts_with("rock5"))))
+}
+
+// Once the config is loaded
+pub fn log_status() {
+    if let Some(e) = FAILED.get() {
+        error!("Could not set up the trace exporter: {}", e);
+    } else if PROVIDER.get().is_some() {
+        info!(" -> Exporting traces over OTLP, sampling {}", f64::from_bits(SAMPLE_RATIO.load(Ordering::Relaxed)));
+    }
+}
+
+// Sends what is still batched
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        let _ = provider.shutdown();
+    }
+}
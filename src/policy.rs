@@ -0,0 +1,159 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tracing::error;
+
+use crate::target::TargetAddr;
+
+// The blocked_domains rule matching a domain target, "*.example.com" matches subdomains only
+pub fn blocked_domain_rule<'a>(target: &TargetAddr, rules: &'a [String]) -> Option<&'a str> {
+    let TargetAddr::Domain(name) = target else { return None };
+    rules.iter().map(String::as_str).find(|rule| match rule.strip_prefix("*.") {
+        Some(suffix) => name.strip_suffix(suffix).is_some_and(|rest| rest.ends_with('.')),
+        None => name == rule,
+    })
+}
+
+// A target that is the proxy's own listener would make us connect to ourselves
+pub fn is_self_connect(target: SocketAddr, listen_addr: SocketAddr) -> bool {
+    if target.port() != listen_addr.port() {
+        return false;
+    }
+    let target_ip = canonical_ip(target.ip());
+    // Connecting to 0.0.0.0 / :: reaches the local host
+    if target_ip.is_unspecified() {
+        return true;
+    }
+    let listen_ip = canonical_ip(listen_addr.ip());
+    if !listen_ip.is_unspecified() {
+        return target_ip == listen_ip;
+    }
+    // Wildcard listeners accept on every local address
+    target_ip.is_loopback() || local_addresses().contains(&target_ip)
+}
+
+// Address range like 10.0.0.0/8 or 2001:db8::/32, a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical_ip(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| "invalid address")?;
+        let prefix = prefix.map(str::parse::<u8>).transpose().map_err(|_| "invalid prefix length")?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err("prefix length too long");
+        }
+        // ::ffff:10.0.0.0/104 is the same range as 10.0.0.0/8
+        if let IpAddr::V6(v6) = addr && let Some(v4) = v6.to_ipv4_mapped() && prefix >= 96 {
+            return Ok(Cidr { addr: IpAddr::V4(v4), prefix: prefix - 96 });
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+// IPv4-mapped IPv6 addresses compare as the IPv4 address they map
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+// Addresses of all local interfaces
+#[cfg(unix)]
+fn local_addresses() -> Vec<IpAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut addrs = Vec::new();
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        error!("Could not list local interface addresses: {}", std::io::Error::last_os_error());
+        return addrs;
+    }
+    let mut cur = ifap;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        if !ifa.ifa_addr.is_null() {
+            match unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int {
+                libc::AF_INET => {
+                    let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                    addrs.push(IpAddr::V4(Ipv4Addr::from(sa.sin_addr.s_addr.to_ne_bytes())));
+                }
+                libc::AF_INET6 => {
+                    let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                    addrs.push(canonical_ip(IpAddr::V6(Ipv6Addr::from(sa.sin6_addr.s6_addr))));
+                }
+                _ => {}
+            }
+        }
+        cur = ifa.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    addrs
+}
+
+#[cfg(not(unix))]
+fn local_addresses() -> Vec<IpAddr> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn self_connects() {
+        let listen = addr("127.0.0.1:1080");
+        assert!(is_self_connect(addr("127.0.0.1:1080"), listen));
+        assert!(is_self_connect(addr("[::ffff:127.0.0.1]:1080"), listen));
+        // Both reach every local listener
+        assert!(is_self_connect(addr("0.0.0.0:1080"), listen));
+        assert!(is_self_connect(addr("[::]:1080"), listen));
+        assert!(!is_self_connect(addr("127.0.0.1:1081"), listen));
+        assert!(!is_self_connect(addr("127.0.0.2:1080"), listen));
+        assert!(!is_self_connect(addr("192.0.2.1:1080"), listen));
+    }
+
+    #[test]
+    fn wildcard_listeners() {
+        for listen in [addr("0.0.0.0:1080"), addr("[::]:1080")] {
+            assert!(is_self_connect(addr("127.0.0.2:1080"), listen), "{listen}");
+            assert!(is_self_connect(addr("[::1]:1080"), listen), "{listen}");
+            for local in local_addresses() {
+                assert!(is_self_connect(SocketAddr::new(local, 1080), listen), "{local} {listen}");
+            }
+            assert!(!is_self_connect(addr("192.0.2.1:1080"), listen), "{listen}");
+            assert!(!is_self_connect(addr("127.0.0.1:1081"), listen), "{listen}");
+        }
+    }
+}
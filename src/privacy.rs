@@ -0,0 +1,132 @@
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::config::LogPrivacy;
+use crate::session::Peer;
+use crate::target::TargetAddr;
+use crate::unix_socket::UnixPeer;
+
+// Set by set on start and reload, only what is logged changes, never what is matched
+static MODE: AtomicU8 = AtomicU8::new(LogPrivacy::None as u8);
+// Host names hash to the same value until the next start only
+static SALT: OnceLock<RandomState> = OnceLock::new();
+
+pub fn set(privacy: LogPrivacy) {
+    MODE.store(privacy as u8, Ordering::Relaxed);
+}
+
+fn mode() -> LogPrivacy {
+    match MODE.load(Ordering::Relaxed) {
+        0 => LogPrivacy::None,
+        1 => LogPrivacy::Partial,
+        _ => LogPrivacy::Full,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Shown<'a> {
+    Ip(IpAddr),
+    Addr(SocketAddr),
+    Name(&'a str),
+    Unix(UnixPeer),
+}
+
+// An address or host name as log_privacy lets the logs, the access log and the admin socket show it
+#[derive(Clone, Copy)]
+pub struct Private<'a> {
+    shown: Shown<'a>,
+    client: bool,
+    // Connection id for the log_privacy = full token, 0 outside of a connection
+    id: u64,
+}
+
+// Clients are shown without their port under partial and full
+pub fn client(peer: impl Into<Peer>, id: u64) -> Private<'static> {
+    let shown = match peer.into() {
+        Peer::Tcp(addr) => Shown::Addr(addr),
+        Peer::Unix(peer) => Shown::Unix(peer),
+    };
+    Private { shown, client: true, id }
+}
+
+pub fn target(target: &TargetAddr, id: u64) -> Private<'_> {
+    match target {
+        TargetAddr::Ip(ip) => ip_addr(*ip, id),
+        TargetAddr::Domain(name) => host(name, id),
+    }
+}
+
+// A destination address, e.g. what the target resolved to
+pub fn addr(addr: SocketAddr, id: u64) -> Private<'static> {
+    Private { shown: Shown::Addr(addr), client: false, id }
+}
+
+pub fn ip_addr(ip: IpAddr, id: u64) -> Private<'static> {
+    Private { shown: Shown::Ip(ip), client: false, id }
+}
+
+pub fn host(name: &str, id: u64) -> Private<'_> {
+    Private { shown: Shown::Name(name), client: false, id }
+}
+
+// A destination kept as host:port text, like Session::target
+pub fn host_port(target: &str, id: u64) -> String {
+    if mode() == LogPrivacy::None {
+        return target.to_string();
+    }
+    let Some((host, port)) = target.rsplit_once(':') else { return self::host(target, id).to_string() };
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    format!("{}:{}", self::target(&TargetAddr::from_domain(host.to_string()), id), port)
+}
+
+impl fmt::Display for Private<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (mode(), &self.shown) {
+            (LogPrivacy::None, Shown::Ip(IpAddr::V6(ip))) => write!(f, "[{}]", ip),
+            (LogPrivacy::None, Shown::Ip(ip)) => write!(f, "{}", ip),
+            (LogPrivacy::None, Shown::Addr(addr)) => write!(f, "{}", addr),
+            (LogPrivacy::None, Shown::Name(name)) => write!(f, "{}", name),
+            (LogPrivacy::Partial, Shown::Ip(ip)) => write!(f, "{}", Network(*ip)),
+            (LogPrivacy::Partial, Shown::Addr(addr)) if self.client => write!(f, "{}", Network(addr.ip())),
+            (LogPrivacy::Partial, Shown::Addr(addr)) => write!(f, "{}:{}", Network(addr.ip()), addr.port()),
+            // Local processes, nothing to hide short of full
+            (LogPrivacy::None | LogPrivacy::Partial, Shown::Unix(peer)) => write!(f, "{}", peer),
+            (LogPrivacy::Partial, Shown::Name(name)) => write!(f, "host-{:012x}", SALT.get_or_init(RandomState::new).hash_one(name) >> 16),
+            (LogPrivacy::Full, shown) => {
+                f.write_str(if self.client { "client" } else { "dest" })?;
+                if self.id != 0 {
+                    write!(f, "-{}", self.id)?;
+                }
+                match shown {
+                    Shown::Addr(addr) if !self.client => write!(f, ":{}", addr.port()),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+// Names as {:?} shows them, e.g. invalid ones from the request
+impl fmt::Debug for Private<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (mode(), &self.shown) {
+            (LogPrivacy::None, Shown::Name(name)) => write!(f, "{:?}", name),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+// The /24 or /48 an address is in, IPv6 in brackets to keep a port apart
+struct Network(IpAddr);
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.to_canonical() {
+            IpAddr::V4(ip) => write!(f, "{}/24", Ipv4Addr::from(u32::from(ip) & 0xffff_ff00)),
+            IpAddr::V6(ip) => write!(f, "[{}/48]", Ipv6Addr::from(u128::from(ip) & !((1u128 << 80) - 1))),
+        }
+    }
+}
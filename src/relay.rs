@@ -0,0 +1,194 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, error_span, info, warn, Instrument};
+
+use crate::buffer_pool;
+use crate::client_stream::ClientStream;
+use crate::config::Config;
+use crate::connections::{Connection, State};
+use crate::logger;
+use crate::privacy;
+use crate::session::{CloseReason, Session, Stage};
+use crate::shaper;
+#[cfg(all(feature = "splice", target_os = "linux"))]
+use crate::splice;
+use crate::stats::{self, Stats};
+use crate::statsd;
+
+// Returns the bytes sent and received and how the relay ended
+pub async fn relay<C: ClientStream>(client_stream: &mut C, target_stream: &mut TcpStream, session: &Session, target_socket_addr: SocketAddr, cfg: &Config, stats: &Stats) -> (u64, u64, CloseReason) {
+    debug!("Relaying data between {} and {}", session, privacy::addr(target_socket_addr, session.id));
+
+    let idle_timeout = cfg.idle_timeout();
+    let buffer_size = cfg.relay_buffer_size();
+    session.live.set_state(State::Relaying);
+    Stats::inc(&stats.active_relays);
+    let span = error_span!(target: logger::TRACE_TARGET, "relay", bytes_sent = tracing::field::Empty, bytes_received = tracing::field::Empty);
+    let rate = cfg.per_connection_limit(session.user.as_deref());
+    let res = relay_loop(client_stream, target_stream, idle_timeout, buffer_size, rate, &session.live).instrument(span.clone()).await;
+    Stats::dec(&stats.active_relays);
+    // What made it through also when the relay did not end cleanly
+    let (sent, received) = (session.live.sent.load(Ordering::Relaxed), session.live.received.load(Ordering::Relaxed));
+    span.record("bytes_sent", sent).record("bytes_received", received);
+    Stats::add(&stats.bytes_sent, sent);
+    Stats::add(&stats.bytes_received, received);
+    statsd::count(&session.listener, "relayed_bytes", "direction:sent", sent);
+    statsd::count(&session.listener, "relayed_bytes", "direction:received", received);
+    let reason = match res {
+        Ok(_) if session.live.target_closed_first() => CloseReason::TargetEof,
+        Ok(_) => CloseReason::ClientEof,
+        // Before the idle timeout, whose error is TimedOut as well
+        Err(e) if peer_lost(&e) => {
+            info!("Closing connection for {}, a peer stopped responding ({})", session, e);
+            CloseReason::PeerLost
+        }
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            info!("Closing idle connection for {}, no traffic for {:?}", session, idle_timeout);
+            CloseReason::IdleTimeout
+        }
+        Err(e) => {
+            warn!(
+                "Error during data relay for client {}: {}",
+                session, e
+            );
+            stats.failed_at(Stage::Relay, stats::error_reason(&e), &session.listener);
+            CloseReason::Error
+        }
+    };
+    debug!(bytes_sent = sent, bytes_received = received, "Relay ended for {} ({})", session, reason.name());
+    (sent, received, reason)
+}
+
+// Both directions at once until both sides closed, each read waits for the rate of its direction (0 unlimited)
+// and bandwidth_limit before it is passed on.
+// Fails with TimedOut when neither side sends anything for idle_timeout (0 never)
+// or a side takes that long to take what the other sent, e.g. a hung target
+async fn relay_loop<C: ClientStream>(client_stream: &mut C, target_stream: &mut TcpStream, idle_timeout: Duration, buffer_size: usize, rate: u64, connection: &Connection) -> io::Result<()> {
+    let activity = Activity { idle_timeout, started: Instant::now(), last_ms: AtomicU64::new(0), shaping: AtomicUsize::new(0) };
+    let (upload_limit, download_limit) = (shaper::Bucket::new(rate), shaper::Bucket::new(rate));
+    let sent = |n: usize| {
+        connection.sent.fetch_add(n as u64, Ordering::Relaxed);
+    };
+    let received = |n: usize| {
+        connection.received.fetch_add(n as u64, Ordering::Relaxed);
+    };
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    match (splice::Pipe::new(buffer_size), splice::Pipe::new(buffer_size)) {
+        (Ok(up), Ok(down)) => {
+            let upload = splice_pump(&*client_stream, &*target_stream, up, &upload_limit, &activity, sent, || connection.saw_eof(true));
+            let download = splice_pump(&*target_stream, &*client_stream, down, &download_limit, &activity, received, || connection.saw_eof(false));
+            return until_idle(upload, download, &activity).await;
+        }
+        (Err(e), _) | (_, Err(e)) => debug!("Relaying without splice, could not create a pipe: {}", e),
+    }
+    let (client_read, client_write) = client_stream.split();
+    let (target_read, target_write) = target_stream.split();
+    let upload = pump(client_read, target_write, buffer_size, &upload_limit, &activity, sent, || connection.saw_eof(true));
+    let download = pump(target_read, client_write, buffer_size, &download_limit, &activity, received, || connection.saw_eof(false));
+    until_idle(upload, download, &activity).await
+}
+
+// Both directions until they are done, or until there was no traffic for idle_timeout
+async fn until_idle(upload: impl Future<Output = io::Result<()>>, download: impl Future<Output = io::Result<()>>, activity: &Activity) -> io::Result<()> {
+    let idle = async {
+        if activity.idle_timeout.is_zero() {
+            return std::future::pending().await;
+        }
+        loop {
+            if activity.shaping.load(Ordering::Relaxed) > 0 {
+                activity.touch();
+            }
+            let deadline = activity.last() + activity.idle_timeout;
+            if Instant::now() >= deadline {
+                return Err::<(), _>(relay_idle());
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    };
+    tokio::select! {
+        res = async { tokio::try_join!(upload, download) } => res.map(|_| ()),
+        res = idle => res,
+    }
+}
+
+// When either direction last read something
+struct Activity {
+    idle_timeout: Duration,
+    started: Instant,
+    last_ms: AtomicU64,
+    // Directions waiting for a rate limit, the relay is not idle meanwhile
+    shaping: AtomicUsize,
+}
+
+impl Activity {
+    fn touch(&self) {
+        self.last_ms.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.started + Duration::from_millis(self.last_ms.load(Ordering::Relaxed))
+    }
+
+    // Its own limit first, so waiting for it holds up no other relay
+    async fn shape(&self, limit: &shaper::Bucket, n: usize) {
+        self.shaping.fetch_add(1, Ordering::Relaxed);
+        limit.take(n).await;
+        shaper::GLOBAL.take(n).await;
+        self.shaping.fetch_sub(1, Ordering::Relaxed);
+        self.touch();
+    }
+}
+
+// What the kernel reports once keepalive probes or sent data went unanswered for too long
+fn peer_lost(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ETIMEDOUT | libc::EHOSTUNREACH | libc::ENETUNREACH | libc::EHOSTDOWN))
+}
+
+fn relay_idle() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Relay idle")
+}
+
+// One direction of the relay, passes EOF on as a shutdown
+async fn pump<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(mut from: R, mut to: W, buffer_size: usize, limit: &shaper::Bucket, activity: &Activity, count: impl Fn(usize), eof: impl Fn()) -> io::Result<()> {
+    let mut buf = buffer_pool::RELAY.get(buffer_size);
+    loop {
+        let chunk = buffer_size.min(limit.quantum()).min(shaper::GLOBAL.quantum());
+        let n = from.read(&mut buf[..chunk]).await?;
+        if n == 0 {
+            eof();
+            return to.shutdown().await;
+        }
+        activity.touch();
+        activity.shape(limit, n).await;
+        if activity.idle_timeout.is_zero() {
+            to.write_all(&buf[..n]).await?;
+        } else {
+            tokio::time::timeout(activity.idle_timeout, to.write_all(&buf[..n])).await.map_err(|_| relay_idle())??;
+        }
+        count(n);
+    }
+}
+
+// pump through a pipe, the bytes stay in the kernel
+#[cfg(all(feature = "splice", target_os = "linux"))]
+async fn splice_pump(from: &impl ClientStream, to: &impl ClientStream, pipe: splice::Pipe, limit: &shaper::Bucket, activity: &Activity, count: impl Fn(usize), eof: impl Fn()) -> io::Result<()> {
+    loop {
+        let chunk = pipe.size.min(limit.quantum()).min(shaper::GLOBAL.quantum());
+        let n = pipe.fill(from, chunk).await?;
+        if n == 0 {
+            eof();
+            return socket2::SockRef::from(to).shutdown(std::net::Shutdown::Write);
+        }
+        activity.touch();
+        activity.shape(limit, n).await;
+        if activity.idle_timeout.is_zero() {
+            pipe.drain(to, n).await?;
+        } else {
+            tokio::time::timeout(activity.idle_timeout, pipe.drain(to, n)).await.map_err(|_| relay_idle())??;
+        }
+        count(n);
+    }
+}
@@ -0,0 +1,176 @@
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use bytes::{BytesMut, BufMut};
+use std::cell::Cell;
+
+use crate::target::TargetAddr;
+use crate::{ATYP_DOMAIN_NAME, ATYP_IPV4, ATYP_IPV6, RSV, SOCKS_VERSION};
+
+// Reply Field values (RFC 1928, section 6)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl From<io::ErrorKind> for Reply {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            io::ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => Reply::HostUnreachable,
+            io::ErrorKind::AddrNotAvailable => Reply::HostUnreachable, // approximated
+            io::ErrorKind::TimedOut => Reply::TtlExpired, // approximated
+            io::ErrorKind::PermissionDenied => Reply::NotAllowed,
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
+impl From<&io::Error> for Reply {
+    fn from(e: &io::Error) -> Self {
+        // Raw OS errors are more precise than the kind where available
+        #[cfg(unix)]
+        match e.raw_os_error() {
+            Some(libc::ENETUNREACH) => return Reply::NetworkUnreachable,
+            Some(libc::EHOSTUNREACH) => return Reply::HostUnreachable,
+            Some(libc::ECONNREFUSED) => return Reply::ConnectionRefused,
+            _ => {}
+        }
+        Reply::from(e.kind())
+    }
+}
+
+tokio::task_local! {
+    // Last REP (or SOCKS4 CD) sent on the connection, for the access log
+    pub static LAST_REPLY: Cell<Option<u8>>;
+    // Where the reply code is recorded, the current span may be one of its sub spans
+    pub static CONN_SPAN: tracing::Span;
+}
+
+// Outside of a connection task there is nothing to remember
+pub fn remember(code: u8) {
+    let _ = LAST_REPLY.try_with(|last| last.set(Some(code)));
+    let _ = CONN_SPAN.try_with(|span| {
+        span.record("reply_code", code);
+    });
+}
+
+// Helper function to send a SOCKS5 reply
+pub async fn send_reply<S: AsyncWrite + Unpin>(stream: &mut S, rep: Reply, bind_addr: SocketAddr) -> io::Result<()> {
+    send_reply_to(stream, rep, &TargetAddr::Ip(bind_addr.ip()), bind_addr.port()).await
+}
+
+// Failure replies carry an all-zeros BND.ADDR, of the IPv6 family only when the request was IPv6
+pub async fn send_failure<S: AsyncWrite + Unpin>(stream: &mut S, rep: Reply, ipv6: bool) -> io::Result<()> {
+    let unspecified = if ipv6 { IpAddr::V6(Ipv6Addr::UNSPECIFIED) } else { IpAddr::V4(Ipv4Addr::UNSPECIFIED) };
+    send_reply(stream, rep, SocketAddr::new(unspecified, 0)).await
+}
+
+// Same as send_reply, but BND.ADDR may also be a domain name
+pub async fn send_reply_to<S: AsyncWrite + Unpin>(stream: &mut S, rep: Reply, bind_addr: &TargetAddr, bind_port: u16) -> io::Result<()> {
+    // +----+-----+-------+------+----------+----------+
+    // |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+    // +----+-----+-------+------+----------+----------+
+    // | 1  |  1  | X'00' |  1   | Variable |    2     |
+    // +----+-----+-------+------+----------+----------+
+    remember(rep as u8);
+    let mut reply = BytesMut::new();
+    reply.put_u8(SOCKS_VERSION);
+    reply.put_u8(rep as u8);
+    reply.put_u8(RSV);
+
+    match bind_addr {
+        TargetAddr::Ip(IpAddr::V4(ipv4)) => {
+            reply.put_u8(ATYP_IPV4);
+            reply.put(&ipv4.octets()[..]);
+        }
+        TargetAddr::Ip(IpAddr::V6(ipv6)) => {
+            reply.put_u8(ATYP_IPV6);
+             reply.put(&ipv6.octets()[..]);
+        }
+        TargetAddr::Domain(name) => {
+            // Length prefix is a single byte
+            let len = u8::try_from(name.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Domain name too long for reply"))?;
+            reply.put_u8(ATYP_DOMAIN_NAME);
+            reply.put_u8(len);
+            reply.put(name.as_bytes());
+        }
+    }
+    reply.put_u16(bind_port);
+
+    stream.write_all(&reply).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn bytes(rep: Reply, bind_addr: SocketAddr) -> Vec<u8> {
+        let mut out = Vec::new();
+        send_reply(&mut out, rep, bind_addr).await.unwrap();
+        out
+    }
+
+    #[test]
+    fn error_kinds() {
+        for (kind, rep) in [
+            (io::ErrorKind::ConnectionRefused, 0x05),
+            (io::ErrorKind::NetworkUnreachable, 0x03),
+            (io::ErrorKind::HostUnreachable, 0x04),
+            (io::ErrorKind::AddrNotAvailable, 0x04),
+            (io::ErrorKind::TimedOut, 0x06),
+            (io::ErrorKind::PermissionDenied, 0x02),
+            (io::ErrorKind::ConnectionReset, 0x01),
+            (io::ErrorKind::Other, 0x01),
+        ] {
+            assert_eq!(Reply::from(kind) as u8, rep, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn raw_os_errors() {
+        for (errno, rep) in [(libc::ENETUNREACH, 0x03), (libc::EHOSTUNREACH, 0x04), (libc::ECONNREFUSED, 0x05), (libc::ETIMEDOUT, 0x06), (libc::EIO, 0x01)] {
+            assert_eq!(Reply::from(&io::Error::from_raw_os_error(errno)) as u8, rep, "{errno}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reply_bytes() {
+        let zeros = SocketAddr::from(([0, 0, 0, 0], 0));
+        for (kind, rep) in [
+            (io::ErrorKind::NetworkUnreachable, 0x03),
+            (io::ErrorKind::HostUnreachable, 0x04),
+            (io::ErrorKind::ConnectionRefused, 0x05),
+            (io::ErrorKind::TimedOut, 0x06),
+            (io::ErrorKind::Other, 0x01),
+        ] {
+            assert_eq!(bytes(Reply::from(kind), zeros).await, [0x05, rep, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+        }
+        let bound = SocketAddr::from(([10, 1, 2, 3], 1080));
+        assert_eq!(bytes(Reply::Succeeded, bound).await, [0x05, 0x00, 0x00, 0x01, 10, 1, 2, 3, 0x04, 0x38]);
+        let mut six = vec![0x05, 0x00, 0x00, 0x04];
+        six.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        six.extend_from_slice(&[0x04, 0x38]);
+        assert_eq!(bytes(Reply::Succeeded, SocketAddr::from((Ipv6Addr::LOCALHOST, 1080))).await, six);
+    }
+
+    #[tokio::test]
+    async fn domain_bind_addr() {
+        let mut out = Vec::new();
+        send_reply_to(&mut out, Reply::Succeeded, &TargetAddr::Domain("a.test".into()), 80).await.unwrap();
+        assert_eq!(out, b"\x05\x00\x00\x03\x06a.test\x00\x50");
+        let long = TargetAddr::Domain("a".repeat(256));
+        assert!(send_reply_to(&mut Vec::new(), Reply::Succeeded, &long, 80).await.is_err());
+    }
+}
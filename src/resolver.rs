@@ -0,0 +1,523 @@
+use tokio::io;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ResolverConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::ProtoErrorKind;
+use hickory_resolver::proto::op::ResponseCode;
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::{Name, ResolveError, TokioResolver};
+use tracing::debug;
+
+use crate::privacy;
+use crate::stats::{Histogram, Stats};
+
+// Label values of rock5_dns_lookups_total, no_address is a name without addresses, cached an answer from dns_cache
+// and hosts one pinned in [hosts]
+pub const LOOKUP_RESULTS: [&str; 5] = ["ok", "no_address", "error", "cached", "hosts"];
+// Kept across reloads, which replace the Resolver
+pub static LOOKUPS: [AtomicU64; LOOKUP_RESULTS.len()] = [const { AtomicU64::new(0) }; LOOKUP_RESULTS.len()];
+pub static LOOKUP_LATENCY: Histogram = Histogram::new();
+
+// Forward lookups, through the system resolver unless dns_servers is set
+#[derive(Debug)]
+pub struct Resolver {
+    // Both shared with the listeners that resolve the same way
+    backend: Arc<Backend>,
+    // None with dns_cache = false, a reload starts with an empty one
+    cache: Option<Arc<Cache>>,
+    // From address_family, Some puts the addresses of that family first
+    prefer_ipv6: Option<bool>,
+    hosts: Hosts,
+    // dns_search and the families to ask for, for the backend over_https or over_tls puts in front
+    #[cfg_attr(not(any(feature = "doh", feature = "dot")), allow(dead_code))]
+    search: Vec<Name>,
+    #[cfg_attr(not(any(feature = "doh", feature = "dot")), allow(dead_code))]
+    strategy: LookupIpStrategy,
+}
+
+// Where an answer came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Backend,
+    Cache,
+    Hosts,
+}
+
+#[derive(Debug)]
+enum Backend {
+    System,
+    // Asked first, the fallback when that fails
+    Dns { resolver: Box<TokioResolver>, via: Via, fallback: Option<Arc<Backend>> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Via {
+    Servers,
+    #[cfg(feature = "doh")]
+    Https,
+    #[cfg(feature = "dot")]
+    Tls,
+}
+
+impl Resolver {
+    pub fn new(servers: &[SocketAddr], search: &[Name], fallback: bool, ipv4: bool, ipv6: bool) -> Resolver {
+        // Don't stop at A records when only AAAA ones are usable, and get both for Happy Eyeballs
+        let strategy = match (ipv4, ipv6) {
+            (true, false) => LookupIpStrategy::Ipv4Only,
+            (false, true) => LookupIpStrategy::Ipv6Only,
+            _ => LookupIpStrategy::Ipv4AndIpv6,
+        };
+        let backend = Arc::new(match servers {
+            [] => Backend::System,
+            servers => {
+                let configs = servers.iter().flat_map(|server| [NameServerConfig::new(*server, Protocol::Udp), NameServerConfig::new(*server, Protocol::Tcp)]);
+                Backend::dns(configs, search, strategy, Via::Servers, fallback.then(|| Arc::new(Backend::System)))
+            }
+        });
+        Resolver { backend, cache: None, prefer_ipv6: None, hosts: Hosts::default(), search: search.to_vec(), strategy }
+    }
+
+    // dns = doh: lookups go over HTTPS to path on the servers, checked to be host. With strict they fail
+    // when those can't answer, otherwise the resolver so far is asked instead.
+    #[cfg(feature = "doh")]
+    pub fn over_https(self, servers: &[SocketAddr], host: &str, path: &str, strict: bool) -> Resolver {
+        let configs = servers.iter().map(|server| NameServerConfig {
+            tls_dns_name: Some(host.to_string()),
+            http_endpoint: Some(path.to_string()),
+            ..NameServerConfig::new(*server, Protocol::Https)
+        });
+        self.encrypted(configs, Via::Https, strict)
+    }
+
+    // dns = dot: the same over TLS, one connection per server kept open between lookups
+    #[cfg(feature = "dot")]
+    pub fn over_tls(self, servers: &[SocketAddr], hostname: &str, strict: bool) -> Resolver {
+        let configs = servers.iter().map(|server| NameServerConfig {
+            tls_dns_name: Some(hostname.to_string()),
+            ..NameServerConfig::new(*server, Protocol::Tls)
+        });
+        self.encrypted(configs, Via::Tls, strict)
+    }
+
+    #[cfg(any(feature = "doh", feature = "dot"))]
+    fn encrypted(self, servers: impl IntoIterator<Item = NameServerConfig>, via: Via, strict: bool) -> Resolver {
+        let fallback = (!strict).then(|| self.backend.clone());
+        Resolver { backend: Arc::new(Backend::dns(servers, &self.search, self.strategy, via, fallback)), ..self }
+    }
+
+    // Whether lookups return IPv6 (Some(true)) or IPv4 addresses first, None keeps the order of the answer
+    pub fn preferring(self, prefer_ipv6: Option<bool>) -> Resolver {
+        Resolver { prefer_ipv6, ..self }
+    }
+
+    // Names answered from [hosts], neither dns_cache nor the backend see them
+    pub fn with_hosts(self, hosts: Hosts) -> Resolver {
+        Resolver { hosts, ..self }
+    }
+
+    // Asks the backend of other and keeps answers in its cache, for a listener resolving the same way as [config]
+    pub fn share(&mut self, other: &Resolver) {
+        self.backend = other.backend.clone();
+        self.cache = other.cache.clone();
+    }
+
+    pub fn hosts(&self) -> &Hosts {
+        &self.hosts
+    }
+
+    // Answers are kept for their TTL, or ttl for those of the system resolver, the least recently used go first.
+    // Names without addresses are kept for negative_ttl and failed lookups for error_ttl, 0 doesn't keep them.
+    pub fn with_cache(self, size: usize, ttl: Duration, negative_ttl: Duration, error_ttl: Duration) -> Resolver {
+        let cache = Cache { size, ttl, negative_ttl, error_ttl, entries: Mutex::new(HashMap::new()), uses: AtomicU64::new(0) };
+        Resolver { cache: Some(Arc::new(cache)), ..self }
+    }
+
+    // First address of the name, Ok(None) when it has none. id is the connection's for log_privacy, 0 outside of one
+    pub async fn lookup(&self, name: &str, port: u16, id: u64) -> io::Result<Option<SocketAddr>> {
+        self.lookup_filtered(name, port, |_| true, id).await
+    }
+
+    // First address the filter allows
+    pub async fn lookup_filtered(&self, name: &str, port: u16, allowed: impl Fn(IpAddr) -> bool, id: u64) -> io::Result<Option<SocketAddr>> {
+        Ok(self.lookup_allowed(name, port, allowed, id).await?.first().copied())
+    }
+
+    // Every address the filter allows, in the order they came unless a family is preferred
+    pub async fn lookup_allowed(&self, name: &str, port: u16, allowed: impl Fn(IpAddr) -> bool, id: u64) -> io::Result<Vec<SocketAddr>> {
+        let started = Instant::now();
+        let (res, source) = self.query(name, port, id).await;
+        let mut usable: Vec<SocketAddr> = res.as_ref().map(|addrs| addrs.iter().filter(|addr| allowed(addr.ip())).copied().collect()).unwrap_or_default();
+        // Stable, so each family keeps its own order, and Happy Eyeballs starts with the preferred one
+        if let Some(prefer_ipv6) = self.prefer_ipv6 {
+            usable.sort_by_key(|addr| addr.is_ipv6() != prefer_ipv6);
+        }
+        record(name, &res, usable.first().copied(), started.elapsed(), source, id);
+        res.map(|_| usable)
+    }
+
+    pub async fn lookup_all(&self, name: &str, port: u16, id: u64) -> io::Result<Vec<SocketAddr>> {
+        let started = Instant::now();
+        let (res, source) = self.query(name, port, id).await;
+        record(name, &res, res.as_ref().ok().and_then(|addrs| addrs.first().copied()), started.elapsed(), source, id);
+        res
+    }
+
+    // PTR lookup, a name [hosts] gives the address first. Ok(None) when the address has no name
+    pub async fn reverse(&self, ip: IpAddr, id: u64) -> io::Result<Option<String>> {
+        if let Some(name) = self.hosts.name_of(ip) {
+            Stats::inc(&LOOKUPS[4]);
+            debug!("Resolved {} to {} from [hosts]", privacy::ip_addr(ip, id), privacy::host(name, id));
+            return Ok(Some(name.to_string()));
+        }
+        let started = Instant::now();
+        let res = self.backend.reverse(ip, id).await;
+        let elapsed = started.elapsed();
+        LOOKUP_LATENCY.observe(elapsed);
+        match &res {
+            Ok(Some(name)) => {
+                Stats::inc(&LOOKUPS[0]);
+                debug!("Resolved {} to {} in {:?}", privacy::ip_addr(ip, id), privacy::host(name, id), elapsed);
+            }
+            Ok(None) => Stats::inc(&LOOKUPS[1]),
+            Err(e) => {
+                Stats::inc(&LOOKUPS[2]);
+                debug!("Could not reverse resolve {} after {:?}: {}", privacy::ip_addr(ip, id), elapsed, e);
+            }
+        }
+        res
+    }
+
+    // From [hosts] or the cache when they have the name
+    async fn query(&self, name: &str, port: u16, id: u64) -> (io::Result<Vec<SocketAddr>>, Source) {
+        let with_port = |ips: &[IpAddr]| ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+        if let Some(ips) = self.hosts.get(name) {
+            return (Ok(with_port(ips)), Source::Hosts);
+        }
+        if let Some(answer) = self.cache.as_ref().and_then(|cache| cache.get(name)) {
+            return (answer.map(|ips| with_port(&ips)), Source::Cache);
+        }
+        let res = self.backend.query(name, id).await;
+        if let Some(cache) = &self.cache {
+            cache.store(name, &res);
+        }
+        (res.map(|(ips, _)| with_port(&ips)), Source::Backend)
+    }
+}
+
+impl Backend {
+    fn dns(servers: impl IntoIterator<Item = NameServerConfig>, search: &[Name], strategy: LookupIpStrategy, via: Via, fallback: Option<Arc<Backend>>) -> Backend {
+        let mut config = ResolverConfig::new();
+        for server in servers {
+            config.add_name_server(server);
+        }
+        for domain in search {
+            config.add_search(domain.clone());
+        }
+        let mut builder = TokioResolver::builder_with_config(config, TokioConnectionProvider::default());
+        builder.options_mut().ip_strategy = strategy;
+        // dns_cache is the only cache, dns_cache = false has to ask every time
+        builder.options_mut().cache_size = 0;
+        Backend::Dns { resolver: Box::new(builder.build()), via, fallback }
+    }
+
+    // The addresses and, from dns_servers, doh_url or dot_server, until when they may be used
+    async fn query(&self, name: &str, id: u64) -> io::Result<(Vec<IpAddr>, Option<Instant>)> {
+        match self {
+            Backend::System => Ok((system_lookup(name).await?, None)),
+            Backend::Dns { resolver, via, fallback } => match resolver.lookup_ip(name).await {
+                Ok(ips) => Ok((ips.iter().collect(), Some(ips.valid_until()))),
+                // That the name has no addresses is an answer, only the system resolver may know local names
+                Err(e) if no_such_records(&e) && *via != Via::Servers => Ok((Vec::new(), None)),
+                Err(e) => match fallback {
+                    Some(fallback) => {
+                        // Its text repeats the whole query, name included
+                        let reason = match e.proto().map(|e| e.kind()) {
+                            _ if no_such_records(&e) => "no records".to_string(),
+                            Some(ProtoErrorKind::NoRecordsFound { response_code, .. }) => response_code.to_string(),
+                            _ => e.to_string(),
+                        };
+                        debug!("{} lookup of {} failed ({}), trying {}", via.name(), privacy::host(name, id), reason, fallback.name());
+                        Box::pin(fallback.query(name, id)).await
+                    }
+                    None if e.is_no_records_found() => Ok((Vec::new(), None)),
+                    None => Err(io::Error::other(e)),
+                },
+            },
+        }
+    }
+
+    // The name of the PTR record, the same way to the fallback as query
+    async fn reverse(&self, ip: IpAddr, id: u64) -> io::Result<Option<String>> {
+        match self {
+            Backend::System => tokio::task::spawn_blocking(move || getnameinfo(ip)).await?,
+            Backend::Dns { resolver, via, fallback } => match resolver.reverse_lookup(ip).await {
+                Ok(names) => Ok(names.iter().next().map(|name| name.0.to_ascii().trim_end_matches('.').to_string())),
+                Err(e) if no_such_records(&e) && *via != Via::Servers => Ok(None),
+                Err(e) => match fallback {
+                    Some(fallback) => {
+                        debug!("{} lookup of the name of {} failed ({}), trying {}", via.name(), privacy::ip_addr(ip, id), e, fallback.name());
+                        Box::pin(fallback.reverse(ip, id)).await
+                    }
+                    None if e.is_no_records_found() => Ok(None),
+                    None => Err(io::Error::other(e)),
+                },
+            },
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Backend::System => "the system resolver",
+            Backend::Dns { via, .. } => via.name(),
+        }
+    }
+}
+
+// NXDOMAIN or an empty answer, hickory reports a server failing (SERVFAIL, REFUSED) as no records too
+fn no_such_records(e: &ResolveError) -> bool {
+    e.proto().is_some_and(|e| matches!(e.kind(), ProtoErrorKind::NoRecordsFound { response_code: ResponseCode::NXDomain | ResponseCode::NoError, .. }))
+}
+
+impl Via {
+    fn name(self) -> &'static str {
+        match self {
+            Via::Servers => "DNS",
+            #[cfg(feature = "doh")]
+            Via::Https => "DoH",
+            #[cfg(feature = "dot")]
+            Via::Tls => "DoT",
+        }
+    }
+}
+
+// [hosts]: names, or *.suffix for the names below it, to their addresses. Exact names win over
+// wildcards and longer suffixes over shorter ones.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Hosts {
+    names: BTreeMap<String, Vec<IpAddr>>,
+}
+
+impl Hosts {
+    // name already lowercased, like parsed domains
+    pub fn insert(&mut self, name: String, ips: Vec<IpAddr>) {
+        self.names.insert(name, ips);
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[IpAddr])> {
+        self.names.iter().map(|(name, ips)| (name.as_str(), ips.as_slice()))
+    }
+
+    // The first name (no wildcard) with the address
+    fn name_of(&self, ip: IpAddr) -> Option<&str> {
+        self.names.iter().find(|(name, ips)| !name.starts_with('*') && ips.contains(&ip)).map(|(name, _)| name.as_str())
+    }
+
+    fn get(&self, name: &str) -> Option<&[IpAddr]> {
+        if self.names.is_empty() {
+            return None;
+        }
+        if let Some(ips) = self.names.get(name) {
+            return Some(ips);
+        }
+        name.match_indices('.').find_map(|(dot, _)| self.names.get(&format!("*{}", &name[dot..]))).map(Vec::as_slice)
+    }
+}
+
+#[derive(Debug)]
+struct Cache {
+    size: usize,
+    // For answers of the system resolver, which come without one
+    ttl: Duration,
+    negative_ttl: Duration,
+    error_ttl: Duration,
+    entries: Mutex<HashMap<String, Cached>>,
+    // Counts every use, the entry used longest ago has the lowest
+    uses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Cached {
+    // A failed lookup keeps its error text
+    answer: Result<Vec<IpAddr>, String>,
+    expires: Instant,
+    used: u64,
+}
+
+impl Cache {
+    fn get(&self, name: &str) -> Option<io::Result<Vec<IpAddr>>> {
+        let key = name.to_ascii_lowercase();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = entries.get_mut(&key)?;
+        if entry.expires > Instant::now() {
+            entry.used = self.uses.fetch_add(1, Ordering::Relaxed);
+            return Some(entry.answer.clone().map_err(io::Error::other));
+        }
+        entries.remove(&key);
+        None
+    }
+
+    fn store(&self, name: &str, res: &io::Result<(Vec<IpAddr>, Option<Instant>)>) {
+        let now = Instant::now();
+        let (answer, ttl) = match res {
+            Ok((ips, valid_until)) if !ips.is_empty() => (Ok(ips.clone()), valid_until.map_or(self.ttl, |until| until.saturating_duration_since(now))),
+            Ok(_) => (Ok(Vec::new()), self.negative_ttl),
+            Err(e) => (Err(e.to_string()), self.error_ttl),
+        };
+        if !ttl.is_zero() {
+            self.insert(name, answer, now + ttl);
+        }
+    }
+
+    fn insert(&self, name: &str, answer: Result<Vec<IpAddr>, String>, expires: Instant) {
+        let key = name.to_ascii_lowercase();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= self.size && !entries.contains_key(&key) {
+            // Expired ones first, then the least recently used
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.size && let Some(oldest) = entries.iter().min_by_key(|(_, entry)| entry.used).map(|(name, _)| name.clone()) {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, Cached { answer, expires, used: self.uses.fetch_add(1, Ordering::Relaxed) });
+    }
+}
+
+// Every lookup is counted and logged at debug level, those from the cache don't count into the latency
+fn record(name: &str, res: &io::Result<Vec<SocketAddr>>, chosen: Option<SocketAddr>, elapsed: Duration, source: Source, id: u64) {
+    if source != Source::Backend {
+        let (counter, from) = if source == Source::Hosts { (4, "[hosts]") } else { (3, "the cache") };
+        Stats::inc(&LOOKUPS[counter]);
+        match res {
+            Ok(addrs) => {
+                let chosen = chosen.map_or("no usable address".to_string(), |addr| privacy::ip_addr(addr.ip(), id).to_string());
+                debug!("Resolved {} to {} ({} candidate(s)) from {}", privacy::host(name, id), chosen, addrs.len(), from);
+            }
+            Err(e) => debug!("Could not resolve {}, cached: {}", privacy::host(name, id), e),
+        }
+        return;
+    }
+    LOOKUP_LATENCY.observe(elapsed);
+    match res {
+        Ok(addrs) => {
+            Stats::inc(&LOOKUPS[if addrs.is_empty() { 1 } else { 0 }]);
+            let chosen = chosen.map_or("no usable address".to_string(), |addr| privacy::ip_addr(addr.ip(), id).to_string());
+            debug!("Resolved {} to {} ({} candidate(s)) in {:?}", privacy::host(name, id), chosen, addrs.len(), elapsed);
+        }
+        Err(e) => {
+            Stats::inc(&LOOKUPS[2]);
+            debug!("Could not resolve {} after {:?}: {}", privacy::host(name, id), elapsed, e);
+        }
+    }
+}
+
+async fn system_lookup(name: &str) -> io::Result<Vec<IpAddr>> {
+    Ok(tokio::net::lookup_host((name, 0)).await?.map(|addr| addr.ip()).collect())
+}
+
+#[cfg(unix)]
+fn getnameinfo(ip: IpAddr) -> io::Result<Option<String>> {
+    use std::ffi::CStr;
+    use std::mem;
+
+    const NI_MAXHOST: usize = 1025;
+    let mut host = [0 as libc::c_char; NI_MAXHOST];
+    // NI_NAMEREQD: fail instead of returning the numeric form when there is no PTR record
+    let ret = match ip {
+        IpAddr::V4(v4) => {
+            let mut sa: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sa.sin_family = libc::AF_INET as libc::sa_family_t;
+            sa.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(v4.octets()) };
+            unsafe {
+                libc::getnameinfo(
+                    &sa as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                    host.as_mut_ptr(), host.len() as libc::socklen_t,
+                    std::ptr::null_mut(), 0,
+                    libc::NI_NAMEREQD,
+                )
+            }
+        }
+        IpAddr::V6(v6) => {
+            let mut sa: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sa.sin6_addr = libc::in6_addr { s6_addr: v6.octets() };
+            unsafe {
+                libc::getnameinfo(
+                    &sa as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                    host.as_mut_ptr(), host.len() as libc::socklen_t,
+                    std::ptr::null_mut(), 0,
+                    libc::NI_NAMEREQD,
+                )
+            }
+        }
+    };
+
+    match ret {
+        0 => {
+            let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+            Ok(Some(name.to_string_lossy().to_string()))
+        }
+        libc::EAI_NONAME => Ok(None),
+        _ => {
+            let reason = unsafe { CStr::from_ptr(libc::gai_strerror(ret)) };
+            Err(io::Error::other(reason.to_string_lossy().to_string()))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn getnameinfo(_ip: IpAddr) -> io::Result<Option<String>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Reverse lookups are not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answered from [hosts], in the order of a mixed answer
+    fn resolver(prefer_ipv6: Option<bool>) -> Resolver {
+        let mut hosts = Hosts::default();
+        hosts.insert("mixed.test".to_string(), ["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"].map(|ip| ip.parse().unwrap()).to_vec());
+        Resolver::new(&[], &[], false, true, true).preferring(prefer_ipv6).with_hosts(hosts)
+    }
+
+    async fn lookup(resolver: &Resolver, allowed: impl Fn(IpAddr) -> bool) -> Vec<String> {
+        let addrs = resolver.lookup_allowed("mixed.test", 80, allowed, 0).await.unwrap();
+        addrs.iter().map(|addr| addr.ip().to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn order_of_the_answer() {
+        assert_eq!(lookup(&resolver(None), |_| true).await, ["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"]);
+    }
+
+    #[tokio::test]
+    async fn preferred_family_first() {
+        // Each family keeps its own order
+        assert_eq!(lookup(&resolver(Some(true)), |_| true).await, ["2001:db8::1", "2001:db8::2", "192.0.2.1", "192.0.2.2"]);
+        assert_eq!(lookup(&resolver(Some(false)), |_| true).await, ["192.0.2.1", "192.0.2.2", "2001:db8::1", "2001:db8::2"]);
+    }
+
+    #[tokio::test]
+    async fn disallowed_addresses_are_dropped() {
+        assert_eq!(lookup(&resolver(Some(true)), |ip| ip.is_ipv4()).await, ["192.0.2.1", "192.0.2.2"]);
+        assert_eq!(lookup(&resolver(None), |ip| ip.is_ipv6()).await, ["2001:db8::1", "2001:db8::2"]);
+        assert!(lookup(&resolver(None), |_| false).await.is_empty());
+        let first = resolver(Some(false)).lookup_filtered("mixed.test", 80, |ip| ip.is_ipv6(), 0).await.unwrap();
+        assert_eq!(first, Some("[2001:db8::1]:80".parse().unwrap()));
+    }
+}
@@ -0,0 +1,143 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+
+use crate::config::Rotation;
+
+// Lines waiting for the disk, more than that are dropped instead of blocking the caller
+const QUEUE_LEN: usize = 4096;
+
+// Appends lines to a file from a thread of its own, which is the only one touching the file
+pub struct Writer {
+    queue: Option<SyncSender<String>>,
+    thread: Option<JoinHandle<()>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Writer {
+    // Opening happens right away so the caller gets the error
+    pub fn open(path: &Path, rotation: Rotation, keep: usize) -> io::Result<Writer> {
+        let mut file = RotatingFile::open(path.to_path_buf(), rotation, keep)?;
+        let (queue, lines) = mpsc::sync_channel::<String>(QUEUE_LEN);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let thread_dropped = dropped.clone();
+        let thread = thread::Builder::new().name("rock5-writer".to_string()).spawn(move || {
+            let mut failing = false;
+            for line in lines {
+                let dropped = thread_dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    warn!("Dropped {} line(s) for {:?}, the disk is too slow", dropped, file.path);
+                }
+                match file.write(line.as_bytes()) {
+                    Ok(()) => failing = false,
+                    // Once per run of failures, not per line
+                    Err(e) if !failing => {
+                        error!("Could not write to {:?}: {}", file.path, e);
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+        })?;
+        Ok(Writer { queue: Some(queue), thread: Some(thread), dropped })
+    }
+
+    pub fn write(&self, line: String) {
+        if let Some(queue) = &self.queue && let Err(TrySendError::Full(_)) = queue.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Waits for the queued lines, so a replacing Writer never rotates under this one
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Always written at path, rotated files are path.1 (newest) to path.<keep>
+struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    keep: usize,
+    file: File,
+    size: u64,
+    // Hour or day the file was started in
+    period: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotation: Rotation, keep: usize) -> io::Result<RotatingFile> {
+        let file = append(&path)?;
+        let metadata = file.metadata()?;
+        // A file left from yesterday is rotated on the first write
+        let period = period(rotation, metadata.modified().unwrap_or_else(|_| SystemTime::now()));
+        Ok(RotatingFile { path, rotation, keep, file, size: metadata.len(), period })
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        // Deleted out from under us
+        if !self.path.exists() {
+            self.file = append(&self.path)?;
+            self.size = 0;
+        }
+        let due = match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max) => self.size > 0 && self.size + line.len() as u64 > max,
+            Rotation::Hourly | Rotation::Daily => period(self.rotation, SystemTime::now()) != self.period,
+        };
+        if due {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = append(&self.path)?;
+        self.size = 0;
+        self.period = period(self.rotation, SystemTime::now());
+        Ok(())
+    }
+}
+
+// Logs name users and destinations, new files are not for everyone
+fn append(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().create(true).append(true).mode(0o640).open(path)
+}
+
+// Hours or days since the epoch, UTC
+fn period(rotation: Rotation, time: SystemTime) -> u64 {
+    let secs = time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+    match rotation {
+        Rotation::Hourly => secs / 3600,
+        Rotation::Daily => secs / 86400,
+        Rotation::Never | Rotation::Size(_) => 0,
+    }
+}
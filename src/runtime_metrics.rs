@@ -0,0 +1,85 @@
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use arc_swap::ArcSwap;
+use tokio::runtime::Handle;
+
+use crate::config::Config;
+
+// The runtime main built, set before anything runs on it
+static HANDLE: OnceLock<Handle> = OnceLock::new();
+// The last sample, what /metrics shows until the next one
+static SAMPLE: Mutex<Option<Sample>> = Mutex::new(None);
+
+struct Sample {
+    workers: usize,
+    alive_tasks: usize,
+    global_queue_depth: usize,
+    // Per worker, in worker order
+    busy: Vec<Duration>,
+    parks: Vec<u64>,
+    #[cfg(tokio_unstable)]
+    spawned_tasks: u64,
+    #[cfg(tokio_unstable)]
+    local_queue_depths: Vec<usize>,
+    #[cfg(tokio_unstable)]
+    blocking_threads: usize,
+}
+
+pub fn set_handle(handle: Handle) {
+    let _ = HANDLE.set(handle);
+}
+
+// Every runtime_metrics_interval, a reload applies from the next sample on
+pub async fn sample(cfg: Arc<ArcSwap<Config>>) {
+    let Some(handle) = HANDLE.get() else { return };
+    loop {
+        let metrics = handle.metrics();
+        let workers = metrics.num_workers();
+        let sample = Sample {
+            workers,
+            alive_tasks: metrics.num_alive_tasks(),
+            global_queue_depth: metrics.global_queue_depth(),
+            busy: (0..workers).map(|worker| metrics.worker_total_busy_duration(worker)).collect(),
+            parks: (0..workers).map(|worker| metrics.worker_park_count(worker)).collect(),
+            #[cfg(tokio_unstable)]
+            spawned_tasks: metrics.spawned_tasks_count(),
+            #[cfg(tokio_unstable)]
+            local_queue_depths: (0..workers).map(|worker| metrics.worker_local_queue_depth(worker)).collect(),
+            #[cfg(tokio_unstable)]
+            blocking_threads: metrics.num_blocking_threads(),
+        };
+        *SAMPLE.lock().unwrap_or_else(|e| e.into_inner()) = Some(sample);
+        tokio::time::sleep(cfg.load().runtime_metrics_interval()).await;
+    }
+}
+
+// Appended to the rock5_ metrics, nothing before the first sample
+pub fn prometheus(text: &mut String) {
+    let sample = SAMPLE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(sample) = sample.as_ref() else { return };
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+        let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(text, "{name}{labels} {value}");
+        }
+    };
+    let value = |value: usize| vec![(String::new(), value.to_string())];
+    let per_worker = |values: Vec<String>| -> Vec<(String, String)> {
+        values.into_iter().enumerate().map(|(worker, value)| (format!("{{worker=\"{worker}\"}}"), value)).collect()
+    };
+    metric("tokio_workers", "gauge", "Worker threads of the runtime", &value(sample.workers));
+    metric("tokio_alive_tasks", "gauge", "Tasks that have not finished yet", &value(sample.alive_tasks));
+    metric("tokio_global_queue_depth", "gauge", "Tasks waiting in the shared queue", &value(sample.global_queue_depth));
+    let busy = sample.busy.iter().map(|busy| format!("{:.6}", busy.as_secs_f64())).collect();
+    metric("tokio_worker_busy_seconds_total", "counter", "Time each worker spent running tasks", &per_worker(busy));
+    let parks = sample.parks.iter().map(u64::to_string).collect();
+    metric("tokio_worker_park_total", "counter", "Times each worker went idle", &per_worker(parks));
+    #[cfg(tokio_unstable)]
+    {
+        metric("tokio_spawned_tasks_total", "counter", "Tasks spawned since start", &[(String::new(), sample.spawned_tasks.to_string())]);
+        let depths = sample.local_queue_depths.iter().map(usize::to_string).collect();
+        metric("tokio_worker_local_queue_depth", "gauge", "Tasks waiting in each worker's own queue", &per_worker(depths));
+        metric("tokio_blocking_threads", "gauge", "Threads of the blocking pool", &value(sample.blocking_threads));
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::Span;
+
+use crate::connections::Connection;
+use crate::privacy;
+use crate::unix_socket::UnixPeer;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// How long the stages of the connection took, None when it did not get there
+#[derive(Debug, Default)]
+pub struct Timings {
+    // Method selection and authentication
+    pub handshake: Option<Duration>,
+    // Only for targets sent as names
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    // tfo_connect: connected without waiting for the target, the SYN went out with the first data
+    pub fast_open: bool,
+    // The connect gave up, after connect_timeout or the system's own timeout
+    pub connect_timed_out: bool,
+}
+
+impl Timings {
+    // Label of the connect latency, "timeout" or the final reply code
+    pub fn connect_result(&self, reply: u8) -> String {
+        if self.connect_timed_out { "timeout".to_string() } else { reply.to_string() }
+    }
+}
+
+// Where a connection got to, failures are counted by the stage they happened in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    // Greeting, method selection and authentication
+    Method,
+    // Reading and checking the request
+    Request,
+    Dns,
+    Connect,
+    Relay,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Method => "method",
+            Stage::Request => "request",
+            Stage::Dns => "dns",
+            Stage::Connect => "connect",
+            Stage::Relay => "relay",
+        }
+    }
+}
+
+// How a connection ended, for the close summary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    // The side that closed first, the relay ends once both have
+    ClientEof,
+    TargetEof,
+    IdleTimeout,
+    // A side stopped answering, its socket timed out or became unreachable
+    PeerLost,
+    Error,
+    // Fully answered without relaying, e.g. RESOLVE or a failure reply
+    Answered,
+}
+
+// In declaration order, label values of rock5_connections_closed_total
+pub const CLOSE_REASONS: [CloseReason; 6] = [CloseReason::ClientEof, CloseReason::TargetEof, CloseReason::IdleTimeout, CloseReason::PeerLost, CloseReason::Error, CloseReason::Answered];
+
+impl CloseReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::TargetEof => "target_eof",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::PeerLost => "peer_lost",
+            CloseReason::Error => "error",
+            CloseReason::Answered => "answered",
+        }
+    }
+}
+
+// Who is on the other end of a client connection
+#[derive(Debug, Clone, Copy)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix(UnixPeer),
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Peer {
+        Peer::Tcp(addr)
+    }
+}
+
+// What is known about a client connection, filled in while negotiating
+#[derive(Debug)]
+pub struct Session {
+    // Unique for the process lifetime, given out in accept order
+    pub id: u64,
+    pub peer: Peer,
+    // Local address of the listener that accepted the connection, 0.0.0.0:0 for unix sockets
+    pub listen_addr: SocketAddr,
+    // Name of that listener, "default" unless set up by a [listener.<name>] section
+    pub listener: String,
+    // Selected SOCKS5 authentication method, None for SOCKS4
+    pub method: Option<u8>,
+    // Authenticated user, None for anonymous sessions
+    pub user: Option<String>,
+    // Accepted beyond max_connections, the request gets refused
+    pub over_limit: bool,
+    // Accepted beyond max_connections_per_client, refused as well
+    pub over_client_limit: bool,
+    // Requested destination as sent by the client, host:port
+    pub target: Option<String>,
+    // Address actually connected to
+    pub resolved: Option<SocketAddr>,
+    // Relayed bytes, client to target and back
+    pub sent: u64,
+    pub received: u64,
+    pub timings: Timings,
+    pub stage: Stage,
+    // Set by the relay, errors override it
+    pub close_reason: CloseReason,
+    // Shared with the registry behind the admin socket
+    pub live: Arc<Connection>,
+    // Every line logged for the connection carries its id and client
+    pub span: Span,
+}
+
+impl Session {
+    pub fn new(peer: Peer, listen_addr: SocketAddr, listener: String) -> Session {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let live = Arc::new(Connection::new(id, peer));
+        // At error level so it is not filtered out while anything is logged
+        let span = tracing::error_span!("conn", id, client_addr = %privacy::client(peer, id), dest = tracing::field::Empty, reply_code = tracing::field::Empty);
+        Session { id, peer, listen_addr, listener, method: None, user: None, over_limit: false, over_client_limit: false, target: None, resolved: None, sent: 0, received: 0, timings: Timings::default(), stage: Stage::Method, close_reason: CloseReason::Answered, live, span }
+    }
+
+    // Also shown on the connection span, so every later log line carries it
+    pub fn set_target(&mut self, target: String) {
+        self.span.record("dest", privacy::host_port(&target, self.id));
+        self.live.set_dest(&target);
+        self.target = Some(target);
+    }
+
+    pub fn set_user(&mut self, user: String) {
+        self.live.set_user(&user);
+        self.user = Some(user);
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.user {
+            Some(user) => write!(f, "{}@{}", user, privacy::client(self.peer, self.id))?,
+            None => write!(f, "{}", privacy::client(self.peer, self.id))?,
+        }
+        if self.listener != crate::config::DEFAULT_LISTENER {
+            write!(f, " ({})", self.listener)?;
+        }
+        Ok(())
+    }
+}
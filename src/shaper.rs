@@ -0,0 +1,94 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// bandwidth_limit, for every relay in both directions together, per_connection_limit has one per direction of a relay
+pub static GLOBAL: Bucket = Bucket::new(0);
+
+// Token bucket in bytes, refilled at rate per second, 0 lets everything through.
+// Takers pay up front and sleep off what they owe, so they queue up in order instead of racing for tokens.
+#[derive(Debug)]
+pub struct Bucket {
+    rate: AtomicU64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    // Below 0 while takers wait
+    tokens: f64,
+    last: Option<Instant>,
+    // What was taken in the current and the previous second, for utilization
+    window_start: Option<Instant>,
+    window_bytes: u64,
+    last_rate: f64,
+}
+
+impl Bucket {
+    pub const fn new(rate: u64) -> Bucket {
+        Bucket { rate: AtomicU64::new(rate), state: Mutex::new(State { tokens: 0.0, last: None, window_start: None, window_bytes: 0, last_rate: 0.0 }) }
+    }
+
+    // On start and reload, waiting takers keep what they owe
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    // The most a relay should read at once, one turn of the queue
+    pub fn quantum(&self) -> usize {
+        match self.rate.load(Ordering::Relaxed) {
+            0 => usize::MAX,
+            rate => burst(rate) as usize,
+        }
+    }
+
+    // Waits until n more bytes may pass
+    pub async fn take(&self, n: usize) {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return;
+        }
+        let wait = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let burst = burst(rate) as f64;
+            let refill = state.last.map_or(burst, |last| now.duration_since(last).as_secs_f64() * rate as f64);
+            state.tokens = (state.tokens + refill).min(burst) - n as f64;
+            state.last = Some(now);
+            state.roll(now);
+            state.window_bytes += n as u64;
+            (state.tokens < 0.0).then(|| Duration::from_secs_f64(-state.tokens / rate as f64))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // Share of the rate used over the last second, 0 without a limit
+    pub fn utilization(&self) -> f64 {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return 0.0;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.roll(Instant::now());
+        state.last_rate / rate as f64
+    }
+}
+
+impl State {
+    fn roll(&mut self, now: Instant) {
+        let start = *self.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(start);
+        if elapsed >= Duration::from_secs(1) {
+            self.last_rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+        }
+    }
+}
+
+// A tenth of a second worth, so no relay gets far ahead of the others
+fn burst(rate: u64) -> u64 {
+    (rate / 10).max(4096)
+}
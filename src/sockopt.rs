@@ -0,0 +1,247 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::io;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+// The socket options every relayed TCP connection gets, side is "client", "target" or "peer" for the log
+pub fn tune(stream: &TcpStream, cfg: &Config, side: &str) {
+    if let Err(e) = stream.set_nodelay(cfg.tcp_nodelay()) {
+        warn!("Could not set TCP_NODELAY on the {} connection: {}", side, e);
+    }
+    keepalive(stream, cfg, side);
+    // client_dscp for what goes back to the client, outbound_dscp for what goes to the target or BIND peer
+    let dscp = if side == "client" { cfg.client_dscp() } else { cfg.outbound_dscp() };
+    if let Some(dscp) = dscp
+        && let Err(e) = set_dscp(stream, dscp) {
+        warn!("Could not set DSCP {} on the {} connection: {}", dscp, side, e);
+    }
+}
+
+// IP_TOS, or IPV6_TCLASS and for IPv4 clients of a dual-stack socket IP_TOS as well
+fn set_dscp(stream: &TcpStream, dscp: u8) -> io::Result<()> {
+    let tos = u32::from(dscp) << 2;
+    let socket = SockRef::from(stream);
+    if stream.local_addr()?.is_ipv4() {
+        return socket.set_tos_v4(tos);
+    }
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos", target_os = "netbsd", target_os = "openbsd"))]
+    socket.set_tclass_v6(tos)?;
+    if stream.peer_addr()?.ip().to_canonical().is_ipv4() {
+        socket.set_tos_v4(tos)?;
+    }
+    Ok(())
+}
+
+// Keepalive with the configured timings
+fn keepalive(stream: &TcpStream, cfg: &Config, side: &str) {
+    let probe = cfg.peer_probe_interval();
+    if !probe.is_zero() {
+        return peer_probe(stream, probe, side);
+    }
+    let time = cfg.tcp_keepalive();
+    if time.is_zero() {
+        return;
+    }
+    let mut keepalive = TcpKeepalive::new().with_time(time);
+    let mut applied = format!("after {:?}", time);
+    // Everywhere rock5 runs, other systems only get the time
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "netbsd", windows))]
+    {
+        let (interval, retries) = (cfg.tcp_keepalive_interval(), cfg.tcp_keepalive_retries());
+        if interval > Duration::ZERO {
+            keepalive = keepalive.with_interval(interval);
+            applied.push_str(&format!(", every {:?}", interval));
+        }
+        if retries > 0 {
+            keepalive = keepalive.with_retries(retries);
+            applied.push_str(&format!(", {} retries", retries));
+        }
+    }
+    match SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+        Ok(()) => debug!("TCP keepalive on the {} connection {}", side, applied),
+        Err(e) => warn!("Could not enable TCP keepalive on the {} connection: {}", side, e),
+    }
+}
+
+// peer_probe_interval: after that long without hearing from the peer, three probes in as much time again.
+// TCP_USER_TIMEOUT ends it just as soon when sent data goes unacknowledged, when keepalive doesn't probe.
+// The relay sees ETIMEDOUT either way and closes as peer_lost.
+fn peer_probe(stream: &TcpStream, probe: Duration, side: &str) {
+    let every = (probe / 3).max(Duration::from_secs(1));
+    #[allow(unused_mut)]
+    let mut keepalive = TcpKeepalive::new().with_time(probe);
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "netbsd", windows))]
+    {
+        keepalive = keepalive.with_interval(every).with_retries(3);
+    }
+    let socket = SockRef::from(stream);
+    if let Err(e) = socket.set_tcp_keepalive(&keepalive) {
+        warn!("Could not enable peer probes on the {} connection: {}", side, e);
+        return;
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Err(e) = socket.set_tcp_user_timeout(Some(probe * 2)) {
+        warn!("Could not set TCP_USER_TIMEOUT on the {} connection: {}", side, e);
+    }
+    debug!("Probing the {} connection after {:?} without traffic, every {:?}", side, probe, every);
+}
+
+// Where the kernel spreads connections over sockets sharing an address
+pub const REUSE_PORT: bool = cfg!(any(target_os = "linux", target_os = "android", target_os = "freebsd"));
+
+// Where one IPv6 socket can take IPv4 connections as well, OpenBSD does not map them
+pub const DUAL_STACK: bool = !cfg!(target_os = "openbsd");
+
+// A listening socket like TcpListener::bind makes, with reuse_port others may listen on addr as well.
+// only_v6 sets IPV6_V6ONLY on IPv6 sockets, None keeps the system default
+pub fn listener(addr: SocketAddr, reuse_port: bool, only_v6: Option<bool>, backlog: i32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        socket.set_reuse_port(true)?;
+        // Plain SO_REUSEPORT there hands every connection to the last socket
+        #[cfg(target_os = "freebsd")]
+        socket.set_reuse_port_lb(true)?;
+    }
+    if let Some(only_v6) = only_v6 && addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+// tfo_listen: clients may put their first bytes, the SOCKS greeting, into the SYN. The queue of such
+// connections not yet accepted is as long as the backlog. Systems without TCP_FASTOPEN listen as before
+pub fn fast_open(listener: &TcpListener, backlog: i32) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let fd = std::os::fd::AsRawFd::as_raw_fd(listener);
+        let set = unsafe { libc::setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, &backlog as *const i32 as *const libc::c_void, size_of::<i32>() as libc::socklen_t) };
+        if set < 0 {
+            warn!("Could not enable TCP Fast Open on {}: {}", describe(listener), io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (listener, backlog);
+        debug!("TCP Fast Open is not supported here, tfo_listen has no effect");
+    }
+}
+
+// tfo_connect: TCP_FASTOPEN_CONNECT, connect() returns at once when the kernel has a cookie of the target and
+// the SYN waits for the first write, without one it is a connect as usual that asks for a cookie
+pub fn fast_open_connect(socket: &TcpSocket) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let fd = std::os::fd::AsRawFd::as_raw_fd(socket);
+        let on: i32 = 1;
+        let set = unsafe { libc::setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, &on as *const i32 as *const libc::c_void, size_of::<i32>() as libc::socklen_t) };
+        if set < 0 {
+            debug!("Could not enable TCP Fast Open for the target: {}", io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = socket;
+        debug!("TCP Fast Open is not supported here, tfo_connect has no effect");
+    }
+}
+
+// Whether the SYN of a connected stream is still waiting for data, see fast_open_connect
+pub fn syn_deferred(stream: &TcpStream) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let fd = std::os::fd::AsRawFd::as_raw_fd(stream);
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = size_of::<libc::tcp_info>() as libc::socklen_t;
+        let got = unsafe { libc::getsockopt(fd, libc::IPPROTO_TCP, libc::TCP_INFO, &mut info as *mut libc::tcp_info as *mut libc::c_void, &mut len) };
+        // TCP_SYN_SENT, connect() would only have returned for an established connection otherwise
+        got == 0 && info.tcpi_state == 2
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = stream;
+        false
+    }
+}
+
+// Sends the SYN a connect left for the first write without data of its own, the send itself fails with EINPROGRESS
+pub fn send_deferred_syn(stream: &TcpStream) {
+    let _ = SockRef::from(stream).send(&[]);
+}
+
+// Whether the kernel answers Fast Open SYNs at all, net.ipv4.tcp_fastopen needs bit 2 for that
+pub fn fast_open_server_enabled() -> bool {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(enabled) = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen").ok().and_then(|enabled| enabled.trim().parse::<u32>().ok()) {
+        return enabled & 2 != 0;
+    }
+    true
+}
+
+// The backlog listen() gets out of listen_backlog, Linux silently caps it at net.core.somaxconn
+pub fn effective_backlog(backlog: i32) -> i32 {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(max) = std::fs::read_to_string("/proc/sys/net/core/somaxconn").ok().and_then(|max| max.trim().parse::<i32>().ok()) {
+        return backlog.min(max);
+    }
+    backlog
+}
+
+// For the log, "[::]:1080 (IPv4 and IPv6)"
+pub fn describe(listener: &TcpListener) -> String {
+    let Ok(addr) = listener.local_addr() else { return "unknown address".to_string() };
+    match SockRef::from(listener).only_v6() {
+        Ok(false) if addr.ip().is_unspecified() && addr.is_ipv6() => format!("{addr} (IPv4 and IPv6)"),
+        _ => addr.to_string(),
+    }
+}
+
+// outbound_interface, SO_BINDTODEVICE before bind and connect
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn bind_device(socket: &impl std::os::fd::AsFd, interface: &str) -> io::Result<()> {
+    SockRef::from(socket).bind_device(Some(interface.as_bytes()))
+}
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn bind_device(_socket: &impl std::os::fd::AsFd, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "outbound_interface is only supported on Linux"))
+}
+
+// outbound_fwmark, SO_MARK for policy routing
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_mark(socket: &impl std::os::fd::AsFd, mark: u32) -> io::Result<()> {
+    SockRef::from(socket).set_mark(mark)
+}
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_mark(_socket: &impl std::os::fd::AsFd, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "outbound_fwmark is only supported on Linux"))
+}
+
+// Tries outbound_interface on a throwaway socket so a typo or missing capability shows when the config is loaded
+pub fn check_interface(interface: &str) -> Result<(), String> {
+    let probe = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).map_err(|e| e.to_string())?;
+    match bind_device(&probe, interface) {
+        Ok(()) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => Err("binding to an interface needs CAP_NET_RAW, run as root or grant it with setcap cap_net_raw+ep".to_string()),
+        Err(e) if e.raw_os_error() == Some(libc::ENODEV) => Err("no such network interface".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// The same for outbound_fwmark
+pub fn check_mark(mark: u32) -> Result<(), String> {
+    let probe = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).map_err(|e| e.to_string())?;
+    match set_mark(&probe, mark) {
+        Ok(()) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => Err("setting a fwmark needs CAP_NET_ADMIN, run as root or grant it with setcap cap_net_admin+ep".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
@@ -0,0 +1,179 @@
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Instant;
+use tracing::{info, warn, Instrument};
+
+use crate::client_stream::ClientStream;
+use crate::config::Config;
+use crate::connections::State;
+use crate::domain;
+use crate::eyeballs;
+use crate::policy;
+use crate::privacy;
+use crate::session::{Session, Stage};
+use crate::statsd;
+use crate::stats::Stats;
+use crate::target::TargetAddr;
+
+pub const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_CONNECT: u8 = 0x01;
+const SOCKS4_REPLY_VERSION: u8 = 0x00;
+pub const SOCKS4_GRANTED: u8 = 0x5A;
+const SOCKS4_REJECTED: u8 = 0x5B;
+// USERID and SOCKS4a hostname are NUL terminated, don't read forever
+const MAX_FIELD_LEN: usize = 255;
+
+// Handle a SOCKS4/4a request up to the reply, VN and CD have already been read
+// Returns None when the request was answered without connecting
+pub async fn negotiate<S: ClientStream>(client_stream: &mut S, session: &mut Session, cmd: u8, cfg: &Config, stats: &Stats) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+    let client = privacy::client(session.peer, session.id);
+    session.stage = Stage::Request;
+    // +----+----+----+----+----+----+----+----+----+----+....+----+
+    // | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+    // +----+----+----+----+----+----+----+----+----+----+....+----+
+    //    1    1      2              4           variable       1
+    let mut port_buf = [0u8; 2];
+    client_stream.read_exact(&mut port_buf).await?;
+    let target_port = u16::from_be_bytes(port_buf);
+    let mut ip_buf = [0u8; 4];
+    client_stream.read_exact(&mut ip_buf).await?;
+    let target_ip = Ipv4Addr::from(ip_buf);
+    let _userid = read_nul_terminated(client_stream).await?;
+
+    // SOCKS4a: DSTIP 0.0.0.x (x != 0) means a hostname follows the USERID
+    let octets = target_ip.octets();
+    let target_addr = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let host = read_nul_terminated(client_stream).await?;
+        match domain::parse_domain(&host, session.id) {
+            Ok(target) => target,
+            Err(reason) => {
+                warn!("Client {} sent invalid domain name {:?}: {}", client, privacy::host(&String::from_utf8_lossy(&host), session.id), reason);
+                send_reply4(client_stream, SOCKS4_REJECTED, SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+            }
+        }
+    } else {
+        TargetAddr::Ip(IpAddr::V4(target_ip))
+    };
+
+    if cmd != SOCKS4_CONNECT {
+        warn!("Client {} requested unsupported SOCKS4 command: {}", client, cmd);
+        send_reply4(client_stream, SOCKS4_REJECTED, SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "Unsupported SOCKS4 command"));
+    }
+    let dest = privacy::target(&target_addr, session.id);
+    info!("Client {} requested SOCKS4 connection to: {}:{}", client, dest, target_port);
+    session.set_target(format!("{}:{}", target_addr, target_port));
+
+    if cfg.maintenance() {
+        info!("Maintenance mode: denied request from client {} for {}:{}", client, dest, target_port);
+        Stats::inc(&stats.maintenance_denied);
+        statsd::count(&session.listener, "maintenance_denied", "", 1);
+        send_reply4(client_stream, SOCKS4_REJECTED, SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+        return Ok(None);
+    }
+
+    if session.over_limit {
+        warn!("Refused request from client {} for {}:{}, max_connections reached", client, dest, target_port);
+        Stats::inc(&stats.limit_rejected);
+        statsd::count(&session.listener, "limit_rejected", "", 1);
+        send_reply4(client_stream, SOCKS4_REJECTED, SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+        return Ok(None);
+    }
+
+    if session.over_client_limit {
+        warn!("Refused request from client {} for {}:{}, max_connections_per_client reached", client, dest, target_port);
+        Stats::inc(&stats.client_limit_rejected);
+        statsd::count(&session.listener, "client_limit_rejected", "", 1);
+        send_reply4(client_stream, SOCKS4_REJECTED, SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+        return Ok(None);
+    }
+
+    if let Some(rule) = policy::blocked_domain_rule(&target_addr, cfg.blocked_domains()) {
+        warn!("Client {} requested blocked domain {} (rule {})", client, dest, rule);
+        send_reply4(client_stream, SOCKS4_REJECTED, SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+        return Ok(None);
+    }
+
+    session.live.set_state(State::Connecting);
+    session.stage = Stage::Dns;
+    let resolve_started = Instant::now();
+    let resolved = target_addr.resolve_all(target_port, cfg.resolver(), |ip| cfg.outbound_allowed(ip), session.id)
+        .instrument(crate::dns_span(&target_addr)).await;
+    if let TargetAddr::Domain(_) = target_addr {
+        session.timings.dns = Some(resolve_started.elapsed());
+    }
+    let target_socket_addrs = match resolved {
+        Ok(addrs) if !addrs.is_empty() => addrs,
+        Ok(_) => {
+            warn!("Could not resolve target address to an allowed family: {}:{}", dest, target_port);
+            send_reply4(client_stream, SOCKS4_REJECTED, SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "Could not resolve target address"));
+        }
+        Err(e) => {
+            warn!("Could not resolve target address: {}:{} ({})", dest, target_port, e);
+            send_reply4(client_stream, SOCKS4_REJECTED, SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+            return Err(e);
+        }
+    };
+
+    session.stage = Stage::Connect;
+    let connect_started = Instant::now();
+    let connected = eyeballs::connect(&target_socket_addrs, cfg, session.id).await;
+    session.timings.connect = Some(connect_started.elapsed());
+    let (target_stream, target_socket_addr) = match connected {
+        Ok(connected) => connected,
+        Err((e, target_socket_addr)) => {
+            if e.kind() == io::ErrorKind::TimedOut {
+                warn!("Timed out connecting to target {} after {:?}", privacy::addr(target_socket_addr, session.id), connect_started.elapsed());
+                session.timings.connect_timed_out = true;
+            } else {
+                warn!("Failed to connect to target {}: {}", privacy::addr(target_socket_addr, session.id), e);
+            }
+            send_reply4(client_stream, SOCKS4_REJECTED, SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+            return Err(e);
+        }
+    };
+    info!("Successfully connected to target: {}", privacy::addr(target_socket_addr, session.id));
+    session.timings.fast_open = cfg.tfo_connect() && crate::sockopt::syn_deferred(&target_stream);
+
+    send_reply4(client_stream, SOCKS4_GRANTED, target_socket_addr).await?;
+    if session.timings.fast_open {
+        crate::start_fast_open(client_stream, &target_stream).await;
+    }
+
+    Ok(Some((target_stream, target_socket_addr)))
+}
+
+async fn read_nul_terminated<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut field = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == 0x00 {
+            return Ok(field);
+        }
+        if field.len() == MAX_FIELD_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "SOCKS4 field too long"));
+        }
+        field.push(byte);
+    }
+}
+
+// +----+----+----+----+----+----+----+----+
+// | VN | CD | DSTPORT |      DSTIP        |
+// +----+----+----+----+----+----+----+----+
+//    1    1      2              4
+async fn send_reply4<S: AsyncWrite + Unpin>(stream: &mut S, code: u8, addr: SocketAddr) -> io::Result<()> {
+    let ip = match addr {
+        SocketAddr::V4(v4) => v4.ip().octets(),
+        SocketAddr::V6(_) => [0, 0, 0, 0], // Not representable in SOCKS4
+    };
+    crate::reply::remember(code);
+    let mut reply = [0u8; 8];
+    reply[0] = SOCKS4_REPLY_VERSION;
+    reply[1] = code;
+    reply[2..4].copy_from_slice(&addr.port().to_be_bytes());
+    reply[4..8].copy_from_slice(&ip);
+    stream.write_all(&reply).await
+}
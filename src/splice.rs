@@ -0,0 +1,55 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use tokio::io::{self, Interest};
+
+use crate::client_stream::ClientStream;
+
+// One direction of the relay moves its bytes through a pipe with splice(2), they never reach userspace
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    // The most one splice moves, relay_buffer_size like the buffer of a copying relay
+    pub size: usize,
+}
+
+impl Pipe {
+    // Room for size bytes if the kernel allows that much, it keeps 64KiB otherwise
+    pub fn new(size: usize) -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pipe = unsafe { Pipe { read: OwnedFd::from_raw_fd(fds[0]), write: OwnedFd::from_raw_fd(fds[1]), size } };
+        unsafe { libc::fcntl(pipe.write.as_raw_fd(), libc::F_SETPIPE_SZ, size as libc::c_int) };
+        Ok(pipe)
+    }
+
+    // Up to len bytes from the socket into the empty pipe, 0 at EOF
+    pub async fn fill(&self, from: &impl ClientStream, len: usize) -> io::Result<usize> {
+        loop {
+            from.ready(Interest::READABLE).await?;
+            match from.try_io(Interest::READABLE, || splice(from.as_fd().as_raw_fd(), self.write.as_raw_fd(), len)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+
+    // All len bytes in the pipe on to the socket
+    pub async fn drain(&self, to: &impl ClientStream, mut len: usize) -> io::Result<()> {
+        while len > 0 {
+            to.ready(Interest::WRITABLE).await?;
+            match to.try_io(Interest::WRITABLE, || splice(self.read.as_raw_fd(), to.as_fd().as_raw_fd(), len)) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => len -= n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe { libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK) };
+    if n < 0 { Err(io::Error::last_os_error()) } else { Ok(n as usize) }
+}
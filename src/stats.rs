@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::info;
+
+use crate::connections::Registry;
+use crate::privacy;
+use crate::resolver;
+use crate::session::{Stage, Timings, CLOSE_REASONS};
+use crate::statsd;
+use crate::target::TargetAddr;
+
+// Why a handshake failed, label values of rock5_handshake_failures_total
+pub const HANDSHAKE_FAILURES: [&str; 6] = ["timeout", "eof", "protocol", "auth", "unsupported", "other"];
+
+// Destinations tracked at most, the one with the least traffic makes room for a new one
+const MAX_DESTINATIONS: usize = 1000;
+// Shown by dump
+const TOP_DESTINATIONS: usize = 10;
+
+// Upper bounds of the latency buckets in seconds, the last bucket is +Inf
+const LATENCY_BUCKETS: [f64; 13] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug)]
+pub struct Histogram {
+    // Not cumulative, one count per bucket
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    // Also for statics
+    pub const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        Stats::inc(&self.buckets[bucket]);
+        Stats::add(&self.sum_micros, duration.as_micros() as u64);
+        Stats::inc(&self.count);
+    }
+
+    // Interpolated within the bucket like Prometheus' histogram_quantile, None without samples
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let counts: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = q * total as f64;
+        let mut below = 0;
+        for (i, count) in counts.iter().enumerate() {
+            if (below + count) as f64 >= rank && *count > 0 {
+                let Some(upper) = LATENCY_BUCKETS.get(i) else { break };
+                let lower = if i == 0 { 0.0 } else { LATENCY_BUCKETS[i - 1] };
+                return Some(lower + (upper - lower) * (rank - below as f64) / *count as f64);
+            }
+            below += count;
+        }
+        // In the +Inf bucket
+        Some(LATENCY_BUCKETS[LATENCY_BUCKETS.len() - 1])
+    }
+
+    // p50/p95/p99 in milliseconds
+    fn summary(&self) -> String {
+        let quantiles: Vec<String> = [0.5, 0.95, 0.99].iter()
+            .map(|q| self.quantile(*q).map_or("-".to_string(), |secs| format!("{:.1}", secs * 1000.0)))
+            .collect();
+        format!("{}ms ({} samples)", quantiles.join("/"), self.count.load(Ordering::Relaxed))
+    }
+
+    // _bucket, _sum and _count samples, labels are without braces
+    fn prometheus(&self, text: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+            let _ = writeln!(text, "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {cumulative}");
+        }
+        let braces = if labels.is_empty() { String::new() } else { format!("{{{labels}}}") };
+        let _ = writeln!(text, "{name}_sum{braces} {}", self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(text, "{name}_count{braces} {}", self.count.load(Ordering::Relaxed));
+    }
+}
+
+// Traffic to one destination host since start (or the last reset)
+#[derive(Debug, Default, Clone)]
+pub struct Traffic {
+    pub connections: u64,
+    pub sent: u64,
+    pub received: u64,
+}
+
+// Counters shared by all connections
+#[derive(Debug)]
+pub struct Stats {
+    // Connections that ended with an error
+    pub failed: AtomicU64,
+    // Requests refused because of maintenance mode
+    pub maintenance_denied: AtomicU64,
+    // Requests refused because max_connections was reached
+    pub limit_rejected: AtomicU64,
+    // Connections dropped or requests refused because max_connections_per_client was reached
+    pub client_limit_rejected: AtomicU64,
+    // Connections currently being handled
+    pub active: AtomicU64,
+    // Connections accepted, including those refused later
+    pub accepted: AtomicU64,
+    // Failed accept() calls the listeners kept going after
+    pub accept_errors: AtomicU64,
+    // Relays currently copying data
+    pub active_relays: AtomicU64,
+    // Relayed bytes, client to target and target to client
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    // Indexed like HANDSHAKE_FAILURES
+    pub handshake_failures: [AtomicU64; HANDSHAKE_FAILURES.len()],
+    // Indexed like CLOSE_REASONS
+    pub closed: [AtomicU64; CLOSE_REASONS.len()],
+    // Last reply of each connection, indexed by REP (or SOCKS4 CD)
+    pub replies: [AtomicU64; 256],
+    // By requested host, name or IP
+    destinations: Mutex<HashMap<String, Traffic>>,
+    pub handshake_latency: Histogram,
+    pub dns_latency: Histogram,
+    // By final reply code, so failures stay apart from successful connects, and whether it was a Fast Open one
+    connect_latency: Mutex<BTreeMap<(String, bool), Histogram>>,
+    // Failed connections by (stage, reason)
+    failures: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    // Live connections for the admin socket
+    pub connections: Registry,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            failed: AtomicU64::new(0),
+            maintenance_denied: AtomicU64::new(0),
+            limit_rejected: AtomicU64::new(0),
+            client_limit_rejected: AtomicU64::new(0),
+            active: AtomicU64::new(0),
+            accepted: AtomicU64::new(0),
+            accept_errors: AtomicU64::new(0),
+            active_relays: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            handshake_failures: [const { AtomicU64::new(0) }; HANDSHAKE_FAILURES.len()],
+            closed: [const { AtomicU64::new(0) }; CLOSE_REASONS.len()],
+            replies: [const { AtomicU64::new(0) }; 256],
+            destinations: Mutex::new(HashMap::new()),
+            handshake_latency: Histogram::default(),
+            dns_latency: Histogram::default(),
+            connect_latency: Mutex::new(BTreeMap::new()),
+            failures: Mutex::new(BTreeMap::new()),
+            connections: Registry::default(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(counter: &AtomicU64) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    // Sort a failed negotiation on listener into one of HANDSHAKE_FAILURES
+    pub fn handshake_failed(&self, e: &io::Error, listener: &str) {
+        let reason = match e.kind() {
+            io::ErrorKind::TimedOut => "timeout",
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset => "eof",
+            io::ErrorKind::InvalidData => "protocol",
+            io::ErrorKind::PermissionDenied => "auth",
+            io::ErrorKind::Unsupported => "unsupported",
+            _ => "other",
+        };
+        if let Some(index) = HANDSHAKE_FAILURES.iter().position(|known| *known == reason) {
+            Stats::inc(&self.handshake_failures[index]);
+        }
+        statsd::count(listener, "handshake_failures", format!("reason:{reason}"), 1);
+    }
+
+    pub fn failed_at(&self, stage: Stage, reason: &'static str, listener: &str) {
+        *self.failures.lock().unwrap_or_else(|e| e.into_inner()).entry((stage.name(), reason)).or_default() += 1;
+        statsd::count(listener, "failures", format!("stage:{},reason:{reason}", stage.name()), 1);
+    }
+
+    // By (stage, reason)
+    pub fn failures(&self) -> BTreeMap<(&'static str, &'static str), u64> {
+        self.failures.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Called once per connection when it closes
+    pub fn record_destination(&self, host: &str, sent: u64, received: u64) {
+        let mut destinations = self.destinations.lock().unwrap_or_else(|e| e.into_inner());
+        if !destinations.contains_key(host) && destinations.len() >= MAX_DESTINATIONS {
+            let smallest = destinations.iter()
+                .min_by_key(|(_, traffic)| traffic.sent + traffic.received)
+                .map(|(host, _)| host.clone());
+            if let Some(smallest) = smallest {
+                destinations.remove(&smallest);
+            }
+        }
+        let traffic = destinations.entry(host.to_string()).or_default();
+        traffic.connections += 1;
+        traffic.sent += sent;
+        traffic.received += received;
+    }
+
+    // Called once per connection when it closes, with its final reply
+    pub fn record_timings(&self, timings: &Timings, reply: Option<u8>) {
+        if let Some(handshake) = timings.handshake {
+            self.handshake_latency.observe(handshake);
+        }
+        if let Some(dns) = timings.dns {
+            self.dns_latency.observe(dns);
+        }
+        if let (Some(connect), Some(reply)) = (timings.connect, reply) {
+            self.connect_latency.lock().unwrap_or_else(|e| e.into_inner()).entry((timings.connect_result(reply), timings.fast_open)).or_default().observe(connect);
+        }
+    }
+
+    // Most bytes first
+    pub fn top_destinations(&self, n: usize) -> Vec<(String, Traffic)> {
+        let destinations = self.destinations.lock().unwrap_or_else(|e| e.into_inner());
+        let mut top: Vec<(String, Traffic)> = destinations.iter().map(|(host, traffic)| (host.clone(), traffic.clone())).collect();
+        top.sort_by_key(|(_, traffic)| std::cmp::Reverse(traffic.sent + traffic.received));
+        top.truncate(n);
+        top
+    }
+
+    pub fn reset_destinations(&self) {
+        self.destinations.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    pub fn dump(&self) {
+        info!(" -> Stats:");
+        info!("    failed: {}", self.failed.load(Ordering::Relaxed));
+        info!("    maintenance_denied: {}", self.maintenance_denied.load(Ordering::Relaxed));
+        info!("    limit_rejected: {}", self.limit_rejected.load(Ordering::Relaxed));
+        info!("    client_limit_rejected: {}", self.client_limit_rejected.load(Ordering::Relaxed));
+        info!("    active: {}", self.active.load(Ordering::Relaxed));
+        info!("    accepted: {}", self.accepted.load(Ordering::Relaxed));
+        info!("    accept_errors: {}", self.accept_errors.load(Ordering::Relaxed));
+        info!("    bytes sent/received: {}/{}", self.bytes_sent.load(Ordering::Relaxed), self.bytes_received.load(Ordering::Relaxed));
+        info!("    handshake p50/p95/p99: {}", self.handshake_latency.summary());
+        info!("    dns p50/p95/p99: {}", self.dns_latency.summary());
+        let lookups: Vec<String> = resolver::LOOKUP_RESULTS.iter().zip(&resolver::LOOKUPS)
+            .map(|(result, counter)| format!("{}={}", result, counter.load(Ordering::Relaxed)))
+            .collect();
+        let pool = &crate::buffer_pool::RELAY;
+        info!("    relay buffers hit={} miss={} pooled={}", pool.hits.load(Ordering::Relaxed), pool.misses.load(Ordering::Relaxed), pool.kept());
+        info!("    dns lookups {}, p50/p95/p99: {}", lookups.join(" "), resolver::LOOKUP_LATENCY.summary());
+        info!("    connect retries: {}", crate::eyeballs::RETRIES.load(Ordering::Relaxed));
+        for ((reply, fast_open), histogram) in self.connect_latency.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            info!("    connect (reply {}{}) p50/p95/p99: {}", reply, if *fast_open { ", fast open" } else { "" }, histogram.summary());
+        }
+        let closed: Vec<String> = CLOSE_REASONS.iter().zip(&self.closed)
+            .map(|(reason, counter)| format!("{}={}", reason.name(), counter.load(Ordering::Relaxed)))
+            .collect();
+        info!("    closed: {}", closed.join(" "));
+        let failures = self.failures();
+        if !failures.is_empty() {
+            info!("    failures (stage/reason):");
+        }
+        for ((stage, reason), count) in failures {
+            info!("      {}/{}: {}", stage, reason, count);
+        }
+        let top = self.top_destinations(TOP_DESTINATIONS);
+        if !top.is_empty() {
+            info!("    top destinations (connections, bytes sent/received):");
+        }
+        for (host, traffic) in top {
+            info!("      {}: {}, {}/{}", privacy::target(&TargetAddr::from_domain(host), 0), traffic.connections, traffic.sent, traffic.received);
+        }
+    }
+
+    // Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+            let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(text, "{name}{labels} {value}");
+            }
+        };
+        let value = |counter: &AtomicU64| vec![(String::new(), counter.load(Ordering::Relaxed))];
+        metric("rock5_connections_accepted_total", "counter", "Accepted client connections", &value(&self.accepted));
+        metric("rock5_accept_errors_total", "counter", "Failed accept() calls, like running out of file descriptors", &value(&self.accept_errors));
+        metric("rock5_connections_failed_total", "counter", "Connections that ended with an error", &value(&self.failed));
+        metric("rock5_maintenance_denied_total", "counter", "Requests refused in maintenance mode", &value(&self.maintenance_denied));
+        metric("rock5_limit_rejected_total", "counter", "Requests refused because max_connections was reached", &value(&self.limit_rejected));
+        metric("rock5_client_limit_rejected_total", "counter", "Connections dropped or requests refused because max_connections_per_client was reached", &value(&self.client_limit_rejected));
+        let failures: Vec<(String, u64)> = HANDSHAKE_FAILURES.iter().zip(&self.handshake_failures)
+            .map(|(reason, counter)| (format!("{{reason=\"{reason}\"}}"), counter.load(Ordering::Relaxed)))
+            .collect();
+        metric("rock5_handshake_failures_total", "counter", "Failed handshakes by reason", &failures);
+        let failures: Vec<(String, u64)> = self.failures.lock().unwrap_or_else(|e| e.into_inner()).iter()
+            .map(|((stage, reason), count)| (format!("{{stage=\"{stage}\",reason=\"{reason}\"}}"), *count))
+            .collect();
+        metric("rock5_failures_total", "counter", "Failed connections by the stage they got to and why", &failures);
+        let closed: Vec<(String, u64)> = CLOSE_REASONS.iter().zip(&self.closed)
+            .map(|(reason, counter)| (format!("{{reason=\"{}\"}}", reason.name()), counter.load(Ordering::Relaxed)))
+            .collect();
+        metric("rock5_connections_closed_total", "counter", "Finished connections by how they ended, idle_timeout for idle relays", &closed);
+        let replies: Vec<(String, u64)> = self.replies.iter().enumerate()
+            .map(|(code, counter)| (format!("{{code=\"{code}\"}}"), counter.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        metric("rock5_replies_total", "counter", "Final reply of each connection by code", &replies);
+        let bytes = vec![
+            ("{direction=\"sent\"}".to_string(), self.bytes_sent.load(Ordering::Relaxed)),
+            ("{direction=\"received\"}".to_string(), self.bytes_received.load(Ordering::Relaxed)),
+        ];
+        metric("rock5_relayed_bytes_total", "counter", "Relayed bytes, sent is client to target", &bytes);
+        let destinations = self.top_destinations(MAX_DESTINATIONS);
+        let connections: Vec<(String, u64)> = destinations.iter()
+            .map(|(host, traffic)| (format!("{{host=\"{}\"}}", label(host)), traffic.connections))
+            .collect();
+        metric("rock5_destination_connections_total", "counter", "Connections by requested host", &connections);
+        let bytes: Vec<(String, u64)> = destinations.iter()
+            .flat_map(|(host, traffic)| [
+                (format!("{{host=\"{}\",direction=\"sent\"}}", label(host)), traffic.sent),
+                (format!("{{host=\"{}\",direction=\"received\"}}", label(host)), traffic.received),
+            ])
+            .collect();
+        metric("rock5_destination_bytes_total", "counter", "Relayed bytes by requested host", &bytes);
+        let lookups: Vec<(String, u64)> = resolver::LOOKUP_RESULTS.iter().zip(&resolver::LOOKUPS)
+            .map(|(result, counter)| (format!("{{result=\"{result}\"}}"), counter.load(Ordering::Relaxed)))
+            .collect();
+        metric("rock5_dns_lookups_total", "counter", "Lookups of names by the resolver, no_address when it has no usable address, cached when answered by dns_cache, hosts by [hosts]", &lookups);
+        let pool = &crate::buffer_pool::RELAY;
+        let pool_results = vec![("{result=\"hit\"}".to_string(), pool.hits.load(Ordering::Relaxed)), ("{result=\"miss\"}".to_string(), pool.misses.load(Ordering::Relaxed))];
+        metric("rock5_relay_buffers_total", "counter", "Relay buffers taken from relay_buffer_pool (hit) or allocated (miss)", &pool_results);
+        metric("rock5_relay_buffers_pooled", "gauge", "Relay buffers kept for reuse", &[(String::new(), pool.kept() as u64)]);
+        metric("rock5_connect_retries_total", "counter", "Connects to a target address tried again, see connect_retries", &[(String::new(), crate::eyeballs::RETRIES.load(Ordering::Relaxed))]);
+        let syslog_dropped = crate::syslog::DROPPED.load(Ordering::Relaxed);
+        metric("rock5_syslog_dropped_total", "counter", "Log messages that could not be sent to syslog", &[(String::new(), syslog_dropped)]);
+        metric("rock5_active_connections", "gauge", "Connections currently being handled", &value(&self.active));
+        metric("rock5_active_relays", "gauge", "Relays currently copying data", &value(&self.active_relays));
+        let histogram = |text: &mut String, name: &str, help: &str| {
+            let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} histogram");
+        };
+        histogram(&mut text, "rock5_handshake_seconds", "Method selection and authentication time");
+        self.handshake_latency.prometheus(&mut text, "rock5_handshake_seconds", "");
+        histogram(&mut text, "rock5_dns_seconds", "Resolution time of target names");
+        self.dns_latency.prometheus(&mut text, "rock5_dns_seconds", "");
+        histogram(&mut text, "rock5_dns_lookup_seconds", "Time of every lookup by the resolver, also for RESOLVE and BIND peers");
+        resolver::LOOKUP_LATENCY.prometheus(&mut text, "rock5_dns_lookup_seconds", "");
+        histogram(&mut text, "rock5_connect_seconds", "Time to connect to the target by final reply code, timeout when it gave up, fast_open for tfo_connect ones that did not wait");
+        for ((reply, fast_open), histogram) in self.connect_latency.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            let fast_open = if *fast_open { ",fast_open=\"true\"" } else { "" };
+            histogram.prometheus(&mut text, "rock5_connect_seconds", &format!("code=\"{reply}\"{fast_open}"));
+        }
+        let _ = writeln!(text, "# HELP rock5_bandwidth_utilization Share of bandwidth_limit used over the last second, 0 without a limit\n# TYPE rock5_bandwidth_utilization gauge");
+        let _ = writeln!(text, "rock5_bandwidth_utilization {:.4}", crate::shaper::GLOBAL.utilization());
+        #[cfg(feature = "runtime-metrics")]
+        crate::runtime_metrics::prometheus(&mut text);
+        text
+    }
+}
+
+// Reason label for a connection that failed without a failure reply
+pub fn error_reason(e: &io::Error) -> &'static str {
+    match e.kind() {
+        io::ErrorKind::TimedOut => "timeout",
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe => "eof",
+        io::ErrorKind::ConnectionRefused => "refused",
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => "unreachable",
+        io::ErrorKind::AddrNotAvailable => "no_address",
+        io::ErrorKind::InvalidData => "malformed",
+        io::ErrorKind::PermissionDenied => "denied",
+        io::ErrorKind::Unsupported => "unsupported",
+        _ => "other",
+    }
+}
+
+// Reason label for a failure reply, SOCKS5 REP or SOCKS4 CD
+pub fn reply_reason(code: u8) -> &'static str {
+    match code {
+        0x01 => "general_failure",
+        0x02 => "not_allowed",
+        0x03 => "network_unreachable",
+        0x04 => "host_unreachable",
+        0x05 => "refused",
+        // Also what connect timeouts are answered with
+        0x06 => "timeout",
+        0x07 => "command_not_supported",
+        0x08 => "address_type_not_supported",
+        0x5B => "rejected",
+        _ => "other",
+    }
+}
+
+// Label values are quoted, escape what would end them
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use arc_swap::ArcSwap;
+use tokio::io;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, Sender};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::resolver;
+use crate::session::Timings;
+use crate::stats::Stats;
+
+// Counters and gauges are sent this often, timings are queued until then
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+// Timings waiting for the next flush, more than that are dropped
+const QUEUE_LEN: usize = 8192;
+// Lines per datagram up to this size, fits a 1500 byte MTU
+const MAX_DATAGRAM: usize = 1432;
+
+// Set once serve runs, without it timings are not even queued
+static TIMINGS: OnceLock<Sender<Timing>> = OnceLock::new();
+// Counts of what happened to connections, by listener, name and the other tags
+static COUNTERS: Mutex<BTreeMap<(String, &'static str, String), u64>> = Mutex::new(BTreeMap::new());
+
+struct Timing {
+    name: &'static str,
+    duration: Duration,
+    tags: String,
+}
+
+// Resolved once, a restart picks up a changed statsd_addr
+pub async fn connect(addr: &str) -> io::Result<UdpSocket> {
+    let target = tokio::net::lookup_host(addr).await?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+// Add n to the counter of a connection of listener, tagged with it and tags. Nothing is kept without statsd_addr
+pub fn count(listener: &str, name: &'static str, tags: impl Into<String>, n: u64) {
+    if TIMINGS.get().is_none() || n == 0 {
+        return;
+    }
+    *COUNTERS.lock().unwrap_or_else(|e| e.into_inner()).entry((listener.to_string(), name, tags.into())).or_default() += n;
+}
+
+// Queue the latencies of a closed connection for the next flush, never waits
+pub fn record_timings(timings: &Timings, listener: &str, reply: Option<u8>) {
+    let Some(queue) = TIMINGS.get() else { return };
+    let listener = format!("listener:{listener}");
+    let queue_timing = |name, duration, tags| {
+        let _ = queue.try_send(Timing { name, duration, tags });
+    };
+    if let Some(handshake) = timings.handshake {
+        queue_timing("handshake", handshake, listener.clone());
+    }
+    if let Some(dns) = timings.dns {
+        queue_timing("dns", dns, listener.clone());
+    }
+    if let (Some(connect), Some(reply)) = (timings.connect, reply) {
+        let fast_open = if timings.fast_open { ",fast_open:true" } else { "" };
+        queue_timing("connect", connect, format!("{listener},code:{}{fast_open}", timings.connect_result(reply)));
+    }
+}
+
+pub async fn serve(socket: UdpSocket, stats: Arc<Stats>, cfg: Arc<ArcSwap<Config>>) {
+    let (queue, mut timings) = mpsc::channel(QUEUE_LEN);
+    let _ = TIMINGS.set(queue);
+    // Counters go out as the increase since the last flush
+    let mut last: HashMap<String, u64> = HashMap::new();
+    let mut failing = false;
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let cfg = cfg.load();
+        let mut lines = Lines { prefix: cfg.statsd_prefix(), tags: cfg.statsd_tags(), lines: Vec::new() };
+        let mut counter = |lines: &mut Lines, name: &str, tags: &str, counter: u64| {
+            let previous = last.insert(format!("{name}|{tags}"), counter).unwrap_or(0);
+            if counter > previous {
+                lines.push(name, counter - previous, "c", tags);
+            }
+        };
+        let value = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        // Those of connections carry the listener like the timings, the others are for the whole process
+        let counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for ((listener, name, tags), count) in counters {
+            let tags = if tags.is_empty() { format!("listener:{listener}") } else { format!("listener:{listener},{tags}") };
+            counter(&mut lines, name, &tags, count);
+        }
+        counter(&mut lines, "accept_errors", "", value(&stats.accept_errors));
+        for (result, lookups) in resolver::LOOKUP_RESULTS.iter().zip(&resolver::LOOKUPS) {
+            counter(&mut lines, "dns.lookups", &format!("result:{result}"), value(lookups));
+        }
+        let pool = &crate::buffer_pool::RELAY;
+        counter(&mut lines, "relay_buffers", "result:hit", pool.hits.load(Ordering::Relaxed));
+        counter(&mut lines, "relay_buffers", "result:miss", pool.misses.load(Ordering::Relaxed));
+        lines.push("relay_buffers_pooled", pool.kept(), "g", "");
+        counter(&mut lines, "connect_retries", "", crate::eyeballs::RETRIES.load(Ordering::Relaxed));
+        counter(&mut lines, "syslog_dropped", "", crate::syslog::DROPPED.load(Ordering::Relaxed));
+        lines.push("active_connections", value(&stats.active), "g", "");
+        lines.push("active_relays", value(&stats.active_relays), "g", "");
+        lines.push("bandwidth_utilization", format!("{:.4}", crate::shaper::GLOBAL.utilization()), "g", "");
+        while let Ok(timing) = timings.try_recv() {
+            lines.push(timing.name, format!("{:.3}", timing.duration.as_secs_f64() * 1000.0), "ms", &timing.tags);
+        }
+
+        let mut sent = Ok(());
+        for datagram in lines.datagrams() {
+            if let Err(e) = socket.send(datagram.as_bytes()).await {
+                sent = Err(e);
+            }
+        }
+        match sent {
+            Ok(()) => failing = false,
+            // Once per run of failures, e.g. while nothing listens on statsd_addr
+            Err(e) if !failing => {
+                warn!("Could not send metrics to StatsD: {}", e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+struct Lines<'a> {
+    prefix: &'a str,
+    // statsd_tags, added to every line
+    tags: &'a str,
+    lines: Vec<String>,
+}
+
+impl Lines<'_> {
+    // <prefix>.<name>:<value>|<type>|#<tags>
+    fn push(&mut self, name: &str, value: impl std::fmt::Display, kind: &str, tags: &str) {
+        let tags = [tags, self.tags].iter().filter(|tags| !tags.is_empty()).copied().collect::<Vec<_>>().join(",");
+        let mut line = if self.prefix.is_empty() { format!("{name}:{value}|{kind}") } else { format!("{}.{name}:{value}|{kind}", self.prefix) };
+        if !tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&tags);
+        }
+        self.lines.push(line);
+    }
+
+    // Newline separated, as many lines per datagram as fit
+    fn datagrams(self) -> Vec<String> {
+        let mut datagrams: Vec<String> = Vec::new();
+        for line in self.lines {
+            match datagrams.last_mut() {
+                Some(datagram) if datagram.len() + 1 + line.len() <= MAX_DATAGRAM => {
+                    datagram.push('\n');
+                    datagram.push_str(&line);
+                }
+                _ => datagrams.push(line),
+            }
+        }
+        datagrams
+    }
+}
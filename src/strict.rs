@@ -0,0 +1,31 @@
+// Whether the client already sent bytes we have not asked for yet
+// Used by strict mode to catch clients that don't wait for our reply before the next stage
+#[cfg(unix)]
+pub fn has_pending_data(stream: &impl std::os::fd::AsRawFd) -> bool {
+    peek(stream).is_ok_and(|n| n > 0)
+}
+
+#[cfg(not(unix))]
+pub fn has_pending_data<S>(_stream: &S) -> bool {
+    false
+}
+
+// Bytes waiting to be read (one at most), 0 once the client closed its side, WouldBlock while there are none
+#[cfg(unix)]
+pub fn peek(stream: &impl std::os::fd::AsRawFd) -> std::io::Result<usize> {
+    let mut probe = [0u8; 1];
+    let ret = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            probe.as_mut_ptr() as *mut libc::c_void,
+            probe.len(),
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    if ret < 0 { Err(std::io::Error::last_os_error()) } else { Ok(ret as usize) }
+}
+
+#[cfg(not(unix))]
+pub fn peek<S>(_stream: &S) -> std::io::Result<usize> {
+    Ok(0)
+}
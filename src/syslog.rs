@@ -0,0 +1,174 @@
+use std::io::{self, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::{Config, SyslogAddress};
+
+// Messages waiting to be sent, more than that are dropped
+const QUEUE_LEN: usize = 4096;
+// Don't try to reconnect more often while the server is away, drop in between
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// Messages that never made it to the syslog server
+pub static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Connection to syslog, None unless log_target or access_log wants it
+static SYSLOG: RwLock<Option<Syslog>> = RwLock::new(None);
+
+struct Syslog {
+    facility: u8,
+    tag: String,
+    queue: SyncSender<Vec<u8>>,
+    remote: bool,
+}
+
+// Replace the connection on start and reload, the old sender drains its queue and exits
+pub fn open(cfg: &Config) {
+    let syslog = cfg.uses_syslog().then(|| {
+        let (queue, messages) = mpsc::sync_channel(QUEUE_LEN);
+        let address = cfg.syslog_address().clone();
+        let remote = !matches!(address, SyslogAddress::Local(_));
+        std::thread::Builder::new()
+            .name("rock5-syslog".to_string())
+            .spawn(move || send_all(address, messages))
+            .map(|_| Syslog { facility: cfg.syslog_facility(), tag: cfg.syslog_tag().to_string(), queue, remote })
+    });
+    let syslog = match syslog {
+        Some(Ok(syslog)) => Some(syslog),
+        Some(Err(e)) => {
+            tracing::error!("Could not start the syslog sender: {}", e);
+            None
+        }
+        None => None,
+    };
+    *SYSLOG.write().unwrap_or_else(|e| e.into_inner()) = syslog;
+}
+
+// Queue one message, the tag gets the suffix (e.g. "-access") appended
+pub fn send(severity: u8, tag_suffix: &str, message: &str) {
+    let syslog = SYSLOG.read().unwrap_or_else(|e| e.into_inner());
+    let Some(syslog) = syslog.as_ref() else { return };
+    let pri = syslog.facility as u32 * 8 + severity as u32;
+    let pid = std::process::id();
+    let message = message.trim_end();
+    // Local daemons (and busybox) want RFC 3164 and add time and host themselves, remote ones get RFC 5424
+    let line = if syslog.remote {
+        let time = humantime::format_rfc3339_millis(SystemTime::now());
+        format!("<{pri}>1 {time} {} {}{tag_suffix} {pid} - - {message}", hostname(), syslog.tag)
+    } else {
+        format!("<{pri}>{}{tag_suffix}[{pid}]: {message}", syslog.tag)
+    };
+    if syslog.queue.try_send(line.into_bytes()).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+enum Socket {
+    Local(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+fn connect(address: &SyslogAddress) -> io::Result<Socket> {
+    match address {
+        SyslogAddress::Local(path) => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            Ok(Socket::Local(socket))
+        }
+        SyslogAddress::Udp(addr) => {
+            let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+            socket.connect(addr)?;
+            Ok(Socket::Udp(socket))
+        }
+        SyslogAddress::Tcp(addr) => Ok(Socket::Tcp(TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?)),
+    }
+}
+
+fn send_all(address: SyslogAddress, messages: Receiver<Vec<u8>>) {
+    let mut socket = None;
+    let mut last_attempt: Option<Instant> = None;
+    for message in messages {
+        if socket.is_none() && last_attempt.is_none_or(|at| at.elapsed() >= RECONNECT_DELAY) {
+            last_attempt = Some(Instant::now());
+            socket = connect(&address).ok();
+        }
+        let sent = match socket.as_mut() {
+            Some(Socket::Local(socket)) => socket.send(&message).map(drop),
+            Some(Socket::Udp(socket)) => socket.send(&message).map(drop),
+            // RFC 6587 octet counting
+            Some(Socket::Tcp(stream)) => stream.write_all(format!("{} ", message.len()).as_bytes())
+                .and_then(|_| stream.write_all(&message)),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        };
+        if sent.is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            socket = None;
+        }
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer outlives the call and its length is passed along
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "-".to_string();
+    }
+    let len = buf.iter().position(|&byte| byte == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+// Lets a fmt layer format events, each one becomes a syslog message
+pub struct MakeSyslogWriter;
+
+pub struct SyslogWriter {
+    severity: u8,
+    buf: Vec<u8>,
+}
+
+impl<'a> MakeWriter<'a> for MakeSyslogWriter {
+    type Writer = SyslogWriter;
+
+    fn make_writer(&'a self) -> SyslogWriter {
+        SyslogWriter { severity: 6, buf: Vec::new() }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> SyslogWriter {
+        SyslogWriter { severity: severity(meta.level()), buf: Vec::new() }
+    }
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The fmt layer drops the writer once the event is formatted
+impl Drop for SyslogWriter {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            send(self.severity, "", &String::from_utf8_lossy(&self.buf));
+        }
+    }
+}
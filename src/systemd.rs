@@ -0,0 +1,25 @@
+use std::os::fd::{FromRawFd, RawFd};
+use socket2::Socket;
+
+// sd_listen_fds(3): passed descriptors start after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+// The sockets systemd passed with socket activation, with their FileDescriptorName=,
+// empty when LISTEN_PID is not this process
+pub fn listen_fds() -> Vec<(Option<String>, Socket)> {
+    let for_us = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok()) == Some(std::process::id());
+    if !for_us {
+        return Vec::new();
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse().ok()).unwrap_or(0);
+    let names: Vec<String> = std::env::var("LISTEN_FDNAMES").map(|names| names.split(':').map(str::to_string).collect()).unwrap_or_default();
+    (0..count).map(|i| {
+        let fd = LISTEN_FDS_START + i;
+        // Not inherited by child processes, as sd_listen_fds does it. Nothing else owns the descriptor
+        let socket = unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            Socket::from_raw_fd(fd)
+        };
+        (names.get(i as usize).cloned(), socket)
+    }).collect()
+}
@@ -0,0 +1,63 @@
+use tokio::io;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::resolver::Resolver;
+
+// Destination named by DST.ADDR
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl TargetAddr {
+    // Clients often send IP literals (optionally bracketed) as domain names, those skip DNS
+    pub fn from_domain(name: String) -> TargetAddr {
+        let literal = name.strip_prefix('[').and_then(|n| n.strip_suffix(']')).unwrap_or(&name);
+        match literal.parse::<IpAddr>() {
+            Ok(ip) => TargetAddr::Ip(ip),
+            Err(_) => TargetAddr::Domain(name),
+        }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self, TargetAddr::Ip(IpAddr::V6(_)))
+    }
+
+    // Resolve to the first socket address, IP targets don't hit the resolver
+    pub async fn resolve(&self, port: u16, resolver: &Resolver, id: u64) -> io::Result<Option<SocketAddr>> {
+        match self {
+            TargetAddr::Ip(ip) => Ok(Some(SocketAddr::new(*ip, port))),
+            TargetAddr::Domain(name) => resolver.lookup(name, port, id).await,
+        }
+    }
+
+    // Same as resolve, skipping addresses the filter rejects
+    pub async fn resolve_filtered(&self, port: u16, resolver: &Resolver, allowed: impl Fn(IpAddr) -> bool, id: u64) -> io::Result<Option<SocketAddr>> {
+        match self {
+            TargetAddr::Ip(ip) => Ok(allowed(*ip).then(|| SocketAddr::new(*ip, port))),
+            TargetAddr::Domain(name) => resolver.lookup_filtered(name, port, allowed, id).await,
+        }
+    }
+
+    // Every address the filter allows, empty when there is none
+    pub async fn resolve_all(&self, port: u16, resolver: &Resolver, allowed: impl Fn(IpAddr) -> bool, id: u64) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            TargetAddr::Ip(ip) => Ok(allowed(*ip).then(|| SocketAddr::new(*ip, port)).into_iter().collect()),
+            TargetAddr::Domain(name) => resolver.lookup_allowed(name, port, allowed, id).await,
+        }
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(IpAddr::V6(ip)) => write!(f, "[{}]", ip),
+            TargetAddr::Ip(ip) => write!(f, "{}", ip),
+            // Names that are no IP literal can still have a colon, e.g. with a zone id, keep a port after them apart
+            TargetAddr::Domain(name) if name.contains(':') && !name.starts_with('[') => write!(f, "[{}]", name),
+            TargetAddr::Domain(name) => write!(f, "{}", name),
+        }
+    }
+}
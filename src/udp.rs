@@ -0,0 +1,163 @@
+use tokio::io::{self, AsyncReadExt};
+use tokio::net::UdpSocket;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use bytes::{BufMut, BytesMut};
+use tracing::{debug, error, info, warn};
+
+use crate::client_stream::ClientStream;
+use crate::config::Config;
+use crate::policy;
+use crate::privacy;
+use crate::reply::{send_failure, send_reply, Reply};
+use crate::session::Peer;
+use crate::target::TargetAddr;
+use crate::{ATYP_DOMAIN_NAME, ATYP_IPV4, ATYP_IPV6, RSV};
+
+const MAX_DATAGRAM: usize = 65535;
+
+// UDP ASSOCIATE: relay datagrams for the client until the controlling TCP connection closes
+pub async fn handle_associate<S: ClientStream>(mut client_stream: S, peer: Peer, id: u64, target_addr: &TargetAddr, target_port: u16, cfg: &Config) -> io::Result<()> {
+    // Bind on the address the client reached us on so the announced BND.ADDR is usable
+    let local_ip = client_stream.local_ip()?;
+    let client = privacy::client(peer, id);
+    // Unix socket clients send from loopback
+    let client_ip = match peer {
+        Peer::Tcp(addr) => addr.ip(),
+        Peer::Unix(_) => local_ip,
+    };
+    let socket = match UdpSocket::bind((local_ip, 0)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to bind UDP socket for client {}: {}", client, e);
+            send_failure(&mut client_stream, Reply::GeneralFailure, target_addr.is_ipv6()).await?;
+            return Err(e);
+        }
+    };
+    let relay_addr = socket.local_addr()?;
+    send_reply(&mut client_stream, Reply::Succeeded, relay_addr).await?;
+    info!("UDP relay for client {} on {}", client, relay_addr);
+
+    // The request may name the port the client will send from, otherwise learn it from the first datagram
+    let mut client_udp_addr: Option<SocketAddr> = None;
+    let expected_port = if matches!(target_addr, TargetAddr::Ip(_)) { target_port } else { 0 };
+    // Remote peers this association has sent to, only these may answer
+    let mut peers: HashSet<SocketAddr> = HashSet::new();
+
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut tcp_buf = [0u8; 64];
+    loop {
+        tokio::select! {
+            // The association ends with the controlling TCP connection
+            res = client_stream.read(&mut tcp_buf) => {
+                match res {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => continue,
+                }
+            }
+            res = socket.recv_from(&mut buf) => {
+                let (len, from) = match res {
+                    Ok(received) => received,
+                    // ICMP for an earlier datagram, e.g. port unreachable, only concerns that peer
+                    Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset) => {
+                        debug!("UDP association of client {} got {}", client, e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                let from_client = from.ip() == client_ip
+                    && match client_udp_addr {
+                        Some(addr) => addr == from,
+                        None => expected_port == 0 || expected_port == from.port(),
+                    };
+
+                if from_client {
+                    client_udp_addr = Some(from);
+                    let Some((host, port, offset)) = parse_udp_header(&buf[..len], id) else {
+                        warn!("Client {} sent malformed UDP datagram", client);
+                        continue;
+                    };
+                    if let Some(rule) = policy::blocked_domain_rule(&host, cfg.blocked_domains()) {
+                        warn!("Client {} sent UDP datagram to blocked domain {} (rule {})", client, privacy::target(&host, id), rule);
+                        continue;
+                    }
+                    let dest = match host.resolve_filtered(port, cfg.resolver(), |ip| cfg.outbound_allowed(ip), id).await {
+                        Ok(Some(addr)) => addr,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            warn!("Could not resolve UDP target {}:{} for client {}: {}", privacy::target(&host, id), port, client, e);
+                            continue;
+                        }
+                    };
+                    peers.insert(dest);
+                    if let Err(e) = socket.send_to(&buf[offset..len], dest).await {
+                        warn!("Failed to send UDP datagram to {} for client {}: {}", privacy::addr(dest, id), client, e);
+                    }
+                } else if peers.contains(&from) {
+                    let Some(client_udp) = client_udp_addr else { continue };
+                    let mut packet = BytesMut::with_capacity(len + 22);
+                    put_udp_header(&mut packet, from);
+                    packet.put(&buf[..len]);
+                    if let Err(e) = socket.send_to(&packet, client_udp).await {
+                        warn!("Failed to send UDP datagram to client {}: {}", client, e);
+                    }
+                }
+                // Anything else is neither our client nor a peer it talked to
+            }
+        }
+    }
+
+    info!("UDP association closed for client {}", client);
+    Ok(())
+}
+
+// Parse the UDP request header, returns host, port and payload offset
+// +----+------+------+----------+----------+----------+
+// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+// +----+------+------+----------+----------+----------+
+// | 2  |  1   |  1   | Variable |    2     | Variable |
+// +----+------+------+----------+----------+----------+
+fn parse_udp_header(buf: &[u8], id: u64) -> Option<(TargetAddr, u16, usize)> {
+    if buf.len() < 4 {
+        return None;
+    }
+    // Fragmentation is not supported, such datagrams are dropped
+    if buf[2] != 0x00 {
+        return None;
+    }
+    let (host, offset) = match buf[3] {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = buf.get(4..8)?.try_into().ok()?;
+            (TargetAddr::Ip(IpAddr::V4(Ipv4Addr::from(octets))), 8)
+        }
+        ATYP_DOMAIN_NAME => {
+            let len = *buf.get(4)? as usize;
+            let name = buf.get(5..5 + len)?;
+            (crate::domain::parse_domain(name, id).ok()?, 5 + len)
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = buf.get(4..20)?.try_into().ok()?;
+            (TargetAddr::Ip(IpAddr::V6(Ipv6Addr::from(octets))), 20)
+        }
+        _ => return None,
+    };
+    let port_bytes: [u8; 2] = buf.get(offset..offset + 2)?.try_into().ok()?;
+    Some((host, u16::from_be_bytes(port_bytes), offset + 2))
+}
+
+fn put_udp_header(packet: &mut BytesMut, from: SocketAddr) {
+    packet.put_u8(RSV);
+    packet.put_u8(RSV);
+    packet.put_u8(0x00); // FRAG
+    match from.ip() {
+        IpAddr::V4(ipv4) => {
+            packet.put_u8(ATYP_IPV4);
+            packet.put(&ipv4.octets()[..]);
+        }
+        IpAddr::V6(ipv6) => {
+            packet.put_u8(ATYP_IPV6);
+            packet.put(&ipv6.octets()[..]);
+        }
+    }
+    packet.put_u16(from.port());
+}
@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+// Split "64KiB" into ("64", "KiB")
+fn split_unit(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    (number, unit.trim())
+}
+
+// "500ms", "10s", "5m" or "1h", plain numbers are seconds
+pub fn parse_duration(s: &str) -> Result<Duration, &'static str> {
+    let (number, unit) = split_unit(s);
+    let number: u64 = number.parse().map_err(|_| "expected a number with an optional ms, s, m or h unit")?;
+    let multiplier = match unit {
+        "ms" => return Ok(Duration::from_millis(number)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err("unknown unit, expected ms, s, m or h"),
+    };
+    number.checked_mul(multiplier).map(Duration::from_secs).ok_or("duration too long")
+}
+
+// "64KiB", "1MB" or plain bytes
+pub fn parse_size(s: &str) -> Result<usize, &'static str> {
+    let (number, unit) = split_unit(s);
+    let number: usize = number.parse().map_err(|_| "expected a number with an optional B, KB, KiB, MB or MiB unit")?;
+    let multiplier = match unit {
+        "" | "B" => 1,
+        "KB" => 1000,
+        "KiB" => 1024,
+        "MB" => 1000 * 1000,
+        "MiB" => 1024 * 1024,
+        _ => return Err("unknown unit, expected B, KB, KiB, MB or MiB"),
+    };
+    number.checked_mul(multiplier).ok_or("size too large")
+}
+
+// Bytes per second from "10Mbps" (bits) or "2MB/s", plain numbers are bytes per second
+pub fn parse_rate(s: &str) -> Result<u64, &'static str> {
+    let (number, unit) = split_unit(s);
+    let number: u64 = number.parse().map_err(|_| "expected a number with an optional unit like 10Mbps or 2MB/s")?;
+    let (bits, bytes) = match unit {
+        "" | "B/s" => (None, 1),
+        "KB/s" => (None, 1000),
+        "KiB/s" => (None, 1024),
+        "MB/s" => (None, 1000 * 1000),
+        "MiB/s" => (None, 1024 * 1024),
+        "GB/s" => (None, 1000 * 1000 * 1000),
+        "bps" => (Some(1), 0),
+        "Kbps" | "kbps" => (Some(1000), 0),
+        "Mbps" => (Some(1000 * 1000), 0),
+        "Gbps" => (Some(1000 * 1000 * 1000), 0),
+        _ => return Err("unknown unit, expected B/s, KB/s, KiB/s, MB/s, MiB/s, GB/s, bps, Kbps, Mbps or Gbps"),
+    };
+    match bits {
+        Some(multiplier) => number.checked_mul(multiplier).map(|bits| bits / 8).ok_or("rate too large"),
+        None => number.checked_mul(bytes).ok_or("rate too large"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        for (s, expected) in [
+            ("500ms", Duration::from_millis(500)),
+            ("10", Duration::from_secs(10)),
+            ("10s", Duration::from_secs(10)),
+            (" 10 s ", Duration::from_secs(10)),
+            ("5m", Duration::from_secs(300)),
+            ("1h", Duration::from_secs(3600)),
+            ("0", Duration::ZERO),
+            ("18446744073709551615ms", Duration::from_millis(u64::MAX)),
+        ] {
+            assert_eq!(parse_duration(s), Ok(expected), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn bad_durations() {
+        for (s, error) in [
+            ("", "expected a number with an optional ms, s, m or h unit"),
+            ("s", "expected a number with an optional ms, s, m or h unit"),
+            ("-1s", "expected a number with an optional ms, s, m or h unit"),
+            ("1.5s", "unknown unit, expected ms, s, m or h"),
+            ("10d", "unknown unit, expected ms, s, m or h"),
+            ("10S", "unknown unit, expected ms, s, m or h"),
+            ("18446744073709551616", "expected a number with an optional ms, s, m or h unit"),
+            ("18446744073709551615h", "duration too long"),
+            ("307445734561825861m", "duration too long"),
+        ] {
+            assert_eq!(parse_duration(s), Err(error), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        for (s, expected) in [("512", 512), ("512B", 512), ("64KB", 64_000), ("64KiB", 65_536), ("1MB", 1_000_000), ("4MiB", 4_194_304), ("0", 0)] {
+            assert_eq!(parse_size(s), Ok(expected), "{s:?}");
+        }
+        assert_eq!(parse_size(&usize::MAX.to_string()), Ok(usize::MAX));
+    }
+
+    #[test]
+    fn bad_sizes() {
+        for (s, error) in [
+            ("", "expected a number with an optional B, KB, KiB, MB or MiB unit"),
+            ("KiB", "expected a number with an optional B, KB, KiB, MB or MiB unit"),
+            ("1GB", "unknown unit, expected B, KB, KiB, MB or MiB"),
+            ("64kb", "unknown unit, expected B, KB, KiB, MB or MiB"),
+            ("64 K iB", "unknown unit, expected B, KB, KiB, MB or MiB"),
+        ] {
+            assert_eq!(parse_size(s), Err(error), "{s:?}");
+        }
+        assert_eq!(parse_size(&format!("{}MiB", usize::MAX / 1024)), Err("size too large"));
+    }
+
+    #[test]
+    fn rates() {
+        for (s, expected) in [
+            ("1000", 1000),
+            ("1000B/s", 1000),
+            ("5KB/s", 5000),
+            ("5KiB/s", 5120),
+            ("2MB/s", 2_000_000),
+            ("2MiB/s", 2_097_152),
+            ("1GB/s", 1_000_000_000),
+            // Bits, eight to the byte and rounded down
+            ("12bps", 1),
+            ("7bps", 0),
+            ("8Kbps", 1000),
+            ("8kbps", 1000),
+            ("10Mbps", 1_250_000),
+            ("1Gbps", 125_000_000),
+        ] {
+            assert_eq!(parse_rate(s), Ok(expected), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn bad_rates() {
+        let units = "unknown unit, expected B/s, KB/s, KiB/s, MB/s, MiB/s, GB/s, bps, Kbps, Mbps or Gbps";
+        for (s, error) in [
+            ("", "expected a number with an optional unit like 10Mbps or 2MB/s"),
+            ("fast", "expected a number with an optional unit like 10Mbps or 2MB/s"),
+            ("Mbps", "expected a number with an optional unit like 10Mbps or 2MB/s"),
+            ("10MB", units),
+            ("10mbps", units),
+            ("10 Mb/s", units),
+            ("18446744073709551615GB/s", "rate too large"),
+            ("18446744073709551615Gbps", "rate too large"),
+        ] {
+            assert_eq!(parse_rate(s), Err(error), "{s:?}");
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::ffi::CString;
+use std::fmt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::io;
+use tokio::net::{UnixListener, UnixStream};
+use tracing::info;
+
+// A client of a listen = unix:<path> listener, shown with the credentials of its process
+#[derive(Debug, Clone, Copy)]
+pub struct UnixPeer {
+    // Leaked once per listener, they live as long as the process
+    pub path: &'static str,
+    pub pid: Option<i32>,
+    pub uid: Option<u32>,
+}
+
+impl UnixPeer {
+    pub fn of(stream: &UnixStream, path: &'static str) -> UnixPeer {
+        let cred = stream.peer_cred().ok();
+        UnixPeer { path, pid: cred.and_then(|cred| cred.pid()), uid: cred.map(|cred| cred.uid()) }
+    }
+}
+
+// unix:/run/rock5.sock(pid=812,uid=1000), without spaces for the access log
+impl fmt::Display for UnixPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unix:{}", self.path)?;
+        match (self.pid, self.uid) {
+            (Some(pid), Some(uid)) => write!(f, "(pid={},uid={})", pid, uid),
+            (None, Some(uid)) => write!(f, "(uid={})", uid),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Listen on path with the given mode and owner, replacing a socket left by a run that did not shut down cleanly.
+// Bound in a directory only we can enter and moved to path once it has them, nobody can connect before
+pub fn bind(path: &Path, mode: u32, owner: (Option<u32>, Option<u32>), backlog: i32) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "another process is listening on it"));
+            }
+            info!(" -> Removing stale socket {:?}", path);
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "exists and is not a socket")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("s");
+    let res = (|| {
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        socket.bind(&SockAddr::unix(&bound)?)?;
+        socket.listen(backlog)?;
+        socket.set_nonblocking(true)?;
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+        if owner != (None, None) {
+            std::os::unix::fs::chown(&bound, owner.0, owner.1)?;
+        }
+        std::fs::rename(&bound, path)?;
+        UnixListener::from_std(socket.into())
+    })();
+    let _ = std::fs::remove_file(&bound);
+    let _ = std::fs::remove_dir(&private);
+    res
+}
+
+// unix_socket_owner: "user", "user:group" or ":group", names or numeric ids
+pub fn parse_owner(owner: &str) -> Result<(Option<u32>, Option<u32>), String> {
+    let (user, group) = owner.split_once(':').unwrap_or((owner, ""));
+    let uid = match user {
+        "" => None,
+        user => Some(user.parse().or_else(|_| lookup(user, true))?),
+    };
+    let gid = match group {
+        "" => None,
+        group => Some(group.parse().or_else(|_| lookup(group, false))?),
+    };
+    Ok((uid, gid))
+}
+
+fn lookup(name: &str, user: bool) -> Result<u32, String> {
+    let unknown = || format!("no {} named {:?}", if user { "user" } else { "group" }, name);
+    let name = CString::new(name).map_err(|_| unknown())?;
+    // Only while loading the config, nothing else calls these
+    unsafe {
+        if user {
+            let passwd = libc::getpwnam(name.as_ptr());
+            passwd.as_ref().map(|passwd| passwd.pw_uid).ok_or_else(unknown)
+        } else {
+            let group = libc::getgrnam(name.as_ptr());
+            group.as_ref().map(|group| group.gr_gid).ok_or_else(unknown)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rock5-unix-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("test.sock")
+    }
+
+    #[tokio::test]
+    async fn binds_with_the_mode_and_nothing_left_behind() {
+        let path = temp_path("mode");
+        let _listener = bind(&path, 0o600, (None, None), 16).unwrap();
+        let meta = std::fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replaces_a_stale_socket() {
+        let path = temp_path("stale");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let _listener = bind(&path, 0o660, (None, None), 16).unwrap();
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_a_socket_in_use() {
+        let path = temp_path("live");
+        let _first = bind(&path, 0o600, (None, None), 16).unwrap();
+        assert_eq!(bind(&path, 0o600, (None, None), 16).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_other_files() {
+        let path = temp_path("file");
+        std::fs::write(&path, "data").unwrap();
+        assert_eq!(bind(&path, 0o600, (None, None), 16).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+    }
+}
@@ -0,0 +1,37 @@
+// Accepting with RLIMIT_NOFILE exhausted: the proxy backs off and serves again once descriptors free up
+mod common;
+
+use common::Proxy;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+// Descriptors rock5 may open, a few more than it needs to start
+const NOFILE: libc::rlim_t = 64;
+
+#[test]
+fn recovers_from_running_out_of_descriptors() {
+    let dir = common::temp_dir("accept");
+    let path = dir.join("config.ini");
+    std::fs::write(&path, "[config]\nlisten = 127.0.0.1:0\nlog_color = never\n").unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rock5"));
+    command.arg("--config").arg(&path).env_remove("ROCK5_CONFIG").env_remove("ROCK5_PROFILE");
+    unsafe {
+        command.pre_exec(|| {
+            let limit = libc::rlimit { rlim_cur: NOFILE, rlim_max: NOFILE };
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut proxy = Proxy::spawn(command, None, dir);
+    // Clients waiting in the handshake hold a descriptor each, the rest stays in the backlog
+    let clients: Vec<_> = (0..NOFILE * 2).map(|_| common::client(proxy.addr)).collect();
+    proxy.wait_for("Too many open files");
+    assert!(proxy.log().contains("trying again in"), "{}", proxy.log());
+    drop(clients);
+    // Those from the backlog are gone by now too, the next one gets its method reply
+    proxy.wait_for_count("Connection closed for", NOFILE as usize);
+    let mut stream = common::client(proxy.addr);
+    assert_eq!(common::greet(&mut stream, &[0x00]), [0x05, 0x00]);
+}
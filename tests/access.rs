@@ -0,0 +1,74 @@
+// access_log: one record per connection, addr:port fields that split on the last colon
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn records(proxy: &mut Proxy, path: &Path, count: usize) -> Vec<Vec<String>> {
+    proxy.wait_for_count("Connection closed for", count);
+    let deadline = Instant::now() + common::TIMEOUT;
+    loop {
+        // Written from a thread of its own, shortly after the close summary
+        let text = std::fs::read_to_string(path).unwrap_or_default();
+        if text.lines().count() >= count || Instant::now() >= deadline {
+            let _ = std::fs::remove_dir_all(path.parent().unwrap());
+            return text.lines().map(|line| line.split(' ').map(str::to_string).collect()).collect();
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// The proxy and where its access log goes
+fn start(extra: &str) -> (Proxy, PathBuf) {
+    let path = common::temp_dir("access").join("access.log");
+    (Proxy::start(&format!("access_log = {}\n{extra}", path.display())), path)
+}
+
+#[test]
+fn ipv6_destinations_are_bracketed() {
+    let echo = common::echo_server("::1");
+    let (mut proxy, path) = start("[hosts]\nsix.test = ::1\n");
+    for dest in [Dest::Addr(echo), Dest::Name("six.test", echo.port())] {
+        let mut stream = common::connect_through(proxy.addr, dest);
+        stream.write_all(b"ping").unwrap();
+        let mut pong = [0u8; 4];
+        stream.read_exact(&mut pong).unwrap();
+    }
+    let records = records(&mut proxy, &path, 2);
+    // time id client user destination resolved reply sent received duration
+    assert_eq!(records[0][4], format!("[::1]:{}", echo.port()), "{records:?}");
+    assert_eq!(records[1][4], format!("six.test:{}", echo.port()), "{records:?}");
+    for record in &records {
+        assert_eq!(record[5], format!("[::1]:{}", echo.port()), "{records:?}");
+        assert_eq!(&record[6..9], ["0", "4", "4"], "{records:?}");
+    }
+}
+
+#[test]
+fn names_with_a_colon_are_bracketed() {
+    let (mut proxy, path) = start("");
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0x01, Dest::Name("fe80::1%lo", 9));
+    assert_ne!(common::reply(&mut stream)[1], 0x00);
+    let records = records(&mut proxy, &path, 1);
+    assert_eq!(records[0][4], "[fe80::1%lo]:9", "{records:?}");
+}
+
+#[test]
+fn idle_relays_keep_their_bytes() {
+    let echo = common::echo_server("127.0.0.1");
+    let (mut proxy, path) = start("idle_timeout = 1s\n");
+    let mut stream = common::connect_through(proxy.addr, Dest::Addr(echo));
+    stream.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    stream.read_exact(&mut pong).unwrap();
+    // Nothing more until the proxy gives up on the connection
+    assert!(common::closed(&mut stream));
+    let records = records(&mut proxy, &path, 1);
+    assert_eq!(&records[0][6..9], ["0", "4", "4"], "{records:?}");
+    assert!(proxy.log().contains("close_reason=\"idle_timeout\""), "{}", proxy.log());
+}
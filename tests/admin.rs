@@ -0,0 +1,46 @@
+// admin_socket: who may reach it, and that a second proxy leaves a socket in use alone
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+
+#[test]
+fn lists_connections_for_the_owner_only() {
+    let echo = common::echo_server("127.0.0.1");
+    let dir = common::temp_dir("admin");
+    let path = dir.join("admin.sock");
+    let mut proxy = Proxy::start(&format!("admin_socket = {}", path.display()));
+    proxy.wait_for("Admin socket at");
+    let meta = std::fs::symlink_metadata(&path).unwrap();
+    assert!(meta.file_type().is_socket());
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+
+    let _relay = common::connect_through(proxy.addr, Dest::Addr(echo));
+    let mut admin = UnixStream::connect(&path).unwrap();
+    admin.write_all(b"list\n").unwrap();
+    let mut line = String::new();
+    BufReader::new(&admin).read_line(&mut line).unwrap();
+    assert!(line.contains(&echo.to_string()) && line.contains("relaying"), "{line:?}");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn refuses_an_admin_socket_in_use() {
+    let dir = common::temp_dir("admin");
+    let path = dir.join("admin.sock");
+    let config = format!("admin_socket = {}", path.display());
+    let mut first = Proxy::start(&config);
+    first.wait_for("Admin socket at");
+
+    let mut second = std::process::Command::new(env!("CARGO_BIN_EXE_rock5"));
+    let config_path = dir.join("second.ini");
+    std::fs::write(&config_path, format!("[config]\nlisten = 127.0.0.1:0\n{config}\n")).unwrap();
+    let output = second.arg("--config").arg(&config_path).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("another process is listening on it"), "{output:?}");
+    // The first one still has it
+    UnixStream::connect(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,91 @@
+// Method selection by auth mode, and what each selected method leads to
+mod common;
+
+use common::Proxy;
+use std::io::Write;
+
+const OFFERS: [&[u8]; 4] = [&[0x00], &[0x02], &[0x00, 0x02], &[0x03, 0x80]];
+const USERS: &str = "[users]\nalice = secret\n";
+
+// The METHOD the proxy answers each of OFFERS with
+fn selected(proxy: &Proxy) -> Vec<u8> {
+    OFFERS.iter().map(|offered| {
+        let mut stream = common::client(proxy.addr);
+        let reply = common::greet(&mut stream, offered);
+        assert_eq!(reply[0], 0x05);
+        if reply[1] == 0xFF {
+            assert!(common::closed(&mut stream), "open after 0xFF to {offered:?}");
+        }
+        reply[1]
+    }).collect()
+}
+
+#[test]
+fn no_users() {
+    assert_eq!(selected(&Proxy::start("")), [0x00, 0xFF, 0x00, 0xFF]);
+}
+
+#[test]
+fn required() {
+    assert_eq!(selected(&Proxy::start(USERS)), [0xFF, 0x02, 0x02, 0xFF]);
+    assert_eq!(selected(&Proxy::start(&format!("auth = required\n{USERS}"))), [0xFF, 0x02, 0x02, 0xFF]);
+}
+
+#[test]
+fn optional() {
+    let mut proxy = Proxy::start(&format!("auth = optional\n{USERS}"));
+    assert_eq!(selected(&proxy), [0x00, 0x02, 0x02, 0xFF]);
+    // Anonymous and authenticated sessions both get through
+    let echo = common::echo_server("127.0.0.1");
+    drop(common::connect_through(proxy.addr, common::Dest::Addr(echo)));
+    let mut stream = common::client(proxy.addr);
+    assert_eq!(common::greet(&mut stream, &[0x00, 0x02]), [0x05, 0x02]);
+    stream.write_all(b"\x01\x05alice\x06secret").unwrap();
+    let mut status = [0u8; 2];
+    assert_eq!(common::read_fully(&mut stream, &mut status), 2);
+    assert_eq!(status, [0x01, 0x00]);
+    proxy.wait_for("authenticated as 'alice'");
+}
+
+#[test]
+fn none_ignores_users() {
+    assert_eq!(selected(&Proxy::start(&format!("auth = none\n{USERS}"))), [0x00, 0xFF, 0x00, 0xFF]);
+}
+
+// The status of logging in as user with passwd
+fn login(proxy: &Proxy, user: &str, passwd: &str) -> u8 {
+    let mut stream = common::client(proxy.addr);
+    assert_eq!(common::greet(&mut stream, &[0x02]), [0x05, 0x02]);
+    let mut request = vec![0x01, user.len() as u8];
+    request.extend_from_slice(user.as_bytes());
+    request.push(passwd.len() as u8);
+    request.extend_from_slice(passwd.as_bytes());
+    stream.write_all(&request).unwrap();
+    let mut status = [0u8; 2];
+    assert_eq!(common::read_fully(&mut stream, &mut status), 2);
+    status[1]
+}
+
+#[test]
+fn hashed_passwords() {
+    // Both "secret"
+    let proxy = Proxy::start(concat!(
+        "[users]\n",
+        "alice = $argon2id$v=19$m=256,t=1,p=1$cm9jazUtdGVzdC1zYWx0$7lFBVoiG9dR12ESjks6c2YD+iwuU4Q60+JFF7oOOcjg\n",
+        "bob = $2b$04$8r71KPpoeSePJN8dhQuGb.iyONnaDkECCUkgk.282Run3D.Odp4sK\n",
+        "carol = secret\n",
+    ));
+    for user in ["alice", "bob", "carol"] {
+        assert_eq!(login(&proxy, user, "secret"), 0x00, "{user}");
+        assert_eq!(login(&proxy, user, "secrets"), 0x01, "{user}");
+    }
+    // The hash itself is no password
+    assert_eq!(login(&proxy, "bob", "$2b$04$8r71KPpoeSePJN8dhQuGb.iyONnaDkECCUkgk.282Run3D.Odp4sK"), 0x01);
+}
+
+#[test]
+fn interpolated_users() {
+    let proxy = Proxy::start_with("[users]\n${ROCK5_TEST_USER} = ${ROCK5_TEST_PASS}\n", &[], &[("ROCK5_TEST_USER", "carol"), ("ROCK5_TEST_PASS", "hunter2")]);
+    assert_eq!(login(&proxy, "carol", "hunter2"), 0x00);
+    assert_eq!(login(&proxy, "${ROCK5_TEST_USER}", "hunter2"), 0x01);
+}
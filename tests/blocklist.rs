@@ -0,0 +1,42 @@
+// blocked_domains and blocked_domains_file: refused targets get 0x02 and the rule is logged
+mod common;
+
+use common::{Dest, Proxy};
+use std::process::Command;
+
+// The REP of a CONNECT to name:80
+fn connect(proxy: &Proxy, name: &str) -> u8 {
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0x01, Dest::Name(name, 80));
+    common::reply(&mut stream)[1]
+}
+
+#[test]
+fn exact_names_and_wildcards() {
+    let mut proxy = Proxy::start("blocked_domains = blocked.invalid, *.ads.invalid\n");
+    assert_eq!(connect(&proxy, "blocked.invalid"), 0x02);
+    assert_eq!(connect(&proxy, "Tracker.Ads.Invalid"), 0x02);
+    proxy.wait_for("(rule *.ads.invalid)");
+    // Only what is below the wildcard, looked up as usual
+    assert_eq!(connect(&proxy, "ads.invalid"), 0x04);
+    assert_eq!(connect(&proxy, "sub.blocked.invalid"), 0x04);
+}
+
+#[test]
+fn relative_file_from_another_directory() {
+    let dir = common::temp_dir("blocklist");
+    std::fs::write(dir.join("config.ini"), "[config]\nlisten = 127.0.0.1:0\nlog_color = never\nblocked_domains_file = blocked.txt\n").unwrap();
+    std::fs::write(dir.join("blocked.txt"), "# ad networks\n*.ads.invalid\n").unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rock5"));
+    command.arg("--config").arg(dir.join("config.ini")).current_dir(common::temp_dir("cwd")).env_remove("ROCK5_CONFIG");
+    let mut proxy = Proxy::spawn(command, None, dir.clone());
+    assert_eq!(connect(&proxy, "x.ads.invalid"), 0x02);
+    assert_eq!(connect(&proxy, "tracker.invalid"), 0x04);
+    // Re-read from the same place on SIGHUP
+    std::fs::write(dir.join("blocked.txt"), "tracker.invalid\n").unwrap();
+    proxy.signal(libc::SIGHUP);
+    proxy.wait_for("Reloaded config");
+    assert_eq!(connect(&proxy, "tracker.invalid"), 0x02);
+    assert_eq!(connect(&proxy, "x.ads.invalid"), 0x04);
+}
@@ -0,0 +1,465 @@
+// Helpers for the tests that run the rock5 binary, each test file uses a part of them
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+// A running proxy, killed when dropped
+pub struct Proxy {
+    child: Child,
+    // Where the first listener is bound, every socket of it in sockets
+    pub addr: SocketAddr,
+    pub sockets: Vec<SocketAddr>,
+    // For the config and whatever else the test writes
+    pub dir: PathBuf,
+    log: Arc<Mutex<String>>,
+}
+
+impl Proxy {
+    // config goes into [config], later sections may follow. Without a listen line of its own the proxy
+    // listens on an unused port of 127.0.0.1
+    pub fn start(config: &str) -> Proxy {
+        Proxy::start_with(config, &[], &[])
+    }
+
+    pub fn start_with(config: &str, args: &[&str], env: &[(&str, &str)]) -> Proxy {
+        let dir = temp_dir("proxy");
+        let path = dir.join("config.ini");
+        let listen = if config.lines().any(|line| line.starts_with("listen =")) { "" } else { "listen = 127.0.0.1:0\n" };
+        std::fs::write(&path, format!("[config]\n{listen}log_color = never\n{config}\n")).unwrap();
+        let mut command = Command::new(env!("CARGO_BIN_EXE_rock5"));
+        command.arg("--config").arg(&path).args(args).env_remove("ROCK5_CONFIG").env_remove("ROCK5_PROFILE");
+        for (name, value) in env {
+            command.env(name, value);
+        }
+        Proxy::spawn(command, None, dir)
+    }
+
+    // Runs the command with stdin fed from input if given, until its first listener is bound
+    pub fn spawn(mut command: Command, input: Option<&str>, dir: PathBuf) -> Proxy {
+        command.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() }).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = command.spawn().expect("cannot run rock5");
+        if let Some(input) = input {
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(input.as_bytes()).unwrap();
+        }
+        let log = Arc::new(Mutex::new(String::new()));
+        collect(child.stdout.take().unwrap(), log.clone());
+        collect(child.stderr.take().unwrap(), log.clone());
+        let mut proxy = Proxy { child, addr: SocketAddr::from(([0, 0, 0, 0], 0)), sockets: Vec::new(), dir, log };
+        let line = proxy.wait_for_line("Listening on").unwrap_or_else(|| panic!("rock5 did not start:\n{}", proxy.log()));
+        proxy.sockets = listening(&line);
+        proxy.addr = proxy.sockets[0];
+        proxy
+    }
+
+    pub fn log(&self) -> String {
+        self.log.lock().unwrap().clone()
+    }
+
+    // The first log line containing needle, None if none shows up within TIMEOUT
+    pub fn wait_for_line(&mut self, needle: &str) -> Option<String> {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            if let Some(line) = self.log().lines().find(|line| line.contains(needle)) {
+                return Some(line.to_string());
+            }
+            if let Ok(Some(status)) = self.child.try_wait() {
+                panic!("rock5 exited with {status} waiting for {needle:?}:\n{}", self.log());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    pub fn wait_for(&mut self, needle: &str) -> String {
+        self.wait_for_line(needle).unwrap_or_else(|| panic!("no {needle:?} in the log:\n{}", self.log()))
+    }
+
+    // The log once needle is in it count times
+    pub fn wait_for_count(&mut self, needle: &str, count: usize) -> String {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let log = self.log();
+            if log.matches(needle).count() >= count {
+                return log;
+            }
+            if Instant::now() >= deadline {
+                panic!("{needle:?} not {count} times in the log:\n{log}");
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    pub fn signal(&self, signal: i32) {
+        unsafe { libc::kill(self.child.id() as libc::pid_t, signal) };
+    }
+
+    // SIGTERM, then the exit status once it is gone
+    pub fn stop(&mut self) -> ExitStatus {
+        self.signal(libc::SIGTERM);
+        self.wait()
+    }
+
+    pub fn wait(&mut self) -> ExitStatus {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                // The last lines may still be on their way through the pipes
+                thread::sleep(Duration::from_millis(50));
+                return status;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("rock5 did not exit:\n{}", self.log());
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn collect(pipe: impl Read + Send + 'static, log: Arc<Mutex<String>>) {
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).lines() {
+            let Ok(line) = line else { break };
+            let mut log = log.lock().unwrap();
+            log.push_str(&line);
+            log.push('\n');
+        }
+    });
+}
+
+// The sockets of a " -> Listening on "…" as name: a, b (log level …)" line
+fn listening(line: &str) -> Vec<SocketAddr> {
+    let sockets = line.split(" as ").nth(1).and_then(|rest| rest.split_once(": ")).map(|(_, sockets)| sockets).unwrap_or_default();
+    let sockets = sockets.split(" (log level").next().unwrap_or_default();
+    sockets.split(", ").filter_map(|socket| socket.split(' ').next()?.parse().ok()).collect()
+}
+
+// A fresh directory under the system temp directory
+pub fn temp_dir(what: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("rock5-test-{what}-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn client(proxy: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(proxy).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream
+}
+
+// Method selection offering methods, the two bytes of the reply
+pub fn greet(stream: &mut TcpStream, methods: &[u8]) -> [u8; 2] {
+    let mut hello = vec![0x05, methods.len() as u8];
+    hello.extend_from_slice(methods);
+    stream.write_all(&hello).unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply
+}
+
+// DST.ADDR and DST.PORT as ATYP and the address
+pub enum Dest<'a> {
+    Addr(SocketAddr),
+    Name(&'a str, u16),
+}
+
+pub fn request(stream: &mut TcpStream, cmd: u8, dest: Dest) {
+    let mut request = vec![0x05, cmd, 0x00];
+    let port = match dest {
+        Dest::Addr(addr) => {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    request.push(0x01);
+                    request.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    request.push(0x04);
+                    request.extend_from_slice(&ip.octets());
+                }
+            }
+            addr.port()
+        }
+        Dest::Name(name, port) => {
+            request.push(0x03);
+            request.push(name.len() as u8);
+            request.extend_from_slice(name.as_bytes());
+            port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).unwrap();
+}
+
+// A whole reply, VER to BND.PORT, as far as the proxy sent it
+pub fn reply(stream: &mut TcpStream) -> Vec<u8> {
+    let mut reply = vec![0u8; 4];
+    if read_fully(stream, &mut reply) < 4 {
+        return reply;
+    }
+    let rest = match reply[3] {
+        0x01 => 6,
+        0x04 => 18,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).unwrap();
+            reply.push(len[0]);
+            len[0] as usize + 2
+        }
+        _ => 0,
+    };
+    let mut tail = vec![0u8; rest];
+    stream.read_exact(&mut tail).unwrap();
+    reply.extend(tail);
+    reply
+}
+
+// Reads until buf is full or the stream ends, the number of bytes read
+pub fn read_fully(stream: &mut TcpStream, buf: &mut [u8]) -> usize {
+    let mut read = 0;
+    while read < buf.len() {
+        match stream.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) => panic!("reading from the proxy: {e}"),
+        }
+    }
+    read
+}
+
+// Whether the proxy closed the connection (and sent nothing more)
+pub fn closed(stream: &mut TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    matches!(stream.read(&mut byte), Ok(0) | Err(_))
+}
+
+// A CONNECT through the proxy without authentication, ready to relay
+pub fn connect_through(proxy: SocketAddr, dest: Dest) -> TcpStream {
+    let mut stream = client(proxy);
+    assert_eq!(greet(&mut stream, &[0x00]), [0x05, 0x00]);
+    request(&mut stream, 0x01, dest);
+    let reply = reply(&mut stream);
+    assert_eq!(&reply[..2], &[0x05, 0x00], "CONNECT failed: {reply:?}");
+    stream
+}
+
+// Echoes what each connection sends, closing its side once the other one did
+pub fn echo_server(ip: &str) -> SocketAddr {
+    echo_server_at(SocketAddr::new(ip.parse().unwrap(), 0))
+}
+
+// One on a given address
+pub fn echo_server_at(addr: SocketAddr) -> SocketAddr {
+    let listener = TcpListener::bind(addr).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            thread::spawn(move || {
+                let mut buf = [0u8; 16384];
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => if stream.write_all(&buf[..n]).is_err() { break },
+                    }
+                }
+                let _ = stream.shutdown(Shutdown::Write);
+            });
+        }
+    });
+    addr
+}
+
+// A free port on 127.0.0.1 nothing listens on
+pub fn closed_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+// Reads each connection to its end, then answers with the number of bytes it got as a u64
+pub fn sink_server(ip: &str) -> SocketAddr {
+    let listener = TcpListener::bind((ip, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            thread::spawn(move || {
+                let mut buf = [0u8; 16384];
+                let mut total = 0u64;
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    total += n as u64;
+                }
+                let _ = stream.write_all(&total.to_be_bytes());
+            });
+        }
+    });
+    addr
+}
+
+// Sends data through stream and closes its side, what the sink_server at the other end counted
+pub fn send_to_sink(stream: &mut TcpStream, data: &[u8]) -> u64 {
+    stream.write_all(data).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut count = [0u8; 8];
+    stream.read_exact(&mut count).unwrap();
+    u64::from_be_bytes(count)
+}
+
+// What the dns_server answers for a name
+pub enum Record {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(&'static str),
+    // SERVFAIL, for every type of the name
+    ServFail,
+}
+
+// A UDP nameserver answering from records, NXDOMAIN for everything else. Counts the questions it got
+pub fn dns_server(records: Vec<(&'static str, Record)>) -> (SocketAddr, Arc<AtomicUsize>) {
+    slow_dns_server(records, Duration::ZERO)
+}
+
+// One taking delay for every answer
+pub fn slow_dns_server(records: Vec<(&'static str, Record)>, delay: Duration) -> (SocketAddr, Arc<AtomicUsize>) {
+    serve_dns(Arc::new(Mutex::new(records)), delay)
+}
+
+// Records of a changing_dns_server
+pub type Records = Arc<Mutex<Vec<(&'static str, Record)>>>;
+
+// One answering from records as they are when asked, the test may change them
+pub fn changing_dns_server(records: Vec<(&'static str, Record)>) -> (SocketAddr, Records) {
+    let records = Arc::new(Mutex::new(records));
+    let (addr, _) = serve_dns(records.clone(), Duration::ZERO);
+    (addr, records)
+}
+
+fn serve_dns(records: Records, delay: Duration) -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let questions = Arc::new(AtomicUsize::new(0));
+    let counted = questions.clone();
+    thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        while let Ok((n, from)) = socket.recv_from(&mut buf) {
+            counted.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(delay);
+            let response = dns_response(&buf[..n], &records.lock().unwrap());
+            if let Some(response) = response {
+                let _ = socket.send_to(&response, from);
+            }
+        }
+    });
+    (addr, questions)
+}
+
+// The answer to a query from records, None for one that can't be parsed
+pub fn dns_response(query: &[u8], records: &[(&str, Record)]) -> Option<Vec<u8>> {
+    // QNAME from the labels after the 12 byte header, then QTYPE and QCLASS
+    let mut labels = Vec::new();
+    let mut at = 12;
+    loop {
+        let len = *query.get(at)? as usize;
+        at += 1;
+        if len == 0 {
+            break;
+        }
+        labels.push(String::from_utf8_lossy(query.get(at..at + len)?).to_ascii_lowercase());
+        at += len;
+    }
+    let name = labels.join(".");
+    let qtype = u16::from_be_bytes([*query.get(at)?, *query.get(at + 1)?]);
+    let question = &query[12..at + 4];
+    let known = records.iter().any(|(record, _)| *record == name);
+    let failed = records.iter().any(|(record, answer)| *record == name && matches!(answer, Record::ServFail));
+    let answers: Vec<(u16, Vec<u8>)> = records.iter().filter(|(record, _)| *record == name).filter_map(|(_, record)| match record {
+        Record::A(ip) if qtype == 1 => Some((1, ip.octets().to_vec())),
+        Record::Aaaa(ip) if qtype == 28 => Some((28, ip.octets().to_vec())),
+        Record::Ptr(target) if qtype == 12 => {
+            let mut rdata = Vec::new();
+            for label in target.split('.') {
+                rdata.push(label.len() as u8);
+                rdata.extend_from_slice(label.as_bytes());
+            }
+            rdata.push(0);
+            Some((12, rdata))
+        }
+        _ => None,
+    }).collect();
+    // ID, QR RD RA and NXDOMAIN for unknown names, one question
+    let rcode = if failed { 2 } else if known { 0 } else { 3 };
+    let mut response = query[..2].to_vec();
+    response.extend_from_slice(&[0x81, 0x80 | rcode, 0, 1]);
+    response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(question);
+    for (rtype, rdata) in answers {
+        // The name as a pointer to the question, class IN, a TTL of 60s
+        response.extend_from_slice(&[0xc0, 12]);
+        response.extend_from_slice(&rtype.to_be_bytes());
+        response.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+        response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        response.extend(rdata);
+    }
+    Some(response)
+}
+
+// The status line of GET path from an HTTP endpoint of the proxy
+pub fn http_get(addr: SocketAddr, path: &str) -> String {
+    http_response(addr, path).lines().next().unwrap_or_default().to_string()
+}
+
+// All of it, headers and body
+pub fn http_response(addr: SocketAddr, path: &str) -> String {
+    let mut stream = client(addr);
+    stream.write_all(format!("GET {path} HTTP/1.0\r\n\r\n").as_bytes()).unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    response
+}
+
+// An address connects hang on until they time out, kept that way while it lives
+pub struct Blackhole {
+    pub addr: SocketAddr,
+    _queued: Option<(socket2::Socket, TcpStream)>,
+}
+
+// 10.255.255.1 where the network drops what is sent to it, where something answers for every address (a sandbox)
+// a listener on 127.0.0.1 whose backlog is full, so that SYNs to it go unanswered
+pub fn blackhole() -> Blackhole {
+    let addr = SocketAddr::from(([10, 255, 255, 1], 9));
+    if let Err(e) = TcpStream::connect_timeout(&addr, Duration::from_millis(200)) && e.kind() == std::io::ErrorKind::TimedOut {
+        return Blackhole { addr, _queued: None };
+    }
+    blackhole_on(Ipv4Addr::LOCALHOST)
+}
+
+// The listener with a full backlog on a local ip, always
+pub fn blackhole_on(ip: Ipv4Addr) -> Blackhole {
+    let listener = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    listener.bind(&SocketAddr::from((ip, 0)).into()).unwrap();
+    listener.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap().as_socket().unwrap();
+    // Never accepted, the one connection a backlog of 0 has room for
+    let queued = TcpStream::connect(addr).unwrap();
+    Blackhole { addr, _queued: Some((listener, queued)) }
+}
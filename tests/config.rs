@@ -0,0 +1,161 @@
+// Loading the config: layering files, environment and options, stdin, and which file and line an error names
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+
+// rock5 --print-config with main.ini of dir and args, whether it loaded and what it printed
+fn print_config(dir: &Path, args: &[&str], env: &[(&str, &str)]) -> (bool, String) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rock5"));
+    command.arg("--config").arg(dir.join("main.ini")).arg("--print-config").args(args).env_remove("ROCK5_CONFIG").env_remove("ROCK5_PROFILE");
+    for (name, value) in env {
+        command.env(name, value);
+    }
+    let output = command.output().unwrap();
+    let _ = std::fs::remove_dir_all(dir);
+    (output.status.success(), String::from_utf8_lossy(&output.stdout).into_owned() + &String::from_utf8_lossy(&output.stderr))
+}
+
+// The value of key in the printed config, and the comment saying where it came from
+fn setting(output: &str, key: &str) -> (String, String) {
+    let lines: Vec<&str> = output.lines().collect();
+    let at = lines.iter().position(|line| line.starts_with(&format!("{key} = "))).unwrap_or_else(|| panic!("no {key} in {output}"));
+    let origin = lines[at - 1].strip_prefix("# from ").unwrap_or_default();
+    (lines[at][key.len() + 3..].to_string(), origin.to_string())
+}
+
+// A directory with each of files written to it
+fn files(files: &[(&str, &str)]) -> std::path::PathBuf {
+    let dir = common::temp_dir("config");
+    for (name, text) in files {
+        std::fs::write(dir.join(name), text).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn missing_include_names_its_line() {
+    let dir = files(&[("main.ini", "[config]\nlisten = 127.0.0.1:0\n\ninclude = users.ini, nope.ini\n"), ("users.ini", "[users]\nalice = secret\n")]);
+    let (loaded, output) = print_config(&dir, &[], &[]);
+    assert!(!loaded, "{output}");
+    assert!(output.contains("nope.ini\" does not exist (include at "), "{output}");
+    assert!(output.contains("main.ini\" line 4)"), "{output}");
+}
+
+#[test]
+fn errors_in_included_sections_name_that_file() {
+    for (section, name, value) in [
+        ("user_limits", "bob", "fast"),
+        ("hosts", "a.test", "192.0.2.1, x"),
+        ("users", "bob", "${ROCK5_TEST_UNSET}"),
+        ("users", "bob", &"x".repeat(256)),
+    ] {
+        let dir = files(&[
+            ("main.ini", "[config]\ninclude = more.ini\n"),
+            ("more.ini", &format!("; from the main config\n[{section}]\n{name} = {value}\n")),
+        ]);
+        let (loaded, output) = print_config(&dir, &[], &[]);
+        assert!(!loaded, "{output}");
+        assert!(output.contains(&format!("{name} in [{section}] of ")), "{output}");
+        assert!(output.contains("more.ini\" line 3 ("), "{output}");
+    }
+}
+
+#[test]
+fn toml_entries_have_lines_too() {
+    let dir = files(&[
+        ("main.ini", "[config]\ninclude = more.toml\n"),
+        ("more.toml", "[listener.a]\nlisten = \"127.0.0.1:0\"\nidle_timeout = \"soon\"\n"),
+    ]);
+    let (loaded, output) = print_config(&dir, &[], &[]);
+    assert!(!loaded, "{output}");
+    assert!(output.contains("'soon' for idle_timeout in [listener.a] of "), "{output}");
+    assert!(output.contains("more.toml\" line 3 ("), "{output}");
+}
+
+#[test]
+fn environment_overrides_the_file() {
+    let dir = files(&[("main.ini", "[config]\nport = 1081\nlog_level = warn\nloop_protection = true\n")]);
+    let env = [("ROCK5_PORT", "2000"), ("ROCK5_LOG_LEVEL", "debug"), ("ROCK5_LOOP_PROTECTION", "false"), ("ROCK5_MAX_CONNECTIONS", "7")];
+    let (loaded, output) = print_config(&dir, &[], &env);
+    assert!(loaded, "{output}");
+    assert_eq!(setting(&output, "port"), ("2000".into(), "ROCK5_PORT".into()));
+    assert_eq!(setting(&output, "log_level"), ("debug".into(), "ROCK5_LOG_LEVEL".into()));
+    assert_eq!(setting(&output, "loop_protection"), ("false".into(), "ROCK5_LOOP_PROTECTION".into()));
+    assert_eq!(setting(&output, "max_connections"), ("7".into(), "ROCK5_MAX_CONNECTIONS".into()));
+    // Untouched keys keep the file's value or the default
+    assert_eq!(setting(&output, "host").0, "0.0.0.0");
+}
+
+#[test]
+fn options_override_the_environment() {
+    let dir = files(&[("main.ini", "[config]\nport = 1081\n")]);
+    let (loaded, output) = print_config(&dir, &["--port", "3000", "--log-level", "error"], &[("ROCK5_PORT", "2000"), ("ROCK5_LOG_LEVEL", "debug")]);
+    assert!(loaded, "{output}");
+    assert_eq!(setting(&output, "port"), ("3000".into(), "--port".into()));
+    assert_eq!(setting(&output, "log_level"), ("error".into(), "--log-level".into()));
+}
+
+#[test]
+fn bad_environment_values_name_the_variable() {
+    for (name, value) in [("ROCK5_PORT", "abc"), ("ROCK5_PORT", "0"), ("ROCK5_PORT", "65536"), ("ROCK5_LOG_LEVEL", "loud"), ("ROCK5_LOOP_PROTECTION", "maybe")] {
+        let dir = files(&[("main.ini", "[config]\n")]);
+        let (loaded, output) = print_config(&dir, &[], &[(name, value)]);
+        assert!(!loaded, "{name}={value}: {output}");
+        assert!(output.contains(&format!("invalid value '{value}' for {name}")), "{output}");
+        assert!(!output.contains("panicked"), "{output}");
+    }
+}
+
+#[test]
+fn missing_config_exits_with_2() {
+    let dir = common::temp_dir("config");
+    let status = Command::new(env!("CARGO_BIN_EXE_rock5")).arg("--config").arg(dir.join("nope.ini")).env_remove("ROCK5_CONFIG").output().unwrap().status;
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn config_from_stdin() {
+    let port = common::closed_port().port();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rock5"));
+    command.args(["--config", "-"]).env_remove("ROCK5_CONFIG").env_remove("ROCK5_PROFILE");
+    let input = format!("[config]\nlisten = 127.0.0.1:{port}\nlog_color = never\n[users]\nalice = secret\n");
+    let mut proxy = common::Proxy::spawn(command, Some(&input), common::temp_dir("stdin"));
+    assert_eq!(proxy.addr, std::net::SocketAddr::from(([127, 0, 0, 1], port)));
+    // The [users] came along
+    let mut stream = common::client(proxy.addr);
+    assert_eq!(common::greet(&mut stream, &[0x00, 0x02]), [0x05, 0x02]);
+    // There is nothing to read again
+    proxy.signal(libc::SIGHUP);
+    proxy.wait_for("The config was read from stdin and cannot be reloaded");
+}
+
+#[test]
+fn toml_from_stdin() {
+    let port = common::closed_port().port();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rock5"));
+    command.args(["--config", "-", "--config-format", "toml"]).env_remove("ROCK5_CONFIG").env_remove("ROCK5_PROFILE");
+    let input = format!("[config]\nlisten = \"127.0.0.1:{port}\"\nlog_color = \"never\"\n");
+    let proxy = common::Proxy::spawn(command, Some(&input), common::temp_dir("stdin"));
+    assert_eq!(proxy.addr, std::net::SocketAddr::from(([127, 0, 0, 1], port)));
+}
+
+#[test]
+fn interpolated_in_every_section_and_redacted() {
+    let dir = files(&[("pass.txt", "hunter2\n")]);
+    std::fs::write(dir.join("main.ini"), format!(
+        "[config]\nidle_timeout = ${{ROCK5_TEST_IDLE}}\n[users]\n${{ROCK5_TEST_USER}} = file:{}\nalice = plain\n[user_limits]\n${{ROCK5_TEST_USER}} = 5KB/s\nalice = ${{ROCK5_TEST_RATE}}\n[hosts]\nbox.test = ${{ROCK5_TEST_IP}}\nopen.test = 192.0.2.45\n",
+        dir.join("pass.txt").display(),
+    )).unwrap();
+    let env = [("ROCK5_TEST_IDLE", "7s"), ("ROCK5_TEST_USER", "carol"), ("ROCK5_TEST_RATE", "9KB/s"), ("ROCK5_TEST_IP", "192.0.2.44")];
+    let (loaded, output) = print_config(&dir, &[], &env);
+    assert!(loaded, "{output}");
+    assert_eq!(setting(&output, "idle_timeout").0, "<redacted>");
+    for line in ["alice = <redacted>\n<redacted> = <redacted>\n", "alice = <redacted>\n", "<redacted> = 5000\n", "box.test = <redacted>\n", "open.test = 192.0.2.45\n"] {
+        assert!(output.contains(line), "no {line:?} in {output}");
+    }
+    for secret in ["carol", "hunter2", "9000", "192.0.2.44"] {
+        assert!(!output.contains(secret), "{secret} in {output}");
+    }
+}
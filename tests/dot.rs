@@ -0,0 +1,126 @@
+// dns = dot against a local DNS-over-TLS server: one connection for many lookups, the certificate name and
+// the plain resolver behind it with dot_strict = false
+#![cfg(feature = "dot")]
+mod common;
+
+use common::{Dest, Proxy, Record};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+// A test CA and a certificate of it for dot.test and 127.0.0.1 with its key, valid until 2126
+const CERTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+
+// TLS connections and the queries that came over them
+#[derive(Default)]
+struct Seen {
+    connections: AtomicUsize,
+    queries: AtomicUsize,
+}
+
+// A DoT server answering from records, with a certificate for dot.test and 127.0.0.1 of the test CA
+fn dot_server(records: Vec<(&'static str, Record)>) -> (SocketAddr, Arc<Seen>) {
+    let certs = CertificateDer::pem_file_iter(format!("{CERTS}/server.pem")).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+    let key = PrivateKeyDer::from_pem_file(format!("{CERTS}/server.key")).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider).with_safe_default_protocol_versions().unwrap()
+        .with_no_client_auth().with_single_cert(certs, key).unwrap();
+    let config = Arc::new(config);
+    let records = Arc::new(records);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seen = Arc::new(Seen::default());
+    let counted = seen.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let (config, records, seen) = (config.clone(), records.clone(), counted.clone());
+            thread::spawn(move || {
+                let mut tls = rustls::StreamOwned::new(rustls::ServerConnection::new(config).unwrap(), stream);
+                // Two bytes of length before each message, as over TCP
+                let mut len = [0u8; 2];
+                let mut counted = false;
+                while tls.read_exact(&mut len).is_ok() {
+                    if !counted {
+                        seen.connections.fetch_add(1, Ordering::Relaxed);
+                        counted = true;
+                    }
+                    let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+                    if tls.read_exact(&mut query).is_err() {
+                        break;
+                    }
+                    seen.queries.fetch_add(1, Ordering::Relaxed);
+                    let Some(response) = common::dns_response(&query, &records) else { break };
+                    let mut message = (response.len() as u16).to_be_bytes().to_vec();
+                    message.extend(response);
+                    if tls.write_all(&message).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (addr, seen)
+}
+
+// The proxy trusting the test CA only
+fn start(config: &str) -> Proxy {
+    Proxy::start_with(&format!("dns = dot\ndns_cache = false\ntor_resolve = true\n{config}"), &[], &[("SSL_CERT_FILE", &format!("{CERTS}/ca.pem"))])
+}
+
+// RESOLVE of name, the IPv4 address of the reply or None for a failure
+fn resolve(proxy: &Proxy, name: &str) -> Option<Ipv4Addr> {
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0xF0, Dest::Name(name, 0));
+    let reply = common::reply(&mut stream);
+    (reply[1] == 0x00 && reply[3] == 0x01).then(|| Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]))
+}
+
+#[test]
+fn lookups_share_one_connection() {
+    let (server, seen) = dot_server(vec![("a.test", Record::A(Ipv4Addr::new(192, 0, 2, 1))), ("b.test", Record::A(Ipv4Addr::new(192, 0, 2, 2)))]);
+    let proxy = start(&format!("dot_server = {server}\ndot_hostname = dot.test\n"));
+    assert_eq!(resolve(&proxy, "a.test"), Some(Ipv4Addr::new(192, 0, 2, 1)));
+    assert_eq!(resolve(&proxy, "b.test"), Some(Ipv4Addr::new(192, 0, 2, 2)));
+    assert_eq!(resolve(&proxy, "a.test"), Some(Ipv4Addr::new(192, 0, 2, 1)));
+    assert_eq!(resolve(&proxy, "nx.test"), None);
+    assert_eq!(seen.connections.load(Ordering::Relaxed), 1);
+    // A and AAAA for each
+    assert!(seen.queries.load(Ordering::Relaxed) >= 4, "{} queries", seen.queries.load(Ordering::Relaxed));
+}
+
+#[test]
+fn address_is_checked_without_a_hostname() {
+    let (server, _) = dot_server(vec![("a.test", Record::A(Ipv4Addr::new(192, 0, 2, 1)))]);
+    let proxy = start(&format!("dot_server = {server}\n"));
+    assert_eq!(resolve(&proxy, "a.test"), Some(Ipv4Addr::new(192, 0, 2, 1)), "{}", proxy.log());
+}
+
+#[test]
+fn wrong_hostname_fails() {
+    let (server, seen) = dot_server(vec![("a.test", Record::A(Ipv4Addr::new(192, 0, 2, 1)))]);
+    let proxy = start(&format!("dot_server = {server}\ndot_hostname = other.test\n"));
+    assert_eq!(resolve(&proxy, "a.test"), None);
+    assert_eq!(seen.queries.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn not_strict_falls_back_to_dns_servers() {
+    let (plain, questions) = common::dns_server(vec![("a.test", Record::A(Ipv4Addr::new(192, 0, 2, 9)))]);
+    let (server, seen) = dot_server(vec![("a.test", Record::A(Ipv4Addr::new(192, 0, 2, 1)))]);
+    // Certificates that don't match, and no server at all
+    for dot_server in [format!("{server}\ndot_hostname = other.test"), common::closed_port().to_string()] {
+        let strict = start(&format!("dot_server = {dot_server}\ndns_servers = {plain}\n"));
+        assert_eq!(resolve(&strict, "a.test"), None);
+        assert_eq!(questions.load(Ordering::Relaxed), 0);
+        let lenient = start(&format!("dot_server = {dot_server}\ndns_servers = {plain}\ndot_strict = false\n"));
+        assert_eq!(resolve(&lenient, "a.test"), Some(Ipv4Addr::new(192, 0, 2, 9)));
+        assert!(questions.swap(0, Ordering::Relaxed) > 0);
+    }
+    assert_eq!(seen.queries.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,65 @@
+// Happy Eyeballs: a blackholed address holds up the connect by happy_eyeballs_delay, not connect_timeout
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// A blackholed address and an echo server on another address with the same port
+fn targets() -> (common::Blackhole, SocketAddr) {
+    let blackhole = common::blackhole_on(Ipv4Addr::new(127, 0, 0, 2));
+    let echo = common::echo_server_at(SocketAddr::from(([127, 0, 0, 1], blackhole.addr.port())));
+    (blackhole, echo)
+}
+
+// How long a CONNECT to name takes, and the address the close summary says it was resolved to
+fn connect(proxy: &mut Proxy, name: &str, port: u16) -> (Duration, String) {
+    let started = Instant::now();
+    let mut stream = common::connect_through(proxy.addr, Dest::Name(name, port));
+    let elapsed = started.elapsed();
+    stream.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    stream.read_exact(&mut pong).unwrap();
+    drop(stream);
+    let summary = proxy.wait_for("Connection closed for");
+    (elapsed, summary.split("resolved=").nth(1).unwrap().split(' ').next().unwrap().to_string())
+}
+
+#[test]
+fn blackholed_first() {
+    let (blackhole, echo) = targets();
+    let mut proxy = Proxy::start(&format!("happy_eyeballs_delay = 300ms\nconnect_timeout = 30s\n[hosts]\nboth.test = {}, {}\n", blackhole.addr.ip(), echo.ip()));
+    let (elapsed, resolved) = connect(&mut proxy, "both.test", echo.port());
+    assert_eq!(resolved, echo.to_string());
+    assert!(elapsed >= Duration::from_millis(250) && elapsed < Duration::from_secs(3), "{elapsed:?}");
+}
+
+#[test]
+fn listening_first() {
+    let (blackhole, echo) = targets();
+    let mut proxy = Proxy::start(&format!("happy_eyeballs_delay = 300ms\n[hosts]\nboth.test = {}, {}\n", echo.ip(), blackhole.addr.ip()));
+    let (elapsed, resolved) = connect(&mut proxy, "both.test", echo.port());
+    assert_eq!(resolved, echo.to_string());
+    assert!(elapsed < Duration::from_millis(250), "{elapsed:?}");
+}
+
+#[test]
+fn one_by_one_waits_for_connect_timeout() {
+    let (blackhole, echo) = targets();
+    let mut proxy = Proxy::start(&format!("happy_eyeballs_delay = 0\nconnect_timeout = 1s\n[hosts]\nboth.test = {}, {}\n", blackhole.addr.ip(), echo.ip()));
+    let (elapsed, resolved) = connect(&mut proxy, "both.test", echo.port());
+    assert_eq!(resolved, echo.to_string());
+    assert!(elapsed >= Duration::from_millis(900), "{elapsed:?}");
+}
+
+#[test]
+fn refused_is_reported_over_timed_out() {
+    let blackhole = common::blackhole_on(Ipv4Addr::new(127, 0, 0, 2));
+    // Nothing listens on 127.0.0.1 with that port
+    let proxy = Proxy::start(&format!("happy_eyeballs_delay = 100ms\nconnect_timeout = 1s\n[hosts]\nboth.test = {}, 127.0.0.1\n", blackhole.addr.ip()));
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0x01, Dest::Name("both.test", blackhole.addr.port()));
+    assert_eq!(common::reply(&mut stream)[1], 0x05);
+}
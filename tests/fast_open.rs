@@ -0,0 +1,99 @@
+// tfo_connect: once the kernel has a cookie, targets are connected without waiting and get the first bytes with the SYN
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::AsRawFd;
+use std::thread;
+
+// Both client and server side Fast Open in net.ipv4.tcp_fastopen, loopback uses either end
+fn enabled() -> bool {
+    let enabled = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen").ok().and_then(|enabled| enabled.trim().parse::<u32>().ok());
+    if enabled.is_none_or(|enabled| enabled & 3 != 3) {
+        eprintln!("net.ipv4.tcp_fastopen does not include 3, skipping");
+        return false;
+    }
+    true
+}
+
+// A Fast Open listener that sends banner to each connection, then echoes what it gets
+fn server(banner: &'static [u8]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let queue: i32 = 16;
+    let set = unsafe { libc::setsockopt(listener.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN, &queue as *const i32 as *const libc::c_void, size_of::<i32>() as libc::socklen_t) };
+    assert_eq!(set, 0);
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            thread::spawn(move || {
+                let _ = stream.write_all(banner);
+                let mut buf = [0u8; 1024];
+                while let Ok(n) = stream.read(&mut buf) && n > 0 {
+                    if stream.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+fn ping(proxy: SocketAddr, target: SocketAddr) {
+    let mut stream = common::connect_through(proxy, Dest::Addr(target));
+    stream.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    stream.read_exact(&mut pong).unwrap();
+    assert_eq!(&pong, b"ping");
+}
+
+#[test]
+fn second_connect_does_not_wait() {
+    if !enabled() {
+        return;
+    }
+    let target = server(b"");
+    let metrics = common::closed_port();
+    let mut proxy = Proxy::start(&format!("tfo_connect = true\nmetrics_listen = {metrics}\n"));
+    proxy.wait_for("Serving metrics");
+    // The first one gets the cookie, unless the kernel still has one of 127.0.0.1
+    ping(proxy.addr, target);
+    ping(proxy.addr, target);
+    proxy.wait_for_count("Connection closed for", 2);
+    let body = common::http_response(metrics, "/metrics");
+    let fast = body.contains("rock5_connect_seconds_count{code=\"0\"} 1") && body.contains("rock5_connect_seconds_count{code=\"0\",fast_open=\"true\"} 1");
+    assert!(fast || body.contains("rock5_connect_seconds_count{code=\"0\",fast_open=\"true\"} 2"), "{body}");
+}
+
+#[test]
+fn target_speaking_first_gets_its_syn() {
+    if !enabled() {
+        return;
+    }
+    let target = server(b"hello\n");
+    let proxy = Proxy::start("tfo_connect = true\n");
+    for _ in 0..2 {
+        let mut stream = common::connect_through(proxy.addr, Dest::Addr(target));
+        let mut banner = [0u8; 6];
+        stream.read_exact(&mut banner).unwrap();
+        assert_eq!(&banner, b"hello\n");
+    }
+}
+
+#[test]
+fn off_by_default() {
+    if !enabled() {
+        return;
+    }
+    let target = server(b"");
+    let metrics = common::closed_port();
+    let mut proxy = Proxy::start(&format!("metrics_listen = {metrics}\n"));
+    proxy.wait_for("Serving metrics");
+    ping(proxy.addr, target);
+    ping(proxy.addr, target);
+    proxy.wait_for_count("Connection closed for", 2);
+    let body = common::http_response(metrics, "/metrics");
+    assert!(body.contains("rock5_connect_seconds_count{code=\"0\"} 2"), "{body}");
+}
@@ -0,0 +1,55 @@
+// Shutdown: /readyz turning, drain_delay and the exit code
+mod common;
+
+use common::{Dest, Proxy};
+use std::time::{Duration, Instant};
+
+#[test]
+fn readyz_fails_for_drain_delay_before_the_listeners_close() {
+    let health = common::closed_port();
+    let echo = common::echo_server("127.0.0.1");
+    let mut proxy = Proxy::start(&format!("health_listen = {health}\ndrain_delay = 1s"));
+    proxy.wait_for("Serving health checks");
+    assert_eq!(common::http_get(health, "/readyz"), "HTTP/1.1 200 OK");
+
+    let stopped = Instant::now();
+    proxy.signal(libc::SIGTERM);
+    proxy.wait_for("before draining");
+    assert_eq!(common::http_get(health, "/readyz"), "HTTP/1.1 503 Service Unavailable");
+    assert_eq!(common::http_get(health, "/healthz"), "HTTP/1.1 200 OK");
+    // Still served until the delay is over
+    drop(common::connect_through(proxy.addr, Dest::Addr(echo)));
+
+    assert!(proxy.wait().success());
+    assert!(stopped.elapsed() >= Duration::from_secs(1), "exited after {:?}", stopped.elapsed());
+}
+
+#[test]
+fn without_drain_delay_the_listeners_close_at_once() {
+    let mut proxy = Proxy::start("");
+    let stopped = Instant::now();
+    assert!(proxy.stop().success());
+    assert!(stopped.elapsed() < Duration::from_secs(1), "exited after {:?}", stopped.elapsed());
+    assert!(!proxy.log().contains("before draining"));
+}
+
+#[test]
+fn exits_cleanly_once_the_connections_finished() {
+    let echo = common::echo_server("127.0.0.1");
+    let mut proxy = Proxy::start("shutdown_timeout = 5s");
+    let relay = common::connect_through(proxy.addr, Dest::Addr(echo));
+    proxy.signal(libc::SIGTERM);
+    proxy.wait_for("waiting for 1 open connections");
+    drop(relay);
+    assert_eq!(proxy.wait().code(), Some(0));
+    assert!(proxy.log().contains("All connections closed"));
+}
+
+#[test]
+fn exits_with_an_error_when_shutdown_timeout_closed_connections() {
+    let echo = common::echo_server("127.0.0.1");
+    let mut proxy = Proxy::start("shutdown_timeout = 200ms");
+    let _relay = common::connect_through(proxy.addr, Dest::Addr(echo));
+    assert_eq!(proxy.stop().code(), Some(1));
+    assert!(proxy.log().contains("Closed 1 connections still open after 200ms"), "{}", proxy.log());
+}
@@ -0,0 +1,88 @@
+// [hosts]: pinned names never reach the resolver, and give every candidate address
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{Read, Write};
+use std::process::Command;
+use std::sync::atomic::Ordering;
+
+// A ping through the proxy to name, and the address the close summary says it was resolved to
+fn ping(proxy: &mut Proxy, name: &str, port: u16, count: usize) -> String {
+    let mut stream = common::connect_through(proxy.addr, Dest::Name(name, port));
+    stream.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    stream.read_exact(&mut pong).unwrap();
+    drop(stream);
+    let log = proxy.wait_for_count("Connection closed for", count);
+    let summary = log.lines().filter(|line| line.contains("Connection closed for")).nth(count - 1).unwrap();
+    summary.split("resolved=").nth(1).unwrap().split(' ').next().unwrap().to_string()
+}
+
+// The reply to a CONNECT to name
+fn reply(proxy: &Proxy, name: &str, port: u16) -> u8 {
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0x01, Dest::Name(name, port));
+    common::reply(&mut stream)[1]
+}
+
+#[test]
+fn exact_names_skip_the_resolver() {
+    let echo = common::echo_server("127.0.0.1");
+    let (dns, questions) = common::dns_server(Vec::new());
+    let mut proxy = Proxy::start(&format!("dns_servers = {dns}\n[hosts]\ninternal.test = 127.0.0.1\n"));
+    assert_eq!(ping(&mut proxy, "internal.test", echo.port(), 1), echo.to_string());
+    // Names are matched the way clients send them, in any case and fully qualified
+    assert_eq!(ping(&mut proxy, "Internal.TEST.", echo.port(), 2), echo.to_string());
+    assert_eq!(questions.load(Ordering::Relaxed), 0);
+    // Other names still go to the resolver
+    assert_eq!(reply(&proxy, "other.test", echo.port()), 0x04);
+    assert!(questions.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn wildcards_match_subdomains_only() {
+    let echo = common::echo_server("127.0.0.1");
+    let (dns, questions) = common::dns_server(Vec::new());
+    let mut proxy = Proxy::start(&format!("dns_servers = {dns}\n[hosts]\n*.corp.test = 127.0.0.1\n"));
+    assert_eq!(ping(&mut proxy, "a.corp.test", echo.port(), 1), echo.to_string());
+    assert_eq!(ping(&mut proxy, "b.a.corp.test", echo.port(), 2), echo.to_string());
+    assert_eq!(questions.load(Ordering::Relaxed), 0);
+    assert_eq!(reply(&proxy, "corp.test", echo.port()), 0x04);
+}
+
+#[test]
+fn every_address_is_a_candidate() {
+    let echo = common::echo_server("127.0.0.1");
+    // Nothing listens on the first one
+    let mut proxy = Proxy::start("log_level = debug\n[hosts]\nmulti.test = 127.0.0.3, 127.0.0.1\n");
+    assert_eq!(ping(&mut proxy, "multi.test", echo.port(), 1), echo.to_string());
+    proxy.wait_for(&format!("Connect attempt to 127.0.0.3:{} failed", echo.port()));
+}
+
+#[test]
+fn reloaded_on_sighup() {
+    let echo = common::echo_server("127.0.0.1");
+    let (dns, questions) = common::dns_server(Vec::new());
+    let mut proxy = Proxy::start(&format!("dns_servers = {dns}\n"));
+    assert_eq!(reply(&proxy, "late.test", echo.port()), 0x04);
+    let config = proxy.dir.join("config.ini");
+    let text = std::fs::read_to_string(&config).unwrap();
+    std::fs::write(&config, format!("{text}[hosts]\nlate.test = 127.0.0.1\n")).unwrap();
+    proxy.signal(libc::SIGHUP);
+    proxy.wait_for("Reloaded config");
+    let asked = questions.load(Ordering::Relaxed);
+    assert_eq!(ping(&mut proxy, "late.test", echo.port(), 2), echo.to_string());
+    assert_eq!(questions.load(Ordering::Relaxed), asked);
+}
+
+#[test]
+fn shown_by_print_config() {
+    let dir = common::temp_dir("hosts");
+    let path = dir.join("config.ini");
+    std::fs::write(&path, "[config]\n[hosts]\nb.test = 192.0.2.1, 2001:db8::1\n*.corp.test = 192.0.2.2\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rock5")).arg("--config").arg(&path).arg("--print-config").env_remove("ROCK5_CONFIG").output().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(text.contains("[hosts]\n*.corp.test = 192.0.2.2\nb.test = 192.0.2.1, 2001:db8::1\n"), "{text}");
+}
@@ -0,0 +1,50 @@
+// Listening on the wildcard hosts: one dual-stack socket reaches handle_client over both families
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+
+// A ping to echo through the proxy at proxy
+fn ping(proxy: SocketAddr, echo: SocketAddr) {
+    let mut stream = common::connect_through(proxy, Dest::Addr(echo));
+    stream.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    stream.read_exact(&mut pong).unwrap();
+    assert_eq!(&pong, b"ping");
+}
+
+#[test]
+fn dual_stack_reaches_both_families() {
+    let echo = common::echo_server("127.0.0.1");
+    let mut proxy = Proxy::start("listen = [::]:0\nlisten_family = dual\n");
+    assert_eq!(proxy.sockets.len(), 1, "{:?}", proxy.sockets);
+    let port = proxy.addr.port();
+    ping(SocketAddr::from((Ipv4Addr::LOCALHOST, port)), echo);
+    ping(SocketAddr::from((Ipv6Addr::LOCALHOST, port)), echo);
+    let log = proxy.wait_for_count("Connection closed for", 2);
+    assert!(log.contains("Accepted connection from: 127.0.0.1:"), "{log}");
+    assert!(log.contains("Accepted connection from: [::1]:"), "{log}");
+}
+
+#[test]
+fn ipv6_only() {
+    let echo = common::echo_server("127.0.0.1");
+    let proxy = Proxy::start("listen = [::]:0\nlisten_family = v6\n");
+    let port = proxy.addr.port();
+    ping(SocketAddr::from((Ipv6Addr::LOCALHOST, port)), echo);
+    assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err());
+}
+
+#[test]
+fn ipv6_host_and_port() {
+    let echo = common::echo_server("127.0.0.1");
+    let port = common::closed_port().port();
+    // An empty listen falls back to host and port
+    for host in ["::", "[::]"] {
+        let mut proxy = Proxy::start(&format!("listen =\nhost = {host}\nport = {port}\n"));
+        proxy.wait_for(&format!("Listening on \"[::]:{port}\""));
+        ping(SocketAddr::from((Ipv4Addr::LOCALHOST, port)), echo);
+        ping(SocketAddr::from((Ipv6Addr::LOCALHOST, port)), echo);
+    }
+}
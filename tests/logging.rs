@@ -0,0 +1,72 @@
+// Console log lines: timestamps by default, none when stderr is the journal
+mod common;
+
+use common::Proxy;
+use std::os::unix::fs::MetadataExt;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+// The first line of that start, e.g. "2026-10-14T09:12:01.123456Z  -> Reading config from …"
+fn first_line(proxy: &mut Proxy) -> String {
+    proxy.wait_for("Reading config from")
+}
+
+fn has_rfc3339(line: &str) -> bool {
+    let stamp = line.split(' ').next().unwrap_or_default();
+    stamp.len() == 27 && stamp.as_bytes()[10] == b'T' && stamp.ends_with('Z')
+}
+
+#[test]
+fn piped_output_gets_rfc3339_timestamps() {
+    let mut proxy = Proxy::start("");
+    let line = first_line(&mut proxy);
+    assert!(has_rfc3339(&line), "{line:?}");
+    assert!(has_rfc3339(&proxy.wait_for("Listening on")));
+}
+
+#[test]
+fn none_leaves_them_out() {
+    let mut proxy = Proxy::start("log_timestamps = none");
+    assert!(proxy.wait_for("Listening on").starts_with(" -> "));
+}
+
+// Output to a file that JOURNAL_STREAM names, as systemd sets it up for the journal
+fn journal_log(journal_stream: impl FnOnce(&std::fs::Metadata) -> String) -> String {
+    let dir = common::temp_dir("journal");
+    let config = dir.join("config.ini");
+    std::fs::write(&config, "[config]\nlisten = 127.0.0.1:0\n").unwrap();
+    let path = dir.join("out.log");
+    let out = std::fs::File::create(&path).unwrap();
+    let stream = journal_stream(&out.metadata().unwrap());
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rock5"))
+        .arg("--config").arg(&config)
+        .env("JOURNAL_STREAM", stream)
+        .stdin(Stdio::null()).stdout(out.try_clone().unwrap()).stderr(out)
+        .spawn().unwrap();
+    let deadline = Instant::now() + common::TIMEOUT;
+    let log = loop {
+        let log = std::fs::read_to_string(&path).unwrap();
+        if log.contains("Listening on") || Instant::now() > deadline {
+            break log;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    let _ = child.kill();
+    let _ = child.wait();
+    std::fs::remove_dir_all(&dir).unwrap();
+    log
+}
+
+#[test]
+fn the_journal_gets_no_timestamps() {
+    let log = journal_log(|meta| format!("{}:{}", meta.dev(), meta.ino()));
+    assert!(log.contains("Listening on"), "{log}");
+    assert!(log.lines().all(|line| line.starts_with(" -> ") || line.starts_with("conn")), "{log}");
+}
+
+#[test]
+fn another_journal_stream_is_not_the_journal() {
+    let log = journal_log(|meta| format!("{}:{}", meta.dev(), meta.ino() + 1));
+    assert!(log.contains("Listening on"), "{log}");
+    assert!(log.lines().all(has_rfc3339), "{log}");
+}
@@ -0,0 +1,78 @@
+// outbound_ipv4 / outbound_ipv6: targets are only reached over the enabled families
+mod common;
+
+use common::{Dest, Proxy, Record};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// The reply to a CONNECT to dest, the proxy closes after a failure
+fn connect(proxy: &Proxy, dest: Dest) -> Vec<u8> {
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0x01, dest);
+    common::reply(&mut stream)
+}
+
+#[test]
+fn dual_stack_name_without_ipv6() {
+    let echo = common::echo_server("127.0.0.1");
+    // Something on ::1 with the same port is preferred, if it may be used
+    let six = std::net::TcpListener::bind(("::1", echo.port())).ok();
+    let (dns, _) = common::dns_server(vec![("dual.test", Record::Aaaa(Ipv6Addr::LOCALHOST)), ("dual.test", Record::A(Ipv4Addr::LOCALHOST))]);
+    let mut proxy = Proxy::start(&format!("dns_servers = {dns}\naddress_family = prefer-ipv6\noutbound_ipv6 = false\n"));
+    let mut stream = common::connect_through(proxy.addr, Dest::Name("dual.test", echo.port()));
+    stream.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    stream.read_exact(&mut pong).unwrap();
+    drop(stream);
+    let summary = proxy.wait_for("Connection closed for");
+    assert!(summary.contains(&format!("resolved={echo}")), "{summary}");
+    if let Some(six) = six {
+        six.set_nonblocking(true).unwrap();
+        assert!(six.accept().is_err(), "connected over IPv6");
+    }
+}
+
+#[test]
+fn ipv6_only_name_without_ipv6() {
+    let (dns, _) = common::dns_server(vec![("six.test", Record::Aaaa(Ipv6Addr::LOCALHOST))]);
+    let mut proxy = Proxy::start(&format!("dns_servers = {dns}\noutbound_ipv6 = false\n"));
+    assert_eq!(connect(&proxy, Dest::Name("six.test", 80)), [0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    proxy.wait_for("Could not resolve target address to an allowed family");
+}
+
+#[test]
+fn literals_of_a_disabled_family() {
+    let six = SocketAddr::from((Ipv6Addr::LOCALHOST, 80));
+    let proxy = Proxy::start("outbound_ipv6 = false\n");
+    let mut reply = vec![0x05, 0x08, 0x00, 0x04];
+    reply.extend_from_slice(&[0; 18]);
+    assert_eq!(connect(&proxy, Dest::Addr(six)), reply);
+    // Also when sent as a name
+    assert_eq!(connect(&proxy, Dest::Name("[::1]", 80)), reply);
+    let proxy = Proxy::start("outbound_ipv4 = false\n");
+    assert_eq!(connect(&proxy, Dest::Addr(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)))), [0x05, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn dual_stack_name_without_ipv4() {
+    let echo = common::echo_server("::1");
+    // The same on 127.0.0.1
+    let _four = std::net::TcpListener::bind(("127.0.0.1", echo.port())).ok();
+    let (dns, _) = common::dns_server(vec![("dual.test", Record::A(Ipv4Addr::LOCALHOST)), ("dual.test", Record::Aaaa(Ipv6Addr::LOCALHOST))]);
+    let mut proxy = Proxy::start(&format!("dns_servers = {dns}\naddress_family = prefer-ipv4\noutbound_ipv4 = false\n"));
+    drop(common::connect_through(proxy.addr, Dest::Name("dual.test", echo.port())));
+    let summary = proxy.wait_for("Connection closed for");
+    assert!(summary.contains(&format!("resolved={echo}")), "{summary}");
+}
+
+#[test]
+fn literals_of_the_other_family_with_only() {
+    let proxy = Proxy::start("address_family = only-ipv4\n");
+    let mut reply = vec![0x05, 0x08, 0x00, 0x04];
+    reply.extend_from_slice(&[0; 18]);
+    assert_eq!(connect(&proxy, Dest::Addr(SocketAddr::from((Ipv6Addr::LOCALHOST, 80)))), reply);
+    let proxy = Proxy::start("address_family = only-ipv6\n");
+    assert_eq!(connect(&proxy, Dest::Addr(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)))), [0x05, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(connect(&proxy, Dest::Name("127.0.0.1", 80)), [0x05, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+}
@@ -0,0 +1,61 @@
+// log_privacy: no destination or client address makes it into the log under partial or full
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{Read, Write};
+
+// 127.0.0.2 is only ever a target, the proxy listens on 127.0.0.1
+fn connections(privacy: &str) -> (String, Vec<String>) {
+    let echo = common::echo_server("127.0.0.2");
+    let mut proxy = Proxy::start(&format!(
+        "log_level = debug\nlog_privacy = {privacy}\noutbound_ipv6 = false\n[hosts]\necho.test = 127.0.0.2\nxn--bcher-kva.test = 127.0.0.2\n"
+    ));
+    let mut clients = Vec::new();
+    for dest in [Dest::Addr(echo), Dest::Name("echo.test", echo.port()), Dest::Name("bücher.test", echo.port())] {
+        let mut stream = common::connect_through(proxy.addr, dest);
+        clients.push(stream.local_addr().unwrap().to_string());
+        stream.write_all(b"ping").unwrap();
+        let mut pong = [0u8; 4];
+        stream.read_exact(&mut pong).unwrap();
+        assert_eq!(&pong, b"ping");
+    }
+    // A target that refuses
+    let refused = common::closed_port();
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0x01, Dest::Addr(refused));
+    assert_eq!(common::reply(&mut stream)[1], 0x05);
+    drop(stream);
+    let log = proxy.wait_for_count("Connection closed for", clients.len() + 1);
+    clients.push(refused.to_string());
+    (log, clients)
+}
+
+fn assert_hidden(log: &str, clients: &[String]) {
+    for raw in ["127.0.0.2", "echo.test", "bücher", "xn--bcher"].iter().map(|raw| raw.to_string()).chain(clients.iter().cloned()) {
+        assert!(!log.contains(&raw), "{raw} in the log:\n{log}");
+    }
+}
+
+#[test]
+fn partial_hides_addresses_and_names() {
+    let (log, clients) = connections("partial");
+    assert_hidden(&log, &clients);
+    assert!(log.contains("127.0.0.0/24"), "{log}");
+}
+
+#[test]
+fn full_hides_addresses_and_names() {
+    let (log, clients) = connections("full");
+    assert_hidden(&log, &clients);
+    // Every line of a connection carries its token
+    assert!(log.contains("dest-1:"), "{log}");
+    assert!(log.lines().filter(|line| line.contains("Resolved") || line.contains("Mapped")).all(|line| line.contains("dest-")), "{log}");
+}
+
+#[test]
+fn none_shows_them() {
+    let (log, _) = connections("none");
+    assert!(log.contains("127.0.0.2"), "{log}");
+    assert!(log.contains("echo.test"), "{log}");
+}
@@ -0,0 +1,101 @@
+// The relay: data both ways, a half-close from either side reaches the other, byte totals and close reasons.
+// With --features splice the relays go through splice(2) instead
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+
+// A target that runs serve on each connection
+fn target(serve: fn(TcpStream)) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            thread::spawn(move || serve(stream));
+        }
+    });
+    addr
+}
+
+// Everything up to EOF
+fn read_all(stream: &mut TcpStream) -> Vec<u8> {
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).unwrap();
+    data
+}
+
+// The value of field in the close summary, e.g. bytes_sent=12
+fn summary_field(line: &str, field: &str) -> String {
+    let start = line.find(&format!("{field}=")).unwrap_or_else(|| panic!("no {field} in {line}")) + field.len() + 1;
+    line[start..].split(' ').next().unwrap().trim_matches('"').to_string()
+}
+
+#[test]
+fn client_closes_first() {
+    // Reads to the end, then answers with the count and closes
+    let target = target(|mut stream| {
+        let got = read_all(&mut stream);
+        stream.write_all(format!("got {}", got.len()).as_bytes()).unwrap();
+    });
+    let mut proxy = Proxy::start("");
+    let mut stream = common::connect_through(proxy.addr, Dest::Addr(target));
+    stream.write_all(&[1u8; 100_000]).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(read_all(&mut stream), b"got 100000");
+    let line = proxy.wait_for("Connection closed for");
+    assert_eq!(summary_field(&line, "bytes_sent"), "100000", "{line}");
+    assert_eq!(summary_field(&line, "bytes_received"), "10", "{line}");
+    assert_eq!(summary_field(&line, "close_reason"), "client_eof", "{line}");
+}
+
+#[test]
+fn target_closes_first() {
+    static GOT: std::sync::Mutex<Option<mpsc::Sender<Vec<u8>>>> = std::sync::Mutex::new(None);
+    let (tx, rx) = mpsc::channel();
+    *GOT.lock().unwrap() = Some(tx);
+    // Sends a banner and closes its side, then takes what comes until the client closes
+    let target = target(|mut stream| {
+        stream.write_all(&[2u8; 50_000]).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let got = read_all(&mut stream);
+        GOT.lock().unwrap().as_ref().unwrap().send(got).unwrap();
+    });
+    let mut proxy = Proxy::start("");
+    let mut stream = common::connect_through(proxy.addr, Dest::Addr(target));
+    assert_eq!(read_all(&mut stream).len(), 50_000);
+    // Still open the other way
+    stream.write_all(b"after the target's EOF").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(rx.recv_timeout(common::TIMEOUT).unwrap(), b"after the target's EOF");
+    let line = proxy.wait_for("Connection closed for");
+    assert_eq!(summary_field(&line, "bytes_sent"), "22", "{line}");
+    assert_eq!(summary_field(&line, "bytes_received"), "50000", "{line}");
+    assert_eq!(summary_field(&line, "close_reason"), "target_eof", "{line}");
+}
+
+#[test]
+fn both_ways_at_once() {
+    let echo = common::echo_server("127.0.0.1");
+    let mut proxy = Proxy::start("");
+    let mut stream = common::connect_through(proxy.addr, Dest::Addr(echo));
+    let data: Vec<u8> = (0..4_000_000u32).map(|i| (i % 251) as u8).collect();
+    let mut writer = stream.try_clone().unwrap();
+    let sent = data.clone();
+    // More than the socket buffers hold, so both directions are in flight together
+    let writing = thread::spawn(move || {
+        writer.write_all(&sent).unwrap();
+        writer.shutdown(Shutdown::Write).unwrap();
+    });
+    let echoed = read_all(&mut stream);
+    writing.join().unwrap();
+    assert!(echoed == data, "{} bytes back of {}", echoed.len(), data.len());
+    let line = proxy.wait_for("Connection closed for");
+    assert_eq!(summary_field(&line, "bytes_sent"), "4000000", "{line}");
+    assert_eq!(summary_field(&line, "bytes_received"), "4000000", "{line}");
+    // The echo server closes once it saw the client's EOF
+    assert_eq!(summary_field(&line, "close_reason"), "client_eof", "{line}");
+}
@@ -0,0 +1,129 @@
+// The request stage: replies to what the proxy can't or won't do, byte for byte
+mod common;
+
+use common::{Dest, Proxy, Record};
+use std::io::Write;
+use std::net::SocketAddr;
+
+// Only VER CMD RSV ATYP, the proxy answers before it reads DST.ADDR
+fn header(proxy: &Proxy, cmd: u8, atyp: u8) -> Vec<u8> {
+    let mut stream = common::client(proxy.addr);
+    assert_eq!(common::greet(&mut stream, &[0x00]), [0x05, 0x00]);
+    stream.write_all(&[0x05, cmd, 0x00, atyp]).unwrap();
+    let reply = common::reply(&mut stream);
+    assert!(common::closed(&mut stream));
+    reply
+}
+
+#[test]
+fn unsupported_command() {
+    let proxy = Proxy::start("");
+    // BIND (0x02) is served, 0x04 never was and the Tor commands need tor_resolve
+    for cmd in [0x04, 0xF0, 0xF1] {
+        assert_eq!(header(&proxy, cmd, 0x01), [0x05, 0x07, 0x00, 0x01, 0, 0, 0, 0, 0, 0], "{cmd:#x}");
+    }
+    let mut six = vec![0x05, 0x07, 0x00, 0x04];
+    six.extend_from_slice(&[0; 18]);
+    assert_eq!(header(&proxy, 0x04, 0x04), six);
+}
+
+#[test]
+fn unsupported_address_type() {
+    let proxy = Proxy::start("");
+    for cmd in [0x01, 0x02, 0x03] {
+        assert_eq!(header(&proxy, cmd, 0x05), [0x05, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0, 0], "{cmd:#x}");
+    }
+}
+
+#[test]
+fn invalid_domain_gets_a_failure_reply() {
+    let mut proxy = Proxy::start("");
+    for name in [&b"a\0b.test"[..], b"\xff.test", b"", b"xn--a.test"] {
+        let mut stream = common::client(proxy.addr);
+        common::greet(&mut stream, &[0x00]);
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, name.len() as u8];
+        request.extend_from_slice(name);
+        request.extend_from_slice(&80u16.to_be_bytes());
+        stream.write_all(&request).unwrap();
+        assert_eq!(common::reply(&mut stream), [0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0], "{name:?}");
+        assert!(common::closed(&mut stream));
+    }
+    proxy.wait_for("domain name is not valid UTF-8");
+}
+
+// CONNECT to name:80, the reply
+fn connect(proxy: &Proxy, name: &str) -> Vec<u8> {
+    connect_to(proxy, Dest::Name(name, 80))
+}
+
+// The same to any dest
+fn connect_to(proxy: &Proxy, dest: Dest) -> Vec<u8> {
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0x01, dest);
+    let reply = common::reply(&mut stream);
+    assert!(common::closed(&mut stream));
+    reply
+}
+
+#[test]
+fn unresolvable_names_get_host_unreachable() {
+    let (dns, _) = common::dns_server(Vec::new());
+    let mut proxy = Proxy::start(&format!("dns_servers = {dns}\n"));
+    assert_eq!(connect(&proxy, "nowhere.invalid"), [0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    proxy.wait_for("Could not resolve target address to an allowed family");
+}
+
+#[test]
+fn failing_servers_get_host_unreachable() {
+    let (dns, _) = common::dns_server(vec![("broken.test", Record::ServFail)]);
+    let mut proxy = Proxy::start(&format!("dns_servers = {dns}\n"));
+    assert_eq!(connect(&proxy, "broken.test"), [0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    proxy.wait_for("Could not resolve target address to an allowed family: broken.test:80");
+}
+
+#[test]
+fn resolver_errors_get_host_unreachable() {
+    // The system resolver fails for names it has no addresses for
+    let mut proxy = Proxy::start("");
+    assert_eq!(connect(&proxy, "nowhere.invalid"), [0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    proxy.wait_for("Could not resolve target address: nowhere.invalid:80 (");
+}
+
+
+#[test]
+fn refused_connects_get_zeros_for_bnd_addr() {
+    let closed = common::closed_port();
+    let closed_six = std::net::TcpListener::bind("[::1]:0").unwrap().local_addr().unwrap();
+    let proxy = Proxy::start("[hosts]\nclosed.test = 127.0.0.1\n");
+    let failure = |dest| connect_to(&proxy, dest);
+    // Not the target, nor its family when the request wasn't IPv6
+    let four = [0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+    assert_eq!(failure(Dest::Addr(closed)), four);
+    assert_eq!(failure(Dest::Name("closed.test", closed.port())), four);
+    let mut six = vec![0x05, 0x05, 0x00, 0x04];
+    six.extend_from_slice(&[0; 18]);
+    assert_eq!(failure(Dest::Addr(closed_six)), six);
+}
+
+#[test]
+fn connects_to_the_proxy_itself_are_not_allowed() {
+    let mut proxy = Proxy::start("[hosts]\nself.test = 127.0.0.1\n");
+    let port = proxy.addr.port();
+    let dests = [Dest::Addr(proxy.addr), Dest::Name("self.test", port), Dest::Addr(SocketAddr::from(([0, 0, 0, 0], port)))];
+    for dest in dests {
+        assert_eq!(connect_to(&proxy, dest), [0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    }
+    proxy.wait_for_count("requested connection to the proxy itself", 3);
+    // Nothing but the three clients connected
+    assert_eq!(proxy.log().matches("Accepted connection from").count(), 3, "{}", proxy.log());
+}
+
+#[test]
+fn loops_may_be_allowed() {
+    let mut proxy = Proxy::start("loop_protection = false\n");
+    let mut stream = common::connect_through(proxy.addr, Dest::Addr(proxy.addr));
+    // The proxy is the target now, greeting itself through itself
+    assert_eq!(common::greet(&mut stream, &[0x00]), [0x05, 0x00]);
+    proxy.wait_for_count("Accepted connection from", 2);
+}
@@ -0,0 +1,86 @@
+// tor_resolve: RESOLVE and RESOLVE_PTR go through the configured resolver
+mod common;
+
+use common::{Dest, Proxy, Record};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+
+const RESOLVE: u8 = 0xF0;
+const RESOLVE_PTR: u8 = 0xF1;
+
+// The reply to RESOLVE of name, sent to proxy
+fn resolve(proxy: SocketAddr, name: &str) -> Vec<u8> {
+    let mut stream = common::client(proxy);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, RESOLVE, Dest::Name(name, 0));
+    common::reply(&mut stream)
+}
+
+// The reply to RESOLVE_PTR of ip
+fn reverse(proxy: &Proxy, ip: [u8; 4]) -> Vec<u8> {
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, RESOLVE_PTR, Dest::Addr(SocketAddr::from((ip, 0))));
+    common::reply(&mut stream)
+}
+
+// VER REP RSV ATYP=3, the name and port 0
+fn named(name: &str) -> Vec<u8> {
+    let mut reply = vec![0x05, 0x00, 0x00, 0x03, name.len() as u8];
+    reply.extend_from_slice(name.as_bytes());
+    reply.extend_from_slice(&[0, 0]);
+    reply
+}
+
+#[test]
+fn ptr_from_hosts() {
+    let (dns, questions) = common::dns_server(Vec::new());
+    let proxy = Proxy::start(&format!("tor_resolve = true\ndns_servers = {dns}\n[hosts]\n*.box.test = 127.0.0.7\nbox.test = 127.0.0.7\n"));
+    assert_eq!(reverse(&proxy, [127, 0, 0, 7]), named("box.test"));
+    assert_eq!(questions.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn ptr_from_dns_servers() {
+    let (dns, questions) = common::dns_server(vec![("5.2.0.192.in-addr.arpa", Record::Ptr("stub.test"))]);
+    let proxy = Proxy::start(&format!("tor_resolve = true\ndns_servers = {dns}\n"));
+    assert_eq!(reverse(&proxy, [192, 0, 2, 5]), named("stub.test"));
+    assert!(questions.load(Ordering::Relaxed) > 0);
+    // NXDOMAIN
+    assert_eq!(reverse(&proxy, [192, 0, 2, 6])[1], 0x04);
+}
+
+#[test]
+fn listeners_share_the_cache() {
+    let (dns, questions) = common::dns_server(vec![("shared.test", Record::A(Ipv4Addr::new(192, 0, 2, 9)))]);
+    let (other_dns, other_questions) = common::dns_server(vec![("shared.test", Record::A(Ipv4Addr::new(192, 0, 2, 10)))]);
+    // Which listener logs first varies, so all of them get a port of their own
+    let (main, same, other) = (common::closed_port(), common::closed_port(), common::closed_port());
+    let mut proxy = Proxy::start(&format!(
+        "listen = {main}\ntor_resolve = true\ndns_servers = {dns}\noutbound_ipv6 = false\n[listener.same]\nlisten = {same}\nmax_connections_per_client = 8\n[listener.other]\nlisten = {other}\ndns_servers = {other_dns}\n"
+    ));
+    for name in ["default", "same", "other"] {
+        proxy.wait_for(&format!("as {name}:"));
+    }
+    let answer = [0x05, 0x00, 0x00, 0x01, 192, 0, 2, 9, 0, 0];
+    assert_eq!(resolve(main, "shared.test"), answer);
+    let asked = questions.load(Ordering::Relaxed);
+    assert!(asked > 0);
+    // From the cache [config] filled
+    assert_eq!(resolve(same, "shared.test"), answer);
+    assert_eq!(questions.load(Ordering::Relaxed), asked);
+    // Its own nameserver, its own cache
+    assert_eq!(resolve(other, "shared.test"), [0x05, 0x00, 0x00, 0x01, 192, 0, 2, 10, 0, 0]);
+    assert!(other_questions.load(Ordering::Relaxed) > 0);
+    assert_eq!(questions.load(Ordering::Relaxed), asked);
+}
+
+#[test]
+fn changed_answers_with_dns_cache_off() {
+    let (dns, records) = common::changing_dns_server(vec![("moving.test", Record::A(Ipv4Addr::new(192, 0, 2, 11)))]);
+    let proxy = Proxy::start(&format!("tor_resolve = true\ndns_servers = {dns}\noutbound_ipv6 = false\ndns_cache = off\n"));
+    assert_eq!(resolve(proxy.addr, "moving.test"), [0x05, 0x00, 0x00, 0x01, 192, 0, 2, 11, 0, 0]);
+    *records.lock().unwrap() = vec![("moving.test", Record::A(Ipv4Addr::new(192, 0, 2, 12)))];
+    // Neither dns_cache nor the resolver library keeps the first answer
+    assert_eq!(resolve(proxy.addr, "moving.test"), [0x05, 0x00, 0x00, 0x01, 192, 0, 2, 12, 0, 0]);
+}
@@ -0,0 +1,74 @@
+// bandwidth_limit and per_connection_limit: the throughput they allow, and that waiting for them is not idling
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn per_connection_limit_paces_a_transfer() {
+    let sink = common::sink_server("127.0.0.1");
+    let mut proxy = Proxy::start("per_connection_limit = 200KB/s");
+    let mut stream = common::connect_through(proxy.addr, Dest::Addr(sink));
+    let data = vec![7u8; 600_000];
+    let started = Instant::now();
+    assert_eq!(common::send_to_sink(&mut stream, &data), data.len() as u64);
+    let elapsed = started.elapsed();
+    // A tenth of a second worth passes at once
+    assert!(elapsed >= Duration::from_millis(2700), "600 KB at 200 KB/s took {elapsed:?}");
+    assert!(elapsed < Duration::from_secs(5), "600 KB at 200 KB/s took {elapsed:?}");
+    drop(stream);
+    proxy.wait_for("bytes_sent=600000");
+}
+
+// Data going out and coming back each have a bucket of their own, at the rate of the user
+#[test]
+fn user_limit_paces_an_echo() {
+    let echo = common::echo_server("127.0.0.1");
+    let mut proxy = Proxy::start("per_connection_limit = 2MB/s\n[users]\nalice = secret\n[user_limits]\nalice = 100KB/s");
+    let mut stream = common::client(proxy.addr);
+    assert_eq!(common::greet(&mut stream, &[0x02]), [0x05, 0x02]);
+    stream.write_all(b"\x01\x05alice\x06secret").unwrap();
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).unwrap();
+    assert_eq!(status, [0x01, 0x00]);
+    common::request(&mut stream, 0x01, Dest::Addr(echo));
+    assert_eq!(common::reply(&mut stream)[1], 0x00);
+
+    let data = vec![7u8; 300_000];
+    let started = Instant::now();
+    let mut writer = stream.try_clone().unwrap();
+    let sent = data.clone();
+    let writing = thread::spawn(move || writer.write_all(&sent).unwrap());
+    let mut echoed = vec![0u8; data.len()];
+    assert_eq!(common::read_fully(&mut stream, &mut echoed), data.len());
+    let elapsed = started.elapsed();
+    writing.join().unwrap();
+    assert!(echoed == data);
+    // Both ways at once, less the tenth of a second that passes at once
+    assert!(elapsed >= Duration::from_millis(2700), "300 KB echoed at 100 KB/s took {elapsed:?}");
+    assert!(elapsed < Duration::from_secs(5), "300 KB echoed at 100 KB/s took {elapsed:?}");
+    drop(stream);
+    proxy.wait_for("bytes_received=300000");
+}
+
+// Each relay waits its turn behind the others for longer than idle_timeout
+#[test]
+fn relays_waiting_for_bandwidth_are_not_idle() {
+    let sink = common::sink_server("127.0.0.1");
+    let mut proxy = Proxy::start("bandwidth_limit = 100KB/s\nidle_timeout = 1s");
+    let relays: Vec<_> = (0..16).map(|_| {
+        let addr = proxy.addr;
+        thread::spawn(move || {
+            let mut stream = common::connect_through(addr, Dest::Addr(sink));
+            common::send_to_sink(&mut stream, &[1u8; 24_000])
+        })
+    }).collect();
+    for relay in relays {
+        assert_eq!(relay.join().unwrap(), 24_000);
+    }
+    let log = proxy.wait_for_count("Connection closed for", 16);
+    assert!(!log.contains("Closing idle connection"), "{log}");
+    assert_eq!(log.matches("bytes_sent=24000 ").count(), 16, "{log}");
+}
@@ -0,0 +1,51 @@
+// statsd_addr: counters, gauges and timings in DogStatsD lines, connections tagged with their listener
+mod common;
+
+use common::{Dest, Proxy};
+use std::io::{Read, Write};
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+// Lines received until every one of wanted is among them, sent every 10 seconds
+fn lines_until(socket: &UdpSocket, wanted: &[&str]) -> Vec<String> {
+    let deadline = Instant::now() + Duration::from_secs(25);
+    let mut lines = Vec::new();
+    let mut buf = [0u8; 1500];
+    while !wanted.iter().all(|line| lines.iter().any(|got| got == line)) {
+        assert!(Instant::now() < deadline, "not all of {wanted:?} in {lines:#?}");
+        if let Ok(n) = socket.recv(&mut buf) {
+            lines.extend(String::from_utf8_lossy(&buf[..n]).lines().map(str::to_string));
+        }
+    }
+    lines
+}
+
+#[test]
+fn connections_are_counted_by_listener() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let echo = common::echo_server("127.0.0.1");
+    let proxy = Proxy::start(&format!("statsd_addr = {}\nstatsd_tags = env:test\n", socket.local_addr().unwrap()));
+
+    let mut stream = common::connect_through(proxy.addr, Dest::Addr(echo));
+    stream.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    stream.read_exact(&mut pong).unwrap();
+    drop(stream);
+    let mut refused = common::client(proxy.addr);
+    common::greet(&mut refused, &[0x00]);
+    common::request(&mut refused, 0x01, Dest::Addr(common::closed_port()));
+    assert_eq!(common::reply(&mut refused)[1], 0x05);
+    drop(refused);
+
+    let lines = lines_until(&socket, &[
+        "rock5.connections.accepted:2|c|#listener:default,env:test",
+        "rock5.replies:1|c|#listener:default,code:0,env:test",
+        "rock5.replies:1|c|#listener:default,code:5,env:test",
+        "rock5.failures:1|c|#listener:default,stage:connect,reason:refused,env:test",
+        "rock5.relayed_bytes:4|c|#listener:default,direction:sent,env:test",
+        "rock5.active_connections:0|g|#env:test",
+    ]);
+    assert!(lines.iter().any(|line| line.starts_with("rock5.connect:") && line.ends_with("|ms|#listener:default,code:0,env:test")), "{lines:#?}");
+    assert!(lines.iter().any(|line| line.starts_with("rock5.connections.closed:") && line.contains("|#listener:default,reason:")), "{lines:#?}");
+}
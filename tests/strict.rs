@@ -0,0 +1,96 @@
+// strict: clients must wait for each reply before they send the next stage
+mod common;
+
+use common::Proxy;
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::Duration;
+
+const USERS: &str = "[users]\nalice = secret\n";
+
+// A CONNECT request to target on 127.0.0.1
+fn connect_request(target: SocketAddr) -> Vec<u8> {
+    let mut bytes = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    bytes.extend_from_slice(&target.port().to_be_bytes());
+    bytes
+}
+
+// The subnegotiation of alice, followed by the request
+fn auth_and_request(target: SocketAddr) -> Vec<u8> {
+    let mut bytes = vec![0x01, 5];
+    bytes.extend_from_slice(b"alice");
+    bytes.push(6);
+    bytes.extend_from_slice(b"secret");
+    bytes.extend(connect_request(target));
+    bytes
+}
+
+// Whether anything connected to the listener by now
+fn connected(listener: &TcpListener) -> bool {
+    thread::sleep(Duration::from_millis(200));
+    listener.set_nonblocking(true).unwrap();
+    match listener.accept() {
+        Ok(_) => true,
+        Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+        Err(e) => panic!("accept: {e}"),
+    }
+}
+
+#[test]
+fn request_before_the_authentication_reply() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut proxy = Proxy::start(&format!("strict = true\n{USERS}"));
+    let mut stream = common::client(proxy.addr);
+    assert_eq!(common::greet(&mut stream, &[0x02]), [0x05, 0x02]);
+    stream.write_all(&auth_and_request(target.local_addr().unwrap())).unwrap();
+    let mut status = [0u8; 2];
+    assert_eq!(common::read_fully(&mut stream, &mut status), 2);
+    assert_eq!(status, [0x01, 0x01]);
+    assert!(common::closed(&mut stream));
+    proxy.wait_for("sent data before the authentication reply (after PASSWD)");
+    assert!(!connected(&target));
+}
+
+#[test]
+fn lenient_takes_it() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy = Proxy::start(USERS);
+    let mut stream = common::client(proxy.addr);
+    assert_eq!(common::greet(&mut stream, &[0x02]), [0x05, 0x02]);
+    stream.write_all(&auth_and_request(target.local_addr().unwrap())).unwrap();
+    let mut status = [0u8; 2];
+    assert_eq!(common::read_fully(&mut stream, &mut status), 2);
+    assert_eq!(status, [0x01, 0x00]);
+    assert_eq!(common::reply(&mut stream)[1], 0x00);
+}
+
+#[test]
+fn data_before_the_request_reply_is_refused_before_connecting() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut proxy = Proxy::start("strict = true\n");
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    // In one write, so the data is there once the proxy has read the request
+    let mut request = connect_request(target.local_addr().unwrap());
+    request.extend_from_slice(b"GET / HTTP/1.0\r\n\r\n");
+    stream.write_all(&request).unwrap();
+    assert_eq!(common::reply(&mut stream)[1], 0x01);
+    proxy.wait_for("sent data before the request reply (after DST.PORT)");
+    assert!(!connected(&target));
+}
+
+#[test]
+fn waiting_client_gets_through() {
+    let echo = common::echo_server("127.0.0.1");
+    let proxy = Proxy::start(&format!("strict = true\n{USERS}"));
+    let mut stream = common::client(proxy.addr);
+    assert_eq!(common::greet(&mut stream, &[0x02]), [0x05, 0x02]);
+    let request = auth_and_request(echo);
+    stream.write_all(&request[..14]).unwrap();
+    let mut status = [0u8; 2];
+    assert_eq!(common::read_fully(&mut stream, &mut status), 2);
+    assert_eq!(status, [0x01, 0x00]);
+    stream.write_all(&request[14..]).unwrap();
+    assert_eq!(common::reply(&mut stream)[1], 0x00);
+}
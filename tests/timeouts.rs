@@ -0,0 +1,60 @@
+// connect_timeout: a target that never answers gets a reply in time, not a handshake timeout
+mod common;
+
+use common::{Dest, Proxy, Record};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+#[test]
+fn connect_timeout_shorter_than_the_handshake() {
+    let blackhole = common::blackhole();
+    let metrics = common::closed_port();
+    let mut proxy = Proxy::start(&format!("metrics_listen = {metrics}\nconnect_timeout = 500ms\nhandshake_timeout = 10s\n"));
+    proxy.wait_for("Serving metrics");
+    let started = Instant::now();
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0x01, Dest::Addr(blackhole.addr));
+    let reply = common::reply(&mut stream);
+    assert!(matches!(reply[1], 0x04 | 0x06), "{reply:?}");
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(500) && elapsed < Duration::from_secs(5), "answered after {elapsed:?}");
+    proxy.wait_for("Timed out connecting to target");
+    let body = common::http_response(metrics, "/metrics");
+    assert!(body.contains("rock5_connect_seconds_count{code=\"timeout\"} 1"), "{body}");
+}
+
+#[test]
+fn connect_timeout_longer_than_the_handshake() {
+    let blackhole = common::blackhole();
+    // Covers method selection and the request only
+    let proxy = Proxy::start("connect_timeout = 2s\nhandshake_timeout = 500ms\n");
+    let started = Instant::now();
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0x01, Dest::Addr(blackhole.addr));
+    let reply = common::reply(&mut stream);
+    assert!(matches!(reply[1], 0x04 | 0x06), "{reply:?}");
+    assert!(started.elapsed() >= Duration::from_secs(2), "answered after {:?}", started.elapsed());
+    assert!(!proxy.log().contains("did not complete the handshake"), "{}", proxy.log());
+}
+
+#[test]
+fn slow_lookups_for_resolve_are_answered() {
+    let (dns, _) = common::slow_dns_server(vec![("slow.test", Record::A(Ipv4Addr::new(192, 0, 2, 9)))], Duration::from_millis(800));
+    let proxy = Proxy::start(&format!("tor_resolve = true\ndns_servers = {dns}\noutbound_ipv6 = false\nhandshake_timeout = 300ms\n"));
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0xF0, Dest::Name("slow.test", 0));
+    assert_eq!(common::reply(&mut stream), [0x05, 0x00, 0x00, 0x01, 192, 0, 2, 9, 0, 0]);
+}
+
+#[test]
+fn handshake_timeout_still_applies_to_the_client() {
+    let mut proxy = Proxy::start("handshake_timeout = 300ms\n");
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    // No request follows
+    assert!(common::closed(&mut stream));
+    proxy.wait_for("did not complete the handshake within 300ms");
+}
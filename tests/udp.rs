@@ -0,0 +1,69 @@
+// UDP ASSOCIATE: datagrams relayed with their header, for as long as the TCP connection lasts
+mod common;
+
+use common::{Dest, Proxy};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+
+// Answers every datagram with its payload
+fn udp_echo() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        while let Ok((n, from)) = socket.recv_from(&mut buf) {
+            let _ = socket.send_to(&buf[..n], from);
+        }
+    });
+    addr
+}
+
+// The controlling connection, a client socket and where the proxy relays from
+fn associate(proxy: &Proxy) -> (TcpStream, UdpSocket, SocketAddr) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    let mut stream = common::client(proxy.addr);
+    common::greet(&mut stream, &[0x00]);
+    common::request(&mut stream, 0x03, Dest::Addr(socket.local_addr().unwrap()));
+    let reply = common::reply(&mut stream);
+    assert_eq!(&reply[..4], &[0x05, 0x00, 0x00, 0x01], "{reply:?}");
+    let port = u16::from_be_bytes([reply[8], reply[9]]);
+    (stream, socket, SocketAddr::new(proxy.addr.ip(), port))
+}
+
+// RSV FRAG ATYP=1 DST.ADDR DST.PORT, then the payload
+fn datagram(to: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let SocketAddr::V4(to) = to else { unreachable!() };
+    let mut datagram = vec![0x00, 0x00, 0x00, 0x01];
+    datagram.extend_from_slice(&to.ip().octets());
+    datagram.extend_from_slice(&to.port().to_be_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+#[test]
+fn relayed_with_the_header() {
+    let proxy = Proxy::start("");
+    let echo = udp_echo();
+    let (_stream, socket, relay) = associate(&proxy);
+    socket.send_to(&datagram(echo, b"ping"), relay).unwrap();
+    let mut buf = [0u8; 1500];
+    let (n, from) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(from, relay);
+    assert_eq!(&buf[..n], datagram(echo, b"ping"));
+}
+
+#[test]
+fn unreachable_peers_keep_the_association() {
+    let proxy = Proxy::start("");
+    let echo = udp_echo();
+    let closed = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (_stream, socket, relay) = associate(&proxy);
+    let mut buf = [0u8; 1500];
+    for round in 0..3 {
+        // ICMP port unreachable comes back for these, some systems fail the next recv_from with it
+        socket.send_to(&datagram(closed, b"lost"), relay).unwrap();
+        socket.send_to(&datagram(echo, &[round]), relay).unwrap();
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], datagram(echo, &[round]));
+    }
+}